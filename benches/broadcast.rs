@@ -0,0 +1,44 @@
+// broadcast - what it costs a room to send a message out to its members,
+// one at a time and in bursts that go out as batches
+//
+//     cargo bench --bench broadcast
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use yee::bench::Room;
+
+const BODY: &str = "Deploy finished, everything is green";
+
+fn fan_out(c: &mut Criterion) {
+    let mut group = c.benchmark_group("fan_out");
+    for members in [10, 100, 1000] {
+        group.throughput(Throughput::Elements(members as u64));
+        group.bench_with_input(
+            BenchmarkId::from_parameter(members),
+            &members,
+            |b, &members| {
+                let mut room = Room::with_members(members);
+                b.iter(|| room.post(BODY, 1));
+            },
+        );
+    }
+    group.finish();
+}
+
+fn burst(c: &mut Criterion) {
+    let mut group = c.benchmark_group("burst");
+    for messages in [1, 10, 50] {
+        group.throughput(Throughput::Elements(messages as u64));
+        group.bench_with_input(
+            BenchmarkId::from_parameter(messages),
+            &messages,
+            |b, &messages| {
+                let mut room = Room::with_members(100);
+                b.iter(|| room.post(BODY, messages));
+            },
+        );
+    }
+    group.finish();
+}
+
+criterion_group!(benches, fan_out, burst);
+criterion_main!(benches);
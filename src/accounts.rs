@@ -0,0 +1,258 @@
+use serde::Serialize;
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+use uuid::Uuid;
+use warp::http::StatusCode;
+use warp::{Filter, Rejection, Reply};
+use xtra::prelude::*;
+
+use crate::connections::{Close, Connections};
+use crate::identity::{self, Signer};
+use crate::preferences::{ForgetPreferences, Preferences};
+use crate::registry::{AllRooms, PurgeArchived, RoomRegistry};
+use crate::services::Services;
+use crate::{metrics, now_millis, PurgeMessages};
+
+// How often deactivated ids are checked for being due a purge
+const SWEEP_EVERY: Duration = Duration::from_secs(60 * 60);
+
+// Deactivation - when a user id was deactivated, and when it's purged
+#[derive(Clone, Copy, Serialize)]
+pub struct Deactivation {
+    pub id: Uuid,
+    // Unix millis
+    pub deactivated_at: u64,
+    pub purge_at: u64,
+}
+
+// Accounts - user ids that have been deactivated. They can't connect, and
+// once their window is up their messages are purged from every room and
+// the id is forgotten, unless it's reactivated first.
+pub struct Accounts {
+    window: Duration,
+    deactivated: HashMap<Uuid, Deactivation>,
+    services: Services,
+}
+impl Actor for Accounts {}
+impl Accounts {
+    pub fn new(window: Duration, services: Services) -> Self {
+        Self {
+            window,
+            deactivated: HashMap::new(),
+            services,
+        }
+    }
+}
+
+// Deactivate - closes a user id's socket and keeps it out. Deactivating
+// again leaves the window as it was.
+pub struct Deactivate(pub Uuid);
+impl Message for Deactivate {
+    type Result = Deactivation;
+}
+#[async_trait::async_trait]
+impl Handler<Deactivate> for Accounts {
+    async fn handle(&mut self, msg: Deactivate, _ctx: &mut Context<Self>) -> Deactivation {
+        let _timer = metrics::timer("deactivate");
+        let now_ms = now_millis();
+        let window = self.window.as_millis() as u64;
+        let deactivation = *self.deactivated.entry(msg.0).or_insert(Deactivation {
+            id: msg.0,
+            deactivated_at: now_ms,
+            purge_at: now_ms + window,
+        });
+        if self
+            .services
+            .get::<Connections>()
+            .do_send(Close(msg.0))
+            .is_err()
+        {
+            log::warn!("Could not close the connections of {}", msg.0);
+        }
+        log::info!("Deactivated {}", msg.0);
+        deactivation
+    }
+}
+
+// Reactivate - lets a deactivated id back in; false if it wasn't
+pub struct Reactivate(pub Uuid);
+impl Message for Reactivate {
+    type Result = bool;
+}
+#[async_trait::async_trait]
+impl Handler<Reactivate> for Accounts {
+    async fn handle(&mut self, msg: Reactivate, _ctx: &mut Context<Self>) -> bool {
+        let _timer = metrics::timer("reactivate");
+        let reactivated = self.deactivated.remove(&msg.0).is_some();
+        if reactivated {
+            log::info!("Reactivated {}", msg.0);
+        }
+        reactivated
+    }
+}
+
+// GetDeactivation - whether an id is deactivated, and till when
+pub struct GetDeactivation(pub Uuid);
+impl Message for GetDeactivation {
+    type Result = Option<Deactivation>;
+}
+#[async_trait::async_trait]
+impl Handler<GetDeactivation> for Accounts {
+    async fn handle(
+        &mut self,
+        msg: GetDeactivation,
+        _ctx: &mut Context<Self>,
+    ) -> Option<Deactivation> {
+        let _timer = metrics::timer("get_deactivation");
+        self.deactivated.get(&msg.0).copied()
+    }
+}
+
+// ListDeactivated - every deactivated id, by id
+pub struct ListDeactivated;
+impl Message for ListDeactivated {
+    type Result = Vec<Deactivation>;
+}
+#[async_trait::async_trait]
+impl Handler<ListDeactivated> for Accounts {
+    async fn handle(
+        &mut self,
+        _msg: ListDeactivated,
+        _ctx: &mut Context<Self>,
+    ) -> Vec<Deactivation> {
+        let _timer = metrics::timer("list_deactivated");
+        let mut deactivated: Vec<_> = self.deactivated.values().copied().collect();
+        deactivated.sort_by_key(|deactivation| deactivation.id);
+        deactivated
+    }
+}
+
+// Sweep - purges the ids whose window is up
+struct Sweep;
+impl Message for Sweep {
+    type Result = ();
+}
+#[async_trait::async_trait]
+impl Handler<Sweep> for Accounts {
+    async fn handle(&mut self, _msg: Sweep, _ctx: &mut Context<Self>) {
+        let _timer = metrics::timer("sweep");
+        let now_ms = now_millis();
+        let due: Vec<Uuid> = self
+            .deactivated
+            .values()
+            .filter(|deactivation| deactivation.purge_at <= now_ms)
+            .map(|deactivation| deactivation.id)
+            .collect();
+        if due.is_empty() {
+            return;
+        }
+        let rooms = match self.services.get::<RoomRegistry>().send(AllRooms).await {
+            Ok(rooms) => rooms,
+            Err(_) => {
+                log::warn!("Could not reach the registry to purge");
+                return;
+            }
+        };
+        for id in due {
+            let mut deleted = 0;
+            for (_, room) in &rooms {
+                let purged = room
+                    .send(PurgeMessages {
+                        last: None,
+                        from: Some(id),
+                    })
+                    .await;
+                // A room removed since it was listed takes its messages with it
+                deleted += purged.unwrap_or(0);
+            }
+            match self
+                .services
+                .get::<RoomRegistry>()
+                .send(PurgeArchived(id))
+                .await
+            {
+                Ok(purged) => deleted += purged,
+                Err(_) => log::warn!("Could not purge archived rooms of {}", id),
+            }
+            if self
+                .services
+                .get::<Preferences>()
+                .send(ForgetPreferences(id))
+                .await
+                .is_err()
+            {
+                log::warn!("Could not forget the preferences of {}", id);
+            }
+            self.deactivated.remove(&id);
+            log::info!("Purged {} and their {} messages", id, deleted);
+        }
+    }
+}
+
+// Sends Sweep every SWEEP_EVERY, until Accounts stops
+pub async fn sweep(accounts: Address<Accounts>) {
+    loop {
+        tokio::time::sleep(SWEEP_EVERY).await;
+        if accounts.send(Sweep).await.is_err() {
+            return;
+        }
+    }
+}
+
+// POST /users/me/deactivate and /users/me/reactivate, for the browser's
+// own user id
+pub fn routes(
+    accounts: Address<Accounts>,
+    signer: Arc<Signer>,
+) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    let accounts = warp::any().map(move || accounts.clone());
+    let deactivate = warp::path!("users" / "me" / "deactivate")
+        .and(warp::post())
+        .and(identity::id(signer.clone()))
+        .and(accounts.clone())
+        .and_then(deactivate);
+    let reactivate = warp::path!("users" / "me" / "reactivate")
+        .and(warp::post())
+        .and(identity::id(signer))
+        .and(accounts)
+        .and_then(reactivate);
+    deactivate.or(reactivate)
+}
+
+async fn deactivate(
+    user: Option<Uuid>,
+    accounts: Address<Accounts>,
+) -> Result<impl Reply, Rejection> {
+    let user = match user {
+        Some(user) => user,
+        None => return Ok(identity::unknown()),
+    };
+    let deactivation = accounts
+        .send(Deactivate(user))
+        .await
+        .expect("Could not deactivate");
+    Ok(warp::reply::with_status(
+        warp::reply::json(&deactivation),
+        StatusCode::OK,
+    ))
+}
+
+async fn reactivate(
+    user: Option<Uuid>,
+    accounts: Address<Accounts>,
+) -> Result<impl Reply, Rejection> {
+    let user = match user {
+        Some(user) => user,
+        None => return Ok(identity::unknown().into_response()),
+    };
+    let reactivated = accounts
+        .send(Reactivate(user))
+        .await
+        .expect("Could not reactivate");
+    if reactivated {
+        Ok(StatusCode::NO_CONTENT.into_response())
+    } else {
+        Err(warp::reject::not_found())
+    }
+}
@@ -0,0 +1,1957 @@
+use bytes::Bytes;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::convert::Infallible;
+use std::sync::Arc;
+use tokio::sync::watch;
+use tokio::time::{Duration, Instant};
+use uuid::Uuid;
+use warp::http::StatusCode;
+use warp::{Filter, Rejection, Reply};
+use xtra::prelude::*;
+
+use crate::accounts::{Accounts, Deactivate, ListDeactivated, Reactivate};
+use crate::connections::{Connections, GetConnections, GetTrace, ListConnections};
+use crate::import;
+use crate::keys::{self, ApiKey, Authenticate, KeyStore, ListKeys, MintKey, RevokeKey, Scope};
+use crate::maintenance::Drain;
+use crate::moderation::{ListReports, ModerationQueue, PendingReport, TakeReport};
+use crate::page::{self, PageQuery};
+use crate::registry::{
+    self, AddAlias, AllRooms, ArchiveRoom, CreateFromTemplate, FindRoom, GetRoom, ListTemplates,
+    ReadArchive, RemoveTemplate, RenameRoom, RoomFeatures, RoomRegistry, RoomSettings,
+    SaveTemplate, UnarchiveRoom, UpdateFeatures, UpdateTimezone,
+};
+use crate::room::Ban;
+use crate::schedule::{
+    AddAnnouncement, Announcement, Announcements, ListAnnouncements, Offset, RemoveAnnouncement,
+    UpdateAnnouncement,
+};
+use crate::services::Services;
+use crate::snapshot::{self, Snapshot};
+use crate::transcript;
+use crate::{
+    now_millis, AssignRole, BanUser, DeleteMessage, ImportMessages, Invite, JoinRequests, KickUser,
+    ListBans, Move, MuteUser, ProtocolError, PurgeMessages, ResolveJoin, RestoreMessage, Room,
+    RoomMembers, Transcript, UnbanUser,
+};
+
+// Most users one request can put in a room, and how often a bulk move
+// logs how it's getting on
+const MAX_BULK: usize = 10_000;
+const PROGRESS_EVERY: usize = 500;
+// Longest flair a key can show, in characters
+const MAX_FLAIR_LEN: usize = 24;
+// Biggest history dump taken in one import
+const MAX_IMPORT: u64 = 16 * 1024 * 1024;
+
+// Access - who is calling: the operator, or a room's admin key
+#[derive(Clone)]
+enum Access {
+    Server,
+    Room(String),
+}
+impl Access {
+    fn covers(&self, room: &str) -> bool {
+        match self {
+            Access::Server => true,
+            Access::Room(name) => registry::normalize(room).is_ok_and(|room| *name == room),
+        }
+    }
+}
+
+// Everything under /admin, guarded by a bearer token. Without a token
+// configured the whole tree 404s. The dashboard page itself is public (a
+// browser can't send the header on navigation); it asks for the token and
+// uses it on the API calls.
+pub fn routes(
+    token: Option<String>,
+    services: Services,
+    drain: Arc<watch::Sender<Option<Drain>>>,
+) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
+    let enabled = token.is_some();
+    let access = authorized(token, services.clone());
+    let server = access
+        .clone()
+        .and_then(|access| async move {
+            match access {
+                Access::Server => Ok(()),
+                Access::Room(_) => Err(warp::reject::custom(keys::Unauthorized)),
+            }
+        })
+        .untuple_one();
+    let registry = service::<RoomRegistry>(services.clone());
+    let moderation = service::<ModerationQueue>(services.clone());
+    let keys = service::<KeyStore>(services.clone());
+    let connections = service::<Connections>(services.clone());
+    let accounts = service::<Accounts>(services.clone());
+    let announcements = service::<Announcements>(services);
+
+    let ui = warp::path!("ui")
+        .and(warp::get())
+        .and_then(move || async move {
+            if enabled {
+                Ok(warp::reply::html(ADMIN_HTML))
+            } else {
+                Err(warp::reject::not_found())
+            }
+        });
+
+    let list_rooms = warp::path!("rooms")
+        .and(warp::get())
+        .and(page::query())
+        .and(access.clone())
+        .and(registry.clone())
+        .and_then(list_rooms);
+
+    let list_members = warp::path!("rooms" / String / "members")
+        .and(warp::get())
+        .and(page::query())
+        .and(access.clone())
+        .and(registry.clone())
+        .and_then(list_members);
+
+    let remove_member = warp::path!("rooms" / String / "members" / Uuid / String)
+        .and(warp::post())
+        .and(access.clone())
+        .and(registry.clone())
+        .and_then(remove_member);
+
+    let move_members = warp::path!("rooms" / String / "members" / "move")
+        .and(warp::post())
+        .and(access.clone())
+        .and(warp::body::json())
+        .and(registry.clone())
+        .and(connections.clone())
+        .and_then(move_members);
+
+    // Any user on the server can be named, so it's for the operator only
+    let add_members = warp::path!("rooms" / String / "members")
+        .and(warp::post())
+        .and(server.clone())
+        .and(warp::body::json())
+        .and(registry.clone())
+        .and(connections.clone())
+        .and_then(add_members);
+
+    let list_bans = warp::path!("rooms" / String / "bans")
+        .and(warp::get())
+        .and(page::query())
+        .and(access.clone())
+        .and(registry.clone())
+        .and_then(list_bans);
+
+    let get_ban = warp::path!("rooms" / String / "bans" / Uuid)
+        .and(warp::get())
+        .and(access.clone())
+        .and(registry.clone())
+        .and_then(get_ban);
+
+    let add_ban = warp::path!("rooms" / String / "bans")
+        .and(warp::post())
+        .and(access.clone())
+        .and(warp::body::json())
+        .and(registry.clone())
+        .and_then(add_ban);
+
+    let update_ban = warp::path!("rooms" / String / "bans" / Uuid)
+        .and(warp::put())
+        .and(access.clone())
+        .and(warp::body::json())
+        .and(registry.clone())
+        .and_then(update_ban);
+
+    let lift_ban = warp::path!("rooms" / String / "bans" / Uuid)
+        .and(warp::delete())
+        .and(access.clone())
+        .and(registry.clone())
+        .and_then(lift_ban);
+
+    let assign_role = warp::path!("rooms" / String / "roles" / Uuid)
+        .and(warp::put())
+        .and(access.clone())
+        .and(warp::body::json())
+        .and(registry.clone())
+        .and_then(assign_role);
+
+    let purge = warp::path!("rooms" / String / "purge")
+        .and(warp::post())
+        .and(access.clone())
+        .and(warp::body::json())
+        .and(registry.clone())
+        .and_then(purge);
+
+    let import = warp::path!("rooms" / String / "import")
+        .and(warp::post())
+        .and(access.clone())
+        .and(warp::body::content_length_limit(MAX_IMPORT))
+        .and(warp::body::bytes())
+        .and(registry.clone())
+        .and_then(import);
+
+    let features = warp::path!("rooms" / String / "features")
+        .and(warp::put())
+        .and(access.clone())
+        .and(warp::body::json())
+        .and(registry.clone())
+        .and_then(set_features);
+
+    let timezone = warp::path!("rooms" / String / "timezone")
+        .and(warp::put())
+        .and(access.clone())
+        .and(warp::body::json())
+        .and(registry.clone())
+        .and_then(set_timezone);
+
+    let join_requests = warp::path!("rooms" / String / "join_requests")
+        .and(warp::get())
+        .and(page::query())
+        .and(access.clone())
+        .and(registry.clone())
+        .and_then(join_requests);
+
+    let resolve_join = warp::path!("rooms" / String / "join_requests" / Uuid / String)
+        .and(warp::post())
+        .and(access.clone())
+        .and(registry.clone())
+        .and_then(resolve_join);
+
+    let invite = warp::path!("rooms" / String / "invites")
+        .and(warp::post())
+        .and(access.clone())
+        .and(registry.clone())
+        .and_then(invite);
+
+    let spectate = warp::path!("rooms" / String / "spectators")
+        .and(warp::post())
+        .and(access.clone())
+        .and(registry.clone())
+        .and(keys.clone())
+        .and_then(spectate);
+
+    let rename = warp::path!("rooms" / String / "rename")
+        .and(warp::post())
+        .and(access.clone())
+        .and(warp::body::json())
+        .and(registry.clone())
+        .and(keys.clone())
+        .and_then(rename);
+
+    let add_alias = warp::path!("rooms" / String / "aliases")
+        .and(warp::post())
+        .and(access.clone())
+        .and(warp::body::json())
+        .and(registry.clone())
+        .and_then(add_alias);
+
+    let archive = warp::path!("rooms" / String / "archive")
+        .and(warp::post())
+        .and(access.clone())
+        .and(registry.clone())
+        .and_then(archive);
+
+    let unarchive = warp::path!("rooms" / String / "unarchive")
+        .and(warp::post())
+        .and(access.clone())
+        .and(registry.clone())
+        .and_then(unarchive);
+
+    let transcript = warp::path!("rooms" / String / "transcript")
+        .and(warp::get())
+        .and(warp::query())
+        .and(access.clone())
+        .and(registry.clone())
+        .and_then(transcript);
+
+    let list_reports = warp::path!("reports")
+        .and(warp::get())
+        .and(page::query())
+        .and(access.clone())
+        .and(moderation.clone())
+        .and_then(list_reports);
+
+    let resolve_report = warp::path!("reports" / String / u64 / String)
+        .and(warp::post())
+        .and(access)
+        .and(moderation)
+        .and(registry.clone())
+        .and_then(resolve_report);
+
+    let mint_key = warp::path!("keys")
+        .and(warp::post())
+        .and(server.clone())
+        .and(warp::body::json())
+        .and(keys.clone())
+        .and(registry.clone())
+        .and_then(mint_key);
+
+    let list_keys = warp::path!("keys")
+        .and(warp::get())
+        .and(server.clone())
+        .and(keys.clone())
+        .and_then(list_keys);
+
+    let revoke_key = warp::path!("keys" / Uuid)
+        .and(warp::delete())
+        .and(server.clone())
+        .and(keys)
+        .and_then(revoke_key);
+
+    let list_connections = warp::path!("connections")
+        .and(warp::get())
+        .and(server.clone())
+        .and(page::query())
+        .and(warp::query::<ConnectionsQuery>())
+        .and(connections.clone())
+        .and_then(list_connections);
+
+    let list_deactivated = warp::path!("users" / "deactivated")
+        .and(warp::get())
+        .and(server.clone())
+        .and(page::query())
+        .and(accounts.clone())
+        .and_then(list_deactivated);
+
+    let deactivate = warp::path!("users" / Uuid / "deactivate")
+        .and(warp::post())
+        .and(server.clone())
+        .and(accounts.clone())
+        .and_then(deactivate);
+
+    let reactivate = warp::path!("users" / Uuid / "reactivate")
+        .and(warp::post())
+        .and(server.clone())
+        .and(accounts)
+        .and_then(reactivate);
+
+    let trace = warp::path!("connections" / Uuid / "trace")
+        .and(warp::get())
+        .and(server.clone())
+        .and(connections)
+        .and_then(trace);
+
+    let export_state = warp::path!("state")
+        .and(warp::get())
+        .and(server.clone())
+        .and(registry.clone())
+        .and_then(export_state);
+
+    let import_state = warp::path!("state")
+        .and(warp::put())
+        .and(server.clone())
+        .and(warp::body::content_length_limit(MAX_IMPORT))
+        .and(warp::body::json())
+        .and(registry.clone())
+        .and_then(import_state);
+
+    let list_templates = warp::path!("templates")
+        .and(warp::get())
+        .and(server.clone())
+        .and(page::query())
+        .and(registry.clone())
+        .and_then(list_templates);
+
+    let save_template = warp::path!("templates")
+        .and(warp::post())
+        .and(server.clone())
+        .and(warp::body::json())
+        .and(registry.clone())
+        .and_then(save_template);
+
+    let remove_template = warp::path!("templates" / String)
+        .and(warp::delete())
+        .and(server.clone())
+        .and(registry.clone())
+        .and_then(remove_template);
+
+    let from_template = warp::path!("templates" / String / "rooms")
+        .and(warp::post())
+        .and(server.clone())
+        .and(warp::body::json())
+        .and(registry.clone())
+        .and_then(from_template);
+
+    let list_announcements = warp::path!("announcements")
+        .and(warp::get())
+        .and(server.clone())
+        .and(page::query())
+        .and(announcements.clone())
+        .and_then(list_announcements);
+
+    let add_announcement = warp::path!("announcements")
+        .and(warp::post())
+        .and(server.clone())
+        .and(warp::body::json())
+        .and(announcements.clone())
+        .and(registry.clone())
+        .and_then(add_announcement);
+
+    let update_announcement = warp::path!("announcements" / Uuid)
+        .and(warp::put())
+        .and(server.clone())
+        .and(warp::body::json())
+        .and(announcements.clone())
+        .and(registry)
+        .and_then(update_announcement);
+
+    let remove_announcement = warp::path!("announcements" / Uuid)
+        .and(warp::delete())
+        .and(server.clone())
+        .and(announcements)
+        .and_then(remove_announcement);
+
+    let drain = warp::any().map(move || drain.clone());
+
+    let start_maintenance = warp::path!("maintenance")
+        .and(warp::post())
+        .and(server.clone())
+        .and(warp::body::json())
+        .and(drain.clone())
+        .and_then(start_maintenance);
+
+    let end_maintenance = warp::path!("maintenance")
+        .and(warp::delete())
+        .and(server)
+        .and(drain)
+        .and_then(end_maintenance);
+
+    warp::path("admin")
+        .and(
+            ui.or(list_rooms)
+                .or(list_members)
+                .or(remove_member)
+                .or(move_members)
+                .or(add_members)
+                .or(list_bans)
+                .or(get_ban)
+                .or(add_ban)
+                .or(update_ban)
+                .or(lift_ban)
+                .or(assign_role)
+                .or(purge)
+                .or(import)
+                .or(features)
+                .or(timezone)
+                .or(join_requests)
+                .or(resolve_join)
+                .or(invite)
+                .or(spectate)
+                .or(rename)
+                .or(add_alias)
+                .or(archive)
+                .or(unarchive)
+                .or(transcript)
+                .or(list_reports)
+                .or(resolve_report)
+                .or(mint_key)
+                .or(list_keys)
+                .or(revoke_key)
+                .or(list_connections)
+                .or(trace)
+                .or(list_deactivated)
+                .or(deactivate)
+                .or(reactivate)
+                .or(list_announcements)
+                .or(add_announcement)
+                .or(update_announcement)
+                .or(remove_announcement)
+                .or(export_state)
+                .or(import_state)
+                .or(list_templates)
+                .or(save_template)
+                .or(remove_template)
+                .or(from_template)
+                .or(start_maintenance)
+                .or(end_maintenance),
+        )
+        .recover(keys::handle_rejection)
+}
+
+// The community's actor of type A, as of each request
+fn service<A: Actor>(
+    services: Services,
+) -> impl Filter<Extract = (Address<A>,), Error = Infallible> + Clone {
+    warp::any().map(move || services.get::<A>())
+}
+
+// The operator's token, or an admin-scoped room key
+fn authorized(
+    token: Option<String>,
+    services: Services,
+) -> impl Filter<Extract = (Access,), Error = Rejection> + Clone {
+    warp::header::optional::<String>("authorization").and_then(move |header: Option<String>| {
+        let token = token.clone();
+        let keys = services.get::<KeyStore>();
+        async move {
+            let token = match token {
+                Some(token) => token,
+                None => return Err(warp::reject::not_found()),
+            };
+            let secret = match header.as_deref().and_then(|h| h.strip_prefix("Bearer ")) {
+                Some(secret) => secret.to_string(),
+                None => return Err(warp::reject::custom(keys::Unauthorized)),
+            };
+            if is_token(&secret, &token) {
+                return Ok(Access::Server);
+            }
+            match keys.send(Authenticate(secret)).await {
+                Ok(Some(ApiKey {
+                    room,
+                    scope: Scope::Admin,
+                    ..
+                })) => Ok(Access::Room(room)),
+                _ => Err(warp::reject::custom(keys::Unauthorized)),
+            }
+        }
+    })
+}
+
+// Whether a bearer secret is the operator's token. Digests are compared
+// rather than the strings, so the length is always the same, and every byte
+// is compared, so timing gives nothing away.
+pub fn is_token(secret: &str, token: &str) -> bool {
+    let (secret, token) = (Sha256::digest(secret), Sha256::digest(token));
+    secret
+        .iter()
+        .zip(token.iter())
+        .fold(0, |differs, (a, b)| differs | (a ^ b))
+        == 0
+}
+
+// A room the caller may manage
+async fn find_room(
+    access: &Access,
+    registry: &Address<RoomRegistry>,
+    name: &str,
+) -> Result<Address<Room>, Rejection> {
+    manage(access, registry, name).await.map(|(_, room)| room)
+}
+
+// Like find_room, along with the room's current name. Keys follow their
+// room through renames, so they're checked against that.
+async fn manage(
+    access: &Access,
+    registry: &Address<RoomRegistry>,
+    name: &str,
+) -> Result<(String, Address<Room>), Rejection> {
+    let found = registry
+        .send(FindRoom(name.to_string()))
+        .await
+        .expect("Could not reach the registry");
+    match found {
+        Some((name, room)) if access.covers(&name) => Ok((name, room)),
+        None if access.covers(name) => Err(warp::reject::not_found()),
+        _ => Err(warp::reject::custom(keys::Unauthorized)),
+    }
+}
+
+#[derive(Serialize)]
+struct RoomSummary {
+    #[serde(flatten)]
+    settings: RoomSettings,
+    members: usize,
+}
+
+// GET /admin/rooms
+async fn list_rooms(
+    query: PageQuery,
+    access: Access,
+    registry: Address<RoomRegistry>,
+) -> Result<impl Reply, Rejection> {
+    let mut rooms = registry.send(AllRooms).await.expect("Could not list rooms");
+    rooms.retain(|(settings, _)| access.covers(&settings.name));
+    let (rooms, next) = match page::page(rooms, |(settings, _)| settings.name.clone(), &query) {
+        Ok(page) => page,
+        Err(e) => return Ok(page::reply::<RoomSummary>(Err(e))),
+    };
+
+    let mut summaries = Vec::new();
+    for (settings, room) in rooms {
+        let members = room
+            .send(RoomMembers)
+            .await
+            .expect("Could not list members")
+            .len();
+        summaries.push(RoomSummary { settings, members });
+    }
+    Ok(page::reply(Ok((summaries, next))))
+}
+
+// GET /admin/rooms/:room/members
+async fn list_members(
+    room_name: String,
+    query: PageQuery,
+    access: Access,
+    registry: Address<RoomRegistry>,
+) -> Result<impl Reply, Rejection> {
+    let room = find_room(&access, &registry, &room_name).await?;
+    let mut members = room
+        .send(RoomMembers)
+        .await
+        .expect("Could not list members");
+    members.sort();
+    let page = page::page(members, |id| id.to_string(), &query);
+    Ok(page::reply(page))
+}
+
+// POST /admin/rooms/:room/members/:id/{kick,ban,mute,unmute}
+async fn remove_member(
+    room_name: String,
+    id: Uuid,
+    action: String,
+    access: Access,
+    registry: Address<RoomRegistry>,
+) -> Result<impl Reply, Rejection> {
+    if !matches!(action.as_str(), "kick" | "ban" | "mute" | "unmute") {
+        return Err(warp::reject::not_found());
+    }
+    let room = find_room(&access, &registry, &room_name).await?;
+
+    match action.as_str() {
+        "kick" => {
+            let kicked = room.send(KickUser(id)).await.expect("Could not kick user");
+            if !kicked {
+                return Err(warp::reject::not_found());
+            }
+        }
+        "ban" => room
+            .send(BanUser(Ban::new(id, None, now_millis())))
+            .await
+            .expect("Could not ban user"),
+        _ => room
+            .send(MuteUser(id, action == "mute"))
+            .await
+            .expect("Could not mute user"),
+    }
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[derive(Deserialize)]
+struct MoveMembers {
+    to: String,
+}
+
+#[derive(Deserialize)]
+struct AddMembers {
+    users: Vec<Uuid>,
+}
+
+// Moved - how a bulk move went: who it worked for, who wasn't connected to
+// be moved, and who it failed for and why
+#[derive(Serialize)]
+struct Moved {
+    moved: usize,
+    offline: Vec<Uuid>,
+    failed: Vec<Failed>,
+}
+
+#[derive(Serialize)]
+struct Failed {
+    user: Uuid,
+    error: ProtocolError,
+}
+
+// Puts each user's connection in `join`, taking it out of `leave`, one at
+// a time so the rooms aren't swamped, with the log kept up to date
+async fn relocate(
+    connections: &Address<Connections>,
+    users: Vec<Uuid>,
+    leave: Option<&str>,
+    join: &str,
+) -> Moved {
+    let total = users.len();
+    let open = connections
+        .send(GetConnections(users.clone()))
+        .await
+        .expect("Could not reach the connections");
+    let mut moved = Moved {
+        moved: 0,
+        offline: users
+            .into_iter()
+            .filter(|user| !open.iter().any(|(id, _)| id == user))
+            .collect(),
+        failed: Vec::new(),
+    };
+    for (n, (user, connection)) in open.into_iter().enumerate() {
+        let sent = connection
+            .send(Move {
+                leave: leave.map(str::to_string),
+                join: join.to_string(),
+            })
+            .await;
+        match sent {
+            Ok(Ok(())) => moved.moved += 1,
+            Ok(Err(error)) => moved.failed.push(Failed { user, error }),
+            // Closed since
+            Err(_) => moved.offline.push(user),
+        }
+        if (n + 1) % PROGRESS_EVERY == 0 {
+            log::info!("Moved {} of {} into {}", n + 1, total, join);
+        }
+    }
+    log::info!(
+        "Moved {} into {}; {} offline, {} failed",
+        moved.moved,
+        join,
+        moved.offline.len(),
+        moved.failed.len()
+    );
+    moved
+}
+
+// POST /admin/rooms/:room/members/move, taking everyone in the room to
+// another the caller may manage too
+async fn move_members(
+    room_name: String,
+    access: Access,
+    body: MoveMembers,
+    registry: Address<RoomRegistry>,
+    connections: Address<Connections>,
+) -> Result<impl Reply, Rejection> {
+    let (from, room) = manage(&access, &registry, &room_name).await?;
+    let (to, _) = manage(&access, &registry, &body.to).await?;
+    if from == to {
+        let error = "Members can't be moved to the room they're in";
+        return Ok(warp::reply::with_status(
+            warp::reply::json(&error),
+            StatusCode::BAD_REQUEST,
+        ));
+    }
+    let members = room
+        .send(RoomMembers)
+        .await
+        .expect("Could not list members");
+    let moved = relocate(&connections, members, Some(&from), &to).await;
+    Ok(warp::reply::with_status(
+        warp::reply::json(&moved),
+        StatusCode::OK,
+    ))
+}
+
+// POST /admin/rooms/:room/members, putting the users given in the room
+async fn add_members(
+    room_name: String,
+    body: AddMembers,
+    registry: Address<RoomRegistry>,
+    connections: Address<Connections>,
+) -> Result<impl Reply, Rejection> {
+    let (name, _) = manage(&Access::Server, &registry, &room_name).await?;
+    if body.users.len() > MAX_BULK {
+        let error = format!("Up to {} users at a time", MAX_BULK);
+        return Ok(warp::reply::with_status(
+            warp::reply::json(&error),
+            StatusCode::BAD_REQUEST,
+        ));
+    }
+    let mut users = body.users;
+    users.sort();
+    users.dedup();
+    let moved = relocate(&connections, users, None, &name).await;
+    Ok(warp::reply::with_status(
+        warp::reply::json(&moved),
+        StatusCode::OK,
+    ))
+}
+
+#[derive(Deserialize)]
+struct Purge {
+    // The newest this many messages; all of them if left out
+    last: Option<usize>,
+    // Only messages from this user
+    from: Option<Uuid>,
+}
+
+#[derive(Serialize)]
+struct Purged {
+    deleted: usize,
+}
+
+// POST /admin/rooms/:room/purge
+async fn purge(
+    room_name: String,
+    access: Access,
+    purge: Purge,
+    registry: Address<RoomRegistry>,
+) -> Result<impl Reply, Rejection> {
+    let room = find_room(&access, &registry, &room_name).await?;
+    if purge.last.is_none() && purge.from.is_none() {
+        let error = "Say which messages: last, from or both";
+        return Ok(warp::reply::with_status(
+            warp::reply::json(&error),
+            StatusCode::BAD_REQUEST,
+        ));
+    }
+
+    let deleted = room
+        .send(PurgeMessages {
+            last: purge.last,
+            from: purge.from,
+        })
+        .await
+        .expect("Could not purge messages");
+    Ok(warp::reply::with_status(
+        warp::reply::json(&Purged { deleted }),
+        StatusCode::OK,
+    ))
+}
+
+#[derive(Serialize)]
+struct Imported {
+    imported: usize,
+}
+
+// POST /admin/rooms/:room/import with a JSONL dump of messages, as another
+// instance's history has them. Authors known only by name come with
+// `author`; see import.rs.
+async fn import(
+    room_name: String,
+    access: Access,
+    dump: Bytes,
+    registry: Address<RoomRegistry>,
+) -> Result<impl Reply, Rejection> {
+    let room = find_room(&access, &registry, &room_name).await?;
+    let messages = match std::str::from_utf8(&dump)
+        .map_err(|_| "The dump isn't UTF-8".to_string())
+        .and_then(import::parse)
+    {
+        Ok(messages) => messages,
+        Err(error) => {
+            return Ok(warp::reply::with_status(
+                warp::reply::json(&error),
+                StatusCode::BAD_REQUEST,
+            ))
+        }
+    };
+
+    let imported = room
+        .send(ImportMessages(messages))
+        .await
+        .expect("Could not import messages");
+    log::info!("Imported {} messages into {}", imported, room_name);
+    Ok(warp::reply::with_status(
+        warp::reply::json(&Imported { imported }),
+        StatusCode::OK,
+    ))
+}
+
+// PUT /admin/rooms/:room/features with the features to have on, e.g.
+// ["history"]. Threads go along with their room.
+async fn set_features(
+    room_name: String,
+    access: Access,
+    features: RoomFeatures,
+    registry: Address<RoomRegistry>,
+) -> Result<impl Reply, Rejection> {
+    let (room_name, _) = manage(&access, &registry, &room_name).await?;
+    let settings = registry
+        .send(UpdateFeatures {
+            room: room_name,
+            features,
+        })
+        .await
+        .expect("Could not reach the registry")
+        .ok_or_else(warp::reject::not_found)?;
+    Ok(warp::reply::json(&settings))
+}
+
+#[derive(Deserialize)]
+struct Timezone {
+    timezone: Offset,
+}
+
+// PUT /admin/rooms/:room/timezone with e.g. {"timezone": "+02:00"}, what the
+// room's transcripts show times in. Threads go along with their room.
+async fn set_timezone(
+    room_name: String,
+    access: Access,
+    body: Timezone,
+    registry: Address<RoomRegistry>,
+) -> Result<impl Reply, Rejection> {
+    let (room_name, _) = manage(&access, &registry, &room_name).await?;
+    let settings = registry
+        .send(UpdateTimezone {
+            room: room_name,
+            timezone: body.timezone,
+        })
+        .await
+        .expect("Could not reach the registry")
+        .ok_or_else(warp::reject::not_found)?;
+    Ok(warp::reply::json(&settings))
+}
+
+// GET /admin/rooms/:room/join_requests, who is waiting to be let in
+async fn join_requests(
+    room_name: String,
+    query: PageQuery,
+    access: Access,
+    registry: Address<RoomRegistry>,
+) -> Result<impl Reply, Rejection> {
+    let room = find_room(&access, &registry, &room_name).await?;
+    let pending = room
+        .send(JoinRequests)
+        .await
+        .expect("Could not list join requests");
+    let page = page::page(pending, |id| id.to_string(), &query);
+    Ok(page::reply(page))
+}
+
+// POST /admin/rooms/:room/join_requests/:id/{approve,deny}
+async fn resolve_join(
+    room_name: String,
+    id: Uuid,
+    action: String,
+    access: Access,
+    registry: Address<RoomRegistry>,
+) -> Result<impl Reply, Rejection> {
+    let approve = match action.as_str() {
+        "approve" => true,
+        "deny" => false,
+        _ => return Err(warp::reject::not_found()),
+    };
+    let room = find_room(&access, &registry, &room_name).await?;
+    let resolved = room
+        .send(ResolveJoin { id, approve })
+        .await
+        .expect("Could not resolve the join");
+    // 409 for a joiner the room can't take as it is
+    Ok(match resolved {
+        Ok(true) => warp::reply::with_status(warp::reply::json(&()), StatusCode::NO_CONTENT),
+        Ok(false) => return Err(warp::reject::not_found()),
+        Err(e) => warp::reply::with_status(warp::reply::json(&e), StatusCode::CONFLICT),
+    })
+}
+
+#[derive(Serialize)]
+struct NewInvite {
+    code: String,
+}
+
+// POST /admin/rooms/:room/invites
+async fn invite(
+    room_name: String,
+    access: Access,
+    registry: Address<RoomRegistry>,
+) -> Result<impl Reply, Rejection> {
+    let room = find_room(&access, &registry, &room_name).await?;
+    let code = room.send(Invite).await.expect("Could not make an invite");
+    Ok(warp::reply::with_status(
+        warp::reply::json(&NewInvite { code }),
+        StatusCode::CREATED,
+    ))
+}
+
+// POST /admin/rooms/:room/spectators mints a spectator key for the room,
+// which its own admin keys may do too
+async fn spectate(
+    room_name: String,
+    access: Access,
+    registry: Address<RoomRegistry>,
+    keys: Address<KeyStore>,
+) -> Result<impl Reply, Rejection> {
+    let (room, _) = manage(&access, &registry, &room_name).await?;
+    let (key, secret) = keys
+        .send(MintKey {
+            room,
+            scope: Scope::Spectator,
+            flair: None,
+        })
+        .await
+        .expect("Could not mint key");
+    Ok(warp::reply::with_status(
+        warp::reply::json(&MintedKey { key, secret }),
+        StatusCode::CREATED,
+    ))
+}
+
+#[derive(Deserialize)]
+struct NewName {
+    name: String,
+}
+
+// POST /admin/rooms/:room/rename with `{"name": "..."}`. The old name
+// becomes an alias.
+async fn rename(
+    room_name: String,
+    access: Access,
+    new: NewName,
+    registry: Address<RoomRegistry>,
+    keys: Address<KeyStore>,
+) -> Result<impl Reply, Rejection> {
+    let (from, _) = manage(&access, &registry, &room_name).await?;
+    let renamed = registry
+        .send(RenameRoom {
+            room: from.clone(),
+            name: new.name,
+        })
+        .await
+        .expect("Could not reach the registry");
+    let settings = match renamed {
+        Ok(settings) => settings,
+        Err(e) => return Ok(naming_error(e)),
+    };
+    keys.send(keys::RenameRoom {
+        from,
+        to: settings.name.clone(),
+    })
+    .await
+    .expect("Could not rename keys");
+    Ok(warp::reply::with_status(
+        warp::reply::json(&settings),
+        StatusCode::OK,
+    ))
+}
+
+#[derive(Deserialize)]
+struct NewAlias {
+    alias: String,
+}
+
+// POST /admin/rooms/:room/aliases with `{"alias": "..."}`
+async fn add_alias(
+    room_name: String,
+    access: Access,
+    new: NewAlias,
+    registry: Address<RoomRegistry>,
+) -> Result<impl Reply, Rejection> {
+    let (room, _) = manage(&access, &registry, &room_name).await?;
+    let added = registry
+        .send(AddAlias {
+            room,
+            alias: new.alias,
+        })
+        .await
+        .expect("Could not reach the registry");
+    Ok(match added {
+        Ok(()) => warp::reply::with_status(warp::reply::json(&()), StatusCode::NO_CONTENT),
+        Err(e) => naming_error(e),
+    })
+}
+
+// BanTerms - what a ban is for and how long, as the admin API sets them
+#[derive(Deserialize)]
+struct BanTerms {
+    #[serde(default)]
+    reason: Option<String>,
+    // Unix millis; for good if left out
+    #[serde(default)]
+    expires_at: Option<u64>,
+    #[serde(default)]
+    notes: Option<String>,
+}
+
+impl BanTerms {
+    fn expiry(&self, now_ms: u64) -> Result<Option<u64>, ProtocolError> {
+        match self.expires_at {
+            Some(at) => Ban::expiring(at, now_ms).map(Some),
+            None => Ok(None),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct NewBan {
+    user: Uuid,
+    // The moderator it's on behalf of, if any
+    #[serde(default)]
+    by: Option<Uuid>,
+    #[serde(flatten)]
+    terms: BanTerms,
+}
+
+// GET /admin/rooms/:room/bans
+async fn list_bans(
+    room_name: String,
+    query: PageQuery,
+    access: Access,
+    registry: Address<RoomRegistry>,
+) -> Result<impl Reply, Rejection> {
+    let room = find_room(&access, &registry, &room_name).await?;
+    let bans = room.send(ListBans).await.expect("Could not list bans");
+    Ok(page::reply(page::page(
+        bans,
+        |ban| ban.user.to_string(),
+        &query,
+    )))
+}
+
+async fn find_ban(room: &Address<Room>, user: Uuid) -> Option<Ban> {
+    let bans = room.send(ListBans).await.expect("Could not list bans");
+    bans.into_iter().find(|ban| ban.user == user)
+}
+
+// GET /admin/rooms/:room/bans/:user
+async fn get_ban(
+    room_name: String,
+    user: Uuid,
+    access: Access,
+    registry: Address<RoomRegistry>,
+) -> Result<impl Reply, Rejection> {
+    let room = find_room(&access, &registry, &room_name).await?;
+    match find_ban(&room, user).await {
+        Some(ban) => Ok(warp::reply::json(&ban)),
+        None => Err(warp::reject::not_found()),
+    }
+}
+
+// POST /admin/rooms/:room/bans bans someone, putting them out if they're
+// in. Banning them again replaces the ban.
+async fn add_ban(
+    room_name: String,
+    access: Access,
+    new: NewBan,
+    registry: Address<RoomRegistry>,
+) -> Result<impl Reply, Rejection> {
+    let room = find_room(&access, &registry, &room_name).await?;
+    let now_ms = now_millis();
+    let expires_at = match new.terms.expiry(now_ms) {
+        Ok(expires_at) => expires_at,
+        Err(e) => {
+            return Ok(warp::reply::with_status(
+                warp::reply::json(&e),
+                StatusCode::BAD_REQUEST,
+            ))
+        }
+    };
+    let ban = Ban {
+        reason: new.terms.reason,
+        expires_at,
+        notes: new.terms.notes,
+        ..Ban::new(new.user, new.by, now_ms)
+    };
+    room.send(BanUser(ban.clone()))
+        .await
+        .expect("Could not ban user");
+    Ok(warp::reply::with_status(
+        warp::reply::json(&ban),
+        StatusCode::CREATED,
+    ))
+}
+
+// PUT /admin/rooms/:room/bans/:user changes its reason, expiry and notes,
+// for appeals; who banned them and when stay as they were
+async fn update_ban(
+    room_name: String,
+    user: Uuid,
+    access: Access,
+    terms: BanTerms,
+    registry: Address<RoomRegistry>,
+) -> Result<impl Reply, Rejection> {
+    let room = find_room(&access, &registry, &room_name).await?;
+    let expires_at = match terms.expiry(now_millis()) {
+        Ok(expires_at) => expires_at,
+        Err(e) => {
+            return Ok(warp::reply::with_status(
+                warp::reply::json(&e),
+                StatusCode::BAD_REQUEST,
+            ))
+        }
+    };
+    let ban = match find_ban(&room, user).await {
+        Some(ban) => Ban {
+            reason: terms.reason,
+            expires_at,
+            notes: terms.notes,
+            ..ban
+        },
+        None => return Err(warp::reject::not_found()),
+    };
+    room.send(BanUser(ban.clone()))
+        .await
+        .expect("Could not ban user");
+    Ok(warp::reply::with_status(
+        warp::reply::json(&ban),
+        StatusCode::OK,
+    ))
+}
+
+// DELETE /admin/rooms/:room/bans/:user
+async fn lift_ban(
+    room_name: String,
+    user: Uuid,
+    access: Access,
+    registry: Address<RoomRegistry>,
+) -> Result<impl Reply, Rejection> {
+    let room = find_room(&access, &registry, &room_name).await?;
+    let lifted = room
+        .send(UnbanUser(user))
+        .await
+        .expect("Could not lift ban");
+    if lifted {
+        Ok(StatusCode::NO_CONTENT)
+    } else {
+        Err(warp::reject::not_found())
+    }
+}
+
+#[derive(Deserialize)]
+struct NewRole {
+    // None takes the user's role away, leaving them a member
+    role: Option<String>,
+}
+
+// PUT /admin/rooms/:room/roles/:user with `{"role": "..."}`
+async fn assign_role(
+    room_name: String,
+    user: Uuid,
+    access: Access,
+    new: NewRole,
+    registry: Address<RoomRegistry>,
+) -> Result<impl Reply, Rejection> {
+    let room = find_room(&access, &registry, &room_name).await?;
+    let assigned = room
+        .send(AssignRole {
+            user,
+            role: new.role,
+        })
+        .await
+        .expect("Could not assign role");
+    Ok(match assigned {
+        Ok(()) => warp::reply::with_status(warp::reply::json(&()), StatusCode::NO_CONTENT),
+        Err(e) => warp::reply::with_status(warp::reply::json(&e), StatusCode::BAD_REQUEST),
+    })
+}
+
+// 409 for a name that's taken, 400 for anything else
+fn naming_error(e: ProtocolError) -> warp::reply::WithStatus<warp::reply::Json> {
+    let status = match e.code {
+        "name_taken" => StatusCode::CONFLICT,
+        _ => StatusCode::BAD_REQUEST,
+    };
+    warp::reply::with_status(warp::reply::json(&e), status)
+}
+
+#[derive(Deserialize)]
+struct Range {
+    // Unix millis; from the start of history up to now if left out
+    from: Option<u64>,
+    until: Option<u64>,
+}
+
+// POST /admin/rooms/:room/archive
+async fn archive(
+    room_name: String,
+    access: Access,
+    registry: Address<RoomRegistry>,
+) -> Result<impl Reply, Rejection> {
+    let (name, _) = manage(&access, &registry, &room_name).await?;
+    let archived = registry
+        .send(ArchiveRoom(name))
+        .await
+        .expect("Could not reach the registry");
+    Ok(match archived {
+        Ok(settings) => warp::reply::with_status(warp::reply::json(&settings), StatusCode::OK),
+        Err(e) => warp::reply::with_status(warp::reply::json(&e), StatusCode::BAD_REQUEST),
+    })
+}
+
+// POST /admin/rooms/:room/unarchive
+async fn unarchive(
+    room_name: String,
+    access: Access,
+    registry: Address<RoomRegistry>,
+) -> Result<impl Reply, Rejection> {
+    if !access.covers(&room_name) {
+        return Err(warp::reject::custom(keys::Unauthorized));
+    }
+    let unarchived = registry
+        .send(UnarchiveRoom(room_name))
+        .await
+        .expect("Could not reach the registry");
+    match unarchived {
+        Ok(settings) => Ok(warp::reply::with_status(
+            warp::reply::json(&settings),
+            StatusCode::OK,
+        )),
+        Err(_) => Err(warp::reject::not_found()),
+    }
+}
+
+// GET /admin/rooms/:room/transcript?from=&until=, the room's messages in
+// that range as an HTML page to publish
+async fn transcript(
+    room_name: String,
+    range: Range,
+    access: Access,
+    registry: Address<RoomRegistry>,
+) -> Result<impl Reply, Rejection> {
+    // None for a room that may be archived, which the registry keeps
+    let found = match manage(&access, &registry, &room_name).await {
+        Ok(found) => Some(found),
+        Err(e) if !access.covers(&room_name) => return Err(e),
+        Err(_) => None,
+    };
+    let from = range.from.unwrap_or(0);
+    let until = range.until.unwrap_or_else(now_millis);
+    if from >= until {
+        let error = "from has to be before until";
+        return Ok(
+            warp::reply::with_status(warp::reply::json(&error), StatusCode::BAD_REQUEST)
+                .into_response(),
+        );
+    }
+
+    let (name, messages, timezone) = match found {
+        Some((name, room)) => {
+            let (messages, timezone) = room
+                .send(Transcript { from, until })
+                .await
+                .expect("Could not get transcript");
+            (name, messages, timezone)
+        }
+        None => registry
+            .send(ReadArchive {
+                room: room_name,
+                from,
+                until,
+            })
+            .await
+            .expect("Could not reach the registry")
+            .filter(|(name, _, _)| access.covers(name))
+            .ok_or_else(warp::reject::not_found)?,
+    };
+    let html = transcript::render(&name, from, until, &messages, timezone);
+    Ok(warp::reply::html(html).into_response())
+}
+
+// GET /admin/reports
+async fn list_reports(
+    query: PageQuery,
+    access: Access,
+    moderation: Address<ModerationQueue>,
+) -> Result<impl Reply, Rejection> {
+    let mut reports = moderation
+        .send(ListReports)
+        .await
+        .expect("Could not list reports");
+    reports.retain(|r| access.covers(&r.room));
+    // Names never have spaces, so this sorts the way the queue does
+    let key = |r: &PendingReport| format!("{} {:020}", r.room, r.message_id);
+    let page = page::page(reports, key, &query);
+    Ok(page::reply(page))
+}
+
+// POST /admin/reports/:room/:message_id/{dismiss,delete,ban}
+async fn resolve_report(
+    room_name: String,
+    message_id: u64,
+    action: String,
+    access: Access,
+    moderation: Address<ModerationQueue>,
+    registry: Address<RoomRegistry>,
+) -> Result<impl Reply, Rejection> {
+    if !matches!(action.as_str(), "dismiss" | "delete" | "ban") {
+        return Err(warp::reject::not_found());
+    }
+    if !access.covers(&room_name) {
+        return Err(warp::reject::custom(keys::Unauthorized));
+    }
+
+    let report = moderation
+        .send(TakeReport {
+            room: room_name,
+            message_id,
+        })
+        .await
+        .expect("Could not take report");
+    let report = match report {
+        Some(report) => report,
+        None => return Err(warp::reject::not_found()),
+    };
+    let room = registry
+        .send(GetRoom(report.room.clone()))
+        .await
+        .expect("Could not reach the registry");
+    let room = match room {
+        Some(room) => room,
+        None => return Err(warp::reject::not_found()),
+    };
+
+    match action.as_str() {
+        "dismiss" => room
+            .send(RestoreMessage(report.message_id))
+            .await
+            .expect("Could not restore message"),
+        "delete" => room
+            .send(DeleteMessage(report.message_id))
+            .await
+            .expect("Could not delete message"),
+        _ => {
+            room.send(DeleteMessage(report.message_id))
+                .await
+                .expect("Could not delete message");
+            room.send(BanUser(Ban::new(report.author, None, now_millis())))
+                .await
+                .expect("Could not ban user");
+        }
+    }
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[derive(Deserialize)]
+struct NewKey {
+    room: String,
+    scope: Scope,
+    #[serde(default)]
+    flair: Option<String>,
+}
+
+// The only time a key's secret is ever shown
+#[derive(Serialize)]
+struct MintedKey {
+    #[serde(flatten)]
+    key: ApiKey,
+    secret: String,
+}
+
+// POST /admin/keys
+async fn mint_key(
+    new: NewKey,
+    keys: Address<KeyStore>,
+    registry: Address<RoomRegistry>,
+) -> Result<impl Reply, Rejection> {
+    let room = match registry::normalize(&new.room) {
+        Ok(name) => registry
+            .send(GetRoom(name.clone()))
+            .await
+            .expect("Could not reach the registry")
+            .map(|_| name),
+        Err(_) => None,
+    };
+    let room = match room {
+        Some(room) => room,
+        None => {
+            let error = format!("No such room: {}", new.room);
+            return Ok(warp::reply::with_status(
+                warp::reply::json(&error),
+                StatusCode::BAD_REQUEST,
+            ));
+        }
+    };
+
+    let flair = new.flair.map(|flair| flair.trim().to_string());
+    if flair
+        .as_ref()
+        .is_some_and(|flair| flair.is_empty() || flair.chars().count() > MAX_FLAIR_LEN)
+    {
+        let error = format!("Flair is 1 to {} characters", MAX_FLAIR_LEN);
+        return Ok(warp::reply::with_status(
+            warp::reply::json(&error),
+            StatusCode::BAD_REQUEST,
+        ));
+    }
+
+    let (key, secret) = keys
+        .send(MintKey {
+            room,
+            scope: new.scope,
+            flair,
+        })
+        .await
+        .expect("Could not mint key");
+    Ok(warp::reply::with_status(
+        warp::reply::json(&MintedKey { key, secret }),
+        StatusCode::CREATED,
+    ))
+}
+
+// GET /admin/keys
+async fn list_keys(keys: Address<KeyStore>) -> Result<impl Reply, Rejection> {
+    let keys = keys.send(ListKeys).await.expect("Could not list keys");
+    Ok(warp::reply::json(&keys))
+}
+
+// ConnectionsQuery - `?lagging=true` for only the connections that are
+#[derive(Deserialize)]
+struct ConnectionsQuery {
+    #[serde(default)]
+    lagging: bool,
+}
+
+// GET /admin/connections
+async fn list_connections(
+    query: PageQuery,
+    filter: ConnectionsQuery,
+    connections: Address<Connections>,
+) -> Result<impl Reply, Rejection> {
+    let mut connections = connections
+        .send(ListConnections)
+        .await
+        .expect("Could not list connections");
+    if filter.lagging {
+        connections.retain(|connection| connection.lagging);
+    }
+    let page = page::page(connections, |connection| connection.id.to_string(), &query);
+    Ok(page::reply(page))
+}
+
+// GET /admin/connections/:id/trace
+async fn trace(id: Uuid, connections: Address<Connections>) -> Result<impl Reply, Rejection> {
+    let frames = connections
+        .send(GetTrace(id))
+        .await
+        .expect("Could not reach the connections");
+    match frames {
+        Some(frames) => Ok(warp::reply::json(&frames)),
+        None => Err(warp::reject::not_found()),
+    }
+}
+
+// GET /admin/users/deactivated
+async fn list_deactivated(
+    query: PageQuery,
+    accounts: Address<Accounts>,
+) -> Result<impl Reply, Rejection> {
+    let deactivated = accounts
+        .send(ListDeactivated)
+        .await
+        .expect("Could not list deactivated users");
+    let page = page::page(
+        deactivated,
+        |deactivation| deactivation.id.to_string(),
+        &query,
+    );
+    Ok(page::reply(page))
+}
+
+// POST /admin/users/:id/deactivate
+async fn deactivate(id: Uuid, accounts: Address<Accounts>) -> Result<impl Reply, Rejection> {
+    let deactivation = accounts
+        .send(Deactivate(id))
+        .await
+        .expect("Could not deactivate");
+    Ok(warp::reply::json(&deactivation))
+}
+
+// POST /admin/users/:id/reactivate
+async fn reactivate(id: Uuid, accounts: Address<Accounts>) -> Result<impl Reply, Rejection> {
+    let reactivated = accounts
+        .send(Reactivate(id))
+        .await
+        .expect("Could not reactivate");
+    if reactivated {
+        Ok(StatusCode::NO_CONTENT)
+    } else {
+        Err(warp::reject::not_found())
+    }
+}
+
+// GET /admin/state
+async fn export_state(registry: Address<RoomRegistry>) -> Result<impl Reply, Rejection> {
+    Ok(warp::reply::json(&snapshot::export(&registry).await))
+}
+
+#[derive(Serialize)]
+struct Restored {
+    rooms: usize,
+}
+
+// PUT /admin/state with what GET gave, here or on another server
+async fn import_state(
+    state: Snapshot,
+    registry: Address<RoomRegistry>,
+) -> Result<impl Reply, Rejection> {
+    Ok(match snapshot::restore(&registry, state).await {
+        Ok(rooms) => {
+            warp::reply::with_status(warp::reply::json(&Restored { rooms }), StatusCode::OK)
+        }
+        Err(e) => naming_error(e),
+    })
+}
+
+// GET /admin/templates
+async fn list_templates(
+    query: PageQuery,
+    registry: Address<RoomRegistry>,
+) -> Result<impl Reply, Rejection> {
+    let templates = registry
+        .send(ListTemplates)
+        .await
+        .expect("Could not list templates");
+    let page = page::page(templates, |template| template.name.clone(), &query);
+    Ok(page::reply(page))
+}
+
+// POST /admin/templates with room settings, named for the template; one
+// of the same name is replaced
+async fn save_template(
+    template: RoomSettings,
+    registry: Address<RoomRegistry>,
+) -> Result<impl Reply, Rejection> {
+    let saved = registry
+        .send(SaveTemplate(template))
+        .await
+        .expect("Could not reach the registry");
+    Ok(match saved {
+        Ok(template) => warp::reply::with_status(warp::reply::json(&template), StatusCode::OK),
+        Err(e) => warp::reply::with_status(warp::reply::json(&e), StatusCode::BAD_REQUEST),
+    })
+}
+
+// DELETE /admin/templates/:name
+async fn remove_template(
+    name: String,
+    registry: Address<RoomRegistry>,
+) -> Result<impl Reply, Rejection> {
+    let removed = registry
+        .send(RemoveTemplate(name))
+        .await
+        .expect("Could not reach the registry");
+    if removed {
+        Ok(StatusCode::NO_CONTENT)
+    } else {
+        Err(warp::reject::not_found())
+    }
+}
+
+#[derive(Deserialize)]
+struct FromTemplate {
+    name: String,
+}
+
+// POST /admin/templates/:name/rooms with `{"name": "..."}`
+async fn from_template(
+    template: String,
+    new: FromTemplate,
+    registry: Address<RoomRegistry>,
+) -> Result<impl Reply, Rejection> {
+    let created = registry
+        .send(CreateFromTemplate {
+            template,
+            name: new.name,
+        })
+        .await
+        .expect("Could not reach the registry");
+    Ok(match created {
+        Ok(settings) => warp::reply::with_status(warp::reply::json(&settings), StatusCode::CREATED),
+        Err(e) if e.code() == "no_such_template" => {
+            return Err(warp::reject::not_found());
+        }
+        Err(e) => naming_error(e),
+    })
+}
+
+// GET /admin/announcements
+async fn list_announcements(
+    query: PageQuery,
+    announcements: Address<Announcements>,
+) -> Result<impl Reply, Rejection> {
+    let listed = announcements
+        .send(ListAnnouncements)
+        .await
+        .expect("Could not list announcements");
+    let page = page::page(listed, |announcement| announcement.id.to_string(), &query);
+    Ok(page::reply(page))
+}
+
+// The announcement with its room's current name, or why it won't do
+async fn check_announcement(
+    announcement: Announcement,
+    registry: &Address<RoomRegistry>,
+) -> Result<Announcement, warp::reply::WithStatus<warp::reply::Json>> {
+    if announcement.body.trim().is_empty() {
+        let error = ProtocolError::new("bad_event", "Announcements need a body".to_string());
+        return Err(warp::reply::with_status(
+            warp::reply::json(&error),
+            StatusCode::BAD_REQUEST,
+        ));
+    }
+    let found = registry
+        .send(FindRoom(announcement.room.clone()))
+        .await
+        .expect("Could not reach the registry");
+    match found {
+        Some((room, _)) => Ok(Announcement {
+            room,
+            ..announcement
+        }),
+        None => {
+            let error = ProtocolError::new(
+                "no_such_room",
+                format!("No such room: {}", announcement.room),
+            );
+            Err(warp::reply::with_status(
+                warp::reply::json(&error),
+                StatusCode::NOT_FOUND,
+            ))
+        }
+    }
+}
+
+// POST /admin/announcements, answering 201 with it and its id
+async fn add_announcement(
+    announcement: Announcement,
+    announcements: Address<Announcements>,
+    registry: Address<RoomRegistry>,
+) -> Result<impl Reply, Rejection> {
+    let announcement = match check_announcement(announcement, &registry).await {
+        Ok(announcement) => announcement,
+        Err(reply) => return Ok(reply),
+    };
+    let added = announcements
+        .send(AddAnnouncement(announcement))
+        .await
+        .expect("Could not add announcement");
+    Ok(warp::reply::with_status(
+        warp::reply::json(&added),
+        StatusCode::CREATED,
+    ))
+}
+
+// PUT /admin/announcements/:id
+async fn update_announcement(
+    id: Uuid,
+    announcement: Announcement,
+    announcements: Address<Announcements>,
+    registry: Address<RoomRegistry>,
+) -> Result<impl Reply, Rejection> {
+    let announcement = match check_announcement(announcement, &registry).await {
+        Ok(announcement) => announcement,
+        Err(reply) => return Ok(reply),
+    };
+    let updated = announcements
+        .send(UpdateAnnouncement(id, announcement))
+        .await
+        .expect("Could not update announcement")
+        .ok_or_else(warp::reject::not_found)?;
+    Ok(warp::reply::with_status(
+        warp::reply::json(&updated),
+        StatusCode::OK,
+    ))
+}
+
+// DELETE /admin/announcements/:id
+async fn remove_announcement(
+    id: Uuid,
+    announcements: Address<Announcements>,
+) -> Result<impl Reply, Rejection> {
+    let removed = announcements
+        .send(RemoveAnnouncement(id))
+        .await
+        .expect("Could not remove announcement");
+    if removed {
+        Ok(StatusCode::NO_CONTENT)
+    } else {
+        Err(warp::reject::not_found())
+    }
+}
+
+// DELETE /admin/keys/:id
+async fn revoke_key(id: Uuid, keys: Address<KeyStore>) -> Result<impl Reply, Rejection> {
+    let revoked = keys
+        .send(RevokeKey(id))
+        .await
+        .expect("Could not revoke key");
+    if revoked {
+        Ok(StatusCode::NO_CONTENT)
+    } else {
+        Err(warp::reject::not_found())
+    }
+}
+
+#[derive(Deserialize)]
+struct NewMaintenance {
+    // How long connected users get before they're disconnected
+    #[serde(default = "default_drain_secs")]
+    drain_secs: u64,
+}
+
+fn default_drain_secs() -> u64 {
+    30
+}
+
+// POST /admin/maintenance
+async fn start_maintenance(
+    new: NewMaintenance,
+    drain: Arc<watch::Sender<Option<Drain>>>,
+) -> Result<impl Reply, Rejection> {
+    let until = Instant::now() + Duration::from_secs(new.drain_secs);
+    log::info!("Maintenance: closing connections in {}s", new.drain_secs);
+    drain
+        .send(Some(Drain { until }))
+        .expect("Could not start maintenance");
+    Ok(StatusCode::NO_CONTENT)
+}
+
+// DELETE /admin/maintenance
+async fn end_maintenance(
+    drain: Arc<watch::Sender<Option<Drain>>>,
+) -> Result<impl Reply, Rejection> {
+    log::info!("Maintenance over");
+    drain.send(None).expect("Could not end maintenance");
+    Ok(StatusCode::NO_CONTENT)
+}
+
+static ADMIN_HTML: &str = r#"<!DOCTYPE html>
+<html lang="en">
+    <head>
+        <title>Warp Chat Admin</title>
+    </head>
+    <body>
+        <h1>Warp chat admin</h1>
+        <p>
+            <input type="password" id="token" placeholder="Admin token or key" />
+            <button type="button" id="save">Use</button>
+            <em id="status"></em>
+        </p>
+        <h2>Messages per second</h2>
+        <canvas id="graph" width="600" height="120"></canvas>
+        <h2>Rooms</h2>
+        <table>
+            <thead><tr><th>Room</th><th>Visibility</th><th>Members</th></tr></thead>
+            <tbody id="rooms"></tbody>
+        </table>
+        <h2 id="members-title"></h2>
+        <ul id="members"></ul>
+        <script type="text/javascript">
+        const status = document.getElementById('status');
+        const token = document.getElementById('token');
+        token.value = sessionStorage.getItem('chat-admin-token') || '';
+        let selected = null;
+
+        function api(method, path) {
+            return fetch('/admin/' + path, {
+                method: method,
+                headers: {'Authorization': 'Bearer ' + token.value},
+            }).then(function(res) {
+                if (!res.ok) {
+                    throw new Error(res.status + ' ' + res.statusText);
+                }
+                status.innerText = '';
+                return res.status === 204 ? null : res.json();
+            }).catch(function(e) {
+                status.innerText = e.message;
+                throw e;
+            });
+        }
+
+        // Every page of a list, following Next-Cursor
+        function list(path, cursor, items) {
+            const page = cursor ? path + '?cursor=' + encodeURIComponent(cursor) : path;
+            return fetch('/admin/' + page, {
+                headers: {'Authorization': 'Bearer ' + token.value},
+            }).then(function(res) {
+                if (!res.ok) {
+                    throw new Error(res.status + ' ' + res.statusText);
+                }
+                status.innerText = '';
+                const next = res.headers.get('Next-Cursor');
+                return res.json().then(function(page) {
+                    items = (items || []).concat(page);
+                    return next ? list(path, next, items) : items;
+                });
+            }).catch(function(e) {
+                status.innerText = e.message;
+                throw e;
+            });
+        }
+
+        function cell(row, text) {
+            const td = document.createElement('td');
+            td.innerText = text;
+            row.appendChild(td);
+            return td;
+        }
+
+        function loadRooms() {
+            list('rooms').then(function(rooms) {
+                const body = document.getElementById('rooms');
+                body.innerHTML = '';
+                rooms.forEach(function(room) {
+                    const row = document.createElement('tr');
+                    const name = cell(row, room.name);
+                    name.style.cursor = 'pointer';
+                    name.style.textDecoration = 'underline';
+                    name.onclick = function() {
+                        selected = room.name;
+                        loadMembers();
+                    };
+                    cell(row, room.visibility);
+                    cell(row, room.members + (room.max_members ? ' / ' + room.max_members : ''));
+                    body.appendChild(row);
+                });
+            });
+        }
+
+        function loadMembers() {
+            if (selected === null) {
+                return;
+            }
+            document.getElementById('members-title').innerText = 'Members of ' + selected;
+            list('rooms/' + selected + '/members').then(function(members) {
+                const list = document.getElementById('members');
+                list.innerHTML = '';
+                members.forEach(function(id) {
+                    const item = document.createElement('li');
+                    item.appendChild(document.createTextNode(id + ' '));
+                    ['kick', 'ban', 'mute', 'unmute'].forEach(function(action) {
+                        const button = document.createElement('button');
+                        button.innerText = action;
+                        button.onclick = function() {
+                            if (action === 'ban' && !confirm('Ban ' + id + ' from ' + selected + '?')) {
+                                return;
+                            }
+                            api('POST', 'rooms/' + selected + '/members/' + id + '/' + action)
+                                .then(refresh);
+                        };
+                        item.appendChild(button);
+                    });
+                    list.appendChild(item);
+                });
+            });
+        }
+
+        // Message throughput, from the got_user_message handler count
+        const samples = [];
+        let lastCount = null;
+        function sample() {
+            fetch('/metrics').then(function(res) {
+                return res.text();
+            }).then(function(text) {
+                const match = text.match(/chat_handler_duration_seconds_count\{handler="got_user_message"\} (\d+)/);
+                const count = match ? parseInt(match[1], 10) : 0;
+                if (lastCount !== null) {
+                    samples.push((count - lastCount) / 5);
+                    if (samples.length > 60) {
+                        samples.shift();
+                    }
+                }
+                lastCount = count;
+                draw();
+            });
+        }
+        function draw() {
+            const canvas = document.getElementById('graph');
+            const ctx = canvas.getContext('2d');
+            ctx.clearRect(0, 0, canvas.width, canvas.height);
+            const max = Math.max(1, ...samples);
+            ctx.beginPath();
+            samples.forEach(function(rate, i) {
+                const x = i * canvas.width / 59;
+                const y = canvas.height - rate / max * (canvas.height - 10);
+                if (i === 0) {
+                    ctx.moveTo(x, y);
+                } else {
+                    ctx.lineTo(x, y);
+                }
+            });
+            ctx.stroke();
+            const last = samples.length ? samples[samples.length - 1] : 0;
+            ctx.fillText(last.toFixed(1) + '/s (max ' + max.toFixed(1) + ')', 5, 12);
+        }
+
+        function refresh() {
+            loadRooms();
+            loadMembers();
+        }
+        document.getElementById('save').onclick = function() {
+            sessionStorage.setItem('chat-admin-token', token.value);
+            refresh();
+        };
+        refresh();
+        sample();
+        setInterval(refresh, 5000);
+        setInterval(sample, 5000);
+        </script>
+    </body>
+</html>
+"#;
@@ -0,0 +1,67 @@
+//! What the benchmarks in `benches/` drive, since they only see the public
+//! API. Not for use otherwise.
+
+use std::time::Instant;
+use uuid::Uuid;
+
+use crate::registry::RoomSettings;
+use crate::room::{Effect, Joiner, RoomState};
+
+/// A room with members in it and nobody to deliver to, so what's measured
+/// is deciding and serializing what each member gets.
+pub struct Room {
+    state: RoomState,
+    poster: Uuid,
+    now: Instant,
+    sent_at: u64,
+}
+
+impl Room {
+    pub fn with_members(members: usize) -> Self {
+        let mut state = RoomState::new(RoomSettings::named("bench"), None);
+        let now = Instant::now();
+        for n in 0..members {
+            let joiner = Joiner {
+                id: Uuid::from_u128(n as u128 + 1),
+                echo: false,
+                bot: false,
+                moderator: false,
+                invite: None,
+                spectator: false,
+                placed: false,
+            };
+            state.join(joiner, now, 0).expect("Could not join");
+        }
+        state.effects();
+        Self {
+            state,
+            poster: Uuid::from_u128(1),
+            now,
+            sent_at: 0,
+        }
+    }
+
+    /// Posts `messages` messages in one burst and sends them out, answering
+    /// how many frames that took.
+    pub fn post(&mut self, body: &str, messages: usize) -> usize {
+        for _ in 0..messages {
+            self.sent_at += 1;
+            self.state
+                .post(
+                    self.poster,
+                    body.to_string(),
+                    None,
+                    Uuid::new_v4(),
+                    self.now,
+                    self.sent_at,
+                )
+                .expect("Could not post");
+        }
+        self.state.flush();
+        self.state
+            .effects()
+            .iter()
+            .filter(|effect| matches!(effect, Effect::Send(..)))
+            .count()
+    }
+}
@@ -0,0 +1,74 @@
+// chat-cli - a line mode client, handy for smoke testing a deployment
+//
+//     chat-cli ws://127.0.0.1:3030/ws [key]
+//
+// Lines are sent as messages to the current room, except for:
+//
+//     /join <room>    follow another room and make it current
+//     /members        list who's in the current room
+//     /quit           leave
+
+use tokio::io::{self, AsyncBufReadExt, BufReader};
+use yee::client::Client;
+
+static DEFAULT_ROOM: &str = "lobby";
+
+#[tokio::main]
+async fn main() {
+    let mut args = std::env::args().skip(1);
+    let url = args
+        .next()
+        .unwrap_or_else(|| "ws://127.0.0.1:3030/ws".to_string());
+    let key = args.next();
+
+    let mut client = match Client::connect(&url, key.as_deref()).await {
+        Ok(client) => client,
+        Err(e) => {
+            eprintln!("Could not connect to {}: {}", url, e);
+            std::process::exit(1);
+        }
+    };
+    client.join(DEFAULT_ROOM);
+    client.on_message(|message| {
+        let from = message.from.to_simple().to_string();
+        println!("[{}] <{}> {}", message.room, &from[..8], message.body);
+    });
+    client.on_members(|room, members| {
+        println!("[{}] {} here:", room, members.len());
+        for member in members {
+            println!("  {}", member);
+        }
+    });
+    client.on_error(|error| eprintln!("error: {}", error));
+
+    let handle = client.handle();
+    let client = tokio::spawn(client.run());
+    println!("Connected to {}, in {}", url, DEFAULT_ROOM);
+
+    let mut room = DEFAULT_ROOM.to_string();
+    let mut lines = BufReader::new(io::stdin()).lines();
+    while let Ok(Some(line)) = lines.next_line().await {
+        let line = line.trim();
+        let sent = match line.split_once(' ') {
+            _ if line.is_empty() => Ok(()),
+            _ if line == "/quit" => break,
+            _ if line == "/members" => handle.members(&room),
+            Some(("/join", name)) => {
+                room = name.trim().to_string();
+                handle.join(&room)
+            }
+            _ if line.starts_with('/') => {
+                eprintln!("Unknown command: {}", line);
+                Ok(())
+            }
+            _ => handle.send(&room, line),
+        };
+        if let Err(e) = sent {
+            eprintln!("{}", e);
+            break;
+        }
+    }
+
+    drop(handle);
+    let _ = client.await;
+}
@@ -0,0 +1,350 @@
+// conformance - checks a server speaks the protocol the README documents,
+// for forks and other deployments to try their own builds against
+//
+//     conformance ws://127.0.0.1:3030/ws [--json]
+//
+// It connects as a few plain clients, so the server has to let anonymous
+// websockets in (no CHAT_POW_BITS) and let them create rooms. Prints a TAP
+// report, or JSON with --json, and exits 1 if any check failed.
+
+use futures::{SinkExt, StreamExt};
+use serde_json::{json, Value};
+use std::collections::VecDeque;
+use std::time::Duration;
+use tokio::net::TcpStream;
+use tokio_tungstenite::tungstenite::Message;
+use tokio_tungstenite::WebSocketStream;
+use uuid::Uuid;
+
+// How long to wait for an answer before the check fails
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+// Client - one connection, and the events it's been sent but that no check
+// has looked at yet, batches taken apart
+struct Client {
+    socket: WebSocketStream<TcpStream>,
+    pending: VecDeque<Value>,
+}
+impl Client {
+    async fn connect(url: &str, query: &str) -> Result<Self, String> {
+        let url = match query {
+            "" => url.to_string(),
+            _ if url.contains('?') => format!("{}&{}", url, query),
+            _ => format!("{}?{}", url, query),
+        };
+        let (socket, _) = tokio_tungstenite::connect_async(url.as_str())
+            .await
+            .map_err(|e| format!("could not connect to {}: {}", url, e))?;
+        Ok(Self {
+            socket,
+            pending: VecDeque::new(),
+        })
+    }
+
+    async fn send(&mut self, event: Value) -> Result<(), String> {
+        self.socket
+            .send(Message::Text(event.to_string()))
+            .await
+            .map_err(|e| format!("could not send: {}", e))
+    }
+
+    // The next event `matches` takes, skipping the ones before it
+    async fn expect(
+        &mut self,
+        what: &str,
+        matches: impl Fn(&Value) -> bool,
+    ) -> Result<Value, String> {
+        let read = async {
+            loop {
+                while let Some(event) = self.pending.pop_front() {
+                    if matches(&event) {
+                        return Ok(event);
+                    }
+                }
+                let text = match self.socket.next().await {
+                    Some(Ok(Message::Text(text))) => text,
+                    Some(Ok(Message::Close(_))) | None => {
+                        return Err(format!("closed while waiting for {}", what))
+                    }
+                    Some(Ok(_)) => continue,
+                    Some(Err(e)) => return Err(format!("websocket error: {}", e)),
+                };
+                let event: Value = serde_json::from_str(&text)
+                    .map_err(|e| format!("sent something that isn't JSON ({}): {}", e, text))?;
+                self.unbatch(event);
+            }
+        };
+        tokio::time::timeout(TIMEOUT, read)
+            .await
+            .unwrap_or_else(|_| Err(format!("no {} within {:?}", what, TIMEOUT)))
+    }
+
+    fn unbatch(&mut self, event: Value) {
+        match event["events"].as_array() {
+            Some(events) if event["type"] == "batch" => {
+                for event in events.clone() {
+                    self.unbatch(event);
+                }
+            }
+            _ => self.pending.push_back(event),
+        }
+    }
+
+    async fn error(&mut self, code: &str) -> Result<(), String> {
+        let error = self
+            .expect("an error", |event| event["type"] == "error")
+            .await?;
+        match error["code"].as_str() {
+            Some(got) if got == code => Ok(()),
+            _ => Err(format!("expected {}, got {}", code, error)),
+        }
+    }
+}
+
+fn of_type<'a>(kind: &'a str, room: &'a str) -> impl Fn(&Value) -> bool + 'a {
+    move |event| event["type"] == kind && event["room"] == room
+}
+
+fn posted<'a>(room: &'a str, body: &'a str) -> impl Fn(&Value) -> bool + 'a {
+    move |event| event["type"] == "message" && event["room"] == room && event["body"] == body
+}
+
+// Report - how each check went, in the order they ran
+#[derive(Default)]
+struct Report {
+    checks: Vec<(&'static str, Result<(), String>)>,
+    bailed: Option<String>,
+}
+impl Report {
+    // Records a check, handing back what it found if it passed
+    fn check<T>(&mut self, name: &'static str, result: Result<T, String>) -> Option<T> {
+        match result {
+            Ok(found) => {
+                self.checks.push((name, Ok(())));
+                Some(found)
+            }
+            Err(e) => {
+                self.checks.push((name, Err(e)));
+                None
+            }
+        }
+    }
+
+    fn failed(&self) -> usize {
+        self.checks
+            .iter()
+            .filter(|(_, result)| result.is_err())
+            .count()
+    }
+
+    fn tap(&self) -> String {
+        let mut tap = String::from("TAP version 13\n");
+        for (n, (name, result)) in self.checks.iter().enumerate() {
+            match result {
+                Ok(()) => tap.push_str(&format!("ok {} - {}\n", n + 1, name)),
+                Err(e) => {
+                    tap.push_str(&format!("not ok {} - {}\n", n + 1, name));
+                    tap.push_str(&format!("  ---\n  message: {}\n  ...\n", json!(e)));
+                }
+            }
+        }
+        if let Some(reason) = &self.bailed {
+            tap.push_str(&format!("Bail out! {}\n", reason));
+        }
+        tap.push_str(&format!("1..{}\n", self.checks.len()));
+        tap
+    }
+
+    fn json(&self) -> Value {
+        let checks: Vec<_> = self
+            .checks
+            .iter()
+            .map(|(name, result)| match result {
+                Ok(()) => json!({"name": name, "ok": true}),
+                Err(e) => json!({"name": name, "ok": false, "error": e}),
+            })
+            .collect();
+        json!({
+            "passed": self.checks.len() - self.failed(),
+            "failed": self.failed(),
+            "bailed": self.bailed,
+            "checks": checks,
+        })
+    }
+}
+
+// The checks, in order. Later ones lean on what earlier ones set up, so an
+// Err is a failure nothing after it could get past.
+async fn run(url: &str, report: &mut Report) -> Result<(), String> {
+    let room = format!(
+        "conformance-{}",
+        &Uuid::new_v4().to_simple().to_string()[..8]
+    );
+    let mut a = Client::connect(url, "echo=true").await?;
+
+    let joined = a.expect("joined", of_type("joined", "lobby")).await;
+    report.check("a new connection starts out in the lobby", joined);
+
+    let pong = async {
+        a.send(json!({"type": "ping", "nonce": "conformance"}))
+            .await?;
+        let pong = a.expect("pong", |event| event["type"] == "pong").await?;
+        match (pong["nonce"].as_str(), pong["server_time"].as_u64()) {
+            (Some("conformance"), Some(_)) => Ok(()),
+            _ => Err(format!("expected the nonce and server_time, got {}", pong)),
+        }
+    };
+    report.check("a ping is answered with a pong", pong.await);
+
+    let created = async {
+        a.send(json!({"type": "create_room", "name": room})).await?;
+        a.expect("room_created", |event| {
+            event["type"] == "room_created" && event["name"] == room.as_str()
+        })
+        .await
+    };
+    report
+        .check("create_room makes a room", created.await)
+        .ok_or("no room to carry on in")?;
+
+    let joined = async {
+        a.send(json!({"type": "join", "room": room})).await?;
+        a.expect("joined", of_type("joined", &room)).await
+    };
+    report
+        .check("join follows a room", joined.await)
+        .ok_or("couldn't join the room")?;
+
+    let mut b = Client::connect(url, "").await?;
+    b.send(json!({"type": "join", "room": room})).await?;
+    b.expect("joined", of_type("joined", &room)).await?;
+
+    let echoed = async {
+        a.send(json!({"type": "message", "room": room, "body": "first"}))
+            .await?;
+        let echoed = a.expect("the echo", posted(&room, "first")).await?;
+        match (
+            echoed["id"].as_str(),
+            echoed["seq"].as_u64(),
+            echoed["sent_at"].as_u64(),
+        ) {
+            (Some(_), Some(seq), Some(_)) => Ok(seq),
+            _ => Err(format!("expected an id, seq and sent_at, got {}", echoed)),
+        }
+    };
+    let first = report
+        .check(
+            "a message is echoed with its id, seq and sent_at",
+            echoed.await,
+        )
+        .ok_or("no seq to resume from")?;
+
+    let delivered = b.expect("the message", posted(&room, "first")).await;
+    report.check("a message reaches the room's other members", delivered);
+
+    let history = async {
+        b.send(json!({"type": "history", "room": room, "limit": 10}))
+            .await?;
+        let page = b.expect("history", of_type("history", &room)).await?;
+        let has = page["messages"]
+            .as_array()
+            .is_some_and(|messages| messages.iter().any(|m| m["seq"] == first));
+        match has {
+            true => Ok(()),
+            false => Err(format!("expected seq {} in {}", first, page)),
+        }
+    };
+    report.check("history has what was posted", history.await);
+
+    let resumed = async {
+        a.send(json!({"type": "message", "room": room, "body": "second"}))
+            .await?;
+        a.expect("the echo", posted(&room, "second")).await?;
+        let query = format!("rooms={}&since={}", room, first);
+        let mut c = Client::connect(url, &query).await?;
+        c.expect("joined", of_type("joined", &room)).await?;
+        let replay = c.expect("replay", of_type("replay", &room)).await?;
+        let messages = replay["messages"].as_array().cloned().unwrap_or_default();
+        let missed = messages.iter().any(|m| m["body"] == "second");
+        let repeated = messages.iter().any(|m| m["seq"].as_u64() <= Some(first));
+        match (missed, repeated) {
+            (true, false) => Ok(()),
+            _ => Err(format!(
+                "expected only what came after {} in {}",
+                first, replay
+            )),
+        }
+    };
+    report.check(
+        "?rooms= and ?since= resume with what was missed",
+        resumed.await,
+    );
+
+    let left = async {
+        b.send(json!({"type": "leave", "room": room})).await?;
+        b.expect("left", of_type("left", &room)).await
+    };
+    report.check("leave stops following a room", left.await);
+
+    let not_joined = async {
+        b.send(json!({"type": "message", "room": room, "body": "gone"}))
+            .await?;
+        b.error("not_joined").await
+    };
+    report.check(
+        "posting to a room not joined is refused with not_joined",
+        not_joined.await,
+    );
+
+    let bad = async {
+        a.send(json!({"type": "no_such_event"})).await?;
+        a.error("bad_event").await
+    };
+    report.check("an unknown event is refused with bad_event", bad.await);
+
+    let missing = async {
+        let missing = format!("{}-missing", room);
+        a.send(json!({"type": "join", "room": missing})).await?;
+        a.error("no_such_room").await
+    };
+    report.check(
+        "joining a room that doesn't exist is refused with no_such_room",
+        missing.await,
+    );
+
+    let too_large = async {
+        let body = "x".repeat(70 * 1024);
+        a.send(json!({"type": "message", "room": room, "body": body}))
+            .await?;
+        a.error("event_too_large").await
+    };
+    report.check(
+        "an event over 64 KiB is refused with event_too_large",
+        too_large.await,
+    );
+    Ok(())
+}
+
+#[tokio::main]
+async fn main() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let as_json = args.iter().any(|arg| arg == "--json");
+    let url = args
+        .iter()
+        .find(|arg| !arg.starts_with("--"))
+        .cloned()
+        .unwrap_or_else(|| "ws://127.0.0.1:3030/ws".to_string());
+
+    let mut report = Report::default();
+    if let Err(e) = run(&url, &mut report).await {
+        report.bailed = Some(e);
+    }
+    if as_json {
+        println!("{}", report.json());
+    } else {
+        print!("{}", report.tap());
+    }
+    if report.failed() > 0 || report.bailed.is_some() {
+        std::process::exit(1);
+    }
+}
@@ -0,0 +1,95 @@
+use std::str::FromStr;
+use std::time::Duration;
+use uuid::Uuid;
+
+// Chaos - how often each fault happens to a frame on its way out, for
+// testing how clients cope. Only built with `--features chaos`.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Chaos {
+    // Each a probability, per frame
+    pub disconnect: f64,
+    pub drop: f64,
+    pub duplicate: f64,
+    pub reorder: f64,
+    pub delay: f64,
+    // Delays are up to this long
+    pub max_delay: Duration,
+}
+
+// Fault - what happens to one frame
+pub enum Fault {
+    // The connection goes, without a word
+    Disconnect,
+    Drop,
+    // Sent twice
+    Duplicate,
+    // Sent after the next one
+    Reorder,
+    Delay(Duration),
+}
+
+impl Chaos {
+    pub fn roll(&self) -> Option<Fault> {
+        let faults = [
+            (self.disconnect, Fault::Disconnect),
+            (self.drop, Fault::Drop),
+            (self.duplicate, Fault::Duplicate),
+            (self.reorder, Fault::Reorder),
+        ];
+        for (chance, fault) in faults {
+            if random() < chance {
+                return Some(fault);
+            }
+        }
+        if random() < self.delay {
+            return Some(Fault::Delay(self.max_delay.mul_f64(random())));
+        }
+        None
+    }
+}
+
+// In [0, 1), from the same source as random ids
+fn random() -> f64 {
+    (Uuid::new_v4().as_u128() >> 75) as f64 / (1u64 << 53) as f64
+}
+
+impl FromStr for Chaos {
+    type Err = String;
+
+    // `drop=0.05,delay=0.1,delay_ms=2000`, and so on
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut chaos = Chaos {
+            max_delay: Duration::from_secs(1),
+            ..Chaos::default()
+        };
+        for setting in s.split(',').map(str::trim).filter(|s| !s.is_empty()) {
+            let (name, value) = setting
+                .split_once('=')
+                .ok_or_else(|| format!("{}: expected <fault>=<probability>", setting))?;
+            if name == "delay_ms" {
+                let ms = value.parse().map_err(|e| format!("{}: {}", setting, e))?;
+                chaos.max_delay = Duration::from_millis(ms);
+                continue;
+            }
+            let chance: f64 = value
+                .parse()
+                .ok()
+                .filter(|chance| (0.0..=1.0).contains(chance))
+                .ok_or_else(|| format!("{}: probabilities are 0 to 1", setting))?;
+            match name {
+                "disconnect" => chaos.disconnect = chance,
+                "drop" => chaos.drop = chance,
+                "duplicate" => chaos.duplicate = chance,
+                "reorder" => chaos.reorder = chance,
+                "delay" => chaos.delay = chance,
+                _ => {
+                    return Err(format!(
+                        "{}: expected disconnect, drop, duplicate, reorder, delay or delay_ms",
+                        name
+                    ))
+                }
+            }
+        }
+        Ok(chaos)
+    }
+}
@@ -0,0 +1,449 @@
+//! A small client for writing bots against the chat server.
+//!
+//! ```no_run
+//! # async fn bot() -> Result<(), yee::client::Error> {
+//! let mut client = yee::client::Client::connect("ws://127.0.0.1:3030/ws", None).await?;
+//! client.join("lobby");
+//! let handle = client.handle();
+//! client.on_message(move |message| {
+//!     if message.body == "ping" {
+//!         let _ = handle.send(&message.room, "pong");
+//!     }
+//! });
+//! client.run().await
+//! # }
+//! ```
+//!
+//! The client reconnects on its own when the connection drops, and on the way
+//! back in pages through history to hand over whatever it missed, in order.
+
+use futures::{SinkExt, StreamExt};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::time::Duration;
+use tokio::net::TcpStream;
+use tokio::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
+use tokio_tungstenite::tungstenite;
+use tokio_tungstenite::WebSocketStream;
+use uuid::Uuid;
+
+// Plain ws:// only; put a TLS terminating proxy in front for wss://
+type Socket = WebSocketStream<TcpStream>;
+
+// The most history the server hands out in one page
+const PAGE_LIMIT: usize = 200;
+const MIN_BACKOFF: Duration = Duration::from_millis(500);
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+
+/// A chat message as delivered to `on_message` callbacks.
+#[derive(Clone, Debug, Deserialize)]
+pub struct Message {
+    pub id: Uuid,
+    pub room: String,
+    pub seq: u64,
+    pub from: Uuid,
+    pub body: String,
+    /// Posted through a room key.
+    #[serde(default)]
+    pub is_bot: bool,
+    /// The key's badge text, if it has any.
+    #[serde(default)]
+    pub flair: Option<String>,
+}
+
+#[derive(Debug)]
+pub enum Error {
+    /// The websocket couldn't be opened or broke.
+    Socket(Box<tungstenite::Error>),
+    /// The client was dropped or `run` has already finished.
+    Closed,
+}
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Socket(e) => write!(f, "websocket error: {}", e),
+            Error::Closed => write!(f, "client is closed"),
+        }
+    }
+}
+impl std::error::Error for Error {}
+impl From<tungstenite::Error> for Error {
+    fn from(e: tungstenite::Error) -> Self {
+        Error::Socket(Box::new(e))
+    }
+}
+
+#[derive(Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum Request<'a> {
+    Message {
+        room: &'a str,
+        body: &'a str,
+    },
+    Join {
+        room: &'a str,
+    },
+    History {
+        room: &'a str,
+        before_seq: Option<u64>,
+        limit: usize,
+    },
+    Members {
+        room: &'a str,
+    },
+}
+
+// Command - what a Handle asks the running client to do
+enum Command {
+    Send(String),
+    Join(String),
+}
+
+#[derive(Deserialize)]
+struct HistoryMessage {
+    id: Uuid,
+    seq: u64,
+    from: Uuid,
+    body: String,
+    #[serde(default)]
+    is_bot: bool,
+    #[serde(default)]
+    flair: Option<String>,
+}
+
+// Only the events the client acts on; anything else is skipped
+#[derive(Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum Event {
+    Message(Message),
+    History {
+        room: String,
+        messages: Vec<HistoryMessage>,
+        has_more: bool,
+    },
+    Members {
+        room: String,
+        members: Vec<Uuid>,
+    },
+    Error {
+        message: String,
+    },
+    Batch {
+        events: Vec<Event>,
+    },
+    #[serde(other)]
+    Other,
+}
+
+// Where a joined room is at
+#[derive(Default)]
+struct RoomState {
+    // Newest message we've passed on; None until the first page comes back
+    last_seq: Option<u64>,
+    // Messages collected while catching up after a reconnect
+    catching_up: Option<BTreeMap<u64, Message>>,
+}
+
+/// Lets other tasks (and callbacks) talk through a running client.
+#[derive(Clone)]
+pub struct Handle {
+    tx: UnboundedSender<Command>,
+}
+impl Handle {
+    /// Posts a message to a room. While reconnecting it's held until the
+    /// connection is back.
+    pub fn send(&self, room: &str, body: &str) -> Result<(), Error> {
+        let frame = serde_json::to_string(&Request::Message { room, body }).unwrap();
+        self.command(Command::Send(frame))
+    }
+
+    /// Starts following another room once the client is running.
+    pub fn join(&self, room: &str) -> Result<(), Error> {
+        self.command(Command::Join(room.to_string()))
+    }
+
+    /// Asks who's in a room; the answer goes to `on_members`.
+    pub fn members(&self, room: &str) -> Result<(), Error> {
+        let frame = serde_json::to_string(&Request::Members { room }).unwrap();
+        self.command(Command::Send(frame))
+    }
+
+    fn command(&self, command: Command) -> Result<(), Error> {
+        self.tx.send(command).map_err(|_| Error::Closed)
+    }
+}
+
+type MessageCallback = Box<dyn FnMut(&Message) + Send>;
+type MembersCallback = Box<dyn FnMut(&str, &[Uuid]) + Send>;
+type ErrorCallback = Box<dyn FnMut(&str) + Send>;
+
+pub struct Client {
+    url: String,
+    socket: Option<Socket>,
+    rooms: HashMap<String, RoomState>,
+    on_message: Vec<MessageCallback>,
+    on_members: Vec<MembersCallback>,
+    on_error: Vec<ErrorCallback>,
+    // Dropped once `run` starts, so the channel closes with the last Handle
+    tx: Option<UnboundedSender<Command>>,
+    rx: UnboundedReceiver<Command>,
+}
+
+impl Client {
+    /// Opens a connection, authenticating with a room key when given one.
+    /// Fails straight away if the first connection can't be made.
+    pub async fn connect(url: &str, token: Option<&str>) -> Result<Self, Error> {
+        let url = match token {
+            Some(token) => {
+                let sep = if url.contains('?') { '&' } else { '?' };
+                format!("{}{}key={}", url, sep, token)
+            }
+            None => url.to_string(),
+        };
+        let (socket, _) = tokio_tungstenite::connect_async(url.as_str()).await?;
+        let (tx, rx) = mpsc::unbounded_channel();
+        Ok(Self {
+            url,
+            socket: Some(socket),
+            rooms: HashMap::new(),
+            on_message: Vec::new(),
+            on_members: Vec::new(),
+            on_error: Vec::new(),
+            tx: Some(tx),
+            rx,
+        })
+    }
+
+    /// Starts following a room's messages.
+    pub fn join(&mut self, room: &str) {
+        self.rooms.entry(room.to_string()).or_default();
+    }
+
+    /// Called for every message in a joined room, including ones caught up
+    /// on after a reconnect.
+    pub fn on_message<F: FnMut(&Message) + Send + 'static>(&mut self, f: F) {
+        self.on_message.push(Box::new(f));
+    }
+
+    /// Called with the answers to `Handle::members`.
+    pub fn on_members<F: FnMut(&str, &[Uuid]) + Send + 'static>(&mut self, f: F) {
+        self.on_members.push(Box::new(f));
+    }
+
+    /// Called with the text of error events from the server.
+    pub fn on_error<F: FnMut(&str) + Send + 'static>(&mut self, f: F) {
+        self.on_error.push(Box::new(f));
+    }
+
+    pub fn handle(&self) -> Handle {
+        let tx = self.tx.clone().expect("Client is already running");
+        Handle { tx }
+    }
+
+    /// Runs until every `Handle` and the client itself are gone, reconnecting
+    /// whenever the connection drops.
+    pub async fn run(mut self) -> Result<(), Error> {
+        self.tx = None;
+        let mut backoff = MIN_BACKOFF;
+        loop {
+            let mut socket = match self.socket.take() {
+                Some(socket) => socket,
+                None => match tokio_tungstenite::connect_async(self.url.as_str()).await {
+                    Ok((socket, _)) => {
+                        backoff = MIN_BACKOFF;
+                        socket
+                    }
+                    Err(_) => {
+                        tokio::time::sleep(backoff).await;
+                        backoff = (backoff * 2).min(MAX_BACKOFF);
+                        continue;
+                    }
+                },
+            };
+
+            if self.resume(&mut socket).await.is_err() {
+                continue;
+            }
+            if !self.pump(socket).await {
+                return Ok(());
+            }
+        }
+    }
+
+    // Joins each room again and asks for its newest page, which either sets
+    // the starting point or starts catching up on what we missed
+    async fn resume(&mut self, socket: &mut Socket) -> Result<(), tungstenite::Error> {
+        let mut requests = Vec::new();
+        for (room, state) in self.rooms.iter_mut() {
+            requests.push(serde_json::to_string(&Request::Join { room }).unwrap());
+            let limit = match state.last_seq {
+                Some(_) => {
+                    state.catching_up = Some(BTreeMap::new());
+                    PAGE_LIMIT
+                }
+                None => 1,
+            };
+            let request = Request::History {
+                room,
+                before_seq: None,
+                limit,
+            };
+            requests.push(serde_json::to_string(&request).unwrap());
+        }
+        for request in requests {
+            socket.send(tungstenite::Message::Text(request)).await?;
+        }
+        Ok(())
+    }
+
+    // Shuttles frames until the socket drops (true) or we're told to stop
+    // (false)
+    async fn pump(&mut self, mut socket: Socket) -> bool {
+        loop {
+            tokio::select! {
+                frame = socket.next() => match frame {
+                    Some(Ok(tungstenite::Message::Text(text))) => {
+                        if let Some(reply) = self.handle_frame(&text) {
+                            if socket.send(tungstenite::Message::Text(reply)).await.is_err() {
+                                return true;
+                            }
+                        }
+                    }
+                    Some(Ok(_)) => {}
+                    Some(Err(_)) | None => return true,
+                },
+                command = self.rx.recv() => {
+                    let frame = match command {
+                        Some(Command::Send(frame)) => frame,
+                        Some(Command::Join(room)) => {
+                            self.join(&room);
+                            let join = serde_json::to_string(&Request::Join { room: &room }).unwrap();
+                            if socket.send(tungstenite::Message::Text(join)).await.is_err() {
+                                return true;
+                            }
+                            let request = Request::History {
+                                room: &room,
+                                before_seq: None,
+                                limit: 1,
+                            };
+                            serde_json::to_string(&request).unwrap()
+                        }
+                        None => return false,
+                    };
+                    if socket.send(tungstenite::Message::Text(frame)).await.is_err() {
+                        return true;
+                    }
+                }
+            }
+        }
+    }
+
+    // Deals with one frame, returning a follow-up request if it needs one
+    fn handle_frame(&mut self, text: &str) -> Option<String> {
+        self.handle_event(serde_json::from_str(text).ok()?)
+    }
+
+    fn handle_event(&mut self, event: Event) -> Option<String> {
+        match event {
+            Event::Message(message) => {
+                let state = self.rooms.get_mut(&message.room)?;
+                match state.catching_up.as_mut() {
+                    Some(buffer) => {
+                        buffer.insert(message.seq, message);
+                    }
+                    None => self.deliver(message),
+                }
+                None
+            }
+            Event::History {
+                room,
+                messages,
+                has_more,
+            } => self.handle_history(room, messages, has_more),
+            Event::Members { room, members } => {
+                for f in self.on_members.iter_mut() {
+                    f(&room, &members);
+                }
+                None
+            }
+            Event::Error { message } => {
+                for f in self.on_error.iter_mut() {
+                    f(&message);
+                }
+                None
+            }
+            // Batches only carry messages, which never need a follow-up
+            Event::Batch { events } => {
+                for event in events {
+                    self.handle_event(event);
+                }
+                None
+            }
+            Event::Other => None,
+        }
+    }
+
+    fn handle_history(
+        &mut self,
+        room: String,
+        messages: Vec<HistoryMessage>,
+        has_more: bool,
+    ) -> Option<String> {
+        let state = self.rooms.get_mut(&room)?;
+        let last_seq = match state.last_seq {
+            Some(seq) => seq,
+            None => {
+                // First connection: we only wanted to know where we are
+                state.last_seq = Some(messages.last().map_or(0, |m| m.seq));
+                return None;
+            }
+        };
+
+        let buffer = state.catching_up.as_mut()?;
+        let oldest = messages.first().map(|m| m.seq);
+        for m in messages.into_iter().filter(|m| m.seq > last_seq) {
+            let message = Message {
+                id: m.id,
+                room: room.clone(),
+                seq: m.seq,
+                from: m.from,
+                body: m.body,
+                is_bot: m.is_bot,
+                flair: m.flair,
+            };
+            buffer.insert(message.seq, message);
+        }
+
+        // Keep paging back until we've overlapped with what we had
+        match oldest {
+            Some(oldest) if has_more && oldest > last_seq + 1 => {
+                let request = Request::History {
+                    room: &room,
+                    before_seq: Some(oldest),
+                    limit: PAGE_LIMIT,
+                };
+                Some(serde_json::to_string(&request).unwrap())
+            }
+            _ => {
+                let missed = state.catching_up.take().unwrap_or_default();
+                for (_, message) in missed {
+                    self.deliver(message);
+                }
+                None
+            }
+        }
+    }
+
+    fn deliver(&mut self, message: Message) {
+        if let Some(state) = self.rooms.get_mut(&message.room) {
+            if state.last_seq.is_some_and(|seq| message.seq <= seq) {
+                return;
+            }
+            state.last_seq = Some(message.seq);
+        }
+        for f in self.on_message.iter_mut() {
+            f(&message);
+        }
+    }
+}
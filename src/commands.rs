@@ -0,0 +1,209 @@
+use serde::{Deserialize, Serialize};
+use std::convert::TryFrom;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use uuid::Uuid;
+
+use crate::ProtocolError;
+
+// Longest command name, in characters
+const MAX_COMMAND_LEN: usize = 24;
+// How long a webhook may take to answer before the command has failed
+const TIMEOUT: Duration = Duration::from_secs(10);
+// Most of a webhook's answer read, head and all, in bytes
+const MAX_RESPONSE_LEN: u64 = 64 * 1024;
+
+// Webhook - where a room's command goes: an http:// URL. It's plain HTTP,
+// like translations, so it's meant for services next to us or a proxy
+// that does TLS for us.
+#[derive(Clone, Deserialize, Serialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct Webhook {
+    url: String,
+    // host:port to connect to, the Host header and the path to POST to
+    addr: String,
+    host: String,
+    path: String,
+}
+impl Webhook {
+    // The host without the port
+    pub fn hostname(&self) -> &str {
+        match self.host.rsplit_once(':') {
+            Some((hostname, _)) => hostname,
+            None => &self.host,
+        }
+    }
+}
+impl TryFrom<String> for Webhook {
+    type Error = String;
+
+    fn try_from(url: String) -> Result<Self, Self::Error> {
+        let rest = url
+            .strip_prefix("http://")
+            .ok_or_else(|| format!("{}: webhooks are http:// URLs", url))?;
+        // It goes into the request as it is, so a line break would start a
+        // header of its own
+        if !rest.chars().all(|c| c.is_ascii_graphic()) {
+            return Err(format!(
+                "{:?}: no spaces, control characters or non-ASCII in webhook URLs",
+                url
+            ));
+        }
+        let (host, path) = match rest.find('/') {
+            Some(slash) => (&rest[..slash], &rest[slash..]),
+            None => (rest, "/"),
+        };
+        if host.is_empty() {
+            return Err(format!("{}: no host", url));
+        }
+        let addr = if host.contains(':') {
+            host.to_string()
+        } else {
+            format!("{}:80", host)
+        };
+        Ok(Self {
+            addr,
+            host: host.to_string(),
+            path: path.to_string(),
+            url,
+        })
+    }
+}
+impl From<Webhook> for String {
+    fn from(webhook: Webhook) -> Self {
+        webhook.url
+    }
+}
+
+// Invocation - a command someone posted, as its webhook is sent it
+#[derive(Serialize)]
+pub struct Invocation {
+    pub room: String,
+    pub user: Uuid,
+    // The seq of the message it was in
+    pub seq: u64,
+    pub command: String,
+    // Everything after the command, trimmed
+    pub args: String,
+}
+
+#[derive(Deserialize)]
+struct Answer {
+    body: Option<String>,
+}
+
+// The command at the start of a message and its arguments, for
+// `/deploy staging`
+pub fn parse(body: &str) -> Option<(&str, &str)> {
+    let body = body.strip_prefix('/')?;
+    let (command, args) = body.split_once(char::is_whitespace).unwrap_or((body, ""));
+    Some((command, args.trim()))
+}
+
+// Whether `command` will do as a command name: lowercase letters, digits,
+// `-` and `_`
+pub fn check_command(command: &str) -> Result<(), ProtocolError> {
+    let fine = !command.is_empty()
+        && command.chars().count() <= MAX_COMMAND_LEN
+        && command
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-' || c == '_');
+    if fine {
+        Ok(())
+    } else {
+        Err(ProtocolError::new(
+            "invalid_settings",
+            format!(
+                "/{}: commands are up to {} lowercase letters, digits, - and _",
+                command, MAX_COMMAND_LEN
+            ),
+        ))
+    }
+}
+
+// POSTs the invocation to the webhook and answers with the `body` of its
+// JSON reply, which the room posts; None when there's nothing to post.
+// HTTP/1.0, so the answer comes in one piece and ends when the connection
+// does.
+pub async fn call(webhook: &Webhook, invocation: &Invocation) -> Result<Option<String>, String> {
+    let request = serde_json::to_vec(invocation).expect("Could not serialize invocation");
+    let response = tokio::time::timeout(TIMEOUT, post(webhook, &request))
+        .await
+        .map_err(|_| "timed out".to_string())?
+        .map_err(|e| e.to_string())?;
+
+    let end = response
+        .windows(4)
+        .position(|w| w == b"\r\n\r\n")
+        .ok_or_else(|| "no body in the response".to_string())?;
+    let status = String::from_utf8_lossy(&response[..end]);
+    let status = status.split_whitespace().nth(1).unwrap_or("");
+    if !status.starts_with('2') {
+        return Err(format!("the webhook answered {}", status));
+    }
+    let body = &response[end + 4..];
+    if body.iter().all(u8::is_ascii_whitespace) {
+        return Ok(None);
+    }
+    let answer: Answer = serde_json::from_slice(body).map_err(|e| e.to_string())?;
+    Ok(answer.body.filter(|body| !body.trim().is_empty()))
+}
+
+async fn post(webhook: &Webhook, body: &[u8]) -> std::io::Result<Vec<u8>> {
+    let mut stream = TcpStream::connect(&webhook.addr).await?;
+    let head = format!(
+        "POST {} HTTP/1.0\r\nHost: {}\r\nContent-Type: application/json\r\n\
+         Content-Length: {}\r\n\r\n",
+        webhook.path,
+        webhook.host,
+        body.len()
+    );
+    stream.write_all(head.as_bytes()).await?;
+    stream.write_all(body).await?;
+    let mut response = Vec::new();
+    stream
+        .take(MAX_RESPONSE_LEN + 1)
+        .read_to_end(&mut response)
+        .await?;
+    if response.len() as u64 > MAX_RESPONSE_LEN {
+        return Err(std::io::Error::other(format!(
+            "the webhook answered more than {} bytes",
+            MAX_RESPONSE_LEN
+        )));
+    }
+    Ok(response)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn webhooks_are_split_into_where_to_connect_and_what_to_ask() {
+        let webhook =
+            Webhook::try_from("http://bot.internal:8080/hooks/deploy".to_string()).unwrap();
+        assert_eq!(webhook.addr, "bot.internal:8080");
+        assert_eq!(webhook.hostname(), "bot.internal");
+        assert_eq!(webhook.path, "/hooks/deploy");
+        let webhook = Webhook::try_from("http://bot.internal".to_string()).unwrap();
+        assert_eq!(
+            (webhook.addr.as_str(), webhook.path.as_str()),
+            ("bot.internal:80", "/")
+        );
+    }
+
+    #[test]
+    fn webhooks_that_would_change_the_request_are_refused() {
+        for url in [
+            "http://bot.internal/hook HTTP/1.0\r\nX-Injected: 1",
+            "http://bot.internal/hook\r\n\r\nGET /admin HTTP/1.0",
+            "http://bot.internal\n/hook",
+            "http://bot.internal/a b",
+            "http://bot.internal/\u{7f}",
+            "https://bot.internal/hook",
+        ] {
+            assert!(Webhook::try_from(url.to_string()).is_err(), "{:?}", url);
+        }
+    }
+}
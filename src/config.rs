@@ -0,0 +1,294 @@
+use std::env;
+use std::fmt::Display;
+use std::str::FromStr;
+use std::time::Duration;
+
+#[cfg(feature = "chaos")]
+use crate::chaos::Chaos;
+use crate::connections::Disclosure;
+use crate::ids::IdStrategy;
+use crate::limits::Budget;
+use crate::listen::Listen;
+use crate::proxy::Proxy;
+use crate::quota::Quota;
+use crate::registry::RoomSettings;
+use crate::reputation::Listed;
+use crate::room::Ban;
+use crate::spam::{LinkPolicy, SpamAction};
+use crate::template;
+use crate::tenant::Tenant;
+use crate::trace::Tracing;
+
+// Config - runtime settings, read from CHAT_* environment variables
+pub struct Config {
+    // Every address the server accepts connections on
+    pub listen: Vec<Listen>,
+    // Permission bits for unix sockets we create, e.g. 0o660
+    pub unix_socket_mode: Option<u32>,
+    // Peers allowed to tell us the client's address and scheme through
+    // Forwarded / X-Forwarded-* headers
+    pub trusted_proxies: Vec<Proxy>,
+    // Origins whose pages may open websockets; empty means our own only
+    pub allowed_origins: Vec<String>,
+    // Handler invocations slower than this get logged
+    pub slow_handler_threshold: Duration,
+    // Bearer token for the /admin API; the API is off when this is unset
+    pub admin_token: Option<String>,
+    // Reports after which a message is hidden until a moderator reviews it
+    pub report_hide_threshold: usize,
+    // How user, message and key ids are made
+    pub id_strategy: IdStrategy,
+    // NATS server (host:port) to publish room events to, if any
+    pub export_nats: Option<String>,
+    // Subjects are `<prefix>.<room>.<kind>`
+    pub export_prefix: String,
+    // Events held while NATS is slow or away; more than this are dropped
+    pub export_buffer: usize,
+    // Whether users get their own messages back, for connections that don't
+    // say with `?echo=`
+    pub echo: bool,
+    // What counts as link spam and what's done about it; off when unset
+    pub link_spam: Option<LinkPolicy>,
+    // Requests each caller gets on the REST API, and messages each
+    // websocket caller gets; off when unset
+    pub rest_limit: Option<Budget>,
+    pub ws_limit: Option<Budget>,
+    // Bytes per second each websocket is sent at most; off when unset
+    pub egress_limit: Option<u32>,
+    // Frames queued for a websocket before it's told it's lagging; off
+    // when unset
+    pub lag_threshold: Option<usize>,
+    // How long a websocket without a key has to send its first event before
+    // it's closed; off when unset
+    pub handshake_timeout: Option<Duration>,
+    // How often every websocket is pinged; off when unset
+    pub heartbeat: Option<Duration>,
+    // Pings in a row a websocket may leave unanswered before it's closed
+    pub heartbeat_misses: u32,
+    // Frames kept per connection for /admin/connections/:id/trace; off
+    // when unset
+    pub tracing: Option<Tracing>,
+    // Faults injected into every websocket's outgoing frames; off when unset
+    #[cfg(feature = "chaos")]
+    pub chaos: Option<Chaos>,
+    // Leading zero bits anonymous connections have to find a hash with
+    // before connecting; off when unset
+    pub pow_bits: Option<u32>,
+    // LibreTranslate server (host:port) for translating messages, and its
+    // API key if it wants one; translation is off when unset
+    pub translate: Option<String>,
+    pub translate_api_key: Option<String>,
+    // Signs the cookie browsers keep their user id in; random, so ids last
+    // until a restart, when unset
+    pub cookie_secret: Option<String>,
+    // What each user may post a day
+    pub quota: Quota,
+    // How long a deactivated user has to come back before their messages
+    // are purged
+    pub deactivation_window: Duration,
+    // DNS blocklist zones websocket clients' addresses are looked up in,
+    // and what's done with those listed
+    pub dnsbl: Vec<String>,
+    pub dnsbl_listed: Listed,
+    // Communities served on hosts of their own, besides the one on every
+    // other host
+    pub tenants: Vec<Tenant>,
+    // Hosts rooms' command webhooks may point at; none without
+    pub webhook_hosts: Vec<String>,
+    // Sent to each joiner of rooms without one of their own; see template
+    pub motd: Option<String>,
+    // How much room moderators are told about their members' connections
+    pub member_info: Disclosure,
+    // Room templates to start out with, from the JSON file
+    // CHAT_ROOM_TEMPLATES names: an array of room settings, each named for
+    // its template
+    pub room_templates: Vec<RoomSettings>,
+}
+
+impl Config {
+    pub fn from_env() -> Self {
+        Self::load(&Source { tenant: None })
+    }
+
+    // The settings for a tenant's community. Only those a community has
+    // its own of are read: listening, proxies, ids and the like are the
+    // server's.
+    pub fn tenant(tenant: &Tenant) -> Self {
+        let source = Source {
+            tenant: Some(tenant.name.to_ascii_uppercase()),
+        };
+        let mut config = Self::load(&source);
+        // Tenants publish under subjects of their own unless told otherwise
+        if source.own("CHAT_EXPORT_PREFIX").is_none() {
+            config.export_prefix = format!("{}.{}", config.export_prefix, tenant.name);
+        }
+        config
+    }
+
+    fn load(source: &Source) -> Self {
+        Self {
+            listen: source.list("CHAT_LISTEN", "127.0.0.1:3030"),
+            unix_socket_mode: source.get("CHAT_UNIX_SOCKET_MODE").map(|mode| {
+                u32::from_str_radix(&mode, 8)
+                    .unwrap_or_else(|_| panic!("Could not parse CHAT_UNIX_SOCKET_MODE={:?}", mode))
+            }),
+            trusted_proxies: source.list("CHAT_TRUSTED_PROXIES", ""),
+            allowed_origins: source.list("CHAT_ALLOWED_ORIGINS", ""),
+            slow_handler_threshold: Duration::from_millis(source.var("CHAT_SLOW_HANDLER_MS", 100)),
+            admin_token: source.get("CHAT_ADMIN_TOKEN"),
+            report_hide_threshold: source.var("CHAT_REPORT_HIDE_THRESHOLD", 3),
+            id_strategy: source.var("CHAT_IDS", IdStrategy::TimeOrdered),
+            export_nats: source.get("CHAT_EXPORT_NATS"),
+            export_prefix: source.var("CHAT_EXPORT_PREFIX", "chat".to_string()),
+            export_buffer: source.var("CHAT_EXPORT_BUFFER", 10_000),
+            echo: source.var("CHAT_ECHO", false),
+            link_spam: source.get("CHAT_LINK_SPAM").map(|action| LinkPolicy {
+                action: action
+                    .parse::<SpamAction>()
+                    .unwrap_or_else(|e| panic!("Could not parse CHAT_LINK_SPAM: {}", e)),
+                limit: source.var("CHAT_LINK_LIMIT", 3),
+                window: Duration::from_secs(source.var("CHAT_LINK_WINDOW_SECS", 60)),
+                ban_secs: match source.var("CHAT_SPAM_BAN_SECS", 24 * 60 * 60) {
+                    0 => None,
+                    secs => match Ban::lasting(secs, 0) {
+                        Ok(_) => Some(secs),
+                        Err(e) => panic!("Could not use CHAT_SPAM_BAN_SECS: {}", e),
+                    },
+                },
+            }),
+            rest_limit: source.budget("CHAT_REST", 600),
+            ws_limit: source.budget("CHAT_WS", 0),
+            egress_limit: match source.var("CHAT_EGRESS_LIMIT", 0) {
+                0 => None,
+                limit => Some(limit),
+            },
+            lag_threshold: match source.var("CHAT_LAG_THRESHOLD", 1000) {
+                0 => None,
+                threshold => Some(threshold),
+            },
+            handshake_timeout: match source.var("CHAT_HANDSHAKE_TIMEOUT", 0) {
+                0 => None,
+                secs => Some(Duration::from_secs(secs)),
+            },
+            heartbeat: match source.var("CHAT_HEARTBEAT_SECS", 30) {
+                0 => None,
+                secs => Some(Duration::from_secs(secs)),
+            },
+            heartbeat_misses: match source.var("CHAT_HEARTBEAT_MISSES", 3) {
+                0 => panic!("Could not parse CHAT_HEARTBEAT_MISSES=0: expected at least 1"),
+                misses => misses,
+            },
+            tracing: match source.var("CHAT_TRACE_FRAMES", 0) {
+                0 => None,
+                frames => Some(Tracing {
+                    frames,
+                    bodies: source.var("CHAT_TRACE_BODIES", false),
+                }),
+            },
+            #[cfg(feature = "chaos")]
+            chaos: source.get("CHAT_CHAOS").map(|chaos| {
+                chaos
+                    .parse()
+                    .unwrap_or_else(|e| panic!("Could not parse CHAT_CHAOS: {}", e))
+            }),
+            pow_bits: source.get("CHAT_POW_BITS").map(|bits| match bits.parse() {
+                Ok(bits @ 1..=32) => bits,
+                _ => panic!("Could not parse CHAT_POW_BITS={:?}: expected 1 to 32", bits),
+            }),
+            translate: source.get("CHAT_TRANSLATE_LIBRETRANSLATE"),
+            translate_api_key: source.get("CHAT_TRANSLATE_API_KEY"),
+            cookie_secret: source.get("CHAT_COOKIE_SECRET"),
+            quota: Quota {
+                messages: match source.var("CHAT_QUOTA_MESSAGES", 0) {
+                    0 => None,
+                    limit => Some(limit),
+                },
+                bytes: match source.var("CHAT_QUOTA_BYTES", 0) {
+                    0 => None,
+                    limit => Some(limit),
+                },
+            },
+            deactivation_window: Duration::from_secs(
+                source.var("CHAT_DEACTIVATION_DAYS", 30) * 24 * 60 * 60,
+            ),
+            dnsbl: source.list("CHAT_DNSBL", ""),
+            dnsbl_listed: source.var("CHAT_DNSBL_ACTION", Listed::Flag),
+            tenants: source.list("CHAT_TENANTS", ""),
+            webhook_hosts: source.list("CHAT_WEBHOOK_HOSTS", ""),
+            member_info: source.var("CHAT_MEMBER_INFO", Disclosure::None),
+            motd: source.get("CHAT_MOTD").inspect(|motd| {
+                template::check(motd).unwrap_or_else(|e| panic!("Could not parse CHAT_MOTD: {}", e))
+            }),
+            room_templates: match source.get("CHAT_ROOM_TEMPLATES") {
+                Some(path) => {
+                    let json = std::fs::read_to_string(&path)
+                        .unwrap_or_else(|e| panic!("Could not read {}: {}", path, e));
+                    serde_json::from_str(&json)
+                        .unwrap_or_else(|e| panic!("Could not parse {}: {}", path, e))
+                }
+                None => Vec::new(),
+            },
+        }
+    }
+}
+
+// Source - where settings come from: CHAT_* environment variables, with a
+// tenant's CHAT_<TENANT>_* ones ahead of them
+struct Source {
+    tenant: Option<String>,
+}
+impl Source {
+    // The tenant's own value for `name`, if it has one
+    fn own(&self, name: &str) -> Option<String> {
+        let tenant = self.tenant.as_ref()?;
+        let name = name.replacen("CHAT_", &format!("CHAT_{}_", tenant), 1);
+        env::var(name).ok()
+    }
+
+    fn get(&self, name: &str) -> Option<String> {
+        self.own(name).or_else(|| env::var(name).ok())
+    }
+
+    // Reads and parses a setting, falling back to `default` when it is
+    // unset and bailing out loudly when it is set to garbage
+    fn var<T: FromStr>(&self, name: &str, default: T) -> T {
+        match self.get(name) {
+            Some(value) => value
+                .parse()
+                .unwrap_or_else(|_| panic!("Could not parse {}={:?}", name, value)),
+            None => default,
+        }
+    }
+
+    // Like `var`, for comma separated lists
+    fn list<T: FromStr>(&self, name: &str, default: &str) -> Vec<T>
+    where
+        T::Err: Display,
+    {
+        let value = self.get(name).unwrap_or_else(|| default.to_string());
+        value
+            .split(',')
+            .map(str::trim)
+            .filter(|item| !item.is_empty())
+            .map(|item| {
+                item.parse()
+                    .unwrap_or_else(|e| panic!("Could not parse {}: {}", name, e))
+            })
+            .collect()
+    }
+
+    // `<prefix>_LIMIT` per `<prefix>_WINDOW_SECS` (a minute by default), with
+    // a limit of 0 for none at all
+    fn budget(&self, prefix: &str, limit: u32) -> Option<Budget> {
+        let limit = self.var(&format!("{}_LIMIT", prefix), limit);
+        let window = self.var(&format!("{}_WINDOW_SECS", prefix), 60);
+        match (limit, window) {
+            (0, _) => None,
+            (_, 0) => panic!("{}_WINDOW_SECS has to be more than 0", prefix),
+            _ => Some(Budget {
+                limit,
+                window: Duration::from_secs(window),
+            }),
+        }
+    }
+}
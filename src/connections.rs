@@ -0,0 +1,344 @@
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::str::FromStr;
+use std::sync::Arc;
+use tokio::sync::Notify;
+use uuid::Uuid;
+use xtra::prelude::*;
+
+use crate::latency::{Latency, Percentiles};
+use crate::trace::{Frame, Trace};
+use crate::{metrics, Connection, Outbox, ProtocolError};
+
+// Connections - the sockets that are open, for seeing which can't keep up.
+// A user id is only ever open on one socket: a browser that reloads keeps
+// its id, and the new socket takes over from the old.
+pub struct Connections {
+    open: HashMap<Uuid, Open>,
+    disclosure: Disclosure,
+    // Mixed into hashed addresses, so they can be matched up with each other
+    // until a restart but not looked up
+    salt: Uuid,
+}
+impl Actor for Connections {}
+impl Connections {
+    pub fn new(disclosure: Disclosure) -> Self {
+        Self {
+            open: HashMap::new(),
+            disclosure,
+            salt: Uuid::new_v4(),
+        }
+    }
+}
+
+// Disclosure - what room moderators may find out about a member's
+// connection with `member_info`: nothing, when and with what it connected,
+// or that and a hash of where from
+#[derive(Clone, Copy, PartialEq)]
+pub enum Disclosure {
+    None,
+    Session,
+    Full,
+}
+impl FromStr for Disclosure {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(Disclosure::None),
+            "session" => Ok(Disclosure::Session),
+            "full" => Ok(Disclosure::Full),
+            _ => Err(format!("{}: expected none, session or full", s)),
+        }
+    }
+}
+
+struct Open {
+    peer: Option<String>,
+    user_agent: Option<String>,
+    flagged: Option<String>,
+    connected_at: u64,
+    outbox: Arc<Outbox>,
+    session: Arc<Session>,
+    trace: Option<Arc<Trace>>,
+    latency: Arc<Latency>,
+    // Where the socket's events go, once it's running
+    connection: Option<xtra::WeakAddress<Connection>>,
+}
+
+// Session - one socket's hold on a user id
+#[derive(Default)]
+pub struct Session {
+    replaced: Notify,
+    ended: Notify,
+    gone: Notify,
+}
+impl Session {
+    // Tells the socket a newer one has the id now
+    pub fn replace(&self) {
+        self.replaced.notify_one();
+    }
+
+    // Until a newer socket has the id
+    pub async fn replaced(&self) {
+        self.replaced.notified().await
+    }
+
+    // Tells the socket its id has been deactivated
+    pub fn end(&self) {
+        self.ended.notify_one();
+    }
+
+    pub async fn ended(&self) {
+        self.ended.notified().await
+    }
+
+    // The socket has left its rooms, so the id is free for the newer one
+    pub fn done(&self) {
+        self.gone.notify_one();
+    }
+
+    pub async fn gone(&self) {
+        self.gone.notified().await
+    }
+}
+
+// Connected - a socket opened. Answers with the session it takes over from,
+// if the id was open already.
+pub struct Connected {
+    pub id: Uuid,
+    pub peer: Option<String>,
+    pub user_agent: Option<String>,
+    pub flagged: Option<String>,
+    // Milliseconds since the unix epoch
+    pub at: u64,
+    pub outbox: Arc<Outbox>,
+    pub session: Arc<Session>,
+    pub trace: Option<Arc<Trace>>,
+    pub latency: Arc<Latency>,
+}
+impl Message for Connected {
+    type Result = Option<Arc<Session>>;
+}
+#[async_trait::async_trait]
+impl Handler<Connected> for Connections {
+    async fn handle(&mut self, msg: Connected, _ctx: &mut Context<Self>) -> Option<Arc<Session>> {
+        let _timer = metrics::timer("connected");
+        let open = Open {
+            peer: msg.peer,
+            user_agent: msg.user_agent,
+            flagged: msg.flagged,
+            connected_at: msg.at,
+            outbox: msg.outbox,
+            session: msg.session,
+            trace: msg.trace,
+            latency: msg.latency,
+            connection: None,
+        };
+        self.open
+            .insert(msg.id, open)
+            .map(|replaced| replaced.session)
+    }
+}
+
+// Attach - the socket's Connection is running, so admins can move it
+// between rooms
+pub(crate) struct Attach {
+    pub id: Uuid,
+    pub session: Arc<Session>,
+    pub connection: xtra::WeakAddress<Connection>,
+}
+impl Message for Attach {
+    type Result = ();
+}
+#[async_trait::async_trait]
+impl Handler<Attach> for Connections {
+    async fn handle(&mut self, msg: Attach, _ctx: &mut Context<Self>) {
+        let _timer = metrics::timer("attach");
+        if let Some(open) = self.open.get_mut(&msg.id) {
+            if Arc::ptr_eq(&open.session, &msg.session) {
+                open.connection = Some(msg.connection);
+            }
+        }
+    }
+}
+
+// GetConnections - the Connections of the ids given that are open, by id
+pub(crate) struct GetConnections(pub Vec<Uuid>);
+impl Message for GetConnections {
+    type Result = Vec<(Uuid, xtra::WeakAddress<Connection>)>;
+}
+#[async_trait::async_trait]
+impl Handler<GetConnections> for Connections {
+    async fn handle(
+        &mut self,
+        msg: GetConnections,
+        _ctx: &mut Context<Self>,
+    ) -> Vec<(Uuid, xtra::WeakAddress<Connection>)> {
+        let _timer = metrics::timer("get_connections");
+        msg.0
+            .into_iter()
+            .filter_map(|id| Some((id, self.open.get(&id)?.connection.clone()?)))
+            .collect()
+    }
+}
+
+// Disconnected - a socket closed. One that was taken over from is gone
+// already.
+pub struct Disconnected {
+    pub id: Uuid,
+    pub session: Arc<Session>,
+}
+impl Message for Disconnected {
+    type Result = ();
+}
+#[async_trait::async_trait]
+impl Handler<Disconnected> for Connections {
+    async fn handle(&mut self, msg: Disconnected, _ctx: &mut Context<Self>) {
+        let _timer = metrics::timer("disconnected");
+        let current = match self.open.get(&msg.id) {
+            Some(open) => Arc::ptr_eq(&open.session, &msg.session),
+            None => false,
+        };
+        if current {
+            self.open.remove(&msg.id);
+        }
+    }
+}
+
+// Close - closes whichever socket has the id, if one does
+pub struct Close(pub Uuid);
+impl Message for Close {
+    type Result = ();
+}
+#[async_trait::async_trait]
+impl Handler<Close> for Connections {
+    async fn handle(&mut self, msg: Close, _ctx: &mut Context<Self>) {
+        let _timer = metrics::timer("close");
+        if let Some(open) = self.open.get(&msg.0) {
+            open.session.end();
+        }
+    }
+}
+
+// GetTrace - the frames a socket has sent and been sent lately, if it's open
+// and being traced
+pub struct GetTrace(pub Uuid);
+impl Message for GetTrace {
+    type Result = Option<Vec<Frame>>;
+}
+#[async_trait::async_trait]
+impl Handler<GetTrace> for Connections {
+    async fn handle(&mut self, msg: GetTrace, _ctx: &mut Context<Self>) -> Option<Vec<Frame>> {
+        let _timer = metrics::timer("get_trace");
+        let trace = self.open.get(&msg.0)?.trace.as_ref()?;
+        Some(trace.frames())
+    }
+}
+
+// ConnectionSummary - an open socket and how far behind it is
+#[derive(Serialize)]
+pub struct ConnectionSummary {
+    pub id: Uuid,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub peer: Option<String>,
+    // What the connection policy found against the peer
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub flagged: Option<String>,
+    pub connected_at: u64,
+    // Frames waiting to be written, and dropped so far
+    pub queued: usize,
+    pub dropped: u64,
+    // Over CHAT_LAG_THRESHOLD and told so
+    pub lagging: bool,
+    // Round trips the client has measured, once it has reported any
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub latency: Option<Percentiles>,
+}
+
+// MemberInfo - what a room moderator is told about a member's connection,
+// as much as CHAT_MEMBER_INFO allows
+#[derive(Serialize)]
+pub struct MemberInfo {
+    pub connected_at: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub user_agent: Option<String>,
+    // The first 16 hex digits of a salted SHA-256 of the address
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ip_hash: Option<String>,
+}
+
+// GetMemberInfo - a user's connection, for a room moderator, or None if
+// it isn't open. Refused when CHAT_MEMBER_INFO is none.
+pub struct GetMemberInfo(pub Uuid);
+impl Message for GetMemberInfo {
+    type Result = Result<Option<MemberInfo>, ProtocolError>;
+}
+#[async_trait::async_trait]
+impl Handler<GetMemberInfo> for Connections {
+    async fn handle(
+        &mut self,
+        msg: GetMemberInfo,
+        _ctx: &mut Context<Self>,
+    ) -> Result<Option<MemberInfo>, ProtocolError> {
+        let _timer = metrics::timer("get_member_info");
+        if self.disclosure == Disclosure::None {
+            return Err(ProtocolError::new(
+                "forbidden",
+                "Member info is turned off".to_string(),
+            ));
+        }
+        let open = match self.open.get(&msg.0) {
+            Some(open) => open,
+            None => return Ok(None),
+        };
+        let ip_hash = match (self.disclosure, &open.peer) {
+            (Disclosure::Full, Some(peer)) => {
+                let digest = Sha256::new()
+                    .chain_update(self.salt.as_bytes())
+                    .chain_update(peer.as_bytes())
+                    .finalize();
+                Some(format!("{:x}", digest)[..16].to_string())
+            }
+            _ => None,
+        };
+        Ok(Some(MemberInfo {
+            connected_at: open.connected_at,
+            user_agent: open.user_agent.clone(),
+            ip_hash,
+        }))
+    }
+}
+
+// ListConnections - every open socket, by id
+pub struct ListConnections;
+impl Message for ListConnections {
+    type Result = Vec<ConnectionSummary>;
+}
+#[async_trait::async_trait]
+impl Handler<ListConnections> for Connections {
+    async fn handle(
+        &mut self,
+        _msg: ListConnections,
+        _ctx: &mut Context<Self>,
+    ) -> Vec<ConnectionSummary> {
+        let _timer = metrics::timer("list_connections");
+        let mut connections: Vec<_> = self
+            .open
+            .iter()
+            .map(|(id, open)| ConnectionSummary {
+                id: *id,
+                peer: open.peer.clone(),
+                flagged: open.flagged.clone(),
+                connected_at: open.connected_at,
+                queued: open.outbox.queued(),
+                dropped: open.outbox.dropped(),
+                lagging: open.outbox.lagging(),
+                latency: open.latency.percentiles(),
+            })
+            .collect();
+        connections.sort_by_key(|connection| connection.id);
+        connections
+    }
+}
@@ -0,0 +1,104 @@
+//! Running the server inside another warp app, as a notification bus.
+//!
+//! ```no_run
+//! # async fn example() {
+//! use warp::Filter;
+//!
+//! let (chat, handle) = yee::embedded().await;
+//! handle.create_room("deploys").await.ok();
+//! let mut events = handle.subscribe();
+//! tokio::spawn(async move {
+//!     while let Ok(event) = events.recv().await {
+//!         println!("{} in {}", event.kind, event.room);
+//!     }
+//! });
+//! handle.post("deploys", "Shipped v1.2", Some("ci")).await.unwrap();
+//! warp::serve(warp::path("chat").and(chat)).run(([127, 0, 0, 1], 8080)).await;
+//! # }
+//! ```
+
+use std::sync::Arc;
+use tokio::sync::broadcast;
+use uuid::Uuid;
+use warp::filters::BoxedFilter;
+use warp::{Filter, Reply};
+
+use crate::config::Config;
+use crate::export::Export;
+use crate::registry::{CreateRoom, FindRoom, RoomRegistry, RoomSettings};
+use crate::services::Services;
+use crate::{community, ids, metrics, proxy, Bot, GotUserMessage, ProtocolError};
+
+// Events a subscriber can fall behind by before it misses some
+const SUBSCRIBER_BUFFER: usize = 1024;
+
+/// The embedding app's way in to the server, without going through HTTP.
+/// Cheap to clone.
+#[derive(Clone)]
+pub struct ChatServerHandle {
+    services: Services,
+    events: broadcast::Sender<Export>,
+}
+
+/// Everything the `yee` binary serves, configured from the same `CHAT_*`
+/// environment variables, as routes to mount in another warp app, and a
+/// handle to it. Metrics and the id generator are set up by the first call
+/// in a process, and later calls share them; `CHAT_LISTEN` and
+/// `CHAT_TENANTS` are left to the app.
+pub async fn embedded() -> (BoxedFilter<(Box<dyn Reply>,)>, ChatServerHandle) {
+    let mut config = Config::from_env();
+    metrics::init(config.slow_handler_threshold);
+    ids::init(&config.id_strategy);
+
+    let peer = proxy::peer(Arc::new(std::mem::take(&mut config.trusted_proxies)));
+    let (events, _) = broadcast::channel(SUBSCRIBER_BUFFER);
+    let (routes, services) = community(&config, peer, Some(events.clone())).await;
+    let metrics = warp::path("metrics")
+        .map(metrics::render)
+        .map(|reply| Box::new(reply) as Box<dyn Reply>);
+    let routes = metrics.or(routes).unify().boxed();
+    (routes, ChatServerHandle { services, events })
+}
+
+impl ChatServerHandle {
+    /// Creates a public room with the default settings.
+    pub async fn create_room(&self, name: &str) -> Result<(), ProtocolError> {
+        self.services
+            .get::<RoomRegistry>()
+            .send(CreateRoom(RoomSettings::named(name)))
+            .await
+            .expect("Could not reach the registry")
+            .map(|_| ())
+    }
+
+    /// Posts to a room as a bot, like a room key with `flair` would, so it
+    /// works in rooms that allow bots.
+    pub async fn post(
+        &self,
+        room: &str,
+        body: &str,
+        flair: Option<&str>,
+    ) -> Result<(), ProtocolError> {
+        let (_, room) = self
+            .services
+            .get::<RoomRegistry>()
+            .send(FindRoom(room.to_string()))
+            .await
+            .expect("Could not reach the registry")
+            .ok_or_else(|| ProtocolError::new("no_such_room", format!("No such room: {}", room)))?;
+        let bot = Bot {
+            flair: flair.map(str::to_string),
+        };
+        room.send(GotUserMessage(Uuid::nil(), body.to_string(), Some(bot)))
+            .await
+            .expect("Could not post message")
+            .map(|_| ())
+    }
+
+    /// Every room's events from now on, as they'd be exported to NATS. A
+    /// subscriber more than 1024 events behind misses the oldest and is told
+    /// so with `RecvError::Lagged`.
+    pub fn subscribe(&self) -> broadcast::Receiver<Export> {
+        self.events.subscribe()
+    }
+}
@@ -0,0 +1,152 @@
+use bytes::Bytes;
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+use tokio::sync::broadcast;
+use tokio::sync::mpsc::{self, error::TrySendError};
+use xtra::prelude::*;
+
+use crate::metrics;
+
+const MIN_BACKOFF: Duration = Duration::from_millis(500);
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+
+// Record - one room event on its way out
+struct Record {
+    subject: String,
+    payload: Bytes,
+}
+
+// Exporter - publishes room events to NATS for whoever wants to consume
+// them, and to subscribers in the same process when the server is
+// embedded. Rooms hand events over without waiting; a buffer absorbs slow
+// patches, and once it's full events are dropped rather than holding up
+// chat.
+pub struct Exporter {
+    prefix: String,
+    tx: Option<mpsc::Sender<Record>>,
+    subscribers: Option<broadcast::Sender<Export>>,
+    dropped: u64,
+}
+impl Actor for Exporter {}
+impl Exporter {
+    // Publishes to the NATS server at `addr`, if there is one, buffering up
+    // to `buffer` events while it's slow or away, and to `subscribers`
+    pub fn new(
+        nats: Option<String>,
+        prefix: String,
+        buffer: usize,
+        subscribers: Option<broadcast::Sender<Export>>,
+    ) -> Self {
+        let tx = nats.map(|addr| {
+            let (tx, rx) = mpsc::channel(buffer);
+            tokio::spawn(publish(addr, rx));
+            tx
+        });
+        Self {
+            prefix,
+            tx,
+            subscribers,
+            dropped: 0,
+        }
+    }
+}
+
+/// A room event, published on `<prefix>.<room>.<kind>`. `kind` is
+/// `message`, `joined`, `left`, `message_hidden`, `message_deleted` or
+/// `bulk_delete`, and `payload` its JSON.
+#[derive(Clone, Debug)]
+pub struct Export {
+    pub room: String,
+    pub kind: &'static str,
+    pub payload: Bytes,
+}
+impl Message for Export {
+    type Result = ();
+}
+#[async_trait::async_trait]
+impl Handler<Export> for Exporter {
+    async fn handle(&mut self, msg: Export, _ctx: &mut Context<Self>) {
+        let _timer = metrics::timer("export");
+        if let Some(subscribers) = &self.subscribers {
+            // Nobody listening is fine
+            let _ = subscribers.send(msg.clone());
+        }
+        let tx = match &self.tx {
+            Some(tx) => tx,
+            None => return,
+        };
+        let record = Record {
+            subject: format!("{}.{}.{}", self.prefix, msg.room, msg.kind),
+            payload: msg.payload,
+        };
+        match tx.try_send(record) {
+            Ok(()) => {}
+            Err(TrySendError::Full(_)) => {
+                self.dropped += 1;
+                if self.dropped == 1 || self.dropped.is_multiple_of(1000) {
+                    log::warn!("Export buffer full, {} events dropped", self.dropped);
+                }
+            }
+            Err(TrySendError::Closed(_)) => log::warn!("Exporter is gone"),
+        }
+    }
+}
+
+// Keeps a connection to NATS up and feeds it records, for as long as the
+// Exporter is around
+async fn publish(addr: String, mut records: mpsc::Receiver<Record>) {
+    let mut backoff = MIN_BACKOFF;
+    loop {
+        match TcpStream::connect(&addr).await {
+            Ok(stream) => {
+                log::info!("Exporting to nats://{}", addr);
+                backoff = MIN_BACKOFF;
+                match pump(stream, &mut records).await {
+                    Ok(()) => return,
+                    Err(e) => log::warn!("Lost nats://{}: {}", addr, e),
+                }
+            }
+            Err(e) => log::warn!("Could not connect to nats://{}: {}", addr, e),
+        }
+        tokio::time::sleep(backoff).await;
+        backoff = (backoff * 2).min(MAX_BACKOFF);
+    }
+}
+
+// Speaks just enough of the NATS protocol to publish: CONNECT, PUB, and
+// PONG back to the server's PINGs. Ok once there's nothing left to send.
+async fn pump(stream: TcpStream, records: &mut mpsc::Receiver<Record>) -> std::io::Result<()> {
+    let (reader, mut writer) = stream.into_split();
+    let mut lines = BufReader::new(reader).lines();
+    let closed = || std::io::Error::new(std::io::ErrorKind::UnexpectedEof, "connection closed");
+
+    // The server opens with INFO
+    lines.next_line().await?.ok_or_else(closed)?;
+    writer
+        .write_all(b"CONNECT {\"verbose\":false,\"pedantic\":false}\r\n")
+        .await?;
+
+    loop {
+        tokio::select! {
+            record = records.recv() => {
+                let record = match record {
+                    Some(record) => record,
+                    None => return Ok(()),
+                };
+                let header = format!("PUB {} {}\r\n", record.subject, record.payload.len());
+                writer.write_all(header.as_bytes()).await?;
+                writer.write_all(&record.payload).await?;
+                writer.write_all(b"\r\n").await?;
+            }
+            line = lines.next_line() => {
+                let line = line?.ok_or_else(closed)?;
+                if line == "PING" {
+                    writer.write_all(b"PONG\r\n").await?;
+                } else if line.starts_with("-ERR") {
+                    log::warn!("nats: {}", line);
+                }
+            }
+        }
+    }
+}
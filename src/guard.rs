@@ -0,0 +1,136 @@
+use crate::ProtocolError;
+
+// Longest event taken, in bytes, however many frames it came in. Websockets
+// are cut off at this too, so nothing longer is ever buffered.
+pub const MAX_EVENT_LEN: usize = 64 * 1024;
+// Deepest nesting of objects and arrays
+const MAX_DEPTH: usize = 8;
+// Most fields across all of an event's objects, and array elements
+const MAX_FIELDS: usize = 256;
+// Longest string, in bytes as sent (escapes count as written)
+const MAX_STRING_LEN: usize = 32 * 1024;
+
+// Checks an event's shape before it's parsed, in one pass over the text
+// that costs the same whatever is in it, so nothing pathological ever
+// reaches serde. Doesn't check it's JSON; parsing does that.
+pub fn check(text: &str) -> Result<(), ProtocolError> {
+    if text.len() > MAX_EVENT_LEN {
+        return Err(too_large(format!(
+            "Events are up to {} bytes",
+            MAX_EVENT_LEN
+        )));
+    }
+    let mut depth = 0;
+    let mut fields = 0;
+    // Where the string we're in started, and whether the last byte was a
+    // backslash
+    let mut string = None;
+    let mut escaped = false;
+    for (i, byte) in text.bytes().enumerate() {
+        if let Some(start) = string {
+            match byte {
+                _ if escaped => escaped = false,
+                b'\\' => escaped = true,
+                b'"' => {
+                    if i - start > MAX_STRING_LEN {
+                        return Err(too_large(format!(
+                            "Strings are up to {} bytes",
+                            MAX_STRING_LEN
+                        )));
+                    }
+                    string = None;
+                }
+                _ => {}
+            }
+            continue;
+        }
+        match byte {
+            b'"' => string = Some(i + 1),
+            b'{' | b'[' => {
+                depth += 1;
+                fields += 1;
+                if depth > MAX_DEPTH {
+                    return Err(too_large(format!("Events nest up to {} deep", MAX_DEPTH)));
+                }
+            }
+            b'}' | b']' => depth = depth.saturating_sub(1),
+            b',' => fields += 1,
+            _ => continue,
+        }
+        if fields > MAX_FIELDS {
+            return Err(too_large(format!(
+                "Events have up to {} fields",
+                MAX_FIELDS
+            )));
+        }
+    }
+    Ok(())
+}
+
+fn too_large(message: String) -> ProtocolError {
+    ProtocolError::new("event_too_large", message)
+}
+
+// Whether a websocket read failed on a message or frame over the limits it
+// was opened with. warp doesn't say what kind of error it has, only what
+// tungstenite called it.
+pub fn over_limit(error: &warp::Error) -> bool {
+    error.to_string().starts_with("Space limit exceeded")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn refused(text: &str) -> bool {
+        check(text).is_err_and(|e| e.code == "event_too_large")
+    }
+
+    #[test]
+    fn events_up_to_the_limits_are_taken() {
+        let nested = format!("{}{}", "[".repeat(MAX_DEPTH), "]".repeat(MAX_DEPTH));
+        assert!(check(&nested).is_ok());
+        let fields = format!("[{}]", vec!["1"; MAX_FIELDS - 1].join(","));
+        assert!(check(&fields).is_ok());
+        let string = format!(r#"{{"body": "{}"}}"#, "x".repeat(MAX_STRING_LEN));
+        assert!(check(&string).is_ok());
+    }
+
+    #[test]
+    fn nesting_too_deep_is_refused() {
+        let nested = format!("{}{}", "[".repeat(MAX_DEPTH + 1), "]".repeat(MAX_DEPTH + 1));
+        assert!(refused(&nested));
+        // Side by side isn't deeper
+        let wide = format!("[{}]", ["[[]]"; 10].join(","));
+        assert!(check(&wide).is_ok());
+    }
+
+    #[test]
+    fn too_many_fields_are_refused() {
+        let fields = format!("[{}]", vec!["1"; MAX_FIELDS + 1].join(","));
+        assert!(refused(&fields));
+        let objects = format!("[{}]", vec!["{}"; MAX_FIELDS].join(","));
+        assert!(refused(&objects));
+    }
+
+    #[test]
+    fn strings_too_long_are_refused() {
+        let string = format!(r#"{{"body": "{}"}}"#, "x".repeat(MAX_STRING_LEN + 1));
+        assert!(refused(&string));
+    }
+
+    #[test]
+    fn brackets_and_escaped_quotes_in_strings_are_text() {
+        let brackets = format!(r#"{{"body": "{}"}}"#, "[{,".repeat(1000));
+        assert!(check(&brackets).is_ok());
+        // An escaped quote doesn't end the string, so what follows is in it
+        let escaped = format!(r#"{{"body": "\"{}"}}"#, "[".repeat(MAX_DEPTH + 1));
+        assert!(check(&escaped).is_ok());
+    }
+
+    #[test]
+    fn events_too_long_are_refused() {
+        let long = format!(r#"{{"body": "{}"}}"#, " ".repeat(MAX_EVENT_LEN));
+        assert!(refused(&long));
+    }
+}
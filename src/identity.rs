@@ -0,0 +1,183 @@
+use sha2::{Digest, Sha256};
+use std::convert::Infallible;
+use std::sync::Arc;
+use uuid::Uuid;
+use warp::http::StatusCode;
+use warp::reply::{Json, WithStatus};
+use warp::Filter;
+
+use crate::ProtocolError;
+
+// The cookie an anonymous browser's user id is kept in, and for how long
+const COOKIE: &str = "yee_id";
+const MAX_AGE_SECS: u64 = 365 * 24 * 60 * 60;
+
+// Signer - vouches for the user ids we hand out, so a browser can keep its
+// own across reloads but can't take up someone else's
+pub struct Signer {
+    secret: Vec<u8>,
+}
+impl Signer {
+    pub fn new(secret: &str) -> Self {
+        Self {
+            secret: secret.as_bytes().to_vec(),
+        }
+    }
+
+    // `<id>.<signature>`, for the cookie
+    fn sign(&self, id: Uuid) -> String {
+        let id = id.to_simple().to_string();
+        let mac = hmac(&self.secret, id.as_bytes());
+        format!(
+            "{}.{}",
+            id,
+            base64::encode_config(mac, base64::URL_SAFE_NO_PAD)
+        )
+    }
+
+    // The id in a cookie we signed, or None for anything else
+    fn verify(&self, value: &str) -> Option<Uuid> {
+        let (id, signature) = value.split_once('.')?;
+        let signature = base64::decode_config(signature, base64::URL_SAFE_NO_PAD).ok()?;
+        if !same(&signature, &hmac(&self.secret, id.as_bytes())) {
+            return None;
+        }
+        Uuid::parse_str(id).ok()
+    }
+
+    // A Set-Cookie value keeping `id` for another MAX_AGE_SECS. Script on
+    // the page has no use for it, so it can't read it either.
+    pub fn cookie(&self, id: Uuid, secure: bool) -> String {
+        format!(
+            "{}={}; Max-Age={}; Path=/; HttpOnly; SameSite=Lax{}",
+            COOKIE,
+            self.sign(id),
+            MAX_AGE_SECS,
+            if secure { "; Secure" } else { "" }
+        )
+    }
+}
+
+// The user id from the request's cookie, if it has one we signed
+pub fn id(
+    signer: Arc<Signer>,
+) -> impl Filter<Extract = (Option<Uuid>,), Error = Infallible> + Clone {
+    warp::cookie::optional::<String>(COOKIE)
+        .map(move |cookie: Option<String>| cookie.and_then(|cookie| signer.verify(&cookie)))
+}
+
+// What /users/me requests get without an id
+pub fn unknown() -> WithStatus<Json> {
+    let error = ProtocolError::new(
+        "unknown_user",
+        "No user id; load the chat page for one".to_string(),
+    );
+    warp::reply::with_status(warp::reply::json(&error), StatusCode::UNAUTHORIZED)
+}
+
+// Whether two MACs are the same, every byte compared so timing gives
+// nothing away
+pub(crate) fn same(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |differs, (a, b)| differs | (a ^ b)) == 0
+}
+
+// HMAC-SHA256 (RFC 2104)
+pub(crate) fn hmac(key: &[u8], message: &[u8]) -> [u8; 32] {
+    let mut block = [0u8; 64];
+    if key.len() > block.len() {
+        block[..32].copy_from_slice(&Sha256::digest(key));
+    } else {
+        block[..key.len()].copy_from_slice(key);
+    }
+    let pad = |byte: u8| block.iter().map(|b| b ^ byte).collect::<Vec<_>>();
+    let inner = Sha256::new()
+        .chain_update(pad(0x36))
+        .chain_update(message)
+        .finalize();
+    Sha256::new()
+        .chain_update(pad(0x5c))
+        .chain_update(inner)
+        .finalize()
+        .into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hex(bytes: &[u8]) -> String {
+        bytes.iter().map(|b| format!("{:02x}", b)).collect()
+    }
+
+    // RFC 4231 section 4, all but the truncated case 5
+    #[test]
+    fn hmac_matches_rfc_4231() {
+        let key_4: Vec<u8> = (1..=25).collect();
+        let cases: [(&[u8], &[u8], &str); 6] = [
+            (
+                &[0x0b; 20],
+                b"Hi There",
+                "b0344c61d8db38535ca8afceaf0bf12b881dc200c9833da726e9376c2e32cff7",
+            ),
+            (
+                b"Jefe",
+                b"what do ya want for nothing?",
+                "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843",
+            ),
+            (
+                &[0xaa; 20],
+                &[0xdd; 50],
+                "773ea91e36800e46854db8ebd09181a72959098b3ef8c122d9635514ced565fe",
+            ),
+            (
+                &key_4,
+                &[0xcd; 50],
+                "82558a389a443c0ea4cc819899f2083a85f0faa3e578f8077a2e3ff46729665b",
+            ),
+            (
+                &[0xaa; 131],
+                b"Test Using Larger Than Block-Size Key - Hash Key First",
+                "60e431591ee0b67f0d8a26aacbf5b77f8e0bc6213728c5140546040f0ee37f54",
+            ),
+            (
+                &[0xaa; 131],
+                b"This is a test using a larger than block-size key and a larger than block-size data. The key needs to be hashed before being used by the HMAC algorithm.",
+                "9b09ffa71b942fcb27635fbcd5b0e944bfdc63644f0713938a7f51535c3a35e2",
+            ),
+        ];
+        for (key, message, mac) in cases {
+            assert_eq!(hex(&hmac(key, message)), mac);
+        }
+    }
+
+    #[test]
+    fn what_is_signed_verifies() {
+        let signer = Signer::new("hunter2");
+        let id = Uuid::new_v4();
+        assert_eq!(signer.verify(&signer.sign(id)), Some(id));
+    }
+
+    #[test]
+    fn tampered_cookies_dont_verify() {
+        let signer = Signer::new("hunter2");
+        let id = Uuid::from_u128(1);
+        let signed = signer.sign(id);
+        let (_, signature) = signed.split_once('.').unwrap();
+
+        // Someone else's id with our signature
+        let other = Uuid::from_u128(2).to_simple().to_string();
+        assert_eq!(signer.verify(&format!("{}.{}", other, signature)), None);
+        // A signature cut short, or with a byte changed
+        assert_eq!(signer.verify(&signed[..signed.len() - 2]), None);
+        let mut flipped = base64::decode_config(signature, base64::URL_SAFE_NO_PAD).unwrap();
+        flipped[0] ^= 1;
+        let flipped = base64::encode_config(flipped, base64::URL_SAFE_NO_PAD);
+        assert_eq!(
+            signer.verify(&format!("{}.{}", id.to_simple(), flipped)),
+            None
+        );
+        // Signed with another secret, or not at all
+        assert_eq!(Signer::new("hunter3").verify(&signed), None);
+        assert_eq!(signer.verify(&id.to_simple().to_string()), None);
+    }
+}
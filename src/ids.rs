@@ -0,0 +1,89 @@
+use std::str::FromStr;
+use std::sync::{Mutex, OnceLock};
+use std::time::{SystemTime, UNIX_EPOCH};
+use uuid::Uuid;
+
+// IdGenerator - where user, message and key ids come from
+pub trait IdGenerator: Send + Sync {
+    fn next_id(&self) -> Uuid;
+}
+
+// Random - plain v4 ids, with no order to them
+pub struct Random;
+impl IdGenerator for Random {
+    fn next_id(&self) -> Uuid {
+        Uuid::new_v4()
+    }
+}
+
+// TimeOrdered - v7 ids: milliseconds since the epoch up front, so they sort
+// by creation time. Ids made in the same millisecond count up in the 12 bits
+// after the version, and the rest is random.
+#[derive(Default)]
+pub struct TimeOrdered {
+    // The millisecond and counter of the last id handed out
+    last: Mutex<(u64, u16)>,
+}
+impl IdGenerator for TimeOrdered {
+    fn next_id(&self) -> Uuid {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .expect("Clock is before 1970")
+            .as_millis() as u64;
+        let mut last = self.last.lock().unwrap();
+        let (millis, counter) = match *last {
+            // Never go backwards, even if the clock does
+            (millis, counter) if now <= millis => match counter.checked_add(1) {
+                Some(counter) if counter <= 0xfff => (millis, counter),
+                _ => (millis + 1, 0),
+            },
+            _ => (now, 0),
+        };
+        *last = (millis, counter);
+
+        let random = *Uuid::new_v4().as_bytes();
+        let mut bytes = [0u8; 16];
+        bytes[..6].copy_from_slice(&millis.to_be_bytes()[2..]);
+        bytes[6] = 0x70 | (counter >> 8) as u8;
+        bytes[7] = counter as u8;
+        bytes[8] = 0x80 | (random[8] & 0x3f);
+        bytes[9..].copy_from_slice(&random[9..]);
+        Uuid::from_bytes(bytes)
+    }
+}
+
+// IdStrategy - which generator CHAT_IDS picks
+#[derive(Debug)]
+pub enum IdStrategy {
+    // `v4`
+    Random,
+    // `v7`
+    TimeOrdered,
+}
+impl FromStr for IdStrategy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "v4" => Ok(IdStrategy::Random),
+            "v7" => Ok(IdStrategy::TimeOrdered),
+            _ => Err(format!("{}: expected v4 or v7", s)),
+        }
+    }
+}
+
+static GENERATOR: OnceLock<Box<dyn IdGenerator>> = OnceLock::new();
+
+// Picks the generator; must be called before any id is made. Later calls
+// change nothing.
+pub fn init(strategy: &IdStrategy) {
+    GENERATOR.get_or_init(|| match strategy {
+        IdStrategy::Random => Box::new(Random),
+        IdStrategy::TimeOrdered => Box::new(TimeOrdered::default()),
+    });
+}
+
+// A fresh id from the configured generator
+pub fn next() -> Uuid {
+    GENERATOR.get().expect("ids::init was not called").next_id()
+}
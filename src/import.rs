@@ -0,0 +1,68 @@
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use uuid::Uuid;
+
+use crate::{ids, ChatMessage};
+
+// Imported - one line of a dump. Messages from another instance's history
+// or exports fit as they are; converters from elsewhere can give `author`,
+// a name, in place of `from`.
+#[derive(Deserialize)]
+struct Imported {
+    #[serde(default)]
+    id: Option<Uuid>,
+    #[serde(default)]
+    from: Option<Uuid>,
+    #[serde(default)]
+    author: Option<String>,
+    body: String,
+    sent_at: u64,
+    #[serde(default)]
+    is_bot: bool,
+    #[serde(default)]
+    flair: Option<String>,
+}
+
+// The id an author known only by name goes by, the same every import, so
+// their messages hang together however many dumps they're spread over
+fn historical(author: &str) -> Uuid {
+    let digest = Sha256::digest(format!("import:{}", author).as_bytes());
+    let mut bytes = [0; 16];
+    bytes.copy_from_slice(&digest[..16]);
+    uuid::Builder::from_bytes(bytes)
+        .set_variant(uuid::Variant::RFC4122)
+        .set_version(uuid::Version::Sha1)
+        .build()
+}
+
+// The messages in a JSONL dump, oldest first, without seqs yet. Blank lines
+// are skipped; anything else that isn't a message fails the lot, saying
+// which line.
+pub fn parse(dump: &str) -> Result<Vec<ChatMessage>, String> {
+    let mut messages = Vec::new();
+    for (n, line) in dump.lines().enumerate() {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let imported: Imported =
+            serde_json::from_str(line).map_err(|e| format!("Line {}: {}", n + 1, e))?;
+        let from = match (imported.from, &imported.author) {
+            (Some(from), _) => from,
+            (None, Some(author)) => historical(author),
+            (None, None) => return Err(format!("Line {}: needs from or author", n + 1)),
+        };
+        messages.push(ChatMessage {
+            id: imported.id.unwrap_or_else(ids::next),
+            seq: 0,
+            from,
+            body: imported.body,
+            sent_at: imported.sent_at,
+            is_bot: imported.is_bot,
+            flair: imported.flair,
+            author: imported.author,
+            hidden: false,
+        });
+    }
+    messages.sort_by_key(|message| message.sent_at);
+    Ok(messages)
+}
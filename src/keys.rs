@@ -0,0 +1,185 @@
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use uuid::Uuid;
+use warp::http::StatusCode;
+use warp::reject::Reject;
+use warp::{Filter, Rejection, Reply};
+use xtra::prelude::*;
+
+use crate::{ids, metrics};
+
+// Scope - what a room key lets its holder do
+#[derive(Clone, Copy, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Scope {
+    // Post messages without receiving the room's traffic
+    PostOnly,
+    // Receive the room's traffic and history, but never post
+    ReadOnly,
+    // Watch the room's traffic and history without joining it: not counted
+    // as a member, not shown in presence and never able to post
+    Spectator,
+    // Everything, plus moderating the room through the admin API
+    Admin,
+}
+
+// ApiKey - a minted key; the secret itself is only ever kept hashed
+#[derive(Clone, Serialize)]
+pub struct ApiKey {
+    pub id: Uuid,
+    pub room: String,
+    pub scope: Scope,
+    // Badge text shown on the key's messages
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub flair: Option<String>,
+}
+
+// KeyStore - all live room keys, indexed by the hash of their secret
+pub struct KeyStore {
+    keys: HashMap<String, ApiKey>,
+}
+impl Actor for KeyStore {}
+impl KeyStore {
+    pub fn new() -> Self {
+        Self {
+            keys: HashMap::new(),
+        }
+    }
+}
+
+fn hash(secret: &str) -> String {
+    format!("{:x}", Sha256::digest(secret.as_bytes()))
+}
+
+// MintKey - creates a key, handing back its secret exactly once
+pub struct MintKey {
+    pub room: String,
+    pub scope: Scope,
+    pub flair: Option<String>,
+}
+impl Message for MintKey {
+    type Result = (ApiKey, String);
+}
+#[async_trait::async_trait]
+impl Handler<MintKey> for KeyStore {
+    async fn handle(&mut self, msg: MintKey, _ctx: &mut Context<Self>) -> (ApiKey, String) {
+        let _timer = metrics::timer("mint_key");
+        let key = ApiKey {
+            id: ids::next(),
+            room: msg.room,
+            scope: msg.scope,
+            flair: msg.flair,
+        };
+        let secret = format!(
+            "{}{}",
+            Uuid::new_v4().to_simple(),
+            Uuid::new_v4().to_simple()
+        );
+        self.keys.insert(hash(&secret), key.clone());
+        (key, secret)
+    }
+}
+
+// ListKeys - every live key, without secrets
+pub struct ListKeys;
+impl Message for ListKeys {
+    type Result = Vec<ApiKey>;
+}
+#[async_trait::async_trait]
+impl Handler<ListKeys> for KeyStore {
+    async fn handle(&mut self, _msg: ListKeys, _ctx: &mut Context<Self>) -> Vec<ApiKey> {
+        let _timer = metrics::timer("list_keys");
+        self.keys.values().cloned().collect()
+    }
+}
+
+// RevokeKey - answers whether there was such a key
+pub struct RevokeKey(pub Uuid);
+impl Message for RevokeKey {
+    type Result = bool;
+}
+#[async_trait::async_trait]
+impl Handler<RevokeKey> for KeyStore {
+    async fn handle(&mut self, msg: RevokeKey, _ctx: &mut Context<Self>) -> bool {
+        let _timer = metrics::timer("revoke_key");
+        let before = self.keys.len();
+        self.keys.retain(|_, key| key.id != msg.0);
+        self.keys.len() != before
+    }
+}
+
+// RenameRoom - keys for a renamed room (or its threads) follow it
+pub struct RenameRoom {
+    pub from: String,
+    pub to: String,
+}
+impl Message for RenameRoom {
+    type Result = ();
+}
+#[async_trait::async_trait]
+impl Handler<RenameRoom> for KeyStore {
+    async fn handle(&mut self, msg: RenameRoom, _ctx: &mut Context<Self>) {
+        let _timer = metrics::timer("rename_keys");
+        for key in self.keys.values_mut() {
+            key.room = match key.room.split_once(':') {
+                None if key.room == msg.from => msg.to.clone(),
+                Some((room, topic)) if room == msg.from => format!("{}:{}", msg.to, topic),
+                _ => continue,
+            };
+        }
+    }
+}
+
+// Authenticate - looks a secret up
+pub struct Authenticate(pub String);
+impl Message for Authenticate {
+    type Result = Option<ApiKey>;
+}
+#[async_trait::async_trait]
+impl Handler<Authenticate> for KeyStore {
+    async fn handle(&mut self, msg: Authenticate, _ctx: &mut Context<Self>) -> Option<ApiKey> {
+        let _timer = metrics::timer("authenticate");
+        self.keys.get(&hash(&msg.0)).cloned()
+    }
+}
+
+#[derive(Debug)]
+pub struct Unauthorized;
+impl Reject for Unauthorized {}
+
+pub async fn handle_rejection(err: Rejection) -> Result<impl Reply, Rejection> {
+    if err.find::<Unauthorized>().is_some() {
+        Ok(StatusCode::UNAUTHORIZED)
+    } else {
+        Err(err)
+    }
+}
+
+// Pulls an optional room key off a request, from either `?key=` (browsers
+// can't set headers on websockets) or an `Authorization: Bearer` header.
+// A key that's there but wrong is rejected rather than ignored.
+pub fn authenticate(
+    keys: Address<KeyStore>,
+) -> impl Filter<Extract = (Option<ApiKey>,), Error = Rejection> + Clone {
+    warp::query::<HashMap<String, String>>()
+        .and(warp::header::optional::<String>("authorization"))
+        .and_then(
+            move |query: HashMap<String, String>, header: Option<String>| {
+                let keys = keys.clone();
+                async move {
+                    let secret = match query.get("key") {
+                        Some(secret) => secret.clone(),
+                        None => match header.as_deref().and_then(|h| h.strip_prefix("Bearer ")) {
+                            Some(secret) => secret.to_string(),
+                            None => return Ok(None),
+                        },
+                    };
+                    match keys.send(Authenticate(secret)).await {
+                        Ok(Some(key)) => Ok(Some(key)),
+                        _ => Err(warp::reject::custom(Unauthorized)),
+                    }
+                }
+            },
+        )
+}
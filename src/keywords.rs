@@ -0,0 +1,107 @@
+use aho_corasick::AhoCorasick;
+use std::collections::HashMap;
+use uuid::Uuid;
+
+use crate::ProtocolError;
+
+// Most keywords one member can have in a room, and the longest, in
+// characters
+const MAX_KEYWORDS: usize = 20;
+const MAX_KEYWORD_LEN: usize = 64;
+
+// Keywords - what each member of a room wants to hear about, matched
+// against a message in one pass over it whoever wants what. The matcher is
+// built again whenever anyone's keywords change, which is seldom next to
+// how often messages are posted.
+#[derive(Default)]
+pub struct Keywords {
+    // Lowercased, as set
+    by_member: HashMap<Uuid, Vec<String>>,
+    matcher: Option<AhoCorasick>,
+    // Whose each of the matcher's patterns is
+    owners: Vec<Uuid>,
+}
+impl Keywords {
+    // Replaces a member's keywords; none at all stops their notifications
+    pub fn set(&mut self, id: Uuid, keywords: Vec<String>) -> Result<(), ProtocolError> {
+        let mut normalized: Vec<String> = Vec::new();
+        for keyword in keywords {
+            let keyword = keyword.trim().to_lowercase();
+            if keyword.is_empty() || keyword.chars().count() > MAX_KEYWORD_LEN {
+                return Err(invalid(format!(
+                    "Keywords are 1 to {} characters",
+                    MAX_KEYWORD_LEN
+                )));
+            }
+            if !normalized.contains(&keyword) {
+                normalized.push(keyword);
+            }
+        }
+        if normalized.len() > MAX_KEYWORDS {
+            return Err(invalid(format!("Up to {} keywords a room", MAX_KEYWORDS)));
+        }
+        if normalized.is_empty() {
+            self.by_member.remove(&id);
+        } else {
+            self.by_member.insert(id, normalized);
+        }
+        self.rebuild();
+        Ok(())
+    }
+
+    pub fn has(&self, id: Uuid) -> bool {
+        self.by_member.contains_key(&id)
+    }
+
+    // Drops a member's keywords, if they had any
+    pub fn forget(&mut self, id: Uuid) {
+        if self.by_member.remove(&id).is_some() {
+            self.rebuild();
+        }
+    }
+
+    fn rebuild(&mut self) {
+        let mut patterns = Vec::new();
+        self.owners.clear();
+        for (id, keywords) in &self.by_member {
+            for keyword in keywords {
+                patterns.push(keyword.as_str());
+                self.owners.push(*id);
+            }
+        }
+        self.matcher = if patterns.is_empty() {
+            None
+        } else {
+            Some(AhoCorasick::new(patterns))
+        };
+    }
+
+    // Everyone with a keyword in `body`, once each, in no particular order,
+    // with the first of theirs it has. Keywords only count as whole words,
+    // whatever their case.
+    pub fn matches(&self, body: &str) -> Vec<(Uuid, String)> {
+        let matcher = match &self.matcher {
+            Some(matcher) => matcher,
+            None => return Vec::new(),
+        };
+        let body = body.to_lowercase();
+        let mut matched: Vec<(Uuid, String)> = Vec::new();
+        for found in matcher.find_overlapping_iter(&body) {
+            let before = body[..found.start()].chars().next_back();
+            let after = body[found.end()..].chars().next();
+            if before.is_some_and(char::is_alphanumeric) || after.is_some_and(char::is_alphanumeric)
+            {
+                continue;
+            }
+            let id = self.owners[found.pattern()];
+            if !matched.iter().any(|(other, _)| *other == id) {
+                matched.push((id, body[found.start()..found.end()].to_string()));
+            }
+        }
+        matched
+    }
+}
+
+fn invalid(message: String) -> ProtocolError {
+    ProtocolError::new("invalid_keywords", message)
+}
@@ -0,0 +1,90 @@
+use bytes::Bytes;
+use std::collections::VecDeque;
+
+// Presence frames queued past this many frames in all are dropped, oldest
+// first, so a backed up connection gets the chat through
+const BACKLOG: usize = 256;
+
+// Lane - how much an outgoing frame matters, most first. A connection that
+// can't keep up is written the higher lanes first; order within a lane is
+// kept.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Lane {
+    // Errors, maintenance and lag notices, which say something has to
+    // change, and pongs, which ought not to wait behind anything
+    Control,
+    // Messages, history and everything else the client keeps track of
+    Chat,
+    // Who is in a room and typing, which soon goes stale
+    Presence,
+}
+impl Lane {
+    // Frames are tagged first thing, so the start is enough to tell
+    pub fn of(frame: &[u8]) -> Self {
+        if frame.starts_with(br#"{"type":"error""#)
+            || frame.starts_with(br#"{"type":"maintenance""#)
+            || frame.starts_with(br#"{"type":"you_are_lagging""#)
+            || frame.starts_with(br#"{"type":"pong""#)
+        {
+            Lane::Control
+        } else if frame.starts_with(br#"{"type":"members""#)
+            || frame.starts_with(br#"{"type":"presence""#)
+        {
+            Lane::Presence
+        } else {
+            Lane::Chat
+        }
+    }
+}
+
+// Whether a frame is live chat, as opposed to a reply the client is
+// waiting on
+pub fn is_live(frame: &[u8]) -> bool {
+    frame.starts_with(br#"{"type":"message""#) || frame.starts_with(br#"{"type":"batch""#)
+}
+
+// Lanes - a connection's frames waiting to be written
+#[derive(Default)]
+pub struct Lanes {
+    control: VecDeque<Bytes>,
+    chat: VecDeque<Bytes>,
+    presence: VecDeque<Bytes>,
+}
+impl Lanes {
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn len(&self) -> usize {
+        self.control.len() + self.chat.len() + self.presence.len()
+    }
+
+    // Queues a frame in its lane. Answers with how many frames were dropped
+    // to make room.
+    pub fn push(&mut self, frame: Bytes) -> usize {
+        let lane = Lane::of(&frame);
+        match lane {
+            Lane::Control => self.control.push_back(frame),
+            Lane::Chat => self.chat.push_back(frame),
+            Lane::Presence => self.presence.push_back(frame),
+        }
+        let mut dropped = 0;
+        while self.len() > BACKLOG && self.presence.pop_front().is_some() {
+            dropped += 1;
+        }
+        dropped
+    }
+
+    // The next frame to write, from the highest lane that has one
+    pub fn pop(&mut self) -> Option<(Lane, Bytes)> {
+        if let Some(frame) = self.control.pop_front() {
+            return Some((Lane::Control, frame));
+        }
+        if let Some(frame) = self.chat.pop_front() {
+            return Some((Lane::Chat, frame));
+        }
+        self.presence
+            .pop_front()
+            .map(|frame| (Lane::Presence, frame))
+    }
+}
@@ -0,0 +1,55 @@
+use serde::Serialize;
+use std::collections::VecDeque;
+use std::sync::Mutex;
+
+// Round trips kept per connection
+const SAMPLES: usize = 100;
+
+// Percentiles - a connection's round trips, in milliseconds, over the last
+// SAMPLES pings
+#[derive(Clone, Copy, Serialize)]
+pub struct Percentiles {
+    pub p50: u32,
+    pub p90: u32,
+    pub p99: u32,
+    pub samples: usize,
+}
+
+// Latency - the round trips a client has measured with ping and told us
+// about in its next one. Its connection records and the admin API reads,
+// so it's behind a lock, held only to push or copy.
+#[derive(Default)]
+pub struct Latency {
+    samples: Mutex<VecDeque<u32>>,
+}
+impl Latency {
+    pub fn record(&self, rtt_ms: u32) {
+        let mut samples = self.samples.lock().expect("Latency lock poisoned");
+        if samples.len() == SAMPLES {
+            samples.pop_front();
+        }
+        samples.push_back(rtt_ms);
+    }
+
+    // None until the client has reported any
+    pub fn percentiles(&self) -> Option<Percentiles> {
+        let mut samples: Vec<_> = self
+            .samples
+            .lock()
+            .expect("Latency lock poisoned")
+            .iter()
+            .copied()
+            .collect();
+        if samples.is_empty() {
+            return None;
+        }
+        samples.sort_unstable();
+        let at = |p: usize| samples[(samples.len() * p / 100).min(samples.len() - 1)];
+        Some(Percentiles {
+            p50: at(50),
+            p90: at(90),
+            p99: at(99),
+            samples: samples.len(),
+        })
+    }
+}
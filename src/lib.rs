@@ -0,0 +1,3335 @@
+//! The chat server, to run as the `yee` binary does or to embed in another
+//! warp app, and pieces of it that are useful from other crates.
+
+// warp's filter types nest deeper than the default allows
+#![recursion_limit = "256"]
+
+use bytes::Bytes;
+use futures::{FutureExt, SinkExt, StreamExt};
+use serde::{Deserialize, Serialize};
+use std::cell::RefCell;
+use std::collections::{BTreeMap, HashMap};
+use std::convert::Infallible;
+use std::mem;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::sync::mpsc::{self, UnboundedSender};
+use tokio::sync::{broadcast, watch, Notify};
+use tokio_stream::wrappers::UnboundedReceiverStream;
+use uuid::Uuid;
+use warp::filters::BoxedFilter;
+use warp::ws::WebSocket;
+use warp::{Filter, Reply};
+use xtra::prelude::*;
+use xtra::spawn::Tokio;
+
+mod accounts;
+mod admin;
+#[doc(hidden)]
+pub mod bench;
+#[cfg(feature = "chaos")]
+mod chaos;
+#[cfg(feature = "client")]
+pub mod client;
+mod commands;
+mod config;
+mod connections;
+mod embed;
+mod export;
+mod guard;
+mod identity;
+mod ids;
+mod import;
+mod keys;
+mod keywords;
+mod lanes;
+mod latency;
+mod limits;
+mod listen;
+mod maintenance;
+mod metrics;
+mod moderation;
+mod names;
+mod origin;
+mod page;
+mod permissions;
+mod pow;
+mod preferences;
+mod proxy;
+mod quota;
+mod registry;
+mod replayer;
+mod reputation;
+mod room;
+mod schedule;
+mod services;
+mod snapshot;
+mod spam;
+mod stats;
+mod template;
+mod tenant;
+mod trace;
+mod transcript;
+mod translate;
+
+pub use embed::{embedded, ChatServerHandle};
+pub use export::Export;
+pub use replayer::replay_log;
+
+use accounts::{Accounts, GetDeactivation};
+use commands::{Invocation, Webhook};
+use config::Config;
+use connections::{
+    Attach, Connected, Connections, Disconnected, GetMemberInfo, MemberInfo, Session,
+};
+use export::Exporter;
+use identity::Signer;
+use keys::{ApiKey, KeyStore, Scope};
+use lanes::{Lane, Lanes};
+use latency::Latency;
+use limits::{Admit, Egress, Limiter, Take};
+use moderation::ModerationQueue;
+use permissions::Permissions;
+use pow::PowGate;
+use preferences::{GetPreferences, Preferences, SetPreferences, UserPreferences};
+use proxy::Peer;
+use quota::{Quotas, Refund, Spend};
+use registry::{
+    CreateRoom, CreateThread, FindRoom, GetRoom, ListRooms, RoomFeatures, RoomNames, RoomRegistry,
+    RoomSettings, SaveTemplate,
+};
+use reputation::{ConnectionPolicy, Dnsbl};
+use room::{
+    Admission, Ban, Delivery, Effect, Joiner, Limits, Preview, RoomState, Unread, PRESENCE_WINDOW,
+};
+use schedule::{Announcements, Offset};
+use services::Services;
+use spam::SpamAction;
+use stats::{Occupancy, Posted, Renamed, Stats};
+use trace::{Direction, Trace, Tracing};
+use translate::{LibreTranslate, Translate, TranslationCache};
+
+// Bumped when a change to the events would break existing clients
+const PROTOCOL_VERSION: u32 = 1;
+// How many messages a room keeps around for history requests
+const MAX_HISTORY: usize = 10_000;
+// Page size used when a history request doesn't ask for one, and the cap
+const DEFAULT_HISTORY_LIMIT: usize = 50;
+const MAX_HISTORY_LIMIT: usize = 200;
+// The newest messages a room's preview shows
+const PREVIEW_LIMIT: usize = 10;
+// Most messages sent out together in one batch frame
+const MAX_BATCH: usize = 64;
+// Replays go out in chunks this big, and wait while a connection has more
+// than this many frames it hasn't written yet
+const REPLAY_CHUNK: usize = 200;
+const REPLAY_WINDOW: usize = 16;
+// How much of the buffer events are serialized into is kept between them
+const BUFFER_KEPT: usize = 64 * 1024;
+// How long a closing connection gets to write out what's queued for it
+const WRITER_GRACE: Duration = Duration::from_secs(5);
+
+static DEFAULT_ROOM: &str = "lobby";
+
+fn default_room() -> String {
+    DEFAULT_ROOM.to_string()
+}
+
+// ClientEvent - what the browser sends us
+#[derive(Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum ClientEvent {
+    Message {
+        #[serde(default = "default_room")]
+        room: String,
+        body: String,
+    },
+    History {
+        room: String,
+        before_seq: Option<u64>,
+        limit: Option<usize>,
+        // A next_cursor from an earlier page, in place of before_seq
+        #[serde(default)]
+        cursor: Option<String>,
+    },
+    Report {
+        #[serde(default = "default_room")]
+        room: String,
+        message_id: u64,
+        reason: String,
+    },
+    Members {
+        room: String,
+    },
+    // The user is typing in `room`; clients send it every few seconds
+    // while they are
+    Typing {
+        room: String,
+    },
+    // Tells the user whenever someone else's message in `room` has one of
+    // `keywords` in it, in place of any they had; none stops it
+    Keywords {
+        room: String,
+        keywords: Vec<String>,
+    },
+    // The user has read everything in `room` up to `seq`, wherever they
+    // read it, for the read_state their next connection gets
+    Read {
+        room: String,
+        seq: u64,
+    },
+    // What the user is writing in `room`, kept for their next connection;
+    // blank drops it
+    DraftSet {
+        room: String,
+        body: String,
+    },
+    // Replaces the user's preferences, as PUT /users/me/preferences does,
+    // for their other sessions to pick up
+    SettingsUpdate(UserPreferences),
+    // Answered with a pong, for timing the round trip. `rtt_ms` is how long
+    // the last one took, for the admin API.
+    Ping {
+        nonce: String,
+        #[serde(default)]
+        rtt_ms: Option<u32>,
+    },
+    Join {
+        room: String,
+        // Replay what was posted after this seq, if it's still in history
+        #[serde(default)]
+        since_seq: Option<u64>,
+        // Gets into an invite-only room, or past approval
+        #[serde(default)]
+        invite: Option<String>,
+    },
+    Leave {
+        room: String,
+    },
+    // Translate other people's messages to `lang` from now on, or stop
+    // with null
+    SetLanguage {
+        lang: Option<String>,
+    },
+    // Moderating, for members whose role allows it
+    Kick {
+        room: String,
+        user: Uuid,
+    },
+    Ban {
+        room: String,
+        user: Uuid,
+        #[serde(default)]
+        reason: Option<String>,
+        // How long for, if not for good
+        #[serde(default)]
+        duration_secs: Option<u64>,
+    },
+    // A moderator deciding on a join_request
+    ApproveJoin {
+        room: String,
+        user: Uuid,
+    },
+    DenyJoin {
+        room: String,
+        user: Uuid,
+    },
+    // A moderator asking about a member's connection
+    MemberInfo {
+        room: String,
+        user: Uuid,
+    },
+    CreateRoom(RoomSettings),
+    CreateThread {
+        room: String,
+        topic: String,
+    },
+}
+
+// ServerEvent - what we send back down
+#[derive(Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum ServerEvent<'a> {
+    Message {
+        room: &'a str,
+        #[serde(flatten)]
+        message: &'a ChatMessage,
+    },
+    History {
+        room: &'a str,
+        messages: Vec<&'a ChatMessage>,
+        has_more: bool,
+        // Where the next page starts, if there is one
+        #[serde(skip_serializing_if = "Option::is_none")]
+        next_cursor: Option<String>,
+    },
+    // A chunk of what a joiner missed, oldest first. `done` is set on the
+    // last one.
+    Replay {
+        room: &'a str,
+        messages: Vec<&'a ChatMessage>,
+        done: bool,
+    },
+    Members {
+        room: &'a str,
+        members: Vec<Uuid>,
+    },
+    // Who joined, left and started typing in the last PRESENCE_WINDOW
+    Presence {
+        room: &'a str,
+        joined: Vec<Uuid>,
+        left: Vec<Uuid>,
+        typing: Vec<Uuid>,
+    },
+    Joined {
+        room: &'a str,
+    },
+    // Once the connection has joined its rooms: how far the user has read
+    // in each, and how much they haven't
+    ReadState {
+        rooms: &'a [Unread],
+    },
+    // The user's preferences, once they've connected and whenever they're
+    // changed
+    PreferencesUpdated(&'a UserPreferences),
+    // Text from the server, like the room's MOTD after `joined`
+    System {
+        room: &'a str,
+        body: &'a str,
+    },
+    // The join is waiting for a moderator; `joined` follows if they let
+    // the user in
+    JoinPending {
+        room: &'a str,
+    },
+    // Message `id` in the language the user asked for
+    Translation {
+        room: &'a str,
+        id: Uuid,
+        seq: u64,
+        lang: &'a str,
+        body: &'a str,
+    },
+    // For a room's moderators: someone is waiting to be let in
+    JoinRequest {
+        room: &'a str,
+        user: Uuid,
+    },
+    // For those who can ban in a room: `user` was caught link spamming with
+    // message `seq`, and was kicked, banned (until `expires_at`) or muted
+    SpamCaught {
+        room: &'a str,
+        user: Uuid,
+        seq: u64,
+        action: SpamAction,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        expires_at: Option<u64>,
+    },
+    Left {
+        room: &'a str,
+    },
+    // Message `seq` mentions everyone in the room
+    Mention {
+        room: &'a str,
+        seq: u64,
+        from: Uuid,
+    },
+    // Message `seq` has `keyword`, one of the user's keywords, in it
+    Keyword {
+        room: &'a str,
+        seq: u64,
+        from: Uuid,
+        keyword: &'a str,
+    },
+    // The room is called `name` now; everything after this uses it
+    RoomRenamed {
+        room: &'a str,
+        name: &'a str,
+    },
+    // What a moderator asked for about a member's connection
+    MemberInfo {
+        room: &'a str,
+        user: Uuid,
+        #[serde(flatten)]
+        info: &'a MemberInfo,
+    },
+    // The room's limits changed while the user was in; they hold from now on
+    ConfigChanged {
+        room: &'a str,
+        #[serde(flatten)]
+        limits: &'a Limits,
+    },
+    RoomCreated(&'a RoomSettings),
+    // Several events at once, oldest first
+    Batch {
+        events: Vec<ServerEvent<'a>>,
+    },
+    MessageHidden {
+        room: &'a str,
+        seq: u64,
+    },
+    MessageDeleted {
+        room: &'a str,
+        seq: u64,
+    },
+    // Several messages deleted at once, oldest first
+    BulkDelete {
+        room: &'a str,
+        seqs: &'a [u64],
+    },
+    // The server is going down for maintenance; connections are closed in
+    // this many seconds
+    Maintenance {
+        closing_in_secs: u64,
+    },
+    // The connection has more than CHAT_LAG_THRESHOLD frames waiting, so
+    // some may be dropped; history is where to catch up
+    YouAreLagging {
+        queued: usize,
+    },
+    // The answer to a ping, with the time it was answered in unix millis,
+    // for working out clock skew
+    Pong {
+        nonce: &'a str,
+        server_time: u64,
+    },
+    Error(&'a ProtocolError),
+}
+impl ServerEvent<'_> {
+    // Serialized once and shared by every recipient. It's written into a
+    // buffer kept for the thread, so a big batch costs one allocation
+    // rather than one each time the Vec outgrows itself. A buffer that grew
+    // past BUFFER_KEPT for a long history page goes back down after.
+    fn to_json(&self) -> Bytes {
+        thread_local! {
+            static BUFFER: RefCell<Vec<u8>> = const { RefCell::new(Vec::new()) };
+        }
+        BUFFER.with(|buffer| {
+            let mut buffer = buffer.borrow_mut();
+            buffer.clear();
+            serde_json::to_writer(&mut *buffer, self).expect("Could not serialize event");
+            let frame = Bytes::copy_from_slice(&buffer);
+            if buffer.capacity() > BUFFER_KEPT {
+                buffer.clear();
+                buffer.shrink_to(BUFFER_KEPT);
+            }
+            frame
+        })
+    }
+}
+
+// ProtocolError - why something was refused, with a stable code for
+// clients to match on
+#[derive(Debug, Serialize)]
+pub struct ProtocolError {
+    code: &'static str,
+    message: String,
+    // Whether the same thing might work later, and how much later if we know
+    retryable: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    retry_after_ms: Option<u64>,
+}
+impl ProtocolError {
+    fn new(code: &'static str, message: String) -> Self {
+        Self {
+            code,
+            message,
+            retryable: false,
+            retry_after_ms: None,
+        }
+    }
+
+    // Marks it worth trying again, after `after` if that's known
+    fn retryable(mut self, after: Option<Duration>) -> Self {
+        self.retryable = true;
+        self.retry_after_ms = after.map(|after| after.as_millis() as u64);
+        self
+    }
+
+    /// The code clients match on, like `no_such_room`.
+    pub fn code(&self) -> &'static str {
+        self.code
+    }
+}
+// For a room that stopped between being looked up and being asked, as one
+// removed does
+pub(crate) fn room_gone(_: xtra::Disconnected) -> ProtocolError {
+    ProtocolError::new("no_such_room", "That room has gone".to_string())
+}
+
+// For the rest of the server, whose actors only stop as it shuts down
+fn unavailable(_: xtra::Disconnected) -> ProtocolError {
+    ProtocolError::new("unavailable", "The server is shutting down".to_string()).retryable(None)
+}
+
+impl std::fmt::Display for ProtocolError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}: {}", self.code, self.message)
+    }
+}
+impl std::error::Error for ProtocolError {}
+
+// ChatMessage - a message as kept in the room history
+#[derive(Clone, Serialize)]
+struct ChatMessage {
+    // Unique across rooms, and time ordered unless CHAT_IDS=v4
+    id: Uuid,
+    seq: u64,
+    from: Uuid,
+    body: String,
+    // Milliseconds since the unix epoch
+    sent_at: u64,
+    // Posted through a room key, by a bot, with that key's flair if it has
+    // one
+    is_bot: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    flair: Option<String>,
+    // Who an imported message was by, for authors who never had an id here
+    #[serde(skip_serializing_if = "Option::is_none")]
+    author: Option<String>,
+    // Hidden messages are left out of history until a moderator decides
+    #[serde(skip)]
+    hidden: bool,
+}
+
+// Bot - a connection using a room key, and the flair its messages carry
+#[derive(Clone)]
+struct Bot {
+    flair: Option<String>,
+}
+
+fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("Clock is before 1970")
+        .as_millis() as u64
+}
+
+// Outbox - counts the frames a connection has queued but not yet written,
+// so bulk senders can hold off until it catches up, and notices when it's
+// fallen too far behind
+#[derive(Default)]
+struct Outbox {
+    queued: AtomicUsize,
+    drained: Notify,
+    // Set once the writer has stopped and nothing more will drain
+    closed: AtomicBool,
+    // Past this many queued frames the connection is lagging, until it's
+    // down to half
+    lag_threshold: Option<usize>,
+    lagging: AtomicBool,
+    // Times it started lagging, and frames dropped rather than written
+    lags: AtomicU64,
+    dropped: AtomicU64,
+}
+impl Outbox {
+    fn new(lag_threshold: Option<usize>) -> Self {
+        Self {
+            lag_threshold,
+            ..Self::default()
+        }
+    }
+
+    // Counts a frame in. Answers with how many are queued if that's just
+    // made the connection lag.
+    fn queue(&self) -> Option<usize> {
+        let queued = self.queued.fetch_add(1, Ordering::Relaxed) + 1;
+        let threshold = self.lag_threshold?;
+        if queued > threshold && !self.lagging.swap(true, Ordering::Relaxed) {
+            self.lags.fetch_add(1, Ordering::Relaxed);
+            return Some(queued);
+        }
+        None
+    }
+
+    fn written(&self) {
+        let queued = self.queued.fetch_sub(1, Ordering::Relaxed);
+        if queued <= REPLAY_WINDOW {
+            self.drained.notify_one();
+        }
+        if let Some(threshold) = self.lag_threshold {
+            if queued <= threshold / 2 {
+                self.lagging.store(false, Ordering::Relaxed);
+            }
+        }
+    }
+
+    // A frame let go without writing it
+    fn drop_frame(&self) {
+        self.dropped.fetch_add(1, Ordering::Relaxed);
+        self.written();
+    }
+
+    fn queued(&self) -> usize {
+        self.queued.load(Ordering::Relaxed)
+    }
+
+    fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+
+    fn lags(&self) -> u64 {
+        self.lags.load(Ordering::Relaxed)
+    }
+
+    fn lagging(&self) -> bool {
+        self.lagging.load(Ordering::Relaxed)
+    }
+
+    fn close(&self) {
+        self.closed.store(true, Ordering::Relaxed);
+        self.drained.notify_one();
+    }
+
+    // Waits until the connection is down to REPLAY_WINDOW queued frames, or
+    // has closed
+    async fn wait(&self) {
+        while self.queued.load(Ordering::Relaxed) > REPLAY_WINDOW
+            && !self.closed.load(Ordering::Relaxed)
+        {
+            self.drained.notified().await;
+        }
+    }
+}
+
+// User
+struct User {
+    id: Uuid,
+    tx: UnboundedSender<Bytes>,
+    outbox: Arc<Outbox>,
+    translations: Option<Address<TranslationCache>>,
+    // What the user reads, if they want messages translated
+    lang: Option<String>,
+}
+impl Actor for User {}
+impl User {
+    fn new(
+        id: Uuid,
+        tx: UnboundedSender<Bytes>,
+        outbox: Arc<Outbox>,
+        translations: Option<Address<TranslationCache>>,
+    ) -> Self {
+        Self {
+            id,
+            tx,
+            outbox,
+            translations,
+            lang: None,
+        }
+    }
+
+    // Sends a `translation` after each message in a live frame from
+    // someone else, as they come back from the translator. The messages
+    // themselves don't wait for it.
+    fn translate(&self, frame: &[u8], ctx: &mut Context<Self>) {
+        let (translations, lang) = match (&self.translations, &self.lang) {
+            (Some(translations), Some(lang)) if lanes::is_live(frame) => {
+                (translations.clone(), lang.clone())
+            }
+            _ => return,
+        };
+        let user = match ctx.address() {
+            Ok(user) => user.downgrade(),
+            Err(_) => return,
+        };
+        let mut originals = translate::originals(frame);
+        originals.retain(|original| original.from != self.id);
+        if originals.is_empty() {
+            return;
+        }
+        tokio::spawn(async move {
+            for original in originals {
+                let translation = translations
+                    .send(Translate {
+                        id: original.id,
+                        body: original.body.clone(),
+                        lang: lang.clone(),
+                    })
+                    .await;
+                let body = match translation {
+                    Ok(translation) => match translation.text().await {
+                        Some(body) if body != original.body => body,
+                        _ => continue,
+                    },
+                    Err(_) => return,
+                };
+                let event = ServerEvent::Translation {
+                    room: &original.room,
+                    id: original.id,
+                    seq: original.seq,
+                    lang: &lang,
+                    body: &body,
+                };
+                if user.do_send(ToUser(event.to_json())).is_err() {
+                    return;
+                }
+            }
+        });
+    }
+}
+
+// ToUser - sends message back up to user
+struct ToUser(Bytes);
+impl Message for ToUser {
+    type Result = ();
+}
+#[async_trait::async_trait]
+impl Handler<ToUser> for User {
+    async fn handle(&mut self, msg: ToUser, ctx: &mut Context<Self>) {
+        let _timer = metrics::timer("to_user");
+        self.translate(&msg.0, ctx);
+        // Counted before it goes in, so the writer never sees it uncounted
+        let lagging = self.outbox.queue();
+        if self.tx.send(msg.0).is_err() {
+            log::warn!("Could not pipe message back to {}", self.id);
+            return;
+        }
+        // Controls go out first, so they hear of it before the backlog
+        if let Some(queued) = lagging {
+            log::warn!("{} is lagging with {} frames queued", self.id, queued);
+            self.outbox.queue();
+            let event = ServerEvent::YouAreLagging { queued }.to_json();
+            let _ = self.tx.send(event);
+        }
+    }
+}
+
+// SetLanguage - the language a user wants messages translated to, or None
+// to stop
+struct SetLanguage(Option<String>);
+impl Message for SetLanguage {
+    type Result = Result<(), ProtocolError>;
+}
+#[async_trait::async_trait]
+impl Handler<SetLanguage> for User {
+    async fn handle(
+        &mut self,
+        msg: SetLanguage,
+        _ctx: &mut Context<Self>,
+    ) -> Result<(), ProtocolError> {
+        let _timer = metrics::timer("set_language");
+        if self.translations.is_none() {
+            return Err(ProtocolError::new(
+                "no_translator",
+                "This server doesn't translate".to_string(),
+            ));
+        }
+        if let Some(lang) = msg.0.as_deref().filter(|lang| !translate::valid_lang(lang)) {
+            return Err(ProtocolError::new(
+                "bad_language",
+                format!("Not a language code: {}", lang),
+            ));
+        }
+        self.lang = msg.0;
+        Ok(())
+    }
+}
+
+// Room - carries out what its RoomState decides, holding the addresses and
+// actors that the state only knows by id
+struct Room {
+    state: RoomState,
+    users: HashMap<Uuid, Address<User>>,
+    // Threads under this room, which take on its bans
+    threads: Vec<Address<Room>>,
+    moderation: Address<ModerationQueue>,
+    exporter: Option<Address<Exporter>>,
+    stats: Address<Stats>,
+}
+impl Actor for Room {}
+impl Room {
+    fn new(
+        state: RoomState,
+        moderation: Address<ModerationQueue>,
+        exporter: Option<Address<Exporter>>,
+        stats: Address<Stats>,
+    ) -> Self {
+        Self {
+            state,
+            users: HashMap::new(),
+            threads: Vec::new(),
+            moderation,
+            exporter,
+            stats,
+        }
+    }
+
+    // Carries out everything the state has decided since last time, in order
+    async fn run(&mut self, ctx: &mut Context<Self>) {
+        for effect in self.state.effects() {
+            match effect {
+                // Queued without waiting for each user to take it in, so a
+                // room doesn't stall its broadcast on its slowest member
+                Effect::Send(id, frame) => {
+                    if let Some(addr) = self.users.get(&id) {
+                        if addr.do_send(ToUser(frame)).is_err() {
+                            log::warn!(
+                                "{} is gone, so it missed a frame from {}",
+                                id,
+                                self.state.name()
+                            );
+                        }
+                    }
+                }
+                Effect::Export(kind, payload) => self.export(kind, payload),
+                Effect::Posted { from, at } => {
+                    let posted = Posted {
+                        room: self.state.name().to_string(),
+                        from,
+                        at,
+                    };
+                    if self.stats.do_send(posted).is_err() {
+                        log::warn!("Could not update stats");
+                    }
+                }
+                Effect::Occupancy(members) => {
+                    let occupancy = Occupancy {
+                        room: self.state.name().to_string(),
+                        members,
+                    };
+                    if self.stats.do_send(occupancy).is_err() {
+                        log::warn!("Could not update stats");
+                    }
+                }
+                Effect::Report(report) => {
+                    if self.moderation.send(report).await.is_err() {
+                        log::warn!("Could not file a report in {}", self.state.name());
+                    }
+                }
+                // Nothing more to do once the room is stopping
+                Effect::Punish(id, action, expires_at) => {
+                    let addr = match ctx.address() {
+                        Ok(addr) => addr,
+                        Err(_) => continue,
+                    };
+                    let queued = match action {
+                        SpamAction::Ban => {
+                            let ban = Ban {
+                                reason: Some("spam".to_string()),
+                                expires_at,
+                                ..Ban::new(id, None, now_millis())
+                            };
+                            addr.do_send(BanUser(ban))
+                        }
+                        SpamAction::Mute => addr.do_send(MuteUser(id, true)),
+                        SpamAction::Kick => addr.do_send(KickUser(id)),
+                    };
+                    if queued.is_err() {
+                        continue;
+                    }
+                    log::info!("{} caught link spamming in {}", id, self.state.name());
+                }
+                Effect::QueueFlush => {
+                    if let Ok(addr) = ctx.address() {
+                        let _ = addr.do_send(Flush);
+                    }
+                }
+                Effect::Command(webhook, invocation) => {
+                    if let Ok(addr) = ctx.address() {
+                        tokio::spawn(command(addr, webhook, invocation));
+                    }
+                }
+                Effect::QueuePresence => {
+                    let addr = match ctx.address() {
+                        Ok(addr) => addr,
+                        Err(_) => continue,
+                    };
+                    tokio::spawn(async move {
+                        tokio::time::sleep(PRESENCE_WINDOW).await;
+                        // Gone by then, and so is everyone it was for
+                        let _ = addr.do_send(FlushPresence);
+                    });
+                }
+            }
+        }
+    }
+
+    // Passes an event on to the exporter, if there is one, without waiting
+    fn export(&self, kind: &'static str, payload: Bytes) {
+        if let Some(exporter) = &self.exporter {
+            let export = Export {
+                room: self.state.name().to_string(),
+                kind,
+                payload,
+            };
+            if exporter.do_send(export).is_err() {
+                log::warn!("Could not export {} event", kind);
+            }
+        }
+    }
+}
+
+// Calls a command's webhook and posts what it answers with, as a bot
+// flaired with the command
+async fn command(addr: Address<Room>, webhook: Webhook, invocation: Invocation) {
+    let posted = match commands::call(&webhook, &invocation).await {
+        Ok(None) => return,
+        Ok(Some(body)) => {
+            let bot = Bot {
+                flair: Some(format!("/{}", invocation.command)),
+            };
+            match addr
+                .send(GotUserMessage(Uuid::nil(), body, Some(bot)))
+                .await
+            {
+                Ok(posted) => posted.map_err(|e| e.message),
+                // The room has gone
+                Err(_) => return,
+            }
+        }
+        Err(reason) => Err(reason),
+    };
+    if let Err(reason) = posted {
+        log::warn!(
+            "/{} in {} failed: {}",
+            invocation.command,
+            invocation.room,
+            reason
+        );
+        let _ = addr.do_send(CommandFailed {
+            user: invocation.user,
+            command: invocation.command,
+            reason,
+        });
+    }
+}
+
+// CommandFailed - a command's webhook didn't work out
+struct CommandFailed {
+    user: Uuid,
+    command: String,
+    reason: String,
+}
+impl Message for CommandFailed {
+    type Result = ();
+}
+#[async_trait::async_trait]
+impl Handler<CommandFailed> for Room {
+    async fn handle(&mut self, msg: CommandFailed, ctx: &mut Context<Self>) {
+        let _timer = metrics::timer("command_failed");
+        self.state
+            .command_failed(msg.user, &msg.command, &msg.reason);
+        self.run(ctx).await;
+    }
+}
+
+// GotUserMessage - a post, and the bot it's from if it is one. Refused
+// outright when the room doesn't take it at all; answers whether it went
+// out when it does.
+struct GotUserMessage(Uuid, String, Option<Bot>);
+impl Message for GotUserMessage {
+    type Result = Result<Delivery, ProtocolError>;
+}
+#[async_trait::async_trait]
+impl Handler<GotUserMessage> for Room {
+    async fn handle(
+        &mut self,
+        msg: GotUserMessage,
+        ctx: &mut Context<Self>,
+    ) -> Result<Delivery, ProtocolError> {
+        let _timer = metrics::timer("got_user_message");
+        let posted = self.state.post(
+            msg.0,
+            msg.1,
+            msg.2,
+            ids::next(),
+            Instant::now(),
+            now_millis(),
+        );
+        self.run(ctx).await;
+        posted
+    }
+}
+
+// Flush - sends out whatever messages are waiting
+struct Flush;
+impl Message for Flush {
+    type Result = ();
+}
+#[async_trait::async_trait]
+impl Handler<Flush> for Room {
+    async fn handle(&mut self, _msg: Flush, ctx: &mut Context<Self>) {
+        let _timer = metrics::timer("flush");
+        self.state.flush_due();
+        self.run(ctx).await;
+    }
+}
+
+// Join - someone asking in, with the User to reach them at. Answers with
+// their Admission: joined as of the last seq posted so far, everything after
+// it reaching them live, or waiting for a moderator.
+struct Join(Joiner, Address<User>);
+impl Message for Join {
+    type Result = Result<Admission, ProtocolError>;
+}
+#[async_trait::async_trait]
+impl Handler<Join> for Room {
+    async fn handle(
+        &mut self,
+        msg: Join,
+        ctx: &mut Context<Self>,
+    ) -> Result<Admission, ProtocolError> {
+        let _timer = metrics::timer("join");
+        let id = msg.0.id;
+        let joined = self.state.join(msg.0, Instant::now(), now_millis());
+        if joined.is_ok() {
+            // Whatever was unsent goes out before they're in to get it.
+            // Pending joiners are kept track of too, to be told how it went.
+            self.run(ctx).await;
+            self.users.insert(id, msg.1);
+            log::debug!(
+                "{} joined {}, now there are {}",
+                id,
+                self.state.name(),
+                self.users.len()
+            );
+        }
+        joined
+    }
+}
+
+// JoinRequests - who is waiting to be let in
+struct JoinRequests;
+impl Message for JoinRequests {
+    type Result = Vec<Uuid>;
+}
+#[async_trait::async_trait]
+impl Handler<JoinRequests> for Room {
+    async fn handle(&mut self, _msg: JoinRequests, _ctx: &mut Context<Self>) -> Vec<Uuid> {
+        let _timer = metrics::timer("join_requests");
+        self.state.pending()
+    }
+}
+
+// ResolveJoin - a moderator letting a joiner in or turning them away;
+// answers whether they were waiting, or why they can't be let in
+struct ResolveJoin {
+    id: Uuid,
+    approve: bool,
+}
+impl Message for ResolveJoin {
+    type Result = Result<bool, ProtocolError>;
+}
+#[async_trait::async_trait]
+impl Handler<ResolveJoin> for Room {
+    async fn handle(
+        &mut self,
+        msg: ResolveJoin,
+        ctx: &mut Context<Self>,
+    ) -> Result<bool, ProtocolError> {
+        let _timer = metrics::timer("resolve_join");
+        if msg.approve {
+            let approved = self.state.approve(msg.id, Instant::now(), now_millis());
+            self.run(ctx).await;
+            if approved.is_err() && !self.state.pending().contains(&msg.id) {
+                self.users.remove(&msg.id);
+            }
+            return approved;
+        }
+        let denied = self.state.deny(msg.id);
+        self.run(ctx).await;
+        if denied {
+            self.users.remove(&msg.id);
+        }
+        Ok(denied)
+    }
+}
+
+// Invite - a new invite code for the room, good for one join
+struct Invite;
+impl Message for Invite {
+    type Result = String;
+}
+#[async_trait::async_trait]
+impl Handler<Invite> for Room {
+    async fn handle(&mut self, _msg: Invite, _ctx: &mut Context<Self>) -> String {
+        let _timer = metrics::timer("invite");
+        let code = Uuid::new_v4().to_simple().to_string();
+        self.state.invite(code.clone());
+        code
+    }
+}
+
+// Leave - someone going, and whether they left on purpose rather than
+// disconnected, so nothing is kept for them
+struct Leave {
+    id: Uuid,
+    for_good: bool,
+}
+impl Message for Leave {
+    type Result = ();
+}
+#[async_trait::async_trait]
+impl Handler<Leave> for Room {
+    async fn handle(&mut self, msg: Leave, ctx: &mut Context<Self>) {
+        let _timer = metrics::timer("leave");
+        log::debug!("{} left {}", msg.id, self.state.name());
+        self.state.leave(msg.id, Instant::now());
+        if msg.for_good {
+            self.state.forget(msg.id);
+        }
+        self.users.remove(&msg.id);
+        self.run(ctx).await;
+    }
+}
+
+// GetHistory - a user asking for a page of older messages
+struct GetHistory {
+    id: Uuid,
+    before_seq: Option<u64>,
+    limit: Option<usize>,
+}
+impl Message for GetHistory {
+    type Result = ();
+}
+#[async_trait::async_trait]
+impl Handler<GetHistory> for Room {
+    async fn handle(&mut self, msg: GetHistory, ctx: &mut Context<Self>) {
+        let _timer = metrics::timer("get_history");
+        self.state
+            .history(msg.id, msg.before_seq, msg.limit, now_millis());
+        self.run(ctx).await;
+    }
+}
+
+// ReplayChunk - the next chunk of a replay, as a frame and the seq to carry
+// on after if there's more. None once the user has left.
+struct ReplayChunk {
+    id: Uuid,
+    after_seq: u64,
+    until_seq: u64,
+}
+impl Message for ReplayChunk {
+    type Result = Option<(Bytes, Option<u64>)>;
+}
+#[async_trait::async_trait]
+impl Handler<ReplayChunk> for Room {
+    async fn handle(
+        &mut self,
+        msg: ReplayChunk,
+        _ctx: &mut Context<Self>,
+    ) -> Option<(Bytes, Option<u64>)> {
+        let _timer = metrics::timer("replay_chunk");
+        self.state
+            .replay(msg.id, msg.after_seq, msg.until_seq, now_millis())
+    }
+}
+
+// ListMembers - a user asking who else is here
+struct ListMembers {
+    id: Uuid,
+}
+impl Message for ListMembers {
+    type Result = ();
+}
+#[async_trait::async_trait]
+impl Handler<ListMembers> for Room {
+    async fn handle(&mut self, msg: ListMembers, ctx: &mut Context<Self>) {
+        let _timer = metrics::timer("list_members");
+        self.state.list_members(msg.id);
+        self.run(ctx).await;
+    }
+}
+
+// Typing - a member typing, sent on with the next presence event
+struct Typing(Uuid);
+impl Message for Typing {
+    type Result = ();
+}
+#[async_trait::async_trait]
+impl Handler<Typing> for Room {
+    async fn handle(&mut self, msg: Typing, ctx: &mut Context<Self>) {
+        let _timer = metrics::timer("typing");
+        self.state.typing(msg.0);
+        self.run(ctx).await;
+    }
+}
+
+// SetKeywords - the keywords a member wants to hear about
+struct SetKeywords(Uuid, Vec<String>);
+impl Message for SetKeywords {
+    type Result = Result<(), ProtocolError>;
+}
+#[async_trait::async_trait]
+impl Handler<SetKeywords> for Room {
+    async fn handle(
+        &mut self,
+        msg: SetKeywords,
+        _ctx: &mut Context<Self>,
+    ) -> Result<(), ProtocolError> {
+        let _timer = metrics::timer("set_keywords");
+        self.state.set_keywords(msg.0, msg.1)
+    }
+}
+
+// SetDraft - what a member is writing and hasn't sent
+struct SetDraft(Uuid, String);
+impl Message for SetDraft {
+    type Result = Result<(), ProtocolError>;
+}
+#[async_trait::async_trait]
+impl Handler<SetDraft> for Room {
+    async fn handle(
+        &mut self,
+        msg: SetDraft,
+        _ctx: &mut Context<Self>,
+    ) -> Result<(), ProtocolError> {
+        let _timer = metrics::timer("set_draft");
+        self.state.set_draft(msg.0, msg.1)
+    }
+}
+
+// MarkRead - a member having read up to a seq
+struct MarkRead(Uuid, u64);
+impl Message for MarkRead {
+    type Result = ();
+}
+#[async_trait::async_trait]
+impl Handler<MarkRead> for Room {
+    async fn handle(&mut self, msg: MarkRead, _ctx: &mut Context<Self>) {
+        let _timer = metrics::timer("mark_read");
+        self.state.mark_read(msg.0, msg.1);
+    }
+}
+
+// GetUnread - how far a user has read here, if they've ever joined
+struct GetUnread(Uuid);
+impl Message for GetUnread {
+    type Result = Option<Unread>;
+}
+#[async_trait::async_trait]
+impl Handler<GetUnread> for Room {
+    async fn handle(&mut self, msg: GetUnread, _ctx: &mut Context<Self>) -> Option<Unread> {
+        let _timer = metrics::timer("get_unread");
+        self.state.unread(msg.0, now_millis())
+    }
+}
+
+// FlushPresence - sends out the presence changes gathered up
+struct FlushPresence;
+impl Message for FlushPresence {
+    type Result = ();
+}
+#[async_trait::async_trait]
+impl Handler<FlushPresence> for Room {
+    async fn handle(&mut self, _msg: FlushPresence, ctx: &mut Context<Self>) {
+        let _timer = metrics::timer("flush_presence");
+        self.state.flush_presence();
+        self.run(ctx).await;
+    }
+}
+
+// ReportMessage - a user flagging a message for the moderators
+struct ReportMessage {
+    id: Uuid,
+    message_id: u64,
+    reason: String,
+}
+impl Message for ReportMessage {
+    type Result = ();
+}
+#[async_trait::async_trait]
+impl Handler<ReportMessage> for Room {
+    async fn handle(&mut self, msg: ReportMessage, ctx: &mut Context<Self>) {
+        let _timer = metrics::timer("report_message");
+        let report = self.state.report(msg.id, msg.message_id, msg.reason);
+        self.run(ctx).await;
+        let report = match report {
+            Some(report) => report,
+            None => return,
+        };
+
+        let hide = match self.moderation.send(report).await {
+            Ok(hide) => hide,
+            Err(_) => {
+                log::warn!("Could not file a report in {}", self.state.name());
+                return;
+            }
+        };
+        if hide {
+            self.state.hide(msg.message_id);
+            self.run(ctx).await;
+        }
+    }
+}
+
+// RestoreMessage - a moderator dismissing the reports against a message
+struct RestoreMessage(u64);
+impl Message for RestoreMessage {
+    type Result = ();
+}
+#[async_trait::async_trait]
+impl Handler<RestoreMessage> for Room {
+    async fn handle(&mut self, msg: RestoreMessage, _ctx: &mut Context<Self>) {
+        let _timer = metrics::timer("restore_message");
+        self.state.restore(msg.0);
+    }
+}
+
+// DeleteMessage - a moderator removing a message for good
+struct DeleteMessage(u64);
+impl Message for DeleteMessage {
+    type Result = ();
+}
+#[async_trait::async_trait]
+impl Handler<DeleteMessage> for Room {
+    async fn handle(&mut self, msg: DeleteMessage, ctx: &mut Context<Self>) {
+        let _timer = metrics::timer("delete_message");
+        self.state.delete(msg.0);
+        self.run(ctx).await;
+    }
+}
+
+// PurgeMessages - a moderator deleting the newest `last` messages, or only
+// those from one user; all of them if `last` is left out. Answers with how
+// many went.
+struct PurgeMessages {
+    last: Option<usize>,
+    from: Option<Uuid>,
+}
+impl Message for PurgeMessages {
+    type Result = usize;
+}
+#[async_trait::async_trait]
+impl Handler<PurgeMessages> for Room {
+    async fn handle(&mut self, msg: PurgeMessages, ctx: &mut Context<Self>) -> usize {
+        let _timer = metrics::timer("purge_messages");
+        let deleted = self.state.purge(msg.last, msg.from);
+        self.run(ctx).await;
+        deleted
+    }
+}
+
+// ImportMessages - history from elsewhere, oldest first. Answers with how
+// many were kept.
+struct ImportMessages(Vec<ChatMessage>);
+impl Message for ImportMessages {
+    type Result = usize;
+}
+#[async_trait::async_trait]
+impl Handler<ImportMessages> for Room {
+    async fn handle(&mut self, msg: ImportMessages, ctx: &mut Context<Self>) -> usize {
+        let _timer = metrics::timer("import_messages");
+        let imported = self.state.import(msg.0, now_millis());
+        self.run(ctx).await;
+        imported
+    }
+}
+
+// Transcript - the visible messages sent in [from, until), unix millis,
+// oldest first, and the timezone to show them in
+struct Transcript {
+    from: u64,
+    until: u64,
+}
+impl Message for Transcript {
+    type Result = (Vec<ChatMessage>, Offset);
+}
+#[async_trait::async_trait]
+impl Handler<Transcript> for Room {
+    async fn handle(
+        &mut self,
+        msg: Transcript,
+        ctx: &mut Context<Self>,
+    ) -> (Vec<ChatMessage>, Offset) {
+        let _timer = metrics::timer("transcript");
+        let messages = self.state.transcript(msg.from, msg.until, now_millis());
+        self.run(ctx).await;
+        (messages, self.state.timezone())
+    }
+}
+
+// BanUser - a moderator throwing someone out of the room, or changing
+// the ban on someone already out
+struct BanUser(Ban);
+impl Message for BanUser {
+    type Result = ();
+}
+#[async_trait::async_trait]
+impl Handler<BanUser> for Room {
+    async fn handle(&mut self, msg: BanUser, ctx: &mut Context<Self>) {
+        let _timer = metrics::timer("ban_user");
+        // A thread that's gone has nobody left to keep out
+        for thread in self.threads.iter() {
+            let _ = thread.send(BanUser(msg.0.clone())).await;
+        }
+        // They hear about it before their address goes
+        let id = msg.0.user;
+        let removed = self.state.ban(msg.0);
+        self.run(ctx).await;
+        if removed {
+            self.users.remove(&id);
+        }
+    }
+}
+
+// UnbanUser - lifting a ban before it's up; answers whether there was one
+struct UnbanUser(Uuid);
+impl Message for UnbanUser {
+    type Result = bool;
+}
+#[async_trait::async_trait]
+impl Handler<UnbanUser> for Room {
+    async fn handle(&mut self, msg: UnbanUser, _ctx: &mut Context<Self>) -> bool {
+        let _timer = metrics::timer("unban_user");
+        for thread in self.threads.iter() {
+            let _ = thread.send(UnbanUser(msg.0)).await;
+        }
+        self.state.unban(msg.0)
+    }
+}
+
+// RoomGrants - the roles given out, by user
+struct RoomGrants;
+impl Message for RoomGrants {
+    type Result = BTreeMap<Uuid, String>;
+}
+#[async_trait::async_trait]
+impl Handler<RoomGrants> for Room {
+    async fn handle(
+        &mut self,
+        _msg: RoomGrants,
+        _ctx: &mut Context<Self>,
+    ) -> BTreeMap<Uuid, String> {
+        let _timer = metrics::timer("room_grants");
+        self.state
+            .grants()
+            .map(|(user, role)| (*user, role.clone()))
+            .collect()
+    }
+}
+
+// ListBans - the bans still in force
+struct ListBans;
+impl Message for ListBans {
+    type Result = Vec<Ban>;
+}
+#[async_trait::async_trait]
+impl Handler<ListBans> for Room {
+    async fn handle(&mut self, _msg: ListBans, _ctx: &mut Context<Self>) -> Vec<Ban> {
+        let _timer = metrics::timer("list_bans");
+        self.state.bans(now_millis())
+    }
+}
+
+// MuteUser - a moderator shadow-muting someone, or lifting it with false.
+// They aren't told, and carry on seeing their own messages.
+struct MuteUser(Uuid, bool);
+impl Message for MuteUser {
+    type Result = ();
+}
+#[async_trait::async_trait]
+impl Handler<MuteUser> for Room {
+    async fn handle(&mut self, msg: MuteUser, ctx: &mut Context<Self>) {
+        let _timer = metrics::timer("mute_user");
+        self.state.mute(msg.0, msg.1);
+        self.run(ctx).await;
+        for thread in self.threads.iter() {
+            let _ = thread.send(MuteUser(msg.0, msg.1)).await;
+        }
+    }
+}
+
+// AddThread - hangs a new thread off the room, passing on its bans, mutes
+// and roles
+struct AddThread(Address<Room>);
+impl Message for AddThread {
+    type Result = ();
+}
+#[async_trait::async_trait]
+impl Handler<AddThread> for Room {
+    async fn handle(&mut self, msg: AddThread, _ctx: &mut Context<Self>) {
+        let _timer = metrics::timer("add_thread");
+        // Stopped already, so there's nothing to hang off the room
+        for ban in self.state.bans(now_millis()) {
+            if msg.0.send(BanUser(ban)).await.is_err() {
+                return;
+            }
+        }
+        for id in self.state.muted() {
+            if msg.0.send(MuteUser(*id, true)).await.is_err() {
+                return;
+            }
+        }
+        for (user, role) in self.state.grants() {
+            let assign = AssignRole {
+                user: *user,
+                role: Some(role.clone()),
+            };
+            match msg.0.send(assign).await {
+                Ok(assigned) => assigned.expect("Threads have their room's roles"),
+                Err(_) => return,
+            }
+        }
+        self.threads.push(msg.0);
+    }
+}
+
+// KickUser - a moderator putting someone out of the room, who may come back
+struct KickUser(Uuid);
+impl Message for KickUser {
+    type Result = bool;
+}
+#[async_trait::async_trait]
+impl Handler<KickUser> for Room {
+    async fn handle(&mut self, msg: KickUser, ctx: &mut Context<Self>) -> bool {
+        let _timer = metrics::timer("kick_user");
+        let removed = self.state.kick(msg.0);
+        self.run(ctx).await;
+        if removed {
+            self.users.remove(&msg.0);
+        }
+        removed
+    }
+}
+
+// Authorize - whether a member may do something, before a Connection does
+// it for them
+struct Authorize(Uuid, Permissions);
+impl Message for Authorize {
+    type Result = Result<(), ProtocolError>;
+}
+#[async_trait::async_trait]
+impl Handler<Authorize> for Room {
+    async fn handle(
+        &mut self,
+        msg: Authorize,
+        _ctx: &mut Context<Self>,
+    ) -> Result<(), ProtocolError> {
+        let _timer = metrics::timer("authorize");
+        self.state.authorize(msg.0, msg.1)
+    }
+}
+
+// AssignRole - gives someone one of the room's roles, or takes theirs away
+// with None, in the room and its threads
+struct AssignRole {
+    user: Uuid,
+    role: Option<String>,
+}
+impl Message for AssignRole {
+    type Result = Result<(), ProtocolError>;
+}
+#[async_trait::async_trait]
+impl Handler<AssignRole> for Room {
+    async fn handle(
+        &mut self,
+        msg: AssignRole,
+        _ctx: &mut Context<Self>,
+    ) -> Result<(), ProtocolError> {
+        let _timer = metrics::timer("assign_role");
+        self.state.assign(msg.user, msg.role.clone())?;
+        for thread in self.threads.iter() {
+            let assign = AssignRole {
+                user: msg.user,
+                role: msg.role.clone(),
+            };
+            if let Ok(assigned) = thread.send(assign).await {
+                assigned?;
+            }
+        }
+        Ok(())
+    }
+}
+
+// RoomMembers - who is in the room, for the admin API
+struct RoomMembers;
+impl Message for RoomMembers {
+    type Result = Vec<Uuid>;
+}
+#[async_trait::async_trait]
+impl Handler<RoomMembers> for Room {
+    async fn handle(&mut self, _msg: RoomMembers, _ctx: &mut Context<Self>) -> Vec<Uuid> {
+        let _timer = metrics::timer("room_members");
+        self.state.members()
+    }
+}
+
+// GetPreview - what someone thinking of joining gets to see
+struct GetPreview;
+impl Message for GetPreview {
+    type Result = Preview;
+}
+#[async_trait::async_trait]
+impl Handler<GetPreview> for Room {
+    async fn handle(&mut self, _msg: GetPreview, ctx: &mut Context<Self>) -> Preview {
+        let _timer = metrics::timer("get_preview");
+        let preview = self.state.preview(now_millis());
+        self.run(ctx).await;
+        preview
+    }
+}
+
+// SetFeatures - the room's admins turning features on or off
+struct SetFeatures(RoomFeatures);
+impl Message for SetFeatures {
+    type Result = ();
+}
+#[async_trait::async_trait]
+impl Handler<SetFeatures> for Room {
+    async fn handle(&mut self, msg: SetFeatures, ctx: &mut Context<Self>) {
+        let _timer = metrics::timer("set_features");
+        self.state.set_features(msg.0);
+        self.run(ctx).await;
+    }
+}
+
+// SetTimezone - the room's admins changing what its transcripts show
+// times in
+struct SetTimezone(Offset);
+impl Message for SetTimezone {
+    type Result = ();
+}
+#[async_trait::async_trait]
+impl Handler<SetTimezone> for Room {
+    async fn handle(&mut self, msg: SetTimezone, _ctx: &mut Context<Self>) {
+        let _timer = metrics::timer("set_timezone");
+        self.state.set_timezone(msg.0);
+    }
+}
+
+// Configure - the room's settings replaced, from the registry
+struct Configure(RoomSettings);
+impl Message for Configure {
+    type Result = ();
+}
+#[async_trait::async_trait]
+impl Handler<Configure> for Room {
+    async fn handle(&mut self, msg: Configure, ctx: &mut Context<Self>) {
+        let _timer = metrics::timer("configure");
+        self.state.configure(msg.0);
+        self.run(ctx).await;
+    }
+}
+
+// Archive - the room putting everyone out and stopping, from the registry.
+// Answers with its state, for the registry to keep and start it again from.
+struct Archive;
+impl Message for Archive {
+    type Result = RoomState;
+}
+#[async_trait::async_trait]
+impl Handler<Archive> for Room {
+    async fn handle(&mut self, _msg: Archive, ctx: &mut Context<Self>) -> RoomState {
+        let _timer = metrics::timer("archive");
+        self.state.archive(Instant::now());
+        self.run(ctx).await;
+        self.users.clear();
+        self.threads.clear();
+        ctx.stop();
+        let stopped = RoomState::new(RoomSettings::named(self.state.name()), None);
+        mem::replace(&mut self.state, stopped)
+    }
+}
+
+// Rename - the room's new name, from the registry
+struct Rename(String);
+impl Message for Rename {
+    type Result = ();
+}
+#[async_trait::async_trait]
+impl Handler<Rename> for Room {
+    async fn handle(&mut self, msg: Rename, ctx: &mut Context<Self>) {
+        let _timer = metrics::timer("rename");
+        let from = self.state.name().to_string();
+        self.state.rename(msg.0.clone());
+        self.run(ctx).await;
+        if self.stats.do_send(Renamed { from, to: msg.0 }).is_err() {
+            log::warn!("Could not update stats");
+        }
+    }
+}
+
+// The server, configured from the environment, until it's stopped
+pub async fn run() {
+    let mut config = Config::from_env();
+    metrics::init(config.slow_handler_threshold);
+    ids::init(&config.id_strategy);
+
+    let peer = proxy::peer(Arc::new(mem::take(&mut config.trusted_proxies)));
+    let claimed = Arc::new(
+        config
+            .tenants
+            .iter()
+            .map(|tenant| tenant.host.clone())
+            .collect::<Vec<_>>(),
+    );
+    let (routes, _) = community(&config, peer.clone(), None).await;
+    let mut routes = tenant::serving(None, claimed.clone(), peer.clone())
+        .and(routes)
+        .boxed();
+    for tenant in &config.tenants {
+        log::info!("Serving {} on {}", tenant.name, tenant.host);
+        let (community, _) = community(&Config::tenant(tenant), peer.clone(), None).await;
+        routes = tenant::serving(Some(tenant.host.clone()), claimed.clone(), peer.clone())
+            .and(community)
+            .or(routes)
+            .unify()
+            .boxed();
+    }
+
+    let metrics = warp::path("metrics").map(metrics::render);
+
+    listen::serve(&config.listen, config.unix_socket_mode, metrics.or(routes)).await;
+}
+
+// Everything one community has: its rooms, users, keys and moderators, and
+// the routes to them. Events go to `subscribers` as well as any NATS
+// server, when it's embedded.
+async fn community<P>(
+    config: &Config,
+    peer: P,
+    subscribers: Option<broadcast::Sender<Export>>,
+) -> (BoxedFilter<(Box<dyn Reply>,)>, Services)
+where
+    P: Filter<Extract = (Peer,), Error = Infallible> + Clone + Send + Sync + 'static,
+{
+    let moderation = ModerationQueue::new(config.report_hide_threshold)
+        .create(None)
+        .spawn(&mut Tokio::Global);
+    let exporter = if config.export_nats.is_some() || subscribers.is_some() {
+        let exporter = Exporter::new(
+            config.export_nats.clone(),
+            config.export_prefix.clone(),
+            config.export_buffer,
+            subscribers,
+        );
+        Some(exporter.create(None).spawn(&mut Tokio::Global))
+    } else {
+        None
+    };
+    let stats = Stats::default().create(None).spawn(&mut Tokio::Global);
+    let registry = RoomRegistry::new(
+        moderation.clone(),
+        exporter,
+        stats.clone(),
+        config.link_spam,
+        config.webhook_hosts.clone(),
+        config.motd.clone(),
+    )
+    .create(None)
+    .spawn(&mut Tokio::Global);
+    registry
+        .send(CreateRoom(RoomSettings::named(DEFAULT_ROOM)))
+        .await
+        .expect("Could not reach the registry")
+        .expect("Could not create the default room");
+    for template in config.room_templates.iter().cloned() {
+        let name = template.name.clone();
+        registry
+            .send(SaveTemplate(template))
+            .await
+            .expect("Could not reach the registry")
+            .unwrap_or_else(|e| panic!("Could not load room template {}: {}", name, e));
+    }
+    let keys = KeyStore::new().create(None).spawn(&mut Tokio::Global);
+    let connections = Connections::new(config.member_info)
+        .create(None)
+        .spawn(&mut Tokio::Global);
+    let quotas = Quotas::new(config.quota)
+        .create(None)
+        .spawn(&mut Tokio::Global);
+    let services = Services::default();
+    services.register(registry.clone());
+    services.register(moderation);
+    services.register(keys.clone());
+    services.register(connections);
+    services.register(quotas.clone());
+    let preferences = Preferences::new(services.clone())
+        .create(None)
+        .spawn(&mut Tokio::Global);
+    services.register(preferences.clone());
+    let accounts = Accounts::new(config.deactivation_window, services.clone())
+        .create(None)
+        .spawn(&mut Tokio::Global);
+    services.register(accounts.clone());
+    tokio::spawn(accounts::sweep(accounts.clone()));
+    let announcements = Announcements::new(services.clone())
+        .create(None)
+        .spawn(&mut Tokio::Global);
+    services.register(announcements.clone());
+    tokio::spawn(schedule::tick(announcements));
+    let (drain, state) = watch::channel(None);
+    let admin = admin::routes(
+        config.admin_token.clone(),
+        services.clone(),
+        Arc::new(drain),
+    );
+    let gate = config
+        .pow_bits
+        .map(|bits| PowGate::new(bits).create(None).spawn(&mut Tokio::Global));
+    let secret = config.cookie_secret.clone().unwrap_or_else(|| {
+        log::warn!("CHAT_COOKIE_SECRET is unset, so browsers keep their ids until a restart");
+        format!("{}{}", Uuid::new_v4(), Uuid::new_v4())
+    });
+    let signer = Arc::new(Signer::new(&secret));
+    let rooms = maintenance::open(state.clone())
+        .and(
+            registry::routes(registry.clone())
+                .or(stats::routes(stats, registry.clone()))
+                .or(pow::routes(gate.clone()))
+                .or(quota::routes(quotas, signer.clone()))
+                .or(preferences::routes(preferences, signer.clone()))
+                .or(accounts::routes(accounts.clone(), signer.clone())),
+        )
+        .recover(maintenance::handle_rejection);
+    let limiter = |budget| Limiter::new(budget).create(None).spawn(&mut Tokio::Global);
+    // Charged once the cheaper routes have had their turn, so nothing else
+    // is counted
+    let api = limits::rest(
+        config.rest_limit.map(limiter),
+        config.admin_token.clone(),
+        services.get(),
+        peer.clone(),
+    )
+    .and(admin.or(rooms))
+    .map(limits::headers)
+    .recover(limits::handle_rejection);
+    let api_key = config.translate_api_key.clone();
+    let translator = config.translate.clone().map(|addr| {
+        let translator = LibreTranslate { addr, api_key };
+        TranslationCache::new(Arc::new(translator))
+            .create(None)
+            .spawn(&mut Tokio::Global)
+    });
+    let shared = Shared {
+        services: services.clone(),
+        limits: limits::Socket {
+            events: config.ws_limit.map(limiter),
+            egress: config.egress_limit,
+        },
+        translations: translator,
+        lag_threshold: config.lag_threshold,
+        handshake_timeout: config.handshake_timeout,
+        heartbeat: config.heartbeat,
+        heartbeat_misses: config.heartbeat_misses,
+        tracing: config.tracing,
+        #[cfg(feature = "chaos")]
+        chaos: config.chaos,
+    };
+    let shared = warp::any().map(move || shared.clone());
+    let registry = warp::any().map(move || registry.clone());
+    let state = warp::any().map(move || state.clone());
+
+    let policy = match config.dnsbl.as_slice() {
+        [] => None,
+        zones => Some(Arc::new(Dnsbl {
+            zones: zones.to_vec(),
+            listed: config.dnsbl_listed,
+        }) as Arc<dyn ConnectionPolicy>),
+    };
+    let origin = origin::check(Arc::new(config.allowed_origins.clone()), peer.clone());
+    let chat = warp::path("ws")
+        .and(warp::ws())
+        .map(|ws: warp::ws::Ws| {
+            // Refused as they come in rather than once they're all here
+            ws.max_message_size(guard::MAX_EVENT_LEN)
+                .max_frame_size(guard::MAX_EVENT_LEN)
+        })
+        .and(origin)
+        .and(shared)
+        .and(pow::guard(gate, keys::authenticate(keys)))
+        .and(reputation::check(policy, peer.clone()))
+        .and(opening(config.echo))
+        .and(state)
+        .and(identity::id(signer.clone()))
+        .map(
+            |ws: warp::ws::Ws, shared, key, peer, opening, state, remembered| {
+                ws.on_upgrade(move |socket| {
+                    user_connected(socket, shared, key, peer, opening, state, remembered)
+                })
+            },
+        )
+        .recover(keys::handle_rejection)
+        .recover(pow::handle_rejection)
+        .recover(reputation::handle_rejection)
+        .recover(origin::handle_rejection);
+
+    let features = Features {
+        echo: config.echo,
+        link_spam: config.link_spam.is_some(),
+        pow: config.pow_bits.is_some(),
+    };
+    let index = warp::path::end()
+        .and(peer)
+        .and(registry)
+        .and(warp::any().map(move || features))
+        .and(identity::id(signer.clone()))
+        .and(warp::any().map(move || signer.clone()))
+        .and_then(index);
+
+    // Admin comes before the rest so it keeps working through maintenance
+    let routes = index
+        .or(chat)
+        .or(api)
+        .map(|reply| Box::new(reply) as Box<dyn Reply>)
+        .boxed();
+    (routes, services)
+}
+
+// Connection - one websocket's view of the world: who it is, what its key
+// allows and which rooms it's in. The socket's frames come in as messages,
+// so everything about the session happens one thing at a time in here.
+struct Connection {
+    id: Uuid,
+    addr: Address<User>,
+    scope: Option<Scope>,
+    // Room keys only work in the room they were minted for
+    key_room: Option<String>,
+    rooms: HashMap<String, Address<Room>>,
+    registry: Address<RoomRegistry>,
+    connections: Address<Connections>,
+    outbox: Arc<Outbox>,
+    // Whether the user gets their own messages back from rooms
+    echo: bool,
+    // Set for connections on a room key
+    bot: Option<Bot>,
+    // What every event sent is charged to, if websockets are rate limited
+    limiter: Option<(Address<Limiter>, String)>,
+    // What users without a key have posted today
+    quotas: Address<Quotas>,
+    preferences: Address<Preferences>,
+    // The round trips the client has told us about
+    latency: Arc<Latency>,
+    // Asks the writer to ping the socket
+    pinger: UnboundedSender<()>,
+    // Pings sent since the socket last answered one, and how many it may
+    // leave unanswered
+    unanswered: u32,
+    max_unanswered: u32,
+}
+impl Actor for Connection {}
+impl Connection {
+    fn refusal(&self, event: &ClientEvent) -> Option<ProtocolError> {
+        let (code, message) = match (self.scope, event) {
+            (Some(Scope::ReadOnly), ClientEvent::Message { .. })
+            | (Some(Scope::ReadOnly), ClientEvent::Typing { .. })
+            | (Some(Scope::ReadOnly), ClientEvent::Report { .. }) => {
+                ("read_only", "This key is read-only")
+            }
+            (Some(Scope::Spectator), ClientEvent::Message { .. })
+            | (Some(Scope::Spectator), ClientEvent::Typing { .. })
+            | (Some(Scope::Spectator), ClientEvent::Report { .. })
+            | (Some(Scope::Spectator), ClientEvent::Members { .. }) => {
+                ("spectator", "Spectator keys can only watch")
+            }
+            (Some(Scope::PostOnly), ClientEvent::History { .. })
+            | (Some(Scope::PostOnly), ClientEvent::Report { .. })
+            | (Some(Scope::PostOnly), ClientEvent::Members { .. }) => {
+                ("post_only", "This key is post-only")
+            }
+            (Some(_), ClientEvent::Join { .. })
+            | (Some(_), ClientEvent::Leave { .. })
+            | (Some(_), ClientEvent::CreateRoom(_))
+            | (Some(_), ClientEvent::CreateThread { .. }) => {
+                ("forbidden", "Room keys can't change rooms")
+            }
+            (Some(_), ClientEvent::Read { .. })
+            | (Some(_), ClientEvent::Keywords { .. })
+            | (Some(_), ClientEvent::DraftSet { .. })
+            | (Some(_), ClientEvent::SettingsUpdate(_)) => (
+                "forbidden",
+                "Room keys don't keep read state, keywords, drafts or settings",
+            ),
+            // Everyone else's role in the room decides
+            (Some(scope), ClientEvent::ApproveJoin { .. })
+            | (Some(scope), ClientEvent::DenyJoin { .. })
+            | (Some(scope), ClientEvent::Kick { .. })
+            | (Some(scope), ClientEvent::Ban { .. })
+            | (Some(scope), ClientEvent::MemberInfo { .. })
+                if scope != Scope::Admin =>
+            {
+                ("forbidden", "Only admin keys can moderate")
+            }
+            _ => return None,
+        };
+        Some(ProtocolError::new(code, message.to_string()))
+    }
+
+    // The room an event is for, which has to be one we're in. Post-only
+    // keys post without joining, so theirs is looked up instead.
+    async fn room(&self, name: &str) -> Result<Address<Room>, ProtocolError> {
+        let (joined, names) = self.joined(name).await?;
+        if let Some(joined) = joined {
+            return Ok(self.rooms[&joined].clone());
+        }
+        let keyed = names
+            .iter()
+            .any(|name| self.key_room.as_ref() == Some(name));
+        if self.scope == Some(Scope::PostOnly) && keyed {
+            if let Some(room) = self.lookup(&names[0]).await? {
+                return Ok(room);
+            }
+        }
+        Err(ProtocolError::new(
+            "not_joined",
+            format!("You are not in {}", registry::normalize(name)?),
+        ))
+    }
+
+    // What a room is kept under in `rooms`, if we're in it, along with all
+    // of its names. Rooms are kept under the name they had when joined, so
+    // one renamed since, or asked for by an alias, is found by its others.
+    async fn joined(&self, name: &str) -> Result<(Option<String>, Vec<String>), ProtocolError> {
+        let name = registry::normalize(name)?;
+        if self.rooms.contains_key(&name) {
+            return Ok((Some(name.clone()), vec![name]));
+        }
+        let names = self
+            .registry
+            .send(RoomNames(name))
+            .await
+            .map_err(unavailable)?;
+        let joined = names
+            .iter()
+            .find(|name| self.rooms.contains_key(*name))
+            .cloned();
+        Ok((joined, names))
+    }
+
+    async fn lookup(&self, name: &str) -> Result<Option<Address<Room>>, ProtocolError> {
+        self.registry
+            .send(GetRoom(name.to_string()))
+            .await
+            .map_err(unavailable)
+    }
+
+    async fn join(
+        &mut self,
+        name: &str,
+        since_seq: Option<u64>,
+        invite: Option<String>,
+        placed: bool,
+    ) -> Result<(), ProtocolError> {
+        let name = registry::normalize(name)?;
+        // Kept under its current name, whichever it was asked for by
+        let found = self
+            .registry
+            .send(FindRoom(name.clone()))
+            .await
+            .map_err(unavailable)?;
+        let (name, room) = match found {
+            Some(found) => found,
+            None => {
+                return Err(ProtocolError::new(
+                    "no_such_room",
+                    format!("No such room: {}", name),
+                ))
+            }
+        };
+        let spectator = self.scope == Some(Scope::Spectator);
+        let joiner = Joiner {
+            id: self.id,
+            echo: self.echo,
+            // Spectators never post, so they're let in where bots aren't
+            bot: self.bot.is_some() && !spectator,
+            moderator: self.scope == Some(Scope::Admin),
+            invite,
+            spectator,
+            placed,
+        };
+        let admission = room
+            .send(Join(joiner, self.addr.clone()))
+            .await
+            .map_err(room_gone)??;
+        // Kept with the rooms joined, so leaving works while it's pending;
+        // the room itself turns away anything else until they're let in
+        let (last_seq, motd) = match admission {
+            Admission::Joined { last_seq, motd } => (last_seq, motd),
+            Admission::Pending => {
+                self.reply(ServerEvent::JoinPending { room: &name }).await;
+                self.rooms.insert(name, room);
+                return Ok(());
+            }
+        };
+        self.reply(ServerEvent::Joined { room: &name }).await;
+        if let Some(motd) = motd {
+            self.reply(ServerEvent::System {
+                room: &name,
+                body: &motd,
+            })
+            .await;
+        }
+        if let Some(since_seq) = since_seq {
+            tokio::spawn(replay(
+                room.clone(),
+                self.id,
+                self.addr.downgrade(),
+                self.outbox.clone(),
+                since_seq,
+                last_seq,
+            ));
+        }
+        self.rooms.insert(name, room);
+        Ok(())
+    }
+
+    async fn leave(&mut self, name: &str) -> Result<(), ProtocolError> {
+        let room = self.room(name).await?;
+        // Gone, and it took everyone out as it went
+        let _ = room
+            .send(Leave {
+                id: self.id,
+                for_good: true,
+            })
+            .await;
+        if let (Some(joined), _) = self.joined(name).await? {
+            self.rooms.remove(&joined);
+        }
+        let name = registry::normalize(name)?;
+        self.reply(ServerEvent::Left { room: &name }).await;
+        Ok(())
+    }
+
+    // Whether our user may do something in a room
+    async fn authorize(
+        &self,
+        room: &Address<Room>,
+        permission: Permissions,
+    ) -> Result<(), ProtocolError> {
+        room.send(Authorize(self.id, permission))
+            .await
+            .map_err(room_gone)?
+    }
+
+    // A moderator deciding on someone waiting to join
+    async fn resolve(&self, name: &str, user: Uuid, approve: bool) -> Result<(), ProtocolError> {
+        let room = self.room(name).await?;
+        self.authorize(&room, Permissions::MANAGE_ROOM).await?;
+        let waiting = room
+            .send(ResolveJoin { id: user, approve })
+            .await
+            .map_err(room_gone)??;
+        if waiting {
+            Ok(())
+        } else {
+            Err(ProtocolError::new(
+                "no_such_request",
+                format!("{} isn't waiting to join {}", user, name),
+            ))
+        }
+    }
+
+    // The User only stops once we have, so this is for the socket closing
+    // under us
+    async fn reply(&self, event: ServerEvent<'_>) {
+        let _ = self.addr.send(ToUser(event.to_json())).await;
+    }
+
+    async fn dispatch(&mut self, event: ClientEvent) -> Result<(), ProtocolError> {
+        if let Some(error) = self.refusal(&event) {
+            return Err(error);
+        }
+
+        match event {
+            ClientEvent::Message { room, body } => {
+                let room = self.room(&room).await?;
+                // Room keys have no lasting id to count against
+                let bytes = body.len() as u64;
+                let counted = self.bot.is_none();
+                if counted {
+                    self.quotas
+                        .send(Spend {
+                            user: self.id,
+                            bytes,
+                        })
+                        .await
+                        .map_err(unavailable)??;
+                }
+                let posted = room
+                    .send(GotUserMessage(self.id, body, self.bot.clone()))
+                    .await
+                    .map_err(room_gone)
+                    .and_then(|posted| posted);
+                // Only what reaches the room counts, so nothing refused or
+                // dropped on the way uses up quota
+                if !matches!(posted, Ok(Delivery::Sent)) && counted {
+                    let refund = Refund {
+                        user: self.id,
+                        bytes,
+                    };
+                    if self.quotas.do_send(refund).is_err() {
+                        log::warn!("Could not refund {}'s quota", self.id);
+                    }
+                }
+                posted.map(|_| ())?
+            }
+            ClientEvent::History {
+                room,
+                before_seq,
+                limit,
+                cursor,
+            } => {
+                let before_seq = match cursor {
+                    Some(cursor) => Some(page::decode_seq(&cursor)?),
+                    None => before_seq,
+                };
+                self.room(&room)
+                    .await?
+                    .send(GetHistory {
+                        id: self.id,
+                        before_seq,
+                        limit,
+                    })
+                    .await
+                    .map_err(room_gone)?
+            }
+            ClientEvent::Report {
+                room,
+                message_id,
+                reason,
+            } => self
+                .room(&room)
+                .await?
+                .send(ReportMessage {
+                    id: self.id,
+                    message_id,
+                    reason,
+                })
+                .await
+                .map_err(room_gone)?,
+            ClientEvent::Members { room } => self
+                .room(&room)
+                .await?
+                .send(ListMembers { id: self.id })
+                .await
+                .map_err(room_gone)?,
+            ClientEvent::Typing { room } => self
+                .room(&room)
+                .await?
+                .send(Typing(self.id))
+                .await
+                .map_err(room_gone)?,
+            ClientEvent::Keywords { room, keywords } => self
+                .room(&room)
+                .await?
+                .send(SetKeywords(self.id, keywords))
+                .await
+                .map_err(room_gone)??,
+            ClientEvent::Read { room, seq } => self
+                .room(&room)
+                .await?
+                .send(MarkRead(self.id, seq))
+                .await
+                .map_err(room_gone)?,
+            ClientEvent::DraftSet { room, body } => self
+                .room(&room)
+                .await?
+                .send(SetDraft(self.id, body))
+                .await
+                .map_err(room_gone)??,
+            // The answer comes back as preferences_updated, like it does
+            // for every other session
+            ClientEvent::SettingsUpdate(settings) => self
+                .preferences
+                .send(SetPreferences(self.id, settings))
+                .await
+                .map_err(unavailable)?
+                .map(|_| ())?,
+            ClientEvent::Ping { nonce, rtt_ms } => {
+                if let Some(rtt_ms) = rtt_ms {
+                    self.latency.record(rtt_ms);
+                }
+                self.reply(ServerEvent::Pong {
+                    nonce: &nonce,
+                    server_time: now_millis(),
+                })
+                .await;
+            }
+            ClientEvent::Join {
+                room,
+                since_seq,
+                invite,
+            } => self.join(&room, since_seq, invite, false).await?,
+            ClientEvent::Leave { room } => self.leave(&room).await?,
+            ClientEvent::SetLanguage { lang } => self
+                .addr
+                .send(SetLanguage(lang))
+                .await
+                .map_err(unavailable)??,
+            ClientEvent::MemberInfo { room, user } => {
+                let room_addr = self.room(&room).await?;
+                self.authorize(&room_addr, Permissions::KICK).await?;
+                let members = room_addr.send(RoomMembers).await.map_err(room_gone)?;
+                let info = self
+                    .connections
+                    .send(GetMemberInfo(user))
+                    .await
+                    .map_err(unavailable)??;
+                let info = match info {
+                    Some(info) if members.contains(&user) => info,
+                    _ => {
+                        return Err(ProtocolError::new(
+                            "no_such_member",
+                            format!("{} isn't in {}", user, room),
+                        ))
+                    }
+                };
+                self.reply(ServerEvent::MemberInfo {
+                    room: &room,
+                    user,
+                    info: &info,
+                })
+                .await;
+            }
+            ClientEvent::Kick { room, user } => {
+                let room_addr = self.room(&room).await?;
+                self.authorize(&room_addr, Permissions::KICK).await?;
+                let kicked = room_addr.send(KickUser(user)).await.map_err(room_gone)?;
+                if !kicked {
+                    return Err(ProtocolError::new(
+                        "no_such_member",
+                        format!("{} isn't in {}", user, room),
+                    ));
+                }
+            }
+            ClientEvent::Ban {
+                room,
+                user,
+                reason,
+                duration_secs,
+            } => {
+                let room = self.room(&room).await?;
+                self.authorize(&room, Permissions::BAN).await?;
+                let now_ms = now_millis();
+                let expires_at = match duration_secs {
+                    Some(secs) => Some(Ban::lasting(secs, now_ms)?),
+                    None => None,
+                };
+                let ban = Ban {
+                    reason,
+                    expires_at,
+                    ..Ban::new(user, Some(self.id), now_ms)
+                };
+                room.send(BanUser(ban)).await.map_err(room_gone)?;
+            }
+            ClientEvent::ApproveJoin { room, user } => self.resolve(&room, user, true).await?,
+            ClientEvent::DenyJoin { room, user } => self.resolve(&room, user, false).await?,
+            ClientEvent::CreateRoom(settings) => {
+                let settings = self
+                    .registry
+                    .send(CreateRoom(settings))
+                    .await
+                    .map_err(unavailable)??;
+                self.reply(ServerEvent::RoomCreated(&settings)).await;
+            }
+            ClientEvent::CreateThread { room, topic } => {
+                let settings = self
+                    .registry
+                    .send(CreateThread { room, topic })
+                    .await
+                    .map_err(unavailable)??;
+                self.reply(ServerEvent::RoomCreated(&settings)).await;
+            }
+        }
+        Ok(())
+    }
+
+    // Tells the user how far they've read in every room they're in
+    async fn read_state(&self) {
+        let mut rooms = Vec::new();
+        // A room that's gone has nothing left to read
+        for room in self.rooms.values() {
+            if let Ok(unread) = room.send(GetUnread(self.id)).await {
+                rooms.extend(unread);
+            }
+        }
+        rooms.sort_by(|a, b| a.room.cmp(&b.room));
+        self.reply(ServerEvent::ReadState { rooms: &rooms }).await;
+    }
+
+    // Tells the user their preferences, as they were left by whichever
+    // session changed them last
+    async fn preferences(&self) {
+        let preferences = match self.preferences.send(GetPreferences(self.id)).await {
+            Ok(preferences) => preferences,
+            Err(_) => return,
+        };
+        self.reply(ServerEvent::PreferencesUpdated(&preferences))
+            .await;
+    }
+
+    async fn leave_all(&mut self) {
+        for (_, room) in self.rooms.drain() {
+            let _ = room
+                .send(Leave {
+                    id: self.id,
+                    for_good: false,
+                })
+                .await;
+        }
+    }
+}
+
+// Heartbeat - time to ping the socket again. Answers false once it has
+// left too many pings in a row unanswered, and should be closed.
+struct Heartbeat;
+impl Message for Heartbeat {
+    type Result = bool;
+}
+#[async_trait::async_trait]
+impl Handler<Heartbeat> for Connection {
+    async fn handle(&mut self, _msg: Heartbeat, _ctx: &mut Context<Self>) -> bool {
+        let _timer = metrics::timer("heartbeat");
+        if self.unanswered >= self.max_unanswered {
+            return false;
+        }
+        self.unanswered += 1;
+        // The writer is gone, and the socket with it
+        let _ = self.pinger.send(());
+        true
+    }
+}
+
+// Ponged - the socket answered a ping
+struct Ponged;
+impl Message for Ponged {
+    type Result = ();
+}
+#[async_trait::async_trait]
+impl Handler<Ponged> for Connection {
+    async fn handle(&mut self, _msg: Ponged, _ctx: &mut Context<Self>) {
+        let _timer = metrics::timer("ponged");
+        self.unanswered = 0;
+    }
+}
+
+// Incoming - a text frame from the socket
+struct Incoming(String);
+impl Message for Incoming {
+    type Result = ();
+}
+#[async_trait::async_trait]
+impl Handler<Incoming> for Connection {
+    async fn handle(&mut self, msg: Incoming, _ctx: &mut Context<Self>) {
+        let _timer = metrics::timer("incoming");
+        if let Some((limiter, caller)) = &self.limiter {
+            let usage = match limiter.send(Take(caller.clone())).await {
+                Ok(usage) => usage,
+                Err(e) => {
+                    send_error(&self.addr, unavailable(e)).await;
+                    return;
+                }
+            };
+            if !usage.allowed() {
+                send_error(&self.addr, usage.error()).await;
+                return;
+            }
+        }
+
+        let parsed = guard::check(&msg.0).and_then(|()| {
+            serde_json::from_str(&msg.0)
+                .map_err(|e| ProtocolError::new("bad_event", format!("Bad event: {}", e)))
+        });
+        let handled = match parsed {
+            Ok(event) => self.dispatch(event).await,
+            Err(error) => Err(error),
+        };
+        if let Err(error) = handled {
+            send_error(&self.addr, error).await;
+        }
+    }
+}
+
+// Warn - tells the user maintenance will close them in `remaining`
+struct Warn(Duration);
+impl Message for Warn {
+    type Result = ();
+}
+#[async_trait::async_trait]
+impl Handler<Warn> for Connection {
+    async fn handle(&mut self, msg: Warn, _ctx: &mut Context<Self>) {
+        let _timer = metrics::timer("warn");
+        let event = ServerEvent::Maintenance {
+            closing_in_secs: msg.0.as_secs_f64().ceil() as u64,
+        };
+        self.reply(event).await;
+    }
+}
+
+// Tell - an event for the user from outside their rooms
+pub(crate) struct Tell(pub Bytes);
+impl Message for Tell {
+    type Result = ();
+}
+#[async_trait::async_trait]
+impl Handler<Tell> for Connection {
+    async fn handle(&mut self, msg: Tell, _ctx: &mut Context<Self>) {
+        let _timer = metrics::timer("tell");
+        let _ = self.addr.send(ToUser(msg.0)).await;
+    }
+}
+
+// Move - an admin putting the user in a room, past its join policy, and
+// taking them out of `leave` once they're in. Room keys stay where they
+// are.
+struct Move {
+    leave: Option<String>,
+    join: String,
+}
+impl Message for Move {
+    type Result = Result<(), ProtocolError>;
+}
+#[async_trait::async_trait]
+impl Handler<Move> for Connection {
+    async fn handle(&mut self, msg: Move, _ctx: &mut Context<Self>) -> Result<(), ProtocolError> {
+        let _timer = metrics::timer("move");
+        if self.scope.is_some() {
+            return Err(ProtocolError::new(
+                "forbidden",
+                "Room keys can't change rooms".to_string(),
+            ));
+        }
+        // Placed past the join policy, though bans and the room's limits
+        // still hold
+        if let (None, _) = self.joined(&msg.join).await? {
+            self.join(&msg.join, None, None, true).await?;
+        }
+        match msg.leave {
+            Some(leave) => self.leave(&leave).await,
+            None => Ok(()),
+        }
+    }
+}
+
+// Disconnect - the socket is done with; leaves every room and stops
+struct Disconnect;
+impl Message for Disconnect {
+    type Result = ();
+}
+#[async_trait::async_trait]
+impl Handler<Disconnect> for Connection {
+    async fn handle(&mut self, _msg: Disconnect, ctx: &mut Context<Self>) {
+        let _timer = metrics::timer("disconnect");
+        self.leave_all().await;
+        ctx.stop();
+    }
+}
+
+// Opening - what a connection asks for in its upgrade's query, so one
+// that's reconnecting is back where it was without a round trip per room
+struct Opening {
+    // `?echo=true` or `?echo=false`, falling back to CHAT_ECHO
+    echo: bool,
+    // `?rooms=lobby,dev`, the rooms to start out in instead of the lobby
+    rooms: Option<Vec<String>>,
+    // `?since=42`, replaying what each of them had after that seq
+    since: Option<u64>,
+}
+
+fn opening(default: bool) -> impl Filter<Extract = (Opening,), Error = warp::Rejection> + Clone {
+    warp::query::<HashMap<String, String>>().map(move |query: HashMap<String, String>| Opening {
+        echo: query
+            .get("echo")
+            .map(|echo| echo == "true" || echo == "1")
+            .unwrap_or(default),
+        rooms: query.get("rooms").map(|rooms| {
+            rooms
+                .split(',')
+                .map(str::trim)
+                .filter(|room| !room.is_empty())
+                .map(str::to_string)
+                .collect()
+        }),
+        since: query.get("since").and_then(|since| since.parse().ok()),
+    })
+}
+
+// Shared - what the server hands every connection
+#[derive(Clone)]
+struct Shared {
+    services: Services,
+    limits: limits::Socket,
+    translations: Option<Address<TranslationCache>>,
+    lag_threshold: Option<usize>,
+    handshake_timeout: Option<Duration>,
+    heartbeat: Option<Duration>,
+    heartbeat_misses: u32,
+    tracing: Option<Tracing>,
+    #[cfg(feature = "chaos")]
+    chaos: Option<chaos::Chaos>,
+}
+
+async fn user_connected(
+    ws: WebSocket,
+    shared: Shared,
+    key: Option<ApiKey>,
+    peer: Peer,
+    opening: Opening,
+    mut state: maintenance::State,
+    remembered: Option<Uuid>,
+) {
+    let Shared {
+        services,
+        limits,
+        translations,
+        lag_threshold,
+        handshake_timeout,
+        heartbeat,
+        heartbeat_misses,
+        tracing,
+        #[cfg(feature = "chaos")]
+        chaos,
+    } = shared;
+    let handshake = handshake_timeout.map(|timeout| tokio::time::Instant::now() + timeout);
+    let connections = services.get::<Connections>();
+    let (mut user_ws_tx, mut user_ws_rx) = ws.split();
+    let (tx, rx) = mpsc::unbounded_channel();
+    let mut rx = UnboundedReceiverStream::new(rx);
+    let (pinger, mut pings) = mpsc::unbounded_channel();
+
+    let outbox = Arc::new(Outbox::new(lag_threshold));
+    let trace = tracing.map(|tracing| Arc::new(Trace::new(tracing)));
+    // Browsers keep their id from the cookie; room keys get a new one each
+    // time
+    let id = match (&key, remembered) {
+        (None, Some(id)) => id,
+        _ => ids::next(),
+    };
+    match (peer.addr, &peer.flagged) {
+        (Some(addr), Some(flagged)) => {
+            log::debug!("{} connected from {}, flagged: {}", id, addr, flagged)
+        }
+        (Some(addr), None) => log::debug!("{} connected from {}", id, addr),
+        (None, _) => log::debug!("{} connected", id),
+    }
+    let addr = User::new(id, tx, outbox.clone(), translations)
+        .create(None)
+        .spawn(&mut Tokio::Global);
+
+    // Pipe mesesages back up to the user, until the User stops or the
+    // socket goes away, keeping under the egress cap if there is one
+    let written = outbox.clone();
+    let traced = trace.clone();
+    let mut egress = limits
+        .egress
+        .map(|limit| Egress::new(limit, Instant::now()));
+    let mut writer = tokio::task::spawn(async move {
+        let mut waiting = Lanes::default();
+        // A frame being sent out of order, after the next
+        #[cfg(feature = "chaos")]
+        let mut held = None;
+        loop {
+            // Wait for a frame only when there's nothing left to write, and
+            // take in whatever else has come, so the one that matters most
+            // goes next
+            let mut ping = false;
+            if waiting.is_empty() {
+                tokio::select! {
+                    frame = rx.next() => match frame {
+                        Some(frame) => (0..waiting.push(frame)).for_each(|_| written.drop_frame()),
+                        None => break,
+                    },
+                    Some(()) = pings.recv() => ping = true,
+                }
+            }
+            // Pings go ahead of everything, so a backlog can't make a socket
+            // that's still there look dead
+            if ping || matches!(pings.recv().now_or_never(), Some(Some(()))) {
+                if let Err(e) = user_ws_tx.send(warp::ws::Message::ping(Vec::new())).await {
+                    log::warn!("websocket send error: {}", e);
+                    break;
+                }
+                continue;
+            }
+            while let Some(Some(frame)) = rx.next().now_or_never() {
+                (0..waiting.push(frame)).for_each(|_| written.drop_frame());
+            }
+            let (lane, value) = match waiting.pop() {
+                Some(next) => next,
+                None => break,
+            };
+
+            if let Some(egress) = egress.as_mut() {
+                let droppable = lane == Lane::Presence || lanes::is_live(&value);
+                match egress.admit(value.len(), droppable, Instant::now()) {
+                    Admit::Send => {}
+                    Admit::Wait(wait) => tokio::time::sleep(wait).await,
+                    Admit::Drop => {
+                        written.drop_frame();
+                        continue;
+                    }
+                }
+            }
+            // warp wants its own String, so this is the one copy each
+            // recipient costs
+            let text = String::from_utf8(value.to_vec()).expect("Frames are JSON");
+            if let Some(trace) = &traced {
+                trace.record(Direction::Out, &text);
+            }
+            let message = warp::ws::Message::text(text);
+            #[cfg(feature = "chaos")]
+            match chaos.as_ref().and_then(chaos::Chaos::roll) {
+                Some(chaos::Fault::Disconnect) => {
+                    log::debug!("Chaos: disconnecting {}", id);
+                    break;
+                }
+                Some(chaos::Fault::Drop) => {
+                    written.drop_frame();
+                    continue;
+                }
+                Some(chaos::Fault::Duplicate) => {
+                    let _ = user_ws_tx.send(message.clone()).await;
+                }
+                Some(chaos::Fault::Reorder) if held.is_none() => {
+                    held = Some(message);
+                    written.written();
+                    continue;
+                }
+                Some(chaos::Fault::Delay(delay)) => tokio::time::sleep(delay).await,
+                _ => {}
+            }
+            if let Err(e) = user_ws_tx.send(message).await {
+                log::warn!("websocket send error: {}", e);
+                break;
+            }
+            written.written();
+            #[cfg(feature = "chaos")]
+            if let Some(held) = held.take() {
+                let _ = user_ws_tx.send(held).await;
+            }
+        }
+        written.close();
+        let (lags, dropped) = (written.lags(), written.dropped());
+        if lags > 0 || dropped > 0 {
+            log::info!(
+                "{} fell behind {} times and had {} frames dropped",
+                id,
+                lags,
+                dropped
+            );
+        }
+        let _ = user_ws_tx.close().await;
+    });
+
+    // Nobody new gets in during maintenance; they're told why and dropped
+    let draining = state.borrow().is_some();
+    if draining {
+        let event = ServerEvent::Maintenance { closing_in_secs: 0 };
+        addr.send(ToUser(event.to_json()))
+            .await
+            .expect("Could not send maintenance notice");
+        drop(addr);
+        finish(writer).await;
+        return;
+    }
+    // Deactivated ids stay out until they're reactivated
+    let deactivation = match (&key, remembered) {
+        (None, Some(id)) => services
+            .get::<Accounts>()
+            .send(GetDeactivation(id))
+            .await
+            .expect("Could not reach the accounts"),
+        _ => None,
+    };
+    if let Some(deactivation) = deactivation {
+        let error = ProtocolError::new(
+            "deactivated",
+            format!(
+                "This user is deactivated; POST /users/me/reactivate before {} to come back",
+                deactivation.purge_at
+            ),
+        );
+        send_error(&addr, error).await;
+        drop(addr);
+        finish(writer).await;
+        return;
+    }
+    // An older socket with the same id is closed, and out of its rooms
+    // before this one joins any, so its leaving can't take this one out
+    let session = Arc::new(Session::default());
+    let latency = Arc::new(Latency::default());
+    let replaced = connections
+        .send(Connected {
+            id,
+            peer: peer.addr.map(|addr| addr.to_string()),
+            user_agent: peer.user_agent.clone(),
+            flagged: peer.flagged.clone(),
+            at: now_millis(),
+            outbox: outbox.clone(),
+            session: session.clone(),
+            trace: trace.clone(),
+            latency: latency.clone(),
+        })
+        .await
+        .expect("Could not reach the connections");
+    if let Some(replaced) = replaced {
+        replaced.replace();
+        let _ = tokio::time::timeout(WRITER_GRACE, replaced.gone()).await;
+    }
+
+    let limiter = limits.events.map(|limiter| {
+        let key = key.as_ref().map(|key| key.id.to_string());
+        (limiter, limits::caller(key, &peer))
+    });
+    // A key is checked at the upgrade, and listeners and read-only keys
+    // aren't meant to say anything, so only sockets without one have to
+    // send an event in time
+    let mut handshake = handshake.filter(|_| key.is_none());
+    let mut connection = Connection {
+        id,
+        addr: addr.clone(),
+        scope: key.as_ref().map(|key| key.scope),
+        bot: key.as_ref().map(|key| Bot {
+            flair: key.flair.clone(),
+        }),
+        key_room: key.map(|key| key.room),
+        rooms: HashMap::new(),
+        registry: services.get(),
+        connections: connections.clone(),
+        outbox,
+        echo: opening.echo,
+        limiter,
+        quotas: services.get(),
+        preferences: services.get(),
+        latency,
+        pinger,
+        unanswered: 0,
+        max_unanswered: heartbeat_misses,
+    };
+
+    // Everyone starts out in the lobby, or the rooms they asked for, or
+    // their key's room. Post-only keys never hear from the room, so they
+    // don't join it.
+    if connection.scope != Some(Scope::PostOnly) {
+        let rooms = match (&connection.key_room, opening.rooms) {
+            (Some(room), _) => vec![room.clone()],
+            (None, Some(rooms)) => rooms,
+            (None, None) => vec![default_room()],
+        };
+        for room in rooms {
+            if let Err(error) = connection.join(&room, opening.since, None, false).await {
+                send_error(&addr, error).await;
+            }
+        }
+    }
+    if connection.scope.is_none() {
+        connection.read_state().await;
+        connection.preferences().await;
+    }
+    let connection = connection.create(None).spawn(&mut Tokio::Global);
+    connections
+        .do_send(Attach {
+            id,
+            session: session.clone(),
+            connection: connection.downgrade(),
+        })
+        .expect("Could not reach the connections");
+
+    // Receive messages, until the connection drops, maintenance closes it, a
+    // newer socket takes over or the user is deactivated
+    let mut closing = None;
+    let mut writing = true;
+    let every = heartbeat.unwrap_or_default();
+    let mut beat = tokio::time::Instant::now() + every;
+    loop {
+        let msg = tokio::select! {
+            result = user_ws_rx.next() => match result {
+                Some(Ok(msg)) => msg,
+                Some(Err(e)) if guard::over_limit(&e) => {
+                    let error = ProtocolError::new(
+                        "event_too_large",
+                        format!("Events are up to {} bytes", guard::MAX_EVENT_LEN),
+                    );
+                    send_error(&addr, error).await;
+                    break;
+                }
+                _ => break,
+            },
+            // Pongs and pings don't count, since those come from the
+            // browser or library whether or not the client is there
+            _ = tokio::time::sleep_until(handshake.unwrap_or_else(tokio::time::Instant::now)),
+                if handshake.is_some() => {
+                let error = ProtocolError::new(
+                    "handshake_timeout",
+                    "Nothing was sent in time after connecting".to_string(),
+                );
+                send_error(&addr, error).await;
+                break;
+            },
+            _ = tokio::time::sleep_until(beat), if heartbeat.is_some() => {
+                beat += every;
+                // A Connection that has stopped has nothing left to say
+                let alive = connection.send(Heartbeat).await;
+                if alive.is_err() {
+                    break;
+                }
+                if alive == Ok(false) {
+                    let error = ProtocolError::new(
+                        "heartbeat_timeout",
+                        "Too many pings went unanswered".to_string(),
+                    );
+                    send_error(&addr, error).await;
+                    break;
+                }
+                continue;
+            },
+            changed = state.changed() => {
+                if changed.is_err() {
+                    break;
+                }
+                let drain = *state.borrow();
+                closing = drain.map(|drain| drain.until);
+                if let Some(drain) = drain {
+                    if connection.send(Warn(drain.remaining())).await.is_err() {
+                        break;
+                    }
+                }
+                continue;
+            },
+            _ = tokio::time::sleep_until(closing.unwrap_or_else(tokio::time::Instant::now)),
+                if closing.is_some() => break,
+            _ = session.replaced() => {
+                let error = ProtocolError::new(
+                    "replaced",
+                    "You connected again from somewhere else".to_string(),
+                );
+                send_error(&addr, error).await;
+                break;
+            },
+            _ = session.ended() => {
+                let error = ProtocolError::new(
+                    "deactivated",
+                    "This user has been deactivated".to_string(),
+                );
+                send_error(&addr, error).await;
+                break;
+            },
+            // Nothing can reach the user any more
+            _ = &mut writer => {
+                writing = false;
+                break;
+            }
+        };
+
+        if msg.is_pong() {
+            if connection.send(Ponged).await.is_err() {
+                break;
+            }
+            continue;
+        }
+        // Send in to actor, waiting for it so frames are taken in order and
+        // a busy connection stops reading
+        if let Ok(s) = msg.to_str() {
+            handshake = None;
+            if let Some(trace) = &trace {
+                trace.record(Direction::In, s);
+            }
+            if connection.send(Incoming(s.to_string())).await.is_err() {
+                break;
+            }
+        };
+    }
+
+    // Already stopped if it went first
+    let _ = connection.send(Disconnect).await;
+    // Out of every room, and the Connection has stopped, so this is the last
+    // address to the User. It stops once it's gone, and the writer follows
+    // when it has written what's left.
+    drop(connection);
+    drop(addr);
+    session.done();
+    if connections.do_send(Disconnected { id, session }).is_err() {
+        log::warn!("Could not reach the connections");
+    }
+    if writing {
+        finish(writer).await;
+    }
+}
+
+// Gives the writer WRITER_GRACE to finish before giving up on it
+async fn finish(mut writer: tokio::task::JoinHandle<()>) {
+    if tokio::time::timeout(WRITER_GRACE, &mut writer)
+        .await
+        .is_err()
+    {
+        writer.abort();
+    }
+}
+
+// Sends a joiner what they missed, a chunk at a time and only as fast as
+// their connection writes it, so live messages don't queue up behind it
+async fn replay(
+    room: Address<Room>,
+    id: Uuid,
+    user: xtra::WeakAddress<User>,
+    outbox: Arc<Outbox>,
+    mut after_seq: u64,
+    until_seq: u64,
+) {
+    loop {
+        outbox.wait().await;
+        let chunk = room
+            .send(ReplayChunk {
+                id,
+                after_seq,
+                until_seq,
+            })
+            .await;
+        let (frame, more) = match chunk {
+            Ok(Some(chunk)) => chunk,
+            _ => return,
+        };
+        if user.send(ToUser(frame)).await.is_err() {
+            return;
+        }
+        match more {
+            Some(seq) => after_seq = seq,
+            None => return,
+        }
+    }
+}
+
+async fn send_error(addr: &Address<User>, error: ProtocolError) {
+    let event = ServerEvent::Error(&error);
+    // Gone, and there's nobody to tell
+    let _ = addr.send(ToUser(event.to_json())).await;
+}
+
+// Features - server settings the page behaves differently under
+#[derive(Clone, Copy, Serialize)]
+struct Features {
+    // Users get their own messages back unless they say otherwise
+    echo: bool,
+    // Links can get you kicked, banned or muted
+    link_spam: bool,
+    // Connecting without a key takes solving a challenge from GET /pow
+    pow: bool,
+}
+
+// PageConfig - what the index page is told when it's served
+#[derive(Serialize)]
+struct PageConfig {
+    // Behind a TLS terminating proxy the page has to be told to use wss
+    ws_url: Option<String>,
+    protocol: u32,
+    features: Features,
+    default_room: &'static str,
+    // The public rooms as of now
+    rooms: Vec<String>,
+}
+
+// GET / - also gives the browser its id to keep, or another year of the
+// one it has
+async fn index(
+    peer: Peer,
+    registry: Address<RoomRegistry>,
+    features: Features,
+    remembered: Option<Uuid>,
+    signer: Arc<Signer>,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let rooms = registry
+        .send(ListRooms)
+        .await
+        .expect("Could not list rooms");
+    let config = PageConfig {
+        ws_url: peer.ws_url(),
+        protocol: PROTOCOL_VERSION,
+        features,
+        default_room: DEFAULT_ROOM,
+        rooms: rooms.into_iter().map(|settings| settings.name).collect(),
+    };
+    // Goes inside a <script>, so nothing in it may close the tag
+    let config = serde_json::to_string(&config)
+        .expect("Could not serialize page config")
+        .replace("</", "<\\/");
+    let cookie = signer.cookie(remembered.unwrap_or_else(ids::next), peer.secure);
+    Ok(warp::reply::with_header(
+        warp::reply::html(INDEX_HTML.replace("{{config}}", &config)),
+        "set-cookie",
+        cookie,
+    ))
+}
+
+static INDEX_HTML: &str = r#"<!DOCTYPE html>
+<html lang="en">
+    <head>
+        <title>Warp Chat</title>
+    </head>
+    <body>
+        <h1>Warp chat</h1>
+        <select id="rooms"></select>
+        <div id="chat">
+            <p><em>Connecting...</em></p>
+        </div>
+        <input type="text" id="text" />
+        <button type="button" id="send">Send</button>
+        <script type="text/javascript">
+        const config = {{config}};
+        const chat = document.getElementById('chat');
+        const text = document.getElementById('text');
+        const rooms = document.getElementById('rooms');
+        const uri = config.ws_url || (location.protocol === 'https:' ? 'wss://' : 'ws://') + location.host + '/ws';
+        let ws = null;
+        let room = config.default_room;
+        if (config.features.link_spam) {
+            text.placeholder = 'Links may be held for moderation';
+        }
+        config.rooms.forEach(function(name) {
+            const option = document.createElement('option');
+            option.value = option.innerText = name;
+            option.selected = name === room;
+            rooms.appendChild(option);
+        });
+        let oldestSeq = null;
+        let hasMore = true;
+        let loading = false;
+        // Waiting for a moderator to let us into the room
+        let pending = false;
+        function line(data, seq) {
+            const line = document.createElement('p');
+            line.innerText = data;
+            if (seq !== undefined) {
+                line.dataset.seq = seq;
+            }
+            return line;
+        }
+        function message(data, seq) {
+            chat.appendChild(line(data, seq));
+        }
+        // Bots' messages get their flair, or just [bot]
+        function label(m) {
+            return (m.is_bot ? '[' + (m.flair || 'bot') + '] ' : '') + m.body;
+        }
+        function remove(seq) {
+            const line = chat.querySelector('[data-seq="' + seq + '"]');
+            if (line) {
+                line.remove();
+            }
+        }
+        function loadOlder() {
+            if (loading || !hasMore) {
+                return;
+            }
+            loading = true;
+            ws.send(JSON.stringify({type: 'history', room: room, before_seq: oldestSeq, limit: 50}));
+        }
+        function opened() {
+            chat.innerHTML = '<p><em>Connected!</em></p>';
+            loadOlder();
+        }
+        rooms.onchange = function() {
+            ws.send(JSON.stringify({type: 'leave', room: room}));
+            room = rooms.value;
+            ws.send(JSON.stringify({type: 'join', room: room}));
+            oldestSeq = null;
+            hasMore = true;
+            loading = false;
+            pending = false;
+            opened();
+        };
+        function handle(event) {
+            // Stragglers from a room we just left
+            if (event.room !== undefined && event.room !== room) {
+                return;
+            }
+            switch (event.type) {
+            case 'message':
+                if (oldestSeq === null) {
+                    oldestSeq = event.seq;
+                }
+                message(label(event), event.seq);
+                break;
+            case 'history':
+                const status = chat.firstChild;
+                event.messages.slice().reverse().forEach(function(m) {
+                    chat.insertBefore(line(label(m), m.seq), status.nextSibling);
+                });
+                if (event.messages.length > 0) {
+                    oldestSeq = event.messages[0].seq;
+                }
+                hasMore = event.has_more;
+                loading = false;
+                break;
+            case 'message_hidden':
+            case 'message_deleted':
+                remove(event.seq);
+                break;
+            case 'bulk_delete':
+                event.seqs.forEach(remove);
+                break;
+            case 'join_pending':
+                pending = true;
+                message('<Server>: waiting for a moderator to let you in');
+                break;
+            case 'joined':
+                // Let in at last, so there's history to load now
+                if (pending) {
+                    pending = false;
+                    loading = false;
+                    opened();
+                }
+                break;
+            case 'you_are_lagging':
+                // Messages may have been dropped; start again from history
+                oldestSeq = null;
+                hasMore = true;
+                loading = false;
+                opened();
+                message('<Server>: you fell behind, so history was reloaded');
+                break;
+            case 'room_renamed':
+                // Events from now on carry the new name
+                room = event.name;
+                Array.from(rooms.options).forEach(function(option) {
+                    if (option.value === event.room) {
+                        option.value = option.innerText = event.name;
+                    }
+                });
+                message('<Server>: ' + event.room + ' is called ' + event.name + ' now');
+                break;
+            case 'config_changed':
+                message('<Server>: ' + event.room + ' changed its settings' +
+                    (event.slow_mode_secs ? ', with ' + event.slow_mode_secs + 's between messages' : ''));
+                break;
+            case 'system':
+                message('<Server>: ' + event.body);
+                break;
+            case 'mention':
+                message('<Server>: ' + event.from + ' mentioned everyone in ' + event.room);
+                break;
+            case 'error':
+                message('<Error>: ' + event.message);
+                break;
+            case 'maintenance':
+                message('<Server>: going down for maintenance' +
+                    (event.closing_in_secs > 0 ? ' in ' + event.closing_in_secs + 's' : ''));
+                break;
+            case 'batch':
+                event.events.forEach(handle);
+                break;
+            }
+        }
+        function received(msg) {
+            handle(JSON.parse(msg.data));
+        }
+        function closed() {
+            chat.getElementsByTagName('em')[0].innerText = 'Disconnected!';
+        }
+        function connect(query) {
+            ws = new WebSocket(uri + query);
+            ws.onopen = opened;
+            ws.onmessage = received;
+            ws.onclose = closed;
+        }
+        // Finds a nonce giving the challenge's hash enough leading zero bits
+        async function solve() {
+            const pow = await fetch('/pow').then(function(res) {
+                return res.json();
+            });
+            const encoder = new TextEncoder();
+            for (let nonce = 0; ; nonce++) {
+                const input = encoder.encode(pow.challenge + ':' + nonce);
+                const hash = new Uint8Array(await crypto.subtle.digest('SHA-256', input));
+                let zeros = 0;
+                for (const byte of hash) {
+                    zeros += byte === 0 ? 8 : Math.clz32(byte) - 24;
+                    if (byte !== 0) {
+                        break;
+                    }
+                }
+                if (zeros >= pow.bits) {
+                    return '?pow=' + encodeURIComponent(pow.challenge) + '&nonce=' + nonce;
+                }
+            }
+        }
+        if (config.features.pow) {
+            solve().then(connect);
+        } else {
+            connect('');
+        }
+        window.onscroll = function() {
+            if (window.scrollY === 0) {
+                loadOlder();
+            }
+        };
+        send.onclick = function() {
+            const msg = text.value;
+            ws.send(JSON.stringify({type: 'message', room: room, body: msg}));
+            text.value = '';
+            // With echo on it comes back from the room instead
+            if (!config.features.echo) {
+                message('<You>: ' + msg);
+            }
+        };
+        </script>
+    </body>
+</html>
+"#;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::connections::Disclosure;
+    use crate::quota::Quota;
+
+    // A connection to nobody, and what its writer would be asked to ping
+    fn connection(misses: u32) -> (Address<Connection>, mpsc::UnboundedReceiver<()>) {
+        let (tx, _rx) = mpsc::unbounded_channel();
+        let (pinger, pings) = mpsc::unbounded_channel();
+        let outbox = Arc::new(Outbox::new(None));
+        let registry = RoomRegistry::new(
+            ModerationQueue::new(3)
+                .create(None)
+                .spawn(&mut Tokio::Global),
+            None,
+            Stats::default().create(None).spawn(&mut Tokio::Global),
+            None,
+            Vec::new(),
+            None,
+        );
+        let connection = Connection {
+            id: Uuid::from_u128(1),
+            addr: User::new(Uuid::from_u128(1), tx, outbox.clone(), None)
+                .create(None)
+                .spawn(&mut Tokio::Global),
+            scope: None,
+            key_room: None,
+            rooms: HashMap::new(),
+            registry: registry.create(None).spawn(&mut Tokio::Global),
+            connections: Connections::new(Disclosure::None)
+                .create(None)
+                .spawn(&mut Tokio::Global),
+            outbox,
+            echo: false,
+            bot: None,
+            limiter: None,
+            quotas: Quotas::new(Quota::default())
+                .create(None)
+                .spawn(&mut Tokio::Global),
+            preferences: Preferences::new(Services::default())
+                .create(None)
+                .spawn(&mut Tokio::Global),
+            latency: Arc::new(Latency::default()),
+            pinger,
+            unanswered: 0,
+            max_unanswered: misses,
+        };
+        (connection.create(None).spawn(&mut Tokio::Global), pings)
+    }
+
+    #[tokio::test]
+    async fn heartbeats_close_sockets_that_stop_answering() {
+        metrics::init(Duration::from_secs(1));
+        let (connection, mut pings) = connection(2);
+        let beat = || async { connection.send(Heartbeat).await.unwrap() };
+
+        // Each beat pings, until two in a row have gone unanswered
+        assert!(beat().await);
+        assert!(beat().await);
+        assert!(!beat().await);
+        assert_eq!(pings.recv().await, Some(()));
+        assert_eq!(pings.recv().await, Some(()));
+        assert!(pings.recv().now_or_never().is_none());
+
+        // A pong clears the misses, however many there were
+        connection.send(Ponged).await.unwrap();
+        assert!(beat().await);
+        connection.send(Ponged).await.unwrap();
+        assert!(beat().await);
+        assert!(beat().await);
+        assert!(!beat().await);
+        assert_eq!(pings.recv().await, Some(()));
+    }
+}
@@ -0,0 +1,276 @@
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::time::{Duration, Instant};
+use warp::http::{HeaderValue, StatusCode};
+use warp::reject::Reject;
+use warp::reply::Response;
+use warp::{Filter, Rejection, Reply};
+use xtra::prelude::*;
+
+use crate::admin;
+use crate::keys::{Authenticate, KeyStore};
+use crate::proxy::Peer;
+use crate::{metrics, ProtocolError};
+
+// Callers are forgotten once their bucket has filled back up; this often
+// we go looking for them
+const SWEEP_EVERY: u64 = 1024;
+
+// Budget - `limit` requests per `window`, refilling steadily
+#[derive(Clone, Copy, Debug)]
+pub struct Budget {
+    pub limit: u32,
+    pub window: Duration,
+}
+
+// Usage - where a caller stands after asking for one more
+#[derive(Clone, Copy, Debug)]
+pub struct Usage {
+    pub limit: u32,
+    pub remaining: u32,
+    // Until the bucket is full again
+    pub reset: Duration,
+    // Set when this one was refused: until there's room for it
+    pub retry_after: Option<Duration>,
+}
+impl Usage {
+    pub fn allowed(&self) -> bool {
+        self.retry_after.is_none()
+    }
+
+    // Why a refused request was refused
+    pub fn error(&self) -> ProtocolError {
+        ProtocolError::new("rate_limited", "Slow down, too many requests".to_string())
+            .retryable(self.retry_after)
+    }
+}
+
+// Bucket - one caller's tokens, as of `updated`
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+// Limiter - a token bucket per caller, all on the same budget. The REST API
+// and websockets each have one.
+pub struct Limiter {
+    budget: Budget,
+    buckets: HashMap<String, Bucket>,
+    taken: u64,
+}
+impl Actor for Limiter {}
+impl Limiter {
+    pub fn new(budget: Budget) -> Self {
+        Self {
+            budget,
+            buckets: HashMap::new(),
+            taken: 0,
+        }
+    }
+
+    // Tokens come back at this many per second
+    fn rate(&self) -> f64 {
+        f64::from(self.budget.limit) / self.budget.window.as_secs_f64()
+    }
+
+    // Takes a token from `caller`'s bucket at `now`, if there's one to take
+    pub fn take(&mut self, caller: &str, now: Instant) -> Usage {
+        let (limit, rate) = (f64::from(self.budget.limit), self.rate());
+        let bucket = self.buckets.entry(caller.to_string()).or_insert(Bucket {
+            tokens: limit,
+            updated: now,
+        });
+        let elapsed = now.saturating_duration_since(bucket.updated).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * rate).min(limit);
+        bucket.updated = now;
+
+        let retry_after = if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            None
+        } else {
+            Some(Duration::from_secs_f64((1.0 - bucket.tokens) / rate))
+        };
+        let usage = Usage {
+            limit: self.budget.limit,
+            remaining: bucket.tokens as u32,
+            reset: Duration::from_secs_f64((limit - bucket.tokens) / rate),
+            retry_after,
+        };
+
+        self.taken += 1;
+        if self.taken.is_multiple_of(SWEEP_EVERY) {
+            self.buckets.retain(|_, bucket| {
+                let elapsed = now.saturating_duration_since(bucket.updated).as_secs_f64();
+                bucket.tokens + elapsed * rate < limit
+            });
+        }
+        usage
+    }
+}
+
+// Take - spends one of a caller's requests
+pub struct Take(pub String);
+impl Message for Take {
+    type Result = Usage;
+}
+#[async_trait::async_trait]
+impl Handler<Take> for Limiter {
+    async fn handle(&mut self, msg: Take, _ctx: &mut Context<Self>) -> Usage {
+        let _timer = metrics::timer("take");
+        self.take(&msg.0, Instant::now())
+    }
+}
+
+// Who a budget belongs to: the key a request carries, or else the address
+// it came from
+pub fn caller(key: Option<String>, peer: &Peer) -> String {
+    match (key, peer.addr) {
+        (Some(key), _) => format!("key:{}", key),
+        (None, Some(addr)) => format!("ip:{}", addr),
+        (None, None) => "local".to_string(),
+    }
+}
+
+// Socket - the limits a websocket is under: events it may send us, and
+// bytes per second we may send it
+#[derive(Clone)]
+pub struct Socket {
+    pub events: Option<Address<Limiter>>,
+    pub egress: Option<u32>,
+}
+
+// Admit - what to do with an outgoing frame
+#[derive(Debug, PartialEq)]
+pub enum Admit {
+    Send,
+    // Send it after this long
+    Wait(Duration),
+    Drop,
+}
+
+// Egress - one connection's outgoing bytes, as a token bucket holding a
+// second's worth. Over the cap, presence and live messages are dropped,
+// since there'll be a newer snapshot and a client can see the gap in `seq`
+// and fill it from history; everything else waits its turn.
+pub struct Egress {
+    rate: f64,
+    tokens: f64,
+    updated: Instant,
+}
+impl Egress {
+    pub fn new(bytes_per_sec: u32, now: Instant) -> Self {
+        Self {
+            rate: f64::from(bytes_per_sec),
+            tokens: f64::from(bytes_per_sec),
+            updated: now,
+        }
+    }
+
+    // Spends `len` bytes at `now`, or says how long to hold off. A frame
+    // bigger than the whole bucket goes when it's full.
+    pub fn admit(&mut self, len: usize, droppable: bool, now: Instant) -> Admit {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.rate);
+        self.updated = now;
+
+        let len = len as f64;
+        let needed = len.min(self.rate);
+        if self.tokens >= needed {
+            self.tokens -= len;
+            Admit::Send
+        } else if droppable {
+            Admit::Drop
+        } else {
+            let wait = Duration::from_secs_f64((needed - self.tokens) / self.rate);
+            // Spent now, and paid back while it waits
+            self.tokens -= len;
+            Admit::Wait(wait)
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct RateLimited(Usage);
+impl Reject for RateLimited {}
+
+pub async fn handle_rejection(err: Rejection) -> Result<impl Reply, Rejection> {
+    match err.find::<RateLimited>() {
+        Some(RateLimited(usage)) => {
+            let reply = warp::reply::with_status(
+                warp::reply::json(&usage.error()),
+                StatusCode::TOO_MANY_REQUESTS,
+            );
+            let mut response = headers(Some(*usage), reply);
+            let retry_after = usage.retry_after.unwrap_or_default().as_secs_f64().ceil();
+            response
+                .headers_mut()
+                .insert("retry-after", HeaderValue::from(retry_after as u64));
+            Ok(response)
+        }
+        None => Err(err),
+    }
+}
+
+// Charges each request to its caller, letting it through with where they
+// stand, or turning it away with a 429. Does nothing without a limiter.
+// Only a room key or the operator's token that checks out gets its own
+// budget; anything else a request carries could be made up fresh each time,
+// so those are charged to their address.
+pub fn rest(
+    limiter: Option<Address<Limiter>>,
+    token: Option<String>,
+    keys: Address<KeyStore>,
+    peer: impl Filter<Extract = (Peer,), Error = Infallible> + Clone + Send,
+) -> impl Filter<Extract = (Option<Usage>,), Error = Rejection> + Clone {
+    warp::query::<HashMap<String, String>>()
+        .and(warp::header::optional::<String>("authorization"))
+        .and(peer)
+        .and_then(
+            move |query: HashMap<String, String>, header: Option<String>, peer: Peer| {
+                let (limiter, token, keys) = (limiter.clone(), token.clone(), keys.clone());
+                async move {
+                    let limiter = match limiter {
+                        Some(limiter) => limiter,
+                        None => return Ok(None),
+                    };
+                    let secret = query
+                        .get("key")
+                        .map(String::as_str)
+                        .or_else(|| header.as_deref().and_then(|h| h.strip_prefix("Bearer ")));
+                    let key = match (secret, &token) {
+                        (Some(secret), Some(token)) if admin::is_token(secret, token) => {
+                            Some("admin".to_string())
+                        }
+                        (Some(secret), _) => keys
+                            .send(Authenticate(secret.to_string()))
+                            .await
+                            .expect("Could not reach the keys")
+                            .map(|key| key.id.to_string()),
+                        (None, _) => None,
+                    };
+                    let usage = limiter
+                        .send(Take(caller(key, &peer)))
+                        .await
+                        .expect("Could not reach the limiter");
+                    if usage.allowed() {
+                        Ok(Some(usage))
+                    } else {
+                        Err(warp::reject::custom(RateLimited(usage)))
+                    }
+                }
+            },
+        )
+}
+
+// Adds the RateLimit-* headers to a reply
+pub fn headers(usage: Option<Usage>, reply: impl Reply) -> Response {
+    let mut response = reply.into_response();
+    if let Some(usage) = usage {
+        let headers = response.headers_mut();
+        headers.insert("ratelimit-limit", HeaderValue::from(usage.limit));
+        headers.insert("ratelimit-remaining", HeaderValue::from(usage.remaining));
+        let reset = usage.reset.as_secs_f64().ceil() as u64;
+        headers.insert("ratelimit-reset", HeaderValue::from(reset));
+    }
+    response
+}
@@ -0,0 +1,140 @@
+use futures::future;
+use std::env;
+use std::fs::{self, Permissions};
+use std::net::{SocketAddr, TcpListener as StdTcpListener};
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::io::{FromRawFd, IntoRawFd, RawFd};
+use std::os::unix::net::UnixListener as StdUnixListener;
+use std::path::PathBuf;
+use std::process;
+use std::str::FromStr;
+use tokio::net::{TcpListener, UnixListener};
+use tokio_stream::wrappers::{TcpListenerStream, UnixListenerStream};
+use warp::{Filter, Rejection, Reply};
+
+// systemd hands sockets over starting at this fd
+const SD_LISTEN_FDS_START: RawFd = 3;
+
+// Listen - somewhere to accept connections
+#[derive(Debug)]
+pub enum Listen {
+    // `127.0.0.1:3030`, `[::]:3030`, ...
+    Tcp(SocketAddr),
+    // `unix:/run/chat.sock`
+    Unix(PathBuf),
+    // `systemd`: whatever sockets systemd passed in through LISTEN_FDS
+    Systemd,
+}
+impl FromStr for Listen {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s == "systemd" {
+            Ok(Listen::Systemd)
+        } else if let Some(path) = s.strip_prefix("unix:") {
+            Ok(Listen::Unix(path.into()))
+        } else {
+            s.parse()
+                .map(Listen::Tcp)
+                .map_err(|e| format!("{}: {}", s, e))
+        }
+    }
+}
+
+// An inherited socket, once we've worked out what kind it is
+enum Inherited {
+    Tcp(StdTcpListener),
+    Unix(StdUnixListener),
+}
+
+// Collects the sockets passed by systemd socket activation, if they're meant
+// for us
+fn systemd_sockets() -> Vec<Inherited> {
+    let ours = env::var("LISTEN_PID")
+        .ok()
+        .and_then(|pid| pid.parse::<u32>().ok())
+        == Some(process::id());
+    let count = match env::var("LISTEN_FDS")
+        .ok()
+        .and_then(|n| n.parse::<RawFd>().ok())
+    {
+        Some(count) if ours => count,
+        _ => {
+            log::warn!("systemd listener requested but LISTEN_FDS is not for us");
+            return Vec::new();
+        }
+    };
+
+    (SD_LISTEN_FDS_START..SD_LISTEN_FDS_START + count)
+        .map(|fd| {
+            // Only a TCP socket has an inet address; anything else we take
+            // back and treat as a unix socket
+            let tcp = unsafe { StdTcpListener::from_raw_fd(fd) };
+            if tcp.local_addr().is_ok() {
+                Inherited::Tcp(tcp)
+            } else {
+                let fd = tcp.into_raw_fd();
+                Inherited::Unix(unsafe { StdUnixListener::from_raw_fd(fd) })
+            }
+        })
+        .collect()
+}
+
+// Serves the same routes on every listener until they all stop. Unix sockets
+// we bind get `unix_socket_mode` permissions, so a proxy in another group can
+// be let in without opening the socket to everyone.
+pub async fn serve<F>(listeners: &[Listen], unix_socket_mode: Option<u32>, routes: F)
+where
+    F: Filter<Error = Rejection> + Clone + Send + Sync + 'static,
+    F::Extract: Reply,
+{
+    let mut servers = Vec::new();
+    for listen in listeners {
+        match listen {
+            Listen::Tcp(addr) => {
+                log::info!("listening on http://{}", addr);
+                servers.push(tokio::spawn(warp::serve(routes.clone()).bind(*addr)));
+            }
+            Listen::Unix(path) => {
+                // A socket left over from the last run would make bind fail
+                let _ = fs::remove_file(path);
+                let listener = UnixListener::bind(path).expect("Could not bind unix socket");
+                if let Some(mode) = unix_socket_mode {
+                    fs::set_permissions(path, Permissions::from_mode(mode))
+                        .expect("Could not set unix socket permissions");
+                }
+                log::info!("listening on unix:{}", path.display());
+                let incoming = UnixListenerStream::new(listener);
+                servers.push(tokio::spawn(
+                    warp::serve(routes.clone()).run_incoming(incoming),
+                ));
+            }
+            Listen::Systemd => {
+                // Note that warp can't see the remote address of connections
+                // accepted on inherited sockets
+                for socket in systemd_sockets() {
+                    let server = warp::serve(routes.clone());
+                    servers.push(match socket {
+                        Inherited::Tcp(listener) => {
+                            log::info!("listening on inherited {:?}", listener.local_addr());
+                            listener.set_nonblocking(true).unwrap();
+                            let listener = TcpListener::from_std(listener).unwrap();
+                            tokio::spawn(server.run_incoming(TcpListenerStream::new(listener)))
+                        }
+                        Inherited::Unix(listener) => {
+                            log::info!("listening on inherited unix socket");
+                            listener.set_nonblocking(true).unwrap();
+                            let listener = UnixListener::from_std(listener).unwrap();
+                            tokio::spawn(server.run_incoming(UnixListenerStream::new(listener)))
+                        }
+                    });
+                }
+            }
+        }
+    }
+
+    if servers.is_empty() {
+        log::error!("Nothing to listen on");
+    }
+    future::join_all(servers).await;
+}
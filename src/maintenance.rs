@@ -0,0 +1,58 @@
+use tokio::sync::watch;
+use tokio::time::{Duration, Instant};
+use warp::http::StatusCode;
+use warp::reject::Reject;
+use warp::{Filter, Rejection, Reply};
+
+use crate::ProtocolError;
+
+// Drain - maintenance is on: nobody new gets in, and whoever is still
+// connected at `until` is disconnected
+#[derive(Clone, Copy, Debug)]
+pub struct Drain {
+    pub until: Instant,
+}
+impl Drain {
+    pub fn remaining(&self) -> Duration {
+        self.until.saturating_duration_since(Instant::now())
+    }
+}
+
+// Every connection and filter watches this; the admin API flips it
+pub type State = watch::Receiver<Option<Drain>>;
+
+#[derive(Debug)]
+pub struct Unavailable;
+impl Reject for Unavailable {}
+
+pub async fn handle_rejection(err: Rejection) -> Result<impl Reply, Rejection> {
+    if err.find::<Unavailable>().is_some() {
+        let error = ProtocolError::new(
+            "maintenance",
+            "Down for maintenance, try again later".to_string(),
+        )
+        .retryable(None);
+        Ok(warp::reply::with_status(
+            warp::reply::json(&error),
+            StatusCode::SERVICE_UNAVAILABLE,
+        ))
+    } else {
+        Err(err)
+    }
+}
+
+// Lets requests through only while we're not in maintenance
+pub fn open(state: State) -> impl Filter<Extract = (), Error = Rejection> + Clone {
+    warp::any()
+        .and_then(move || {
+            let closed = state.borrow().is_some();
+            async move {
+                if closed {
+                    Err(warp::reject::custom(Unavailable))
+                } else {
+                    Ok(())
+                }
+            }
+        })
+        .untuple_one()
+}
@@ -0,0 +1,214 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, OnceLock, RwLock};
+use std::time::{Duration, Instant};
+
+// Upper bounds, in seconds, of the handler timing buckets
+const BUCKETS: [f64; 10] = [0.0001, 0.0005, 0.001, 0.005, 0.01, 0.05, 0.1, 0.5, 1.0, 5.0];
+// How often the runtime probe wakes up to see how late it is
+const PROBE_EVERY: Duration = Duration::from_millis(250);
+
+// Histogram - per-bucket counts plus a running sum
+#[derive(Default)]
+struct Histogram {
+    buckets: [AtomicU64; BUCKETS.len()],
+    count: AtomicU64,
+    sum_micros: AtomicU64,
+}
+impl Histogram {
+    fn observe(&self, elapsed: Duration) {
+        let secs = elapsed.as_secs_f64();
+        if let Some(i) = BUCKETS.iter().position(|le| secs <= *le) {
+            self.buckets[i].fetch_add(1, Ordering::Relaxed);
+        }
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.sum_micros
+            .fetch_add(elapsed.as_micros() as u64, Ordering::Relaxed);
+    }
+}
+
+// Registry - one histogram per handler name, and how the process is doing
+struct Registry {
+    slow_threshold: Duration,
+    handlers: RwLock<BTreeMap<&'static str, Arc<Histogram>>>,
+    started: Instant,
+    // How late the probe woke up, which is how long a task that's ready
+    // sits waiting for a worker
+    lag: Histogram,
+}
+impl Registry {
+    fn histogram(&self, handler: &'static str) -> Arc<Histogram> {
+        if let Some(h) = self.handlers.read().unwrap().get(handler) {
+            return h.clone();
+        }
+        self.handlers
+            .write()
+            .unwrap()
+            .entry(handler)
+            .or_default()
+            .clone()
+    }
+}
+
+static REGISTRY: OnceLock<Registry> = OnceLock::new();
+
+// Sets the slow handler threshold and starts the runtime probe; must be
+// called on the runtime before any handler runs. Later calls change nothing.
+pub fn init(slow_threshold: Duration) {
+    let mut first = false;
+    REGISTRY.get_or_init(|| {
+        first = true;
+        Registry {
+            slow_threshold,
+            handlers: RwLock::new(BTreeMap::new()),
+            started: Instant::now(),
+            lag: Histogram::default(),
+        }
+    });
+    if first {
+        tokio::spawn(probe());
+    }
+}
+
+// Sleeps and wakes forever, noting how much later than asked it got to run
+async fn probe() {
+    loop {
+        let start = Instant::now();
+        tokio::time::sleep(PROBE_EVERY).await;
+        let lag = start.elapsed().saturating_sub(PROBE_EVERY);
+        registry().lag.observe(lag);
+    }
+}
+
+fn registry() -> &'static Registry {
+    REGISTRY.get().expect("metrics::init was not called")
+}
+
+// Timer - records how long a handler took when dropped
+pub struct Timer {
+    handler: &'static str,
+    start: Instant,
+}
+impl Drop for Timer {
+    fn drop(&mut self) {
+        let elapsed = self.start.elapsed();
+        let registry = registry();
+        registry.histogram(self.handler).observe(elapsed);
+        if elapsed >= registry.slow_threshold {
+            log::warn!("slow handler: {} took {:?}", self.handler, elapsed);
+        }
+    }
+}
+
+// Starts timing a handler invocation; hold on to the result until it's done
+pub fn timer(handler: &'static str) -> Timer {
+    Timer {
+        handler,
+        start: Instant::now(),
+    }
+}
+
+// Writes one histogram's lines; `labels` go first inside the braces
+fn write_histogram(out: &mut String, name: &str, labels: &str, h: &Histogram) {
+    let mut cumulative = 0;
+    for (le, bucket) in BUCKETS.iter().zip(h.buckets.iter()) {
+        cumulative += bucket.load(Ordering::Relaxed);
+        let _ = writeln!(
+            out,
+            "{}_bucket{{{}le=\"{}\"}} {}",
+            name, labels, le, cumulative
+        );
+    }
+    let count = h.count.load(Ordering::Relaxed);
+    let sum = h.sum_micros.load(Ordering::Relaxed) as f64 / 1_000_000.0;
+    let _ = writeln!(out, "{}_bucket{{{}le=\"+Inf\"}} {}", name, labels, count);
+    let braces = |labels: &str| match labels.strip_suffix(',') {
+        Some(labels) => format!("{{{}}}", labels),
+        None => String::new(),
+    };
+    let _ = writeln!(out, "{}_sum{} {}", name, braces(labels), sum);
+    let _ = writeln!(out, "{}_count{} {}", name, braces(labels), count);
+}
+
+fn gauge(out: &mut String, name: &str, help: &str, value: impl std::fmt::Display) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} gauge", name);
+    let _ = writeln!(out, "{} {}", name, value);
+}
+
+// The process's own view of itself, from /proc where there is one
+fn process(out: &mut String) {
+    // VmRSS is in kB
+    let rss = std::fs::read_to_string("/proc/self/status")
+        .ok()
+        .and_then(|status| {
+            let line = status.lines().find(|line| line.starts_with("VmRSS:"))?;
+            line.split_whitespace().nth(1)?.parse::<u64>().ok()
+        });
+    if let Some(kb) = rss {
+        gauge(
+            out,
+            "process_resident_memory_bytes",
+            "Resident memory size in bytes",
+            kb * 1024,
+        );
+    }
+    if let Ok(fds) = std::fs::read_dir("/proc/self/fd") {
+        gauge(
+            out,
+            "process_open_fds",
+            "Number of open file descriptors",
+            fds.count(),
+        );
+    }
+    let max_fds = std::fs::read_to_string("/proc/self/limits")
+        .ok()
+        .and_then(|limits| {
+            let line = limits
+                .lines()
+                .find(|line| line.starts_with("Max open files"))?;
+            line.split_whitespace().nth(3)?.parse::<u64>().ok()
+        });
+    if let Some(max) = max_fds {
+        gauge(
+            out,
+            "process_max_fds",
+            "Maximum number of open file descriptors",
+            max,
+        );
+    }
+}
+
+// Renders everything in the Prometheus text format
+pub fn render() -> String {
+    let mut out = String::new();
+    out.push_str("# HELP chat_handler_duration_seconds Time spent in actor handlers\n");
+    out.push_str("# TYPE chat_handler_duration_seconds histogram\n");
+    let registry = registry();
+    for (handler, h) in registry.handlers.read().unwrap().iter() {
+        let labels = format!("handler=\"{}\",", handler);
+        write_histogram(&mut out, "chat_handler_duration_seconds", &labels, h);
+    }
+
+    out.push_str("# HELP chat_runtime_lag_seconds How late a sleeping task wakes up\n");
+    out.push_str("# TYPE chat_runtime_lag_seconds histogram\n");
+    write_histogram(&mut out, "chat_runtime_lag_seconds", "", &registry.lag);
+    // What #[tokio::main] sizes its worker pool by
+    let workers = std::thread::available_parallelism().map_or(1, |n| n.get());
+    gauge(
+        &mut out,
+        "chat_runtime_workers",
+        "Tokio worker threads",
+        workers,
+    );
+    let uptime = registry.started.elapsed().as_secs_f64();
+    gauge(
+        &mut out,
+        "chat_uptime_seconds",
+        "Seconds since the server started",
+        uptime,
+    );
+    process(&mut out);
+    out
+}
@@ -0,0 +1,123 @@
+use serde::Serialize;
+use std::collections::BTreeMap;
+use uuid::Uuid;
+use xtra::prelude::*;
+
+use crate::metrics;
+
+// Report - one user's complaint about a message
+#[derive(Clone, Serialize)]
+pub struct Report {
+    pub reporter: Uuid,
+    pub reason: String,
+}
+
+// PendingReport - a reported message waiting for a moderator
+#[derive(Clone, Serialize)]
+pub struct PendingReport {
+    pub room: String,
+    pub message_id: u64,
+    pub author: Uuid,
+    pub body: String,
+    pub hidden: bool,
+    pub reports: Vec<Report>,
+}
+
+// ModerationQueue - collects reports until a moderator deals with them
+pub struct ModerationQueue {
+    // Reports at which a message gets hidden pending review
+    hide_threshold: usize,
+    pending: BTreeMap<(String, u64), PendingReport>,
+}
+impl Actor for ModerationQueue {}
+impl ModerationQueue {
+    pub fn new(hide_threshold: usize) -> Self {
+        Self {
+            hide_threshold,
+            pending: BTreeMap::new(),
+        }
+    }
+}
+
+// FileReport - a room passing on a report; answers whether to hide the message
+pub struct FileReport {
+    pub room: String,
+    pub message_id: u64,
+    pub author: Uuid,
+    pub body: String,
+    // Whether the room has hidden it already
+    pub hidden: bool,
+    pub report: Report,
+}
+impl Message for FileReport {
+    type Result = bool;
+}
+#[async_trait::async_trait]
+impl Handler<FileReport> for ModerationQueue {
+    async fn handle(&mut self, msg: FileReport, _ctx: &mut Context<Self>) -> bool {
+        let _timer = metrics::timer("file_report");
+        let FileReport {
+            room,
+            message_id,
+            author,
+            body,
+            hidden,
+            report,
+        } = msg;
+        let pending = self
+            .pending
+            .entry((room.clone(), message_id))
+            .or_insert_with(|| PendingReport {
+                room,
+                message_id,
+                author,
+                body,
+                hidden,
+                reports: Vec::new(),
+            });
+
+        // Reporting the same message twice doesn't count twice
+        if pending
+            .reports
+            .iter()
+            .all(|r| r.reporter != report.reporter)
+        {
+            pending.reports.push(report);
+        }
+
+        if !pending.hidden && pending.reports.len() >= self.hide_threshold {
+            pending.hidden = true;
+            return true;
+        }
+        false
+    }
+}
+
+// ListReports - everything waiting for review, oldest message first
+pub struct ListReports;
+impl Message for ListReports {
+    type Result = Vec<PendingReport>;
+}
+#[async_trait::async_trait]
+impl Handler<ListReports> for ModerationQueue {
+    async fn handle(&mut self, _msg: ListReports, _ctx: &mut Context<Self>) -> Vec<PendingReport> {
+        let _timer = metrics::timer("list_reports");
+        self.pending.values().cloned().collect()
+    }
+}
+
+// TakeReport - removes a report from the queue so a moderator can act on it
+pub struct TakeReport {
+    pub room: String,
+    pub message_id: u64,
+}
+impl Message for TakeReport {
+    type Result = Option<PendingReport>;
+}
+#[async_trait::async_trait]
+impl Handler<TakeReport> for ModerationQueue {
+    async fn handle(&mut self, msg: TakeReport, _ctx: &mut Context<Self>) -> Option<PendingReport> {
+        let _timer = metrics::timer("take_report");
+        self.pending.remove(&(msg.room, msg.message_id))
+    }
+}
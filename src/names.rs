@@ -0,0 +1,29 @@
+use unicode_normalization::UnicodeNormalization;
+use uuid::Uuid;
+
+pub const MAX_NAME_LEN: usize = 32;
+
+// Puts a user-chosen name (a room, a nick) into its one canonical form, so
+// `Dev`, `dev` and fullwidth `ｄｅｖ` are all the same name. NFKC folds the
+// compatibility lookalikes, and keeping to a-z, 0-9, `-` and `_` afterwards
+// shuts out homoglyphs from other scripts. None if nothing sensible is left.
+pub fn normalize(name: &str) -> Option<String> {
+    let name: String = name.trim().nfkc().collect::<String>().to_lowercase();
+    let ok = !name.is_empty()
+        && name.len() <= MAX_NAME_LEN
+        && name
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-' || c == '_');
+    if ok {
+        Some(name)
+    } else {
+        None
+    }
+}
+
+// What to call someone, since users have no names of their own: told apart
+// by the random end of their id
+pub fn guest(id: Uuid) -> String {
+    let id = id.to_simple().to_string();
+    format!("guest-{}", &id[id.len() - 8..])
+}
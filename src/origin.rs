@@ -0,0 +1,61 @@
+use std::convert::Infallible;
+use std::sync::Arc;
+use warp::http::StatusCode;
+use warp::reject::Reject;
+use warp::{Filter, Rejection, Reply};
+
+use crate::proxy::Peer;
+
+#[derive(Debug)]
+pub struct BadOrigin;
+impl Reject for BadOrigin {}
+
+pub async fn handle_rejection(err: Rejection) -> Result<impl Reply, Rejection> {
+    if err.find::<BadOrigin>().is_some() {
+        Ok(StatusCode::FORBIDDEN)
+    } else {
+        Err(err)
+    }
+}
+
+// Whether a page served from `origin` may open a websocket to us. With no
+// allowlist only our own origin may; `*` lets anyone in.
+fn permitted(allowed: &[String], origin: &str, peer: &Peer) -> bool {
+    if allowed.is_empty() {
+        let host = origin.split_once("://").map(|(_, host)| host);
+        return host.is_some() && host == peer.host.as_deref();
+    }
+    allowed
+        .iter()
+        .any(|allowed| allowed == "*" || allowed.eq_ignore_ascii_case(origin))
+}
+
+// Turns away websocket upgrades started by pages on other sites, which
+// would otherwise ride on the visitor's network position. Requests without
+// an Origin don't come from a browser and are let through.
+pub fn check<P>(
+    allowed: Arc<Vec<String>>,
+    peer: P,
+) -> impl Filter<Extract = (), Error = Rejection> + Clone
+where
+    P: Filter<Extract = (Peer,), Error = Infallible> + Clone + Send + Sync + 'static,
+{
+    warp::header::optional::<String>("origin")
+        .and(peer)
+        .and_then(move |origin: Option<String>, peer: Peer| {
+            let allowed = allowed.clone();
+            async move {
+                match origin {
+                    Some(origin) if !permitted(&allowed, &origin, &peer) => {
+                        match peer.addr {
+                            Some(addr) => log::warn!("Refused origin {} from {}", origin, addr),
+                            None => log::warn!("Refused origin {}", origin),
+                        }
+                        Err(warp::reject::custom(BadOrigin))
+                    }
+                    _ => Ok(()),
+                }
+            }
+        })
+        .untuple_one()
+}
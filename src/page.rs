@@ -0,0 +1,82 @@
+use serde::{Deserialize, Serialize};
+use warp::http::{HeaderValue, StatusCode};
+use warp::reply::Response;
+use warp::{Filter, Rejection, Reply};
+
+use crate::ProtocolError;
+
+// Page size used when a list request doesn't ask for one, and the cap
+const DEFAULT_LIMIT: usize = 100;
+const MAX_LIMIT: usize = 500;
+
+// PageQuery - `?limit=&cursor=`, taken by every list endpoint
+#[derive(Deserialize)]
+pub struct PageQuery {
+    limit: Option<usize>,
+    cursor: Option<String>,
+}
+
+pub fn query() -> impl Filter<Extract = (PageQuery,), Error = Rejection> + Clone {
+    warp::query::<PageQuery>()
+}
+
+// A cursor is the sort key of the last item on a page, base64 so clients
+// don't come to depend on what's inside
+pub fn encode(key: &str) -> String {
+    base64::encode_config(key, base64::URL_SAFE_NO_PAD)
+}
+
+pub fn decode(cursor: &str) -> Result<String, ProtocolError> {
+    base64::decode_config(cursor, base64::URL_SAFE_NO_PAD)
+        .ok()
+        .and_then(|key| String::from_utf8(key).ok())
+        .ok_or_else(|| bad_cursor(cursor))
+}
+
+// A cursor over seqs, as history pages have
+pub fn decode_seq(cursor: &str) -> Result<u64, ProtocolError> {
+    decode(cursor)?.parse().map_err(|_| bad_cursor(cursor))
+}
+
+fn bad_cursor(cursor: &str) -> ProtocolError {
+    ProtocolError::new("bad_cursor", format!("Bad cursor: {}", cursor))
+}
+
+// The page of `items` after the query's cursor, and the cursor for the one
+// after that if there's more. `items` have to be in order of `key`.
+pub fn page<T>(
+    items: Vec<T>,
+    key: impl Fn(&T) -> String,
+    query: &PageQuery,
+) -> Result<(Vec<T>, Option<String>), ProtocolError> {
+    let after = query.cursor.as_deref().map(decode).transpose()?;
+    let limit = query.limit.unwrap_or(DEFAULT_LIMIT).clamp(1, MAX_LIMIT);
+
+    let mut rest = items
+        .into_iter()
+        .filter(|item| after.as_ref().is_none_or(|after| key(item) > *after));
+    let items: Vec<_> = rest.by_ref().take(limit).collect();
+    let next = match rest.next() {
+        Some(_) => items.last().map(|item| encode(&key(item))),
+        None => None,
+    };
+    Ok((items, next))
+}
+
+// A page as JSON, with where to carry on from in a Next-Cursor header, or
+// the 400 for a bad cursor
+pub fn reply<T: Serialize>(page: Result<(Vec<T>, Option<String>), ProtocolError>) -> Response {
+    match page {
+        Ok((items, next)) => {
+            let mut response = warp::reply::json(&items).into_response();
+            if let Some(next) = next {
+                let next = HeaderValue::from_str(&next).expect("Cursors are base64");
+                response.headers_mut().insert("next-cursor", next);
+            }
+            response
+        }
+        Err(e) => {
+            warp::reply::with_status(warp::reply::json(&e), StatusCode::BAD_REQUEST).into_response()
+        }
+    }
+}
@@ -0,0 +1,113 @@
+use serde::{Deserialize, Serialize};
+use std::convert::TryFrom;
+
+use crate::ProtocolError;
+
+// Longest role name, in characters
+const MAX_ROLE_LEN: usize = 24;
+
+// Permissions - what someone may do in a room, as a list of names on the
+// wire. Members get theirs from their role in the room; those joined on an
+// admin key may do everything.
+#[derive(Clone, Copy, Deserialize, Serialize, PartialEq)]
+#[serde(try_from = "Vec<String>", into = "Vec<String>")]
+pub struct Permissions(u8);
+impl Permissions {
+    pub const SEND: Permissions = Permissions(1);
+    pub const KICK: Permissions = Permissions(1 << 1);
+    pub const BAN: Permissions = Permissions(1 << 2);
+    // Letting people in to rooms that need approval
+    pub const MANAGE_ROOM: Permissions = Permissions(1 << 3);
+    // Notifying everyone in the room with `@all` or `@here`
+    pub const MENTION_ALL: Permissions = Permissions(1 << 4);
+    const NAMED: [(&'static str, Permissions); 5] = [
+        ("send", Self::SEND),
+        ("mention_all", Self::MENTION_ALL),
+        ("kick", Self::KICK),
+        ("ban", Self::BAN),
+        ("manage_room", Self::MANAGE_ROOM),
+    ];
+
+    pub fn none() -> Self {
+        Permissions(0)
+    }
+
+    pub fn all() -> Self {
+        Permissions(
+            Self::NAMED
+                .iter()
+                .fold(0, |bits, (_, permission)| bits | permission.0),
+        )
+    }
+
+    pub fn has(self, permission: Permissions) -> bool {
+        self.0 & permission.0 == permission.0
+    }
+
+    // Why an event needing `permission` was refused
+    pub fn missing(permission: Permissions, room: &str) -> ProtocolError {
+        let (name, _) = Self::NAMED
+            .iter()
+            .find(|(_, named)| *named == permission)
+            .expect("Permissions are all named");
+        ProtocolError::new("forbidden", format!("You don't have {} in {}", name, room))
+    }
+}
+impl TryFrom<Vec<String>> for Permissions {
+    type Error = String;
+
+    fn try_from(names: Vec<String>) -> Result<Self, Self::Error> {
+        names.iter().try_fold(Permissions(0), |permissions, name| {
+            match Self::NAMED.iter().find(|(named, _)| named == name) {
+                Some((_, permission)) => Ok(Permissions(permissions.0 | permission.0)),
+                None => Err(format!(
+                    "{}: expected send, mention_all, kick, ban or manage_room",
+                    name
+                )),
+            }
+        })
+    }
+}
+impl From<Permissions> for Vec<String> {
+    fn from(permissions: Permissions) -> Self {
+        Permissions::NAMED
+            .iter()
+            .filter(|(_, permission)| permissions.has(*permission))
+            .map(|(name, _)| name.to_string())
+            .collect()
+    }
+}
+
+// The role everyone has until they're given another
+pub const MEMBER: &str = "member";
+
+// The roles every room has, unless it defines them differently: members
+// may send, and moderators may do everything
+pub fn builtin(role: &str) -> Option<Permissions> {
+    match role {
+        MEMBER => Some(Permissions::SEND),
+        "moderator" => Some(Permissions::all()),
+        _ => None,
+    }
+}
+
+// Whether `role` will do as a role name: lowercase letters, digits, `-`
+// and `_`
+pub fn check_role(role: &str) -> Result<(), ProtocolError> {
+    let fine = !role.is_empty()
+        && role.chars().count() <= MAX_ROLE_LEN
+        && role
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-' || c == '_');
+    if fine {
+        Ok(())
+    } else {
+        Err(ProtocolError::new(
+            "invalid_settings",
+            format!(
+                "{:?}: roles are up to {} lowercase letters, digits, - and _",
+                role, MAX_ROLE_LEN
+            ),
+        ))
+    }
+}
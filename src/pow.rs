@@ -0,0 +1,226 @@
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::time::Duration;
+use uuid::Uuid;
+use warp::http::StatusCode;
+use warp::reject::Reject;
+use warp::{Filter, Rejection, Reply};
+use xtra::prelude::*;
+
+use crate::identity;
+use crate::keys::ApiKey;
+use crate::{metrics, now_millis, ProtocolError};
+
+// How long a challenge can be solved and used for
+const CHALLENGE_TTL: Duration = Duration::from_secs(120);
+
+// Challenge - what GET /pow answers with: find a nonce for which
+// sha256("<challenge>:<nonce>") starts with `bits` zero bits
+#[derive(Serialize)]
+pub struct Challenge {
+    challenge: String,
+    bits: u32,
+    expires_in_secs: u64,
+}
+
+// PowGate - hands out challenges and checks solutions. Challenges are
+// signed rather than stored, so handing them out costs nothing; only
+// solved ones are remembered, until they expire, so none is used twice.
+pub struct PowGate {
+    bits: u32,
+    secret: String,
+    // Solved challenges, and when they expire
+    used: HashMap<String, u64>,
+}
+impl Actor for PowGate {}
+impl PowGate {
+    pub fn new(bits: u32) -> Self {
+        Self {
+            bits,
+            secret: format!(
+                "{}{}",
+                Uuid::new_v4().to_simple(),
+                Uuid::new_v4().to_simple()
+            ),
+            used: HashMap::new(),
+        }
+    }
+
+    fn sign(&self, salt: &str, expires: u64) -> String {
+        let mac = identity::hmac(
+            self.secret.as_bytes(),
+            format!("{}.{}", salt, expires).as_bytes(),
+        );
+        mac.iter().map(|byte| format!("{:02x}", byte)).collect()
+    }
+
+    // Whether `challenge` is one of ours and hasn't expired
+    fn genuine(&self, challenge: &str, now: u64) -> bool {
+        let mut parts = challenge.split('.');
+        match (parts.next(), parts.next(), parts.next(), parts.next()) {
+            (Some(salt), Some(expires), Some(signature), None) => match expires.parse() {
+                Ok(expires) => {
+                    now < expires
+                        && identity::same(self.sign(salt, expires).as_bytes(), signature.as_bytes())
+                }
+                Err(_) => false,
+            },
+            _ => false,
+        }
+    }
+}
+
+// Whether a hash starts with at least `bits` zero bits
+fn leading_zeros(hash: &[u8], bits: u32) -> bool {
+    let mut seen = 0;
+    for byte in hash {
+        if seen >= bits {
+            break;
+        }
+        if *byte != 0 {
+            return seen + byte.leading_zeros() >= bits;
+        }
+        seen += 8;
+    }
+    seen >= bits
+}
+
+// Issue - a fresh challenge
+pub struct Issue;
+impl Message for Issue {
+    type Result = Challenge;
+}
+#[async_trait::async_trait]
+impl Handler<Issue> for PowGate {
+    async fn handle(&mut self, _msg: Issue, _ctx: &mut Context<Self>) -> Challenge {
+        let _timer = metrics::timer("issue_challenge");
+        let salt = Uuid::new_v4().to_simple().to_string();
+        let expires = now_millis() + CHALLENGE_TTL.as_millis() as u64;
+        Challenge {
+            challenge: format!("{}.{}.{}", salt, expires, self.sign(&salt, expires)),
+            bits: self.bits,
+            expires_in_secs: CHALLENGE_TTL.as_secs(),
+        }
+    }
+}
+
+// Redeem - spends a solved challenge; false if it's not ours, expired,
+// used already or not solved
+pub struct Redeem {
+    pub challenge: String,
+    pub nonce: String,
+}
+impl Message for Redeem {
+    type Result = bool;
+}
+#[async_trait::async_trait]
+impl Handler<Redeem> for PowGate {
+    async fn handle(&mut self, msg: Redeem, _ctx: &mut Context<Self>) -> bool {
+        let _timer = metrics::timer("redeem_challenge");
+        let now = now_millis();
+        self.used.retain(|_, expires| now < *expires);
+        if !self.genuine(&msg.challenge, now) || self.used.contains_key(&msg.challenge) {
+            return false;
+        }
+        let hash = Sha256::digest(format!("{}:{}", msg.challenge, msg.nonce).as_bytes());
+        if !leading_zeros(&hash, self.bits) {
+            return false;
+        }
+        self.used
+            .insert(msg.challenge, now + CHALLENGE_TTL.as_millis() as u64);
+        true
+    }
+}
+
+#[derive(Debug)]
+pub struct Unsolved;
+impl Reject for Unsolved {}
+
+pub async fn handle_rejection(err: Rejection) -> Result<impl Reply, Rejection> {
+    if err.find::<Unsolved>().is_some() {
+        let error = ProtocolError::new(
+            "pow_required",
+            "Solve a challenge from GET /pow to connect".to_string(),
+        )
+        .retryable(None);
+        Ok(warp::reply::with_status(
+            warp::reply::json(&error),
+            StatusCode::FORBIDDEN,
+        ))
+    } else {
+        Err(err)
+    }
+}
+
+// `?pow=<challenge>&nonce=<nonce>` on the upgrade
+#[derive(Deserialize)]
+struct Solution {
+    pow: Option<String>,
+    nonce: Option<String>,
+}
+
+// Lets through connections on a room key, and anonymous ones with a solved
+// challenge. Everyone gets through without a gate.
+pub fn guard(
+    gate: Option<Address<PowGate>>,
+    key: impl Filter<Extract = (Option<ApiKey>,), Error = Rejection> + Clone + Send,
+) -> impl Filter<Extract = (Option<ApiKey>,), Error = Rejection> + Clone {
+    key.and(warp::query::<Solution>())
+        .and_then(move |key: Option<ApiKey>, solution: Solution| {
+            let gate = gate.clone();
+            async move {
+                let gate = match gate {
+                    Some(gate) if key.is_none() => gate,
+                    _ => return Ok(key),
+                };
+                let (challenge, nonce) = match (solution.pow, solution.nonce) {
+                    (Some(challenge), Some(nonce)) => (challenge, nonce),
+                    _ => return Err(warp::reject::custom(Unsolved)),
+                };
+                let solved = gate
+                    .send(Redeem { challenge, nonce })
+                    .await
+                    .expect("Could not reach the pow gate");
+                if solved {
+                    Ok(None)
+                } else {
+                    Err(warp::reject::custom(Unsolved))
+                }
+            }
+        })
+}
+
+// GET /pow, when there's a gate
+pub fn routes(
+    gate: Option<Address<PowGate>>,
+) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    warp::path!("pow").and(warp::get()).and_then(move || {
+        let gate = gate.clone();
+        async move {
+            let gate = gate.ok_or_else(warp::reject::not_found)?;
+            let challenge = gate.send(Issue).await.expect("Could not issue challenge");
+            Ok::<_, Rejection>(warp::reply::json(&challenge))
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_our_unexpired_challenges_are_genuine() {
+        let gate = PowGate::new(8);
+        let now = 1_000_000;
+        let signed = format!("salt.{}.{}", now + 1, gate.sign("salt", now + 1));
+        assert!(gate.genuine(&signed, now));
+        assert!(!gate.genuine(&signed, now + 1));
+
+        let other = PowGate::new(8);
+        assert!(!other.genuine(&signed, now));
+        let resalted = signed.replacen("salt", "pepper", 1);
+        assert!(!gate.genuine(&resalted, now));
+        assert!(!gate.genuine(&signed[..signed.len() - 1], now));
+    }
+}
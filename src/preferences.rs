@@ -0,0 +1,197 @@
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeSet, HashMap};
+use std::sync::Arc;
+use uuid::Uuid;
+use warp::http::StatusCode;
+use warp::{Filter, Rejection, Reply};
+use xtra::prelude::*;
+
+use crate::connections::{Connections, GetConnections};
+use crate::identity::{self, Signer};
+use crate::services::Services;
+use crate::{metrics, registry, ProtocolError, ServerEvent, Tell};
+
+const MAX_THEME_LEN: usize = 32;
+const MAX_MUTED_ROOMS: usize = 100;
+
+// Notifications - what a user wants to be notified about
+#[derive(Clone, Copy, Debug, Default, Deserialize, Serialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Notifications {
+    #[default]
+    All,
+    // Only messages mentioning them, or everyone, and their keywords
+    Mentions,
+    None,
+}
+
+// UserPreferences - how a user wants the chat, whichever browser tab or
+// device they're on. The server only keeps them; clients apply them.
+#[derive(Clone, Default, Deserialize, Serialize)]
+pub struct UserPreferences {
+    #[serde(default)]
+    pub theme: Option<String>,
+    #[serde(default)]
+    pub notifications: Notifications,
+    // Rooms they aren't notified about at all, by name
+    #[serde(default)]
+    pub muted_rooms: BTreeSet<String>,
+}
+impl UserPreferences {
+    // Settles on canonical room names and checks the rest makes sense
+    fn validate(&mut self) -> Result<(), ProtocolError> {
+        let bad = |message: String| ProtocolError::new("bad_preferences", message);
+        if let Some(theme) = &self.theme {
+            if theme.is_empty() || theme.chars().count() > MAX_THEME_LEN {
+                return Err(bad(format!("A theme is 1 to {} characters", MAX_THEME_LEN)));
+            }
+        }
+        if self.muted_rooms.len() > MAX_MUTED_ROOMS {
+            return Err(bad(format!("Up to {} muted rooms", MAX_MUTED_ROOMS)));
+        }
+        self.muted_rooms = self
+            .muted_rooms
+            .iter()
+            .map(|room| registry::normalize(room))
+            .collect::<Result<_, _>>()?;
+        Ok(())
+    }
+}
+
+// Preferences - each user's preferences, by id, in memory like read
+// markers are. A user's open socket hears when they change.
+pub struct Preferences {
+    saved: HashMap<Uuid, UserPreferences>,
+    services: Services,
+}
+impl Actor for Preferences {}
+impl Preferences {
+    pub fn new(services: Services) -> Self {
+        Self {
+            saved: HashMap::new(),
+            services,
+        }
+    }
+}
+
+// GetPreferences - a user's preferences, the defaults if they never set any
+pub struct GetPreferences(pub Uuid);
+impl Message for GetPreferences {
+    type Result = UserPreferences;
+}
+#[async_trait::async_trait]
+impl Handler<GetPreferences> for Preferences {
+    async fn handle(&mut self, msg: GetPreferences, _ctx: &mut Context<Self>) -> UserPreferences {
+        let _timer = metrics::timer("get_preferences");
+        self.saved.get(&msg.0).cloned().unwrap_or_default()
+    }
+}
+
+// SetPreferences - replaces a user's preferences, answering with them as
+// kept, and sends them to the user's socket as preferences_updated
+pub struct SetPreferences(pub Uuid, pub UserPreferences);
+impl Message for SetPreferences {
+    type Result = Result<UserPreferences, ProtocolError>;
+}
+#[async_trait::async_trait]
+impl Handler<SetPreferences> for Preferences {
+    async fn handle(
+        &mut self,
+        msg: SetPreferences,
+        _ctx: &mut Context<Self>,
+    ) -> Result<UserPreferences, ProtocolError> {
+        let _timer = metrics::timer("set_preferences");
+        let SetPreferences(user, mut preferences) = msg;
+        preferences.validate()?;
+        self.saved.insert(user, preferences.clone());
+
+        let open = match self
+            .services
+            .get::<Connections>()
+            .send(GetConnections(vec![user]))
+            .await
+        {
+            Ok(open) => open,
+            Err(_) => {
+                log::warn!("Could not reach connections");
+                return Ok(preferences);
+            }
+        };
+        let event = ServerEvent::PreferencesUpdated(&preferences).to_json();
+        for (_, connection) in open {
+            // Gone since, and so is whoever it was for
+            let _ = connection.do_send(Tell(event.clone()));
+        }
+        Ok(preferences)
+    }
+}
+
+// ForgetPreferences - drops a purged user's preferences
+pub struct ForgetPreferences(pub Uuid);
+impl Message for ForgetPreferences {
+    type Result = ();
+}
+#[async_trait::async_trait]
+impl Handler<ForgetPreferences> for Preferences {
+    async fn handle(&mut self, msg: ForgetPreferences, _ctx: &mut Context<Self>) {
+        let _timer = metrics::timer("forget_preferences");
+        self.saved.remove(&msg.0);
+    }
+}
+
+// GET and PUT /users/me/preferences, for the browser's own user id
+pub fn routes(
+    preferences: Address<Preferences>,
+    signer: Arc<Signer>,
+) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    let preferences = warp::any().map(move || preferences.clone());
+    let get = warp::path!("users" / "me" / "preferences")
+        .and(warp::get())
+        .and(identity::id(signer.clone()))
+        .and(preferences.clone())
+        .and_then(get_preferences);
+    let put = warp::path!("users" / "me" / "preferences")
+        .and(warp::put())
+        .and(identity::id(signer))
+        .and(warp::body::json())
+        .and(preferences)
+        .and_then(put_preferences);
+    get.or(put)
+}
+
+async fn get_preferences(
+    user: Option<Uuid>,
+    preferences: Address<Preferences>,
+) -> Result<impl Reply, Rejection> {
+    let user = match user {
+        Some(user) => user,
+        None => return Ok(identity::unknown()),
+    };
+    let saved = preferences
+        .send(GetPreferences(user))
+        .await
+        .expect("Could not get preferences");
+    Ok(warp::reply::with_status(
+        warp::reply::json(&saved),
+        StatusCode::OK,
+    ))
+}
+
+async fn put_preferences(
+    user: Option<Uuid>,
+    body: UserPreferences,
+    preferences: Address<Preferences>,
+) -> Result<impl Reply, Rejection> {
+    let user = match user {
+        Some(user) => user,
+        None => return Ok(identity::unknown()),
+    };
+    let saved = preferences
+        .send(SetPreferences(user, body))
+        .await
+        .expect("Could not set preferences");
+    Ok(match saved {
+        Ok(saved) => warp::reply::with_status(warp::reply::json(&saved), StatusCode::OK),
+        Err(e) => warp::reply::with_status(warp::reply::json(&e), StatusCode::BAD_REQUEST),
+    })
+}
@@ -0,0 +1,262 @@
+use std::convert::Infallible;
+use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
+use std::sync::Arc;
+use warp::http::HeaderMap;
+use warp::Filter;
+
+// Proxy - a peer whose forwarding headers we believe
+#[derive(Debug)]
+pub enum Proxy {
+    // `*`: anyone, including peers on unix sockets, which have no address
+    Any,
+    Addr(IpAddr),
+}
+impl FromStr for Proxy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s == "*" {
+            Ok(Proxy::Any)
+        } else {
+            s.parse()
+                .map(Proxy::Addr)
+                .map_err(|e| format!("{}: {}", s, e))
+        }
+    }
+}
+
+// Peer - who we're talking to, after taking trusted proxies into account
+pub struct Peer {
+    pub addr: Option<IpAddr>,
+    pub secure: bool,
+    pub host: Option<String>,
+    // Why the connection policy has its doubts about the address, if it
+    // let it in anyway
+    pub flagged: Option<String>,
+    // What the client says it is
+    pub user_agent: Option<String>,
+}
+impl Peer {
+    // Where the browser should open its websocket, if we know our own host.
+    // The host ends up inside the page, so anything that isn't plainly a
+    // hostname and port is ignored.
+    pub fn ws_url(&self) -> Option<String> {
+        let scheme = if self.secure { "wss" } else { "ws" };
+        self.host
+            .as_ref()
+            .filter(|host| {
+                host.chars()
+                    .all(|c| c.is_ascii_alphanumeric() || "-.:[]".contains(c))
+            })
+            .map(|host| format!("{}://{}/ws", scheme, host))
+    }
+}
+
+fn header<'a>(headers: &'a HeaderMap, name: &str) -> Option<&'a str> {
+    headers
+        .get(name)
+        .and_then(|value| value.to_str().ok())
+        .map(str::trim)
+}
+
+// Hop - one proxy's say on where a request came from
+#[derive(Debug, Default)]
+struct Hop {
+    addr: Option<String>,
+    proto: Option<String>,
+    host: Option<String>,
+}
+
+// The hops of an RFC 7239 `Forwarded` header, the client's end first
+fn forwarded(value: &str) -> Vec<Hop> {
+    value
+        .split(',')
+        .map(|element| {
+            let mut hop = Hop::default();
+            for pair in element.split(';') {
+                let mut parts = pair.trim().splitn(2, '=');
+                let key = parts.next().unwrap_or("").to_ascii_lowercase();
+                let value = parts.next().unwrap_or("").trim_matches('"').to_string();
+                match key.as_str() {
+                    "for" => hop.addr = Some(value),
+                    "proto" => hop.proto = Some(value),
+                    "host" => hop.host = Some(value),
+                    _ => {}
+                }
+            }
+            hop
+        })
+        .collect()
+}
+
+// The hops of an `X-Forwarded-For` header, the client's end first
+fn forwarded_for(value: &str) -> Vec<Hop> {
+    value
+        .split(',')
+        .map(|addr| Hop {
+            addr: Some(addr.trim().to_string()),
+            ..Hop::default()
+        })
+        .collect()
+}
+
+fn trusts(trusted: &[Proxy], addr: Option<IpAddr>) -> bool {
+    trusted.iter().any(|proxy| match proxy {
+        Proxy::Any => true,
+        Proxy::Addr(proxy) => Some(*proxy) == addr,
+    })
+}
+
+// The hop the client is at. Proxies add theirs to the end, and anyone can
+// put whatever they like in front of that, so this walks back from our end
+// and stops at the first address that isn't a proxy we trust. If all of
+// them are, the client is the first.
+fn client(trusted: &[Proxy], hops: Vec<Hop>) -> Hop {
+    let mut first = Hop::default();
+    for hop in hops.into_iter().rev() {
+        if !trusts(trusted, hop.addr.as_deref().and_then(parse_ip)) {
+            return hop;
+        }
+        first = hop;
+    }
+    first
+}
+
+// `1.2.3.4`, `1.2.3.4:5678`, `[::1]` and `[::1]:5678` all come through here
+fn parse_ip(value: &str) -> Option<IpAddr> {
+    value
+        .parse()
+        .ok()
+        .or_else(|| value.parse::<SocketAddr>().ok().map(|addr| addr.ip()))
+        .or_else(|| value.trim_matches(|c| c == '[' || c == ']').parse().ok())
+}
+
+fn resolve(trusted: &[Proxy], remote: Option<SocketAddr>, headers: &HeaderMap) -> Peer {
+    let remote = remote.map(|addr| addr.ip());
+    let host = header(headers, "host").map(str::to_string);
+    let user_agent = header(headers, "user-agent").map(str::to_string);
+    if !trusts(trusted, remote) {
+        return Peer {
+            addr: remote,
+            secure: false,
+            host,
+            flagged: None,
+            user_agent,
+        };
+    }
+
+    let (addr, proto, forwarded_host) = match header(headers, "forwarded") {
+        Some(value) => {
+            let hop = client(trusted, forwarded(value));
+            (hop.addr, hop.proto, hop.host)
+        }
+        None => (
+            header(headers, "x-forwarded-for")
+                .and_then(|value| client(trusted, forwarded_for(value)).addr),
+            header(headers, "x-forwarded-proto").map(str::to_string),
+            header(headers, "x-forwarded-host").map(str::to_string),
+        ),
+    };
+    Peer {
+        addr: addr.as_deref().and_then(parse_ip).or(remote),
+        secure: proto.is_some_and(|p| p.eq_ignore_ascii_case("https")),
+        host: forwarded_host.or(host),
+        flagged: None,
+        user_agent,
+    }
+}
+
+pub fn peer(
+    trusted: Arc<Vec<Proxy>>,
+) -> impl Filter<Extract = (Peer,), Error = Infallible> + Clone {
+    warp::addr::remote()
+        .and(warp::header::headers_cloned())
+        .map(move |remote, headers: HeaderMap| resolve(&trusted, remote, &headers))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use warp::http::HeaderValue;
+
+    fn proxies(addrs: &[&str]) -> Vec<Proxy> {
+        addrs.iter().map(|addr| addr.parse().unwrap()).collect()
+    }
+
+    fn resolved(trusted: &[Proxy], remote: &str, headers: &[(&'static str, &'static str)]) -> Peer {
+        let mut map = HeaderMap::new();
+        for (name, value) in headers {
+            map.insert(*name, HeaderValue::from_static(value));
+        }
+        resolve(trusted, Some(remote.parse().unwrap()), &map)
+    }
+
+    fn ip(addr: &str) -> Option<IpAddr> {
+        Some(addr.parse().unwrap())
+    }
+
+    #[test]
+    fn untrusted_peers_are_taken_at_their_address() {
+        let peer = resolved(
+            &proxies(&["10.0.0.1"]),
+            "5.5.5.5:1000",
+            &[("x-forwarded-for", "1.2.3.4")],
+        );
+        assert_eq!(peer.addr, ip("5.5.5.5"));
+    }
+
+    #[test]
+    fn spoofed_hops_in_front_are_skipped() {
+        // nginx's proxy_add_x_forwarded_for keeps what the client sent
+        let trusted = proxies(&["10.0.0.1"]);
+        let headers = [("x-forwarded-for", "6.6.6.6, 1.2.3.4")];
+        let peer = resolved(&trusted, "10.0.0.1:1000", &headers);
+        assert_eq!(peer.addr, ip("1.2.3.4"));
+
+        let headers = [(
+            "forwarded",
+            "for=6.6.6.6;proto=https, for=1.2.3.4;proto=http",
+        )];
+        let peer = resolved(&trusted, "10.0.0.1:1000", &headers);
+        assert_eq!(peer.addr, ip("1.2.3.4"));
+        assert!(!peer.secure);
+    }
+
+    #[test]
+    fn trusted_proxies_in_the_chain_are_walked_past() {
+        let trusted = proxies(&["10.0.0.1", "10.0.0.2"]);
+        let headers = [("x-forwarded-for", "6.6.6.6, 1.2.3.4, 10.0.0.2")];
+        let peer = resolved(&trusted, "10.0.0.1:1000", &headers);
+        assert_eq!(peer.addr, ip("1.2.3.4"));
+
+        let headers = [(
+            "forwarded",
+            "for=6.6.6.6, for=\"[2001:db8::1]:4711\";proto=https;host=chat.example, for=10.0.0.2",
+        )];
+        let peer = resolved(&trusted, "10.0.0.1:1000", &headers);
+        assert_eq!(peer.addr, ip("2001:db8::1"));
+        assert!(peer.secure);
+        assert_eq!(peer.host.as_deref(), Some("chat.example"));
+    }
+
+    #[test]
+    fn with_every_hop_trusted_the_client_is_the_first() {
+        let peer = resolved(
+            &proxies(&["*"]),
+            "10.0.0.1:1000",
+            &[("x-forwarded-for", "1.2.3.4, 10.0.0.2")],
+        );
+        assert_eq!(peer.addr, ip("1.2.3.4"));
+    }
+
+    #[test]
+    fn hops_that_are_not_addresses_fall_back_to_the_peer() {
+        let peer = resolved(
+            &proxies(&["10.0.0.1"]),
+            "10.0.0.1:1000",
+            &[("forwarded", "for=1.2.3.4, for=unknown")],
+        );
+        assert_eq!(peer.addr, ip("10.0.0.1"));
+    }
+}
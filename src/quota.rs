@@ -0,0 +1,176 @@
+use serde::Serialize;
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+use uuid::Uuid;
+use warp::http::StatusCode;
+use warp::{Filter, Rejection, Reply};
+use xtra::prelude::*;
+
+use crate::identity::{self, Signer};
+use crate::{metrics, now_millis, ProtocolError};
+
+const DAY_MS: u64 = 24 * 60 * 60 * 1000;
+
+// Quota - how much each user may post in a UTC day; no limit when unset
+#[derive(Clone, Copy, Default)]
+pub struct Quota {
+    pub messages: Option<u64>,
+    // Message bodies, in bytes, since that's what the server stores
+    pub bytes: Option<u64>,
+}
+
+#[derive(Clone, Copy, Default)]
+struct Used {
+    messages: u64,
+    bytes: u64,
+}
+
+// Quotas - what each user has posted today. Everyone starts over at
+// midnight UTC.
+pub struct Quotas {
+    quota: Quota,
+    // Days since the epoch that `used` is for
+    day: u64,
+    used: HashMap<Uuid, Used>,
+}
+impl Actor for Quotas {}
+impl Quotas {
+    pub fn new(quota: Quota) -> Self {
+        Self {
+            quota,
+            day: 0,
+            used: HashMap::new(),
+        }
+    }
+
+    // Forgets yesterday's usage, come `now_ms` on a new day
+    fn roll(&mut self, now_ms: u64) {
+        let day = now_ms / DAY_MS;
+        if day != self.day {
+            self.day = day;
+            self.used.clear();
+        }
+    }
+
+    // The user's usage as of `now_ms`
+    fn today(&mut self, user: Uuid, now_ms: u64) -> &mut Used {
+        self.roll(now_ms);
+        self.used.entry(user).or_default()
+    }
+}
+
+// Spend - a user posting a message of `bytes`, if their quota allows
+pub struct Spend {
+    pub user: Uuid,
+    pub bytes: u64,
+}
+impl Message for Spend {
+    type Result = Result<(), ProtocolError>;
+}
+#[async_trait::async_trait]
+impl Handler<Spend> for Quotas {
+    async fn handle(&mut self, msg: Spend, _ctx: &mut Context<Self>) -> Result<(), ProtocolError> {
+        let _timer = metrics::timer("spend");
+        let now_ms = now_millis();
+        let quota = self.quota;
+        let used = self.today(msg.user, now_ms);
+        let over = quota
+            .messages
+            .is_some_and(|limit| used.messages + 1 > limit)
+            || quota
+                .bytes
+                .is_some_and(|limit| used.bytes + msg.bytes > limit);
+        if over {
+            let midnight = (now_ms / DAY_MS + 1) * DAY_MS;
+            return Err(ProtocolError::new(
+                "quota_exceeded",
+                "You've posted all you can for today".to_string(),
+            )
+            .retryable(Some(Duration::from_millis(midnight - now_ms))));
+        }
+        used.messages += 1;
+        used.bytes += msg.bytes;
+        Ok(())
+    }
+}
+
+// Refund - gives back a Spend for a message that wasn't posted after all
+pub struct Refund {
+    pub user: Uuid,
+    pub bytes: u64,
+}
+impl Message for Refund {
+    type Result = ();
+}
+#[async_trait::async_trait]
+impl Handler<Refund> for Quotas {
+    async fn handle(&mut self, msg: Refund, _ctx: &mut Context<Self>) {
+        let _timer = metrics::timer("refund");
+        let used = self.today(msg.user, now_millis());
+        used.messages = used.messages.saturating_sub(1);
+        used.bytes = used.bytes.saturating_sub(msg.bytes);
+    }
+}
+
+// Usage - what GET /users/me/usage answers with. Limits are null when
+// there's none.
+#[derive(Serialize)]
+pub struct Usage {
+    messages: u64,
+    messages_limit: Option<u64>,
+    bytes: u64,
+    bytes_limit: Option<u64>,
+    // Unix millis when it starts over
+    resets_at: u64,
+}
+
+// GetUsage - where a user stands today
+pub struct GetUsage(pub Uuid);
+impl Message for GetUsage {
+    type Result = Usage;
+}
+#[async_trait::async_trait]
+impl Handler<GetUsage> for Quotas {
+    async fn handle(&mut self, msg: GetUsage, _ctx: &mut Context<Self>) -> Usage {
+        let _timer = metrics::timer("get_usage");
+        let now_ms = now_millis();
+        let quota = self.quota;
+        self.roll(now_ms);
+        let used = self.used.get(&msg.0).copied().unwrap_or_default();
+        Usage {
+            messages: used.messages,
+            messages_limit: quota.messages,
+            bytes: used.bytes,
+            bytes_limit: quota.bytes,
+            resets_at: (now_ms / DAY_MS + 1) * DAY_MS,
+        }
+    }
+}
+
+// GET /users/me/usage, for the browser's own user id
+pub fn routes(
+    quotas: Address<Quotas>,
+    signer: Arc<Signer>,
+) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    warp::path!("users" / "me" / "usage")
+        .and(warp::get())
+        .and(identity::id(signer))
+        .and(warp::any().map(move || quotas.clone()))
+        .and_then(usage)
+}
+
+async fn usage(user: Option<Uuid>, quotas: Address<Quotas>) -> Result<impl Reply, Rejection> {
+    let user = match user {
+        Some(user) => user,
+        None => return Ok(identity::unknown()),
+    };
+    let usage = quotas
+        .send(GetUsage(user))
+        .await
+        .expect("Could not get usage");
+    Ok(warp::reply::with_status(
+        warp::reply::json(&usage),
+        StatusCode::OK,
+    ))
+}
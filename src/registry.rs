@@ -0,0 +1,1161 @@
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::convert::TryFrom;
+use uuid::Uuid;
+use warp::http::StatusCode;
+use warp::{Filter, Rejection, Reply};
+use xtra::prelude::*;
+use xtra::spawn::Tokio;
+
+use crate::commands::{self, Webhook};
+use crate::export::{Export, Exporter};
+use crate::moderation::ModerationQueue;
+use crate::names::{self, MAX_NAME_LEN};
+use crate::page::{self, PageQuery};
+use crate::permissions::{self, Permissions};
+use crate::room::{Effect, RoomState};
+use crate::schedule::Offset;
+use crate::spam::LinkPolicy;
+use crate::stats::Stats;
+use crate::template;
+use crate::{
+    metrics, now_millis, AddThread, Archive, ChatMessage, Configure, GetPreview, ProtocolError,
+    Rename, Room, SetFeatures, SetTimezone,
+};
+
+#[derive(Clone, Copy, Default, Deserialize, Serialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Visibility {
+    // Listed in GET /rooms
+    #[default]
+    Public,
+    // Joinable by name only
+    Private,
+}
+
+// JoinPolicy - who gets in when they ask
+#[derive(Clone, Copy, Debug, Default, Deserialize, Serialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum JoinPolicy {
+    // Anyone
+    #[default]
+    Open,
+    // Anyone a moderator lets in; joiners wait until then
+    Approval,
+    // Only those with an invite code
+    Invite,
+}
+
+// RoomFeatures - which optional parts of chat a room has turned on, as a
+// list of names on the wire. Rooms start out with all of them.
+#[derive(Clone, Copy, Deserialize, Serialize, PartialEq)]
+#[serde(try_from = "Vec<String>", into = "Vec<String>")]
+pub struct RoomFeatures(u8);
+impl RoomFeatures {
+    pub const THREADS: RoomFeatures = RoomFeatures(1);
+    // History pages, and replays on join
+    pub const HISTORY: RoomFeatures = RoomFeatures(1 << 1);
+    const NAMED: [(&'static str, RoomFeatures); 2] =
+        [("threads", Self::THREADS), ("history", Self::HISTORY)];
+
+    pub fn has(self, feature: RoomFeatures) -> bool {
+        self.0 & feature.0 == feature.0
+    }
+
+    // Why an event needing `feature` was refused
+    pub fn disabled(feature: RoomFeatures, room: &str) -> ProtocolError {
+        let (name, _) = Self::NAMED
+            .iter()
+            .find(|(_, named)| *named == feature)
+            .expect("Features are all named");
+        ProtocolError::new(
+            "feature_disabled",
+            format!("{} has {} turned off", room, name),
+        )
+    }
+}
+impl Default for RoomFeatures {
+    fn default() -> Self {
+        RoomFeatures(
+            Self::NAMED
+                .iter()
+                .fold(0, |bits, (_, feature)| bits | feature.0),
+        )
+    }
+}
+impl TryFrom<Vec<String>> for RoomFeatures {
+    type Error = String;
+
+    fn try_from(names: Vec<String>) -> Result<Self, Self::Error> {
+        names.iter().try_fold(RoomFeatures(0), |features, name| {
+            match Self::NAMED.iter().find(|(named, _)| named == name) {
+                Some((_, feature)) => Ok(RoomFeatures(features.0 | feature.0)),
+                None => Err(format!("{}: expected threads or history", name)),
+            }
+        })
+    }
+}
+impl From<RoomFeatures> for Vec<String> {
+    fn from(features: RoomFeatures) -> Self {
+        RoomFeatures::NAMED
+            .iter()
+            .filter(|(_, feature)| features.has(*feature))
+            .map(|(name, _)| name.to_string())
+            .collect()
+    }
+}
+
+// RoomSettings - everything a room is created with
+#[derive(Clone, Deserialize, Serialize)]
+pub struct RoomSettings {
+    pub name: String,
+    #[serde(default)]
+    pub visibility: Visibility,
+    // Joins are refused once this many are in
+    #[serde(default)]
+    pub max_members: Option<usize>,
+    // Seconds each member has to wait between messages
+    #[serde(default)]
+    pub slow_mode_secs: Option<u64>,
+    // Messages older than this many seconds drop out of history
+    #[serde(default)]
+    pub retention_secs: Option<u64>,
+    // Turned on and off by the room's admins, after it's created too
+    #[serde(default)]
+    pub features: RoomFeatures,
+    // Whether room keys may post and join here
+    #[serde(default = "bots_allowed")]
+    pub bots_allowed: bool,
+    // Seconds between `@all` mentions, from anyone
+    #[serde(default = "mention_cooldown_secs")]
+    pub mention_cooldown_secs: u64,
+    #[serde(default)]
+    pub join_policy: JoinPolicy,
+    // What transcripts show times in
+    #[serde(default)]
+    pub timezone: Offset,
+    // Sent to each joiner, with `template` variables; CHAT_MOTD if unset
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub motd: Option<String>,
+    // Roles members can be given, by name, besides (or in place of) the
+    // built-in member and moderator
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub roles: BTreeMap<String, Permissions>,
+    // Slash commands, by name, and the webhooks they're sent to
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub commands: BTreeMap<String, Webhook>,
+    // The room a thread hangs off; threads are made with CreateThread
+    #[serde(skip_deserializing, skip_serializing_if = "Option::is_none")]
+    pub parent: Option<String>,
+}
+fn bots_allowed() -> bool {
+    true
+}
+fn mention_cooldown_secs() -> u64 {
+    5 * 60
+}
+
+impl RoomSettings {
+    pub fn named(name: &str) -> Self {
+        Self {
+            name: name.to_string(),
+            visibility: Visibility::Public,
+            max_members: None,
+            slow_mode_secs: None,
+            retention_secs: None,
+            features: RoomFeatures::default(),
+            bots_allowed: true,
+            mention_cooldown_secs: mention_cooldown_secs(),
+            join_policy: JoinPolicy::Open,
+            timezone: Offset::default(),
+            motd: None,
+            roles: BTreeMap::new(),
+            commands: BTreeMap::new(),
+            parent: None,
+        }
+    }
+
+    // Settles on the canonical name and checks the rest makes sense
+    fn validate(&mut self) -> Result<(), ProtocolError> {
+        self.name = room_name(&self.name)?;
+        if self.max_members == Some(0) {
+            return Err(ProtocolError::new(
+                "invalid_settings",
+                "max_members must be at least 1".to_string(),
+            ));
+        }
+        if let Some(motd) = &self.motd {
+            template::check(motd)
+                .map_err(|e| ProtocolError::new("invalid_settings", format!("motd: {}", e)))?;
+        }
+        for role in self.roles.keys() {
+            permissions::check_role(role)?;
+        }
+        for command in self.commands.keys() {
+            commands::check_command(command)?;
+        }
+        Ok(())
+    }
+}
+
+fn room_name(name: &str) -> Result<String, ProtocolError> {
+    names::normalize(name).ok_or_else(|| {
+        ProtocolError::new(
+            "invalid_name",
+            format!("Room names are 1 to {} of a-z, 0-9, - and _", MAX_NAME_LEN),
+        )
+    })
+}
+
+// The canonical form of a room name, which is what rooms are known by.
+// Threads are `room:topic`, each half normalized on its own.
+pub fn normalize(name: &str) -> Result<String, ProtocolError> {
+    match name.split_once(':') {
+        Some((room, topic)) => Ok(format!("{}:{}", room_name(room)?, room_name(topic)?)),
+        None => room_name(name),
+    }
+}
+
+// RoomRegistry - owns every room, by name
+pub struct RoomRegistry {
+    rooms: HashMap<String, (Address<Room>, RoomSettings)>,
+    // Other names for rooms, old ones included, to their current name.
+    // Threads go by their room's aliases too.
+    aliases: HashMap<String, String>,
+    // Rooms and threads whose actors have stopped, with what they had, to
+    // start them again from. Their names and aliases stay theirs.
+    archived: HashMap<String, (RoomSettings, RoomState)>,
+    moderation: Address<ModerationQueue>,
+    exporter: Option<Address<Exporter>>,
+    stats: Address<Stats>,
+    link_policy: Option<LinkPolicy>,
+    // Hosts command webhooks may go to
+    webhook_hosts: Vec<String>,
+    // For rooms without a MOTD of their own
+    motd: Option<String>,
+    // Settings rooms can be created from, by template name. A template's
+    // own `name` is that name.
+    templates: BTreeMap<String, RoomSettings>,
+}
+impl Actor for RoomRegistry {}
+impl RoomRegistry {
+    pub fn new(
+        moderation: Address<ModerationQueue>,
+        exporter: Option<Address<Exporter>>,
+        stats: Address<Stats>,
+        link_policy: Option<LinkPolicy>,
+        webhook_hosts: Vec<String>,
+        motd: Option<String>,
+    ) -> Self {
+        Self {
+            rooms: HashMap::new(),
+            aliases: HashMap::new(),
+            archived: HashMap::new(),
+            moderation,
+            exporter,
+            stats,
+            link_policy,
+            webhook_hosts,
+            motd,
+            templates: BTreeMap::new(),
+        }
+    }
+
+    fn create(&mut self, mut settings: RoomSettings) -> Result<RoomSettings, ProtocolError> {
+        settings.validate()?;
+        self.check_webhooks(&settings)?;
+        if self.taken(&settings.name) {
+            return Err(ProtocolError::new(
+                "name_taken",
+                format!("There is already a room called {}", settings.name),
+            ));
+        }
+
+        let addr = self.spawn(&settings);
+        self.rooms
+            .insert(settings.name.clone(), (addr, settings.clone()));
+        log::info!("Created room {}", settings.name);
+        Ok(settings)
+    }
+
+    // The current name of the room `name` is one of the names of
+    fn resolve(&self, name: &str) -> Option<String> {
+        self.current(name)
+            .filter(|name| self.rooms.contains_key(name))
+    }
+
+    // Like resolve, for an archived room
+    fn resolve_archived(&self, name: &str) -> Option<String> {
+        self.current(name)
+            .filter(|name| self.archived.contains_key(name))
+    }
+
+    // What `name` is now called, if it's an alias
+    fn current(&self, name: &str) -> Option<String> {
+        let name = normalize(name).ok()?;
+        Some(match name.split_once(':') {
+            Some((room, topic)) => match self.aliases.get(room) {
+                Some(room) => format!("{}:{}", room, topic),
+                None => name,
+            },
+            None => self.aliases.get(&name).cloned().unwrap_or(name),
+        })
+    }
+
+    // The current name of a room that isn't a thread, for renaming and
+    // aliases; threads go by their room's
+    fn top_level(&self, name: &str) -> Result<String, ProtocolError> {
+        let name = self
+            .resolve(name)
+            .ok_or_else(|| ProtocolError::new("no_such_room", format!("No such room: {}", name)))?;
+        if name.contains(':') {
+            return Err(ProtocolError::new(
+                "invalid_settings",
+                "Threads are named after their room".to_string(),
+            ));
+        }
+        Ok(name)
+    }
+
+    // Webhooks only go where the server allows, or anyone creating a room
+    // could have us POST to whatever we can reach
+    fn check_webhooks(&self, settings: &RoomSettings) -> Result<(), ProtocolError> {
+        match settings.commands.values().find(|webhook| {
+            !self
+                .webhook_hosts
+                .iter()
+                .any(|host| host == webhook.hostname())
+        }) {
+            Some(webhook) => Err(ProtocolError::new(
+                "invalid_settings",
+                format!("{}: that host isn't allowed webhooks", webhook.hostname()),
+            )),
+            None => Ok(()),
+        }
+    }
+
+    // Whether a new room or alias can't be called `name`
+    fn taken(&self, name: &str) -> bool {
+        self.rooms.contains_key(name)
+            || self.aliases.contains_key(name)
+            || self.archived.contains_key(name)
+    }
+
+    // The settings a room goes by, which get the server's MOTD if they have
+    // none
+    fn effective(&self, settings: &RoomSettings) -> RoomSettings {
+        RoomSettings {
+            motd: settings.motd.clone().or_else(|| self.motd.clone()),
+            ..settings.clone()
+        }
+    }
+
+    // A room for `settings`
+    fn spawn(&self, settings: &RoomSettings) -> Address<Room> {
+        self.start(RoomState::new(self.effective(settings), self.link_policy))
+    }
+
+    // A room carrying on from `state`
+    fn start(&self, state: RoomState) -> Address<Room> {
+        Room::new(
+            state,
+            self.moderation.clone(),
+            self.exporter.clone(),
+            self.stats.clone(),
+        )
+        .create(None)
+        .spawn(&mut Tokio::Global)
+    }
+}
+
+// CreateRoom - answers with the new room's settings
+pub struct CreateRoom(pub RoomSettings);
+impl Message for CreateRoom {
+    type Result = Result<RoomSettings, ProtocolError>;
+}
+#[async_trait::async_trait]
+impl Handler<CreateRoom> for RoomRegistry {
+    async fn handle(
+        &mut self,
+        msg: CreateRoom,
+        _ctx: &mut Context<Self>,
+    ) -> Result<RoomSettings, ProtocolError> {
+        let _timer = metrics::timer("create_room");
+        self.create(msg.0)
+    }
+}
+
+// SaveTemplate - adds a room template, or replaces the one of that name,
+// answering with it as saved
+pub struct SaveTemplate(pub RoomSettings);
+impl Message for SaveTemplate {
+    type Result = Result<RoomSettings, ProtocolError>;
+}
+#[async_trait::async_trait]
+impl Handler<SaveTemplate> for RoomRegistry {
+    async fn handle(
+        &mut self,
+        msg: SaveTemplate,
+        _ctx: &mut Context<Self>,
+    ) -> Result<RoomSettings, ProtocolError> {
+        let _timer = metrics::timer("save_template");
+        let mut template = msg.0;
+        template.validate()?;
+        self.check_webhooks(&template)?;
+        self.templates
+            .insert(template.name.clone(), template.clone());
+        Ok(template)
+    }
+}
+
+// RemoveTemplate - answers whether there was such a template. Rooms made
+// from it keep their settings.
+pub struct RemoveTemplate(pub String);
+impl Message for RemoveTemplate {
+    type Result = bool;
+}
+#[async_trait::async_trait]
+impl Handler<RemoveTemplate> for RoomRegistry {
+    async fn handle(&mut self, msg: RemoveTemplate, _ctx: &mut Context<Self>) -> bool {
+        let _timer = metrics::timer("remove_template");
+        match names::normalize(&msg.0) {
+            Some(name) => self.templates.remove(&name).is_some(),
+            None => false,
+        }
+    }
+}
+
+// ListTemplates - every template, by name
+pub struct ListTemplates;
+impl Message for ListTemplates {
+    type Result = Vec<RoomSettings>;
+}
+#[async_trait::async_trait]
+impl Handler<ListTemplates> for RoomRegistry {
+    async fn handle(&mut self, _msg: ListTemplates, _ctx: &mut Context<Self>) -> Vec<RoomSettings> {
+        let _timer = metrics::timer("list_templates");
+        self.templates.values().cloned().collect()
+    }
+}
+
+// CreateFromTemplate - a new room called `name` with a template's
+// settings, answering with them
+pub struct CreateFromTemplate {
+    pub template: String,
+    pub name: String,
+}
+impl Message for CreateFromTemplate {
+    type Result = Result<RoomSettings, ProtocolError>;
+}
+#[async_trait::async_trait]
+impl Handler<CreateFromTemplate> for RoomRegistry {
+    async fn handle(
+        &mut self,
+        msg: CreateFromTemplate,
+        _ctx: &mut Context<Self>,
+    ) -> Result<RoomSettings, ProtocolError> {
+        let _timer = metrics::timer("create_from_template");
+        let template = names::normalize(&msg.template)
+            .and_then(|name| self.templates.get(&name))
+            .ok_or_else(|| {
+                ProtocolError::new(
+                    "no_such_template",
+                    format!("No such template: {}", msg.template),
+                )
+            })?;
+        let settings = RoomSettings {
+            name: msg.name,
+            ..template.clone()
+        };
+        self.create(settings)
+    }
+}
+
+// CreateThread - starts a sub-channel of a room, named `room:topic`. It has
+// its own members and history but the room's settings and bans, and stays
+// out of the public listing.
+pub struct CreateThread {
+    pub room: String,
+    pub topic: String,
+}
+impl Message for CreateThread {
+    type Result = Result<RoomSettings, ProtocolError>;
+}
+#[async_trait::async_trait]
+impl Handler<CreateThread> for RoomRegistry {
+    async fn handle(
+        &mut self,
+        msg: CreateThread,
+        _ctx: &mut Context<Self>,
+    ) -> Result<RoomSettings, ProtocolError> {
+        let _timer = metrics::timer("create_thread");
+        let parent = room_name(&msg.room)?;
+        let parent = self.aliases.get(&parent).cloned().unwrap_or(parent);
+        let (parent_addr, parent_settings) = match self.rooms.get(&parent) {
+            Some((_, settings)) if settings.parent.is_some() => {
+                return Err(ProtocolError::new(
+                    "invalid_settings",
+                    "Threads can't have threads".to_string(),
+                ))
+            }
+            Some(room) => room.clone(),
+            None => {
+                return Err(ProtocolError::new(
+                    "no_such_room",
+                    format!("No such room: {}", parent),
+                ))
+            }
+        };
+        if !parent_settings.features.has(RoomFeatures::THREADS) {
+            return Err(RoomFeatures::disabled(RoomFeatures::THREADS, &parent));
+        }
+        let name = format!("{}:{}", parent, room_name(&msg.topic)?);
+        if self.rooms.contains_key(&name) {
+            return Err(ProtocolError::new(
+                "name_taken",
+                format!("There is already a thread called {}", name),
+            ));
+        }
+
+        let settings = RoomSettings {
+            name: name.clone(),
+            parent: Some(parent),
+            ..parent_settings
+        };
+        let addr = self.spawn(&settings);
+        parent_addr
+            .send(AddThread(addr.clone()))
+            .await
+            .map_err(crate::room_gone)?;
+        self.rooms.insert(name.clone(), (addr, settings.clone()));
+        log::info!("Created thread {}", name);
+        Ok(settings)
+    }
+}
+
+// UpdateFeatures - turns a room's features on and off, and its threads'
+// with it. Answers with the room's settings, or None if there's no such
+// room.
+pub(crate) struct UpdateFeatures {
+    pub room: String,
+    pub features: RoomFeatures,
+}
+impl Message for UpdateFeatures {
+    type Result = Option<RoomSettings>;
+}
+#[async_trait::async_trait]
+impl Handler<UpdateFeatures> for RoomRegistry {
+    async fn handle(
+        &mut self,
+        msg: UpdateFeatures,
+        _ctx: &mut Context<Self>,
+    ) -> Option<RoomSettings> {
+        let _timer = metrics::timer("update_features");
+        let name = self.resolve(&msg.room)?;
+        let settings = self
+            .rooms
+            .get(&name)
+            .map(|(_, settings)| settings.clone())?;
+        for (addr, settings) in self.rooms.values_mut() {
+            if settings.name == name || settings.parent.as_ref() == Some(&name) {
+                settings.features = msg.features;
+                if addr.send(SetFeatures(msg.features)).await.is_err() {
+                    log::warn!("Could not set features of {}", settings.name);
+                }
+            }
+        }
+        Some(RoomSettings {
+            features: msg.features,
+            ..settings
+        })
+    }
+}
+
+// UpdateTimezone - changes what a room's transcripts, and its threads',
+// show times in. Answers with the room's settings, or None if there's no
+// such room.
+pub(crate) struct UpdateTimezone {
+    pub room: String,
+    pub timezone: Offset,
+}
+impl Message for UpdateTimezone {
+    type Result = Option<RoomSettings>;
+}
+#[async_trait::async_trait]
+impl Handler<UpdateTimezone> for RoomRegistry {
+    async fn handle(
+        &mut self,
+        msg: UpdateTimezone,
+        _ctx: &mut Context<Self>,
+    ) -> Option<RoomSettings> {
+        let _timer = metrics::timer("update_timezone");
+        let name = self.resolve(&msg.room)?;
+        let settings = self
+            .rooms
+            .get(&name)
+            .map(|(_, settings)| settings.clone())?;
+        for (addr, settings) in self.rooms.values_mut() {
+            if settings.name == name || settings.parent.as_ref() == Some(&name) {
+                settings.timezone = msg.timezone;
+                if addr.send(SetTimezone(msg.timezone)).await.is_err() {
+                    log::warn!("Could not set timezone of {}", settings.name);
+                }
+            }
+        }
+        Some(RoomSettings {
+            timezone: msg.timezone,
+            ..settings
+        })
+    }
+}
+
+// GetRoom - looks a room up by name, in any of its spellings
+pub(crate) struct GetRoom(pub String);
+impl Message for GetRoom {
+    type Result = Option<Address<Room>>;
+}
+#[async_trait::async_trait]
+impl Handler<GetRoom> for RoomRegistry {
+    async fn handle(&mut self, msg: GetRoom, _ctx: &mut Context<Self>) -> Option<Address<Room>> {
+        let _timer = metrics::timer("get_room");
+        let name = self.resolve(&msg.0)?;
+        self.rooms.get(&name).map(|(addr, _)| addr.clone())
+    }
+}
+
+// FindRoom - like GetRoom, along with the room's current name
+pub(crate) struct FindRoom(pub String);
+impl Message for FindRoom {
+    type Result = Option<(String, Address<Room>)>;
+}
+#[async_trait::async_trait]
+impl Handler<FindRoom> for RoomRegistry {
+    async fn handle(
+        &mut self,
+        msg: FindRoom,
+        _ctx: &mut Context<Self>,
+    ) -> Option<(String, Address<Room>)> {
+        let _timer = metrics::timer("find_room");
+        let name = self.resolve(&msg.0)?;
+        let addr = self.rooms.get(&name).map(|(addr, _)| addr.clone())?;
+        Some((name, addr))
+    }
+}
+
+// RoomNames - every name a room goes by, its current one first. Empty if
+// there's no such room.
+pub(crate) struct RoomNames(pub String);
+impl Message for RoomNames {
+    type Result = Vec<String>;
+}
+#[async_trait::async_trait]
+impl Handler<RoomNames> for RoomRegistry {
+    async fn handle(&mut self, msg: RoomNames, _ctx: &mut Context<Self>) -> Vec<String> {
+        let _timer = metrics::timer("room_names");
+        let name = match self.resolve(&msg.0) {
+            Some(name) => name,
+            None => return Vec::new(),
+        };
+        let (room, topic) = match name.split_once(':') {
+            Some((room, topic)) => (room, Some(topic)),
+            None => (name.as_str(), None),
+        };
+        let mut names = vec![name.clone()];
+        for (alias, current) in self.aliases.iter() {
+            if current == room {
+                names.push(match topic {
+                    Some(topic) => format!("{}:{}", alias, topic),
+                    None => alias.clone(),
+                });
+            }
+        }
+        names
+    }
+}
+
+// RenameRoom - gives a room, and its threads, a new name. The old one
+// stays on as an alias, so links to it keep working. Answers with the
+// room's settings.
+pub(crate) struct RenameRoom {
+    pub room: String,
+    pub name: String,
+}
+impl Message for RenameRoom {
+    type Result = Result<RoomSettings, ProtocolError>;
+}
+#[async_trait::async_trait]
+impl Handler<RenameRoom> for RoomRegistry {
+    async fn handle(
+        &mut self,
+        msg: RenameRoom,
+        _ctx: &mut Context<Self>,
+    ) -> Result<RoomSettings, ProtocolError> {
+        let _timer = metrics::timer("rename_room");
+        let from = self.top_level(&msg.room)?;
+        let to = room_name(&msg.name)?;
+        if to == from {
+            return Ok(self.rooms[&from].1.clone());
+        }
+        // Its own old names are free to go back to
+        if self.rooms.contains_key(&to) || self.aliases.get(&to).is_some_and(|room| *room != from) {
+            return Err(ProtocolError::new(
+                "name_taken",
+                format!("There is already a room called {}", to),
+            ));
+        }
+
+        let renamed: Vec<_> = self
+            .rooms
+            .keys()
+            .filter(|name| {
+                **name == from || name.split_once(':').is_some_and(|(room, _)| room == from)
+            })
+            .cloned()
+            .collect();
+        for old in renamed {
+            let (addr, mut settings) = self.rooms.remove(&old).expect("Room went missing");
+            settings.name = match old.split_once(':') {
+                Some((_, topic)) => format!("{}:{}", to, topic),
+                None => to.clone(),
+            };
+            if settings.parent.is_some() {
+                settings.parent = Some(to.clone());
+            }
+            if addr.send(Rename(settings.name.clone())).await.is_err() {
+                log::warn!("Could not rename {}", old);
+            }
+            self.rooms.insert(settings.name.clone(), (addr, settings));
+        }
+        self.aliases.remove(&to);
+        for room in self.aliases.values_mut() {
+            if *room == from {
+                *room = to.clone();
+            }
+        }
+        self.aliases.insert(from.clone(), to.clone());
+        log::info!("Renamed room {} to {}", from, to);
+        Ok(self.rooms[&to].1.clone())
+    }
+}
+
+// AddAlias - another name for a room
+pub(crate) struct AddAlias {
+    pub room: String,
+    pub alias: String,
+}
+impl Message for AddAlias {
+    type Result = Result<(), ProtocolError>;
+}
+#[async_trait::async_trait]
+impl Handler<AddAlias> for RoomRegistry {
+    async fn handle(
+        &mut self,
+        msg: AddAlias,
+        _ctx: &mut Context<Self>,
+    ) -> Result<(), ProtocolError> {
+        let _timer = metrics::timer("add_alias");
+        let room = self.top_level(&msg.room)?;
+        let alias = room_name(&msg.alias)?;
+        if self.taken(&alias) {
+            return Err(ProtocolError::new(
+                "name_taken",
+                format!("There is already a room called {}", alias),
+            ));
+        }
+        self.aliases.insert(alias, room);
+        Ok(())
+    }
+}
+
+// AllRooms - every room, private ones too, by name
+pub(crate) struct AllRooms;
+impl Message for AllRooms {
+    type Result = Vec<(RoomSettings, Address<Room>)>;
+}
+#[async_trait::async_trait]
+impl Handler<AllRooms> for RoomRegistry {
+    async fn handle(
+        &mut self,
+        _msg: AllRooms,
+        _ctx: &mut Context<Self>,
+    ) -> Vec<(RoomSettings, Address<Room>)> {
+        let _timer = metrics::timer("all_rooms");
+        let mut rooms: Vec<_> = self
+            .rooms
+            .values()
+            .map(|(addr, settings)| (settings.clone(), addr.clone()))
+            .collect();
+        rooms.sort_by(|a, b| a.0.name.cmp(&b.0.name));
+        rooms
+    }
+}
+
+// ArchiveRoom - stops a room and its threads, everyone in them put out,
+// keeping what they had for UnarchiveRoom. Answers with the room's settings.
+pub(crate) struct ArchiveRoom(pub String);
+impl Message for ArchiveRoom {
+    type Result = Result<RoomSettings, ProtocolError>;
+}
+#[async_trait::async_trait]
+impl Handler<ArchiveRoom> for RoomRegistry {
+    async fn handle(
+        &mut self,
+        msg: ArchiveRoom,
+        _ctx: &mut Context<Self>,
+    ) -> Result<RoomSettings, ProtocolError> {
+        let _timer = metrics::timer("archive_room");
+        let name = self.top_level(&msg.0)?;
+        // Threads first, so none is left hanging off a stopped room
+        let mut stopping: Vec<String> = self
+            .rooms
+            .values()
+            .filter(|(_, settings)| settings.parent.as_ref() == Some(&name))
+            .map(|(_, settings)| settings.name.clone())
+            .collect();
+        stopping.push(name.clone());
+        let settings = self.rooms[&name].1.clone();
+        for room in stopping {
+            let (addr, settings) = self.rooms.remove(&room).expect("Room went missing");
+            match addr.send(Archive).await {
+                Ok(state) => {
+                    self.archived.insert(room, (settings, state));
+                }
+                Err(_) => log::warn!("{} stopped before it could be archived", room),
+            }
+        }
+        log::info!("Archived room {}", name);
+        Ok(settings)
+    }
+}
+
+// UnarchiveRoom - starts an archived room and its threads again, as they
+// were. Answers with the room's settings.
+pub(crate) struct UnarchiveRoom(pub String);
+impl Message for UnarchiveRoom {
+    type Result = Result<RoomSettings, ProtocolError>;
+}
+#[async_trait::async_trait]
+impl Handler<UnarchiveRoom> for RoomRegistry {
+    async fn handle(
+        &mut self,
+        msg: UnarchiveRoom,
+        _ctx: &mut Context<Self>,
+    ) -> Result<RoomSettings, ProtocolError> {
+        let _timer = metrics::timer("unarchive_room");
+        let name = self
+            .resolve_archived(&msg.0)
+            .filter(|name| !name.contains(':'))
+            .ok_or_else(|| {
+                ProtocolError::new("no_such_room", format!("No archived room called {}", msg.0))
+            })?;
+        let (settings, state) = self.archived.remove(&name).expect("Room went missing");
+        let room = self.start(state);
+        let threads: Vec<String> = self
+            .archived
+            .values()
+            .filter(|(settings, _)| settings.parent.as_ref() == Some(&name))
+            .map(|(settings, _)| settings.name.clone())
+            .collect();
+        for thread in threads {
+            let (settings, state) = self.archived.remove(&thread).expect("Room went missing");
+            let addr = self.start(state);
+            if room.send(AddThread(addr.clone())).await.is_err() {
+                log::warn!("Could not hang {} off its room", thread);
+            }
+            self.rooms.insert(thread, (addr, settings));
+        }
+        self.rooms.insert(name.clone(), (room, settings.clone()));
+        log::info!("Unarchived room {}", name);
+        Ok(settings)
+    }
+}
+
+// ReadArchive - an archived room's current name, with its visible messages
+// sent in [from, until), unix millis, oldest first, and the timezone to show
+// them in. None if there's no such archived room.
+pub(crate) struct ReadArchive {
+    pub room: String,
+    pub from: u64,
+    pub until: u64,
+}
+impl Message for ReadArchive {
+    type Result = Option<(String, Vec<ChatMessage>, Offset)>;
+}
+#[async_trait::async_trait]
+impl Handler<ReadArchive> for RoomRegistry {
+    async fn handle(
+        &mut self,
+        msg: ReadArchive,
+        _ctx: &mut Context<Self>,
+    ) -> Option<(String, Vec<ChatMessage>, Offset)> {
+        let _timer = metrics::timer("read_archive");
+        let name = self.resolve_archived(&msg.room)?;
+        let (_, state) = self.archived.get_mut(&name)?;
+        let messages = state.transcript(msg.from, msg.until, now_millis());
+        let timezone = state.timezone();
+        Some((name, messages, timezone))
+    }
+}
+
+// PurgeArchived - deletes one user's messages from every archived room, as
+// PurgeMessages does for the rest. Answers with how many went.
+pub(crate) struct PurgeArchived(pub Uuid);
+impl Message for PurgeArchived {
+    type Result = usize;
+}
+#[async_trait::async_trait]
+impl Handler<PurgeArchived> for RoomRegistry {
+    async fn handle(&mut self, msg: PurgeArchived, _ctx: &mut Context<Self>) -> usize {
+        let _timer = metrics::timer("purge_archived");
+        let mut deleted = 0;
+        for (name, (_, state)) in self.archived.iter_mut() {
+            deleted += state.purge(None, Some(msg.0));
+            // Nobody is there to be told, but the export still goes out
+            for effect in state.effects() {
+                if let (Effect::Export(kind, payload), Some(exporter)) = (effect, &self.exporter) {
+                    let export = Export {
+                        room: name.clone(),
+                        kind,
+                        payload,
+                    };
+                    if exporter.do_send(export).is_err() {
+                        log::warn!("Could not export {} event", kind);
+                    }
+                }
+            }
+        }
+        deleted
+    }
+}
+
+// ListRooms - the public rooms, by name
+pub struct ListRooms;
+impl Message for ListRooms {
+    type Result = Vec<RoomSettings>;
+}
+#[async_trait::async_trait]
+impl Handler<ListRooms> for RoomRegistry {
+    async fn handle(&mut self, _msg: ListRooms, _ctx: &mut Context<Self>) -> Vec<RoomSettings> {
+        let _timer = metrics::timer("list_rooms");
+        let mut rooms: Vec<_> = self
+            .rooms
+            .values()
+            .map(|(_, settings)| settings)
+            .filter(|settings| {
+                settings.visibility == Visibility::Public && settings.parent.is_none()
+            })
+            // Where commands go is the room's business
+            .map(|settings| RoomSettings {
+                commands: BTreeMap::new(),
+                ..settings.clone()
+            })
+            .collect();
+        rooms.sort_by(|a, b| a.name.cmp(&b.name));
+        rooms
+    }
+}
+
+// GET /rooms, GET /rooms/:name/preview and POST /rooms
+pub fn routes(
+    registry: Address<RoomRegistry>,
+) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    let registry = warp::any().map(move || registry.clone());
+
+    let list = warp::path!("rooms")
+        .and(warp::get())
+        .and(page::query())
+        .and(registry.clone())
+        .and_then(list_rooms);
+
+    let preview = warp::path!("rooms" / String / "preview")
+        .and(warp::get())
+        .and(registry.clone())
+        .and_then(preview_room);
+
+    let create = warp::path!("rooms")
+        .and(warp::post())
+        .and(warp::body::json())
+        .and(registry)
+        .and_then(create_room);
+
+    list.or(preview).or(create)
+}
+
+// GET /rooms/:name/preview, for deciding whether to join
+async fn preview_room(
+    name: String,
+    registry: Address<RoomRegistry>,
+) -> Result<impl Reply, Rejection> {
+    let (_, room) = registry
+        .send(FindRoom(name))
+        .await
+        .expect("Could not reach the registry")
+        .ok_or_else(warp::reject::not_found)?;
+    let preview = room.send(GetPreview).await.expect("Could not get preview");
+    Ok(warp::reply::json(&preview))
+}
+
+async fn list_rooms(
+    query: PageQuery,
+    registry: Address<RoomRegistry>,
+) -> Result<impl Reply, Rejection> {
+    let rooms = registry
+        .send(ListRooms)
+        .await
+        .expect("Could not list rooms");
+    let page = page::page(rooms, |room| room.name.clone(), &query);
+    Ok(page::reply(page))
+}
+
+async fn create_room(
+    settings: RoomSettings,
+    registry: Address<RoomRegistry>,
+) -> Result<impl Reply, Rejection> {
+    let created = registry
+        .send(CreateRoom(settings))
+        .await
+        .expect("Could not create room");
+    Ok(match created {
+        Ok(settings) => warp::reply::with_status(warp::reply::json(&settings), StatusCode::CREATED),
+        Err(e) => {
+            let status = match e.code {
+                "name_taken" => StatusCode::CONFLICT,
+                _ => StatusCode::BAD_REQUEST,
+            };
+            warp::reply::with_status(warp::reply::json(&e), status)
+        }
+    })
+}
+
+// ExportRooms - every room that isn't a thread, with its aliases, for
+// backing up
+pub(crate) struct ExportRooms;
+impl Message for ExportRooms {
+    type Result = Vec<(RoomSettings, Vec<String>, Address<Room>)>;
+}
+#[async_trait::async_trait]
+impl Handler<ExportRooms> for RoomRegistry {
+    async fn handle(
+        &mut self,
+        _msg: ExportRooms,
+        _ctx: &mut Context<Self>,
+    ) -> Vec<(RoomSettings, Vec<String>, Address<Room>)> {
+        let _timer = metrics::timer("export_rooms");
+        let mut rooms: Vec<_> = self
+            .rooms
+            .values()
+            .filter(|(_, settings)| settings.parent.is_none())
+            .map(|(addr, settings)| {
+                let mut aliases: Vec<_> = self
+                    .aliases
+                    .iter()
+                    .filter(|(_, room)| **room == settings.name)
+                    .map(|(alias, _)| alias.clone())
+                    .collect();
+                aliases.sort();
+                (settings.clone(), aliases, addr.clone())
+            })
+            .collect();
+        rooms.sort_by(|a, b| a.0.name.cmp(&b.0.name));
+        rooms
+    }
+}
+
+// RestoreRooms - rooms from a backup, with their aliases, and templates.
+// Rooms that don't exist are created and those that do take on the
+// backed-up settings, threads too; nothing that's not in the backup is
+// touched. It all goes in or none of it does: answers with the rooms'
+// addresses, in order, or what's wrong with the first thing that won't do.
+pub(crate) struct RestoreRooms {
+    pub rooms: Vec<(RoomSettings, Vec<String>)>,
+    pub templates: Vec<RoomSettings>,
+}
+impl Message for RestoreRooms {
+    type Result = Result<Vec<Address<Room>>, ProtocolError>;
+}
+#[async_trait::async_trait]
+impl Handler<RestoreRooms> for RoomRegistry {
+    async fn handle(
+        &mut self,
+        msg: RestoreRooms,
+        _ctx: &mut Context<Self>,
+    ) -> Result<Vec<Address<Room>>, ProtocolError> {
+        let _timer = metrics::timer("restore_rooms");
+        let RestoreRooms {
+            mut rooms,
+            mut templates,
+        } = msg;
+
+        // Everything is checked before anything changes
+        let taken = |name: &String| {
+            ProtocolError::new(
+                "name_taken",
+                format!("{} is used more than once, or by another room", name),
+            )
+        };
+        let mut names = HashSet::new();
+        for (settings, _) in rooms.iter_mut() {
+            settings.validate()?;
+            self.check_webhooks(settings)?;
+            if !names.insert(settings.name.clone()) || self.aliases.contains_key(&settings.name) {
+                return Err(taken(&settings.name));
+            }
+        }
+        for (settings, aliases) in rooms.iter_mut() {
+            for alias in aliases.iter_mut() {
+                *alias = room_name(alias)?;
+                let elsewhere = self
+                    .aliases
+                    .get(alias)
+                    .is_some_and(|room| *room != settings.name);
+                if !names.insert(alias.clone()) || self.rooms.contains_key(alias) || elsewhere {
+                    return Err(taken(alias));
+                }
+            }
+        }
+        for template in templates.iter_mut() {
+            template.validate()?;
+            self.check_webhooks(template)?;
+        }
+
+        let mut addrs = Vec::new();
+        for (settings, aliases) in rooms {
+            let addr = match self.rooms.get(&settings.name) {
+                Some((addr, _)) => addr.clone(),
+                None => {
+                    let addr = self.spawn(&settings);
+                    self.rooms
+                        .insert(settings.name.clone(), (addr.clone(), settings.clone()));
+                    log::info!("Created room {}", settings.name);
+                    addr
+                }
+            };
+            let name = settings.name.clone();
+            let motd = &self.motd;
+            for (room, stored) in self.rooms.values_mut() {
+                if stored.name == name || stored.parent.as_ref() == Some(&name) {
+                    *stored = RoomSettings {
+                        name: stored.name.clone(),
+                        parent: stored.parent.clone(),
+                        ..settings.clone()
+                    };
+                    let effective = RoomSettings {
+                        motd: stored.motd.clone().or_else(|| motd.clone()),
+                        ..stored.clone()
+                    };
+                    if room.send(Configure(effective)).await.is_err() {
+                        log::warn!("Could not configure {}", stored.name);
+                    }
+                }
+            }
+            for alias in aliases {
+                self.aliases.insert(alias, name.clone());
+            }
+            addrs.push(addr);
+        }
+        for template in templates {
+            self.templates.insert(template.name.clone(), template);
+        }
+        Ok(addrs)
+    }
+}
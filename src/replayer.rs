@@ -0,0 +1,258 @@
+//! Replaying a room's event log into a fresh room, for working out how a
+//! room got into the state someone reported. `yee replay` runs it.
+
+use serde::Deserialize;
+use serde_json::Value;
+use std::time::{Duration, Instant};
+use uuid::Uuid;
+
+use crate::registry::RoomSettings;
+use crate::room::{Admission, Effect, Joiner, RoomState};
+use crate::{now_millis, Bot};
+
+// Logged - one line of the log: an event as the exporter published it
+#[derive(Deserialize)]
+struct Logged {
+    kind: String,
+    payload: Value,
+}
+
+#[derive(Deserialize)]
+struct Membership {
+    user: Uuid,
+}
+
+#[derive(Deserialize)]
+struct Posted {
+    id: Uuid,
+    from: Uuid,
+    body: String,
+    sent_at: u64,
+    #[serde(default)]
+    is_bot: bool,
+    #[serde(default)]
+    flair: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct Seq {
+    seq: u64,
+}
+
+#[derive(Deserialize)]
+struct Seqs {
+    seqs: Vec<u64>,
+}
+
+/// Replays a room's event log into a fresh room and prints every way the
+/// replayed room parts from it, answering with how many there were.
+///
+/// The log is JSONL, one exported event a line: its `kind` (`message`,
+/// `joined`, `left`, `message_hidden`, `message_deleted` or `bulk_delete`)
+/// and its `payload`, as published to NATS or handed to subscribers. The
+/// room starts with `settings`, JSON as for `POST /rooms`, or the defaults
+/// for the log's room. It plays `speed` times as fast as it happened, going
+/// by when messages were sent, or as fast as it can at 0.
+pub async fn replay_log(log: &str, settings: Option<&str>, speed: f64) -> Result<usize, String> {
+    let mut events = Vec::new();
+    for (n, line) in log.lines().enumerate() {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let logged: Logged =
+            serde_json::from_str(line).map_err(|e| format!("Line {}: {}", n + 1, e))?;
+        events.push((n + 1, logged));
+    }
+    let settings = match settings {
+        Some(settings) => serde_json::from_str(settings).map_err(|e| e.to_string())?,
+        None => {
+            let room = events
+                .iter()
+                .find_map(|(_, logged)| logged.payload.get("room")?.as_str())
+                .ok_or_else(|| "Nothing in the log says which room it's for".to_string())?;
+            RoomSettings::named(room)
+        }
+    };
+    let mut state = RoomState::new(settings, None);
+
+    // The room's clock follows the log's, from the first message on
+    let started = Instant::now();
+    let first_ms = events
+        .iter()
+        .find_map(|(_, logged)| sent_at(logged))
+        .unwrap_or_else(now_millis);
+    let mut now_ms = first_ms;
+    let mut diverged = 0;
+    for (line, logged) in &events {
+        if let Some(at) = sent_at(logged).filter(|at| *at > now_ms) {
+            if speed > 0.0 {
+                let wait = Duration::from_millis(at - now_ms).div_f64(speed);
+                tokio::time::sleep(wait).await;
+            }
+            now_ms = at;
+        }
+        let now = started + Duration::from_millis(now_ms - first_ms);
+        let difference = play(&mut state, logged, now, now_ms)
+            .unwrap_or_else(|e| Some(format!("can't be replayed: {}", e)));
+        if let Some(difference) = difference {
+            println!("Line {} ({}): {}", line, logged.kind, difference);
+            diverged += 1;
+        }
+    }
+    println!(
+        "Replayed {} events: {} divergences, {} members at the end",
+        events.len(),
+        diverged,
+        state.members().len(),
+    );
+    Ok(diverged)
+}
+
+// When a logged message was sent; other events don't say
+fn sent_at(logged: &Logged) -> Option<u64> {
+    match logged.kind.as_str() {
+        "message" => logged.payload.get("sent_at")?.as_u64(),
+        _ => None,
+    }
+}
+
+// Does to the room what the logged event says happened, and answers with
+// how what it exported for it differs, if it does
+fn play(
+    state: &mut RoomState,
+    logged: &Logged,
+    now: Instant,
+    now_ms: u64,
+) -> Result<Option<String>, String> {
+    let payload = || logged.payload.clone();
+    match logged.kind.as_str() {
+        "joined" => {
+            let Membership { user } =
+                serde_json::from_value(payload()).map_err(|e| e.to_string())?;
+            // They got in, however they did, so they're placed past the
+            // join policy
+            let joiner = Joiner {
+                id: user,
+                echo: false,
+                bot: false,
+                moderator: false,
+                invite: None,
+                spectator: false,
+                placed: true,
+            };
+            match state.join(joiner, now, now_ms) {
+                Ok(Admission::Joined { .. }) => {}
+                Ok(Admission::Pending) => {
+                    return Ok(Some("the join waits for approval".to_string()))
+                }
+                Err(e) => return Ok(Some(format!("the join is refused: {}", e))),
+            }
+        }
+        "left" => {
+            let Membership { user } =
+                serde_json::from_value(payload()).map_err(|e| e.to_string())?;
+            state.leave(user, now);
+        }
+        "message" => {
+            let posted: Posted = serde_json::from_value(payload()).map_err(|e| e.to_string())?;
+            let bot = if posted.is_bot {
+                Some(Bot {
+                    flair: posted.flair,
+                })
+            } else {
+                None
+            };
+            let result = state.post(
+                posted.from,
+                posted.body,
+                bot,
+                posted.id,
+                now,
+                posted.sent_at,
+            );
+            if let Err(e) = result {
+                return Ok(Some(format!("the message is refused: {}", e)));
+            }
+        }
+        "message_hidden" => {
+            let Seq { seq } = serde_json::from_value(payload()).map_err(|e| e.to_string())?;
+            state.hide(seq);
+        }
+        "message_deleted" => {
+            let Seq { seq } = serde_json::from_value(payload()).map_err(|e| e.to_string())?;
+            state.delete(seq);
+        }
+        // A purge, which can't be told apart from deleting each message
+        "bulk_delete" => {
+            let Seqs { seqs } = serde_json::from_value(payload()).map_err(|e| e.to_string())?;
+            for seq in &seqs {
+                state.delete(*seq);
+            }
+            let deleted = exported(state);
+            let missing: Vec<_> = seqs
+                .iter()
+                .filter(|seq| {
+                    !deleted
+                        .iter()
+                        .any(|(_, event)| event.get("seq") == Some(&Value::from(**seq)))
+                })
+                .collect();
+            if missing.is_empty() {
+                return Ok(None);
+            }
+            return Ok(Some(format!(
+                "{:?} aren't in the replayed history",
+                missing
+            )));
+        }
+        kind => return Err(format!("no such kind: {}", kind)),
+    }
+
+    let exported = exported(state);
+    let theirs = exported
+        .iter()
+        .filter(|(kind, _)| *kind == logged.kind)
+        .collect::<Vec<_>>();
+    Ok(match theirs.as_slice() {
+        [] => match exported.iter().find(|(kind, _)| *kind == "error") {
+            Some((_, error)) => Some(format!(
+                "the replayed room refused it: {}",
+                error["message"]
+            )),
+            None => Some("the replayed room didn't export it".to_string()),
+        },
+        [(_, event)] if *event == logged.payload => None,
+        [(_, event)] => Some(format!("the replayed room exported {}", event)),
+        _ => Some(format!(
+            "the replayed room exported it {} times",
+            theirs.len()
+        )),
+    })
+}
+
+// What the room has exported since last time, after anything it was
+// holding back has gone out, along with any errors it sent, as `error`
+fn exported(state: &mut RoomState) -> Vec<(&'static str, Value)> {
+    let mut exported = Vec::new();
+    loop {
+        let effects = state.effects();
+        if effects.is_empty() {
+            return exported;
+        }
+        for effect in effects {
+            match effect {
+                Effect::Export(kind, payload) => {
+                    let event = serde_json::from_slice(&payload).expect("Exports are JSON");
+                    exported.push((kind, event));
+                }
+                Effect::Send(_, frame) if frame.starts_with(br#"{"type":"error""#) => {
+                    let error = serde_json::from_slice(&frame).expect("Frames are JSON");
+                    exported.push(("error", error));
+                }
+                Effect::QueueFlush => state.flush_due(),
+                Effect::QueuePresence => state.flush_presence(),
+                _ => {}
+            }
+        }
+    }
+}
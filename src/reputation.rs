@@ -0,0 +1,151 @@
+use std::convert::Infallible;
+use std::fmt::Write;
+use std::net::IpAddr;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+use warp::http::StatusCode;
+use warp::reject::Reject;
+use warp::{Filter, Rejection, Reply};
+
+use crate::proxy::Peer;
+use crate::ProtocolError;
+
+// How long a lookup may take before the address is let through unchecked
+const TIMEOUT: Duration = Duration::from_secs(2);
+
+// Verdict - what a ConnectionPolicy makes of an address
+pub enum Verdict {
+    Allow,
+    // Let in, but marked with why in the logs and /admin/connections
+    Flag(String),
+    Deny(String),
+}
+
+// ConnectionPolicy - decides on websocket upgrades by the client's address.
+// Policies that can't tell, say because a lookup failed, should Allow.
+#[async_trait::async_trait]
+pub trait ConnectionPolicy: Send + Sync {
+    async fn check(&self, addr: IpAddr) -> Verdict;
+}
+
+// Listed - what to do with addresses on a blocklist
+#[derive(Clone, Copy)]
+pub enum Listed {
+    Flag,
+    Deny,
+}
+impl FromStr for Listed {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "flag" => Ok(Listed::Flag),
+            "deny" => Ok(Listed::Deny),
+            _ => Err(format!("{}: expected flag or deny", s)),
+        }
+    }
+}
+
+// Dnsbl - looks addresses up in DNS blocklists like zen.spamhaus.org,
+// through the system resolver. An address is listed in a zone when
+// `<reversed address>.<zone>` resolves.
+pub struct Dnsbl {
+    pub zones: Vec<String>,
+    pub listed: Listed,
+}
+
+impl Dnsbl {
+    // The name to look `addr` up by in `zone`: octets reversed for IPv4,
+    // nibbles reversed for IPv6
+    fn query(addr: IpAddr, zone: &str) -> String {
+        let mut name = String::new();
+        match addr {
+            IpAddr::V4(addr) => {
+                for octet in addr.octets().iter().rev() {
+                    write!(name, "{}.", octet).expect("Could not build query");
+                }
+            }
+            IpAddr::V6(addr) => {
+                for byte in addr.octets().iter().rev() {
+                    write!(name, "{:x}.{:x}.", byte & 0xf, byte >> 4)
+                        .expect("Could not build query");
+                }
+            }
+        }
+        name.push_str(zone);
+        name
+    }
+}
+
+#[async_trait::async_trait]
+impl ConnectionPolicy for Dnsbl {
+    async fn check(&self, addr: IpAddr) -> Verdict {
+        for zone in &self.zones {
+            // A port, since that's what lookup_host wants
+            let query = format!("{}:0", Dnsbl::query(addr, zone));
+            let listed = tokio::time::timeout(TIMEOUT, tokio::net::lookup_host(query)).await;
+            if let Ok(Ok(mut found)) = listed {
+                if found.next().is_some() {
+                    let reason = format!("listed in {}", zone);
+                    return match self.listed {
+                        Listed::Flag => Verdict::Flag(reason),
+                        Listed::Deny => Verdict::Deny(reason),
+                    };
+                }
+            }
+        }
+        Verdict::Allow
+    }
+}
+
+#[derive(Debug)]
+pub struct Denied;
+impl Reject for Denied {}
+
+pub async fn handle_rejection(err: Rejection) -> Result<impl Reply, Rejection> {
+    if err.find::<Denied>().is_some() {
+        let error = ProtocolError::new(
+            "denied",
+            "Connections from your address aren't allowed".to_string(),
+        );
+        Ok(warp::reply::with_status(
+            warp::reply::json(&error),
+            StatusCode::FORBIDDEN,
+        ))
+    } else {
+        Err(err)
+    }
+}
+
+// The peer, once the policy has let it through, with anything it was
+// flagged for. Peers without an address, and everyone when there's no
+// policy, go through unchecked.
+pub fn check<P>(
+    policy: Option<Arc<dyn ConnectionPolicy>>,
+    peer: P,
+) -> impl Filter<Extract = (Peer,), Error = Rejection> + Clone
+where
+    P: Filter<Extract = (Peer,), Error = Infallible> + Clone + Send + Sync + 'static,
+{
+    peer.and_then(move |mut peer: Peer| {
+        let policy = policy.clone();
+        async move {
+            let (policy, addr) = match (policy, peer.addr) {
+                (Some(policy), Some(addr)) => (policy, addr),
+                _ => return Ok(peer),
+            };
+            match policy.check(addr).await {
+                Verdict::Allow => Ok(peer),
+                Verdict::Flag(reason) => {
+                    peer.flagged = Some(reason);
+                    Ok(peer)
+                }
+                Verdict::Deny(reason) => {
+                    log::warn!("Refused {}: {}", addr, reason);
+                    Err(warp::reject::custom(Denied))
+                }
+            }
+        }
+    })
+}
@@ -0,0 +1,1874 @@
+use bytes::Bytes;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeSet, HashMap, HashSet, VecDeque};
+use std::time::{Duration, Instant};
+use uuid::Uuid;
+
+use crate::commands::{self, Invocation, Webhook};
+use crate::keywords::Keywords;
+use crate::moderation::{FileReport, Report};
+use crate::page;
+use crate::permissions::{self, Permissions};
+use crate::registry::{JoinPolicy, RoomFeatures, RoomSettings, Visibility};
+use crate::schedule::Offset;
+use crate::spam::{LinkPolicy, LinkTracker, SpamAction};
+use crate::{
+    names, template, Bot, ChatMessage, ProtocolError, ServerEvent, DEFAULT_HISTORY_LIMIT,
+    MAX_BATCH, MAX_HISTORY, MAX_HISTORY_LIMIT, PREVIEW_LIMIT, REPLAY_CHUNK,
+};
+
+// Effect - something a room wants done outside itself. RoomState only
+// decides; the Room actor carries these out, in order.
+pub enum Effect {
+    // A frame for one member
+    Send(Uuid, Bytes),
+    // An event for the exporter
+    Export(&'static str, Bytes),
+    // A message went out, for Stats
+    Posted { from: Uuid, at: u64 },
+    // The member count changed, for Stats
+    Occupancy(usize),
+    // A report for the moderation queue
+    Report(FileReport),
+    // Deal with a link spammer, once the current message is done; a ban
+    // lifts at the unix millis given, if any
+    Punish(Uuid, SpamAction, Option<u64>),
+    // Send the unsent messages once the mailbox has caught up
+    QueueFlush,
+    // Send the presence changes after PRESENCE_WINDOW
+    QueuePresence,
+    // Someone posted one of the room's commands, for its webhook
+    Command(Webhook, Invocation),
+}
+
+// How long membership changes and typing are gathered up before they go out
+// as one presence event
+pub const PRESENCE_WINDOW: Duration = Duration::from_millis(250);
+// Longest a ban can last before it has to be for good
+const MAX_BAN_MS: u64 = 100 * 365 * 24 * 60 * 60 * 1000;
+// Longest draft kept, in characters
+const MAX_DRAFT_LEN: usize = 4000;
+// Most people gone from a room whose read markers, drafts and keywords it
+// keeps for when they're back, and for how long
+const MAX_REMEMBERED: usize = 256;
+const REMEMBER_FOR: Duration = Duration::from_secs(30 * 24 * 60 * 60);
+
+// Presence - who came, went and started typing since the last presence
+// event, in order so members who don't figure share one frame
+#[derive(Default)]
+struct Presence {
+    joined: BTreeSet<Uuid>,
+    left: BTreeSet<Uuid>,
+    typing: BTreeSet<Uuid>,
+}
+impl Presence {
+    fn is_empty(&self) -> bool {
+        self.joined.is_empty() && self.left.is_empty() && self.typing.is_empty()
+    }
+
+    fn involves(&self, id: &Uuid) -> bool {
+        self.joined.contains(id) || self.left.contains(id) || self.typing.contains(id)
+    }
+
+    // What `id` hears: everyone but themselves
+    fn without(&self, id: &Uuid) -> [Vec<Uuid>; 3] {
+        [&self.joined, &self.left, &self.typing]
+            .map(|ids| ids.iter().copied().filter(|other| other != id).collect())
+    }
+}
+
+// Delivery - what became of a message the room took
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Delivery {
+    // Kept and on its way to the room
+    Sent,
+    // Taken without reaching anyone else: slow mode, a ban or kick, a mute,
+    // or link spam. The poster isn't told any more than they already are.
+    Dropped,
+}
+
+// Joiner - someone asking to join
+pub struct Joiner {
+    pub id: Uuid,
+    // Whether they get their own messages back
+    pub echo: bool,
+    // On a room key, and on an admin key
+    pub bot: bool,
+    pub moderator: bool,
+    pub invite: Option<String>,
+    // On a spectator key: they get the room's traffic without being one of
+    // its members
+    pub spectator: bool,
+    // Put in by an operator: past the join policy, but not bans or the
+    // room's limits
+    pub placed: bool,
+}
+
+// Unread - how far someone has read in a room, how many messages from
+// other people came after, and what they were writing there if anything
+#[derive(Serialize)]
+pub struct Unread {
+    pub room: String,
+    pub last_read_seq: u64,
+    pub unread: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub draft: Option<String>,
+}
+
+// Preview - a room as someone who hasn't joined sees it: how many are in,
+// and the newest messages where anyone could read them by joining
+#[derive(Serialize)]
+pub struct Preview {
+    pub room: String,
+    pub members: usize,
+    pub messages: Vec<ChatMessage>,
+}
+
+// Limits - the settings that change what members may do, sent to them
+// when they change
+#[derive(PartialEq, Serialize)]
+pub struct Limits {
+    features: RoomFeatures,
+    max_members: Option<usize>,
+    slow_mode_secs: Option<u64>,
+    mention_cooldown_secs: u64,
+    bots_allowed: bool,
+}
+
+// Ban - someone kept out of a room and its threads, and why
+#[derive(Clone, Deserialize, Serialize)]
+pub struct Ban {
+    pub user: Uuid,
+    #[serde(default)]
+    pub reason: Option<String>,
+    // The moderator who banned them, when it was one over the websocket
+    #[serde(default)]
+    pub by: Option<Uuid>,
+    // Unix millis
+    pub banned_at: u64,
+    // Lifted by itself at this, in unix millis; never if None
+    #[serde(default)]
+    pub expires_at: Option<u64>,
+    // Moderators' notes, on appeals and the like
+    #[serde(default)]
+    pub notes: Option<String>,
+}
+impl Ban {
+    pub fn new(user: Uuid, by: Option<Uuid>, banned_at: u64) -> Self {
+        Self {
+            user,
+            reason: None,
+            by,
+            banned_at,
+            expires_at: None,
+            notes: None,
+        }
+    }
+
+    fn lifted(&self, now_ms: u64) -> bool {
+        self.expires_at.is_some_and(|at| at <= now_ms)
+    }
+
+    // When a ban lasting `duration_secs` from `now_ms` lifts
+    pub fn lasting(duration_secs: u64, now_ms: u64) -> Result<u64, ProtocolError> {
+        let expires_at = duration_secs
+            .checked_mul(1000)
+            .and_then(|ms| now_ms.checked_add(ms))
+            .ok_or_else(|| bad_duration("duration_secs is too long"))?;
+        Self::expiring(expires_at, now_ms)
+    }
+
+    // Checks a ban is to lift after `now_ms`, and within MAX_BAN_MS of it;
+    // bans meant to last longer should be for good
+    pub fn expiring(expires_at: u64, now_ms: u64) -> Result<u64, ProtocolError> {
+        if expires_at <= now_ms {
+            return Err(bad_duration("A ban has to lift in the future"));
+        }
+        if expires_at - now_ms > MAX_BAN_MS {
+            return Err(bad_duration(
+                "Bans lift within 100 years; leave the expiry out for good",
+            ));
+        }
+        Ok(expires_at)
+    }
+}
+
+fn bad_duration(message: &str) -> ProtocolError {
+    ProtocolError::new("bad_duration", message.to_string())
+}
+
+// Admission - how a join went
+#[derive(Debug)]
+pub enum Admission {
+    // In, as of this seq, with the MOTD to greet them with
+    Joined { last_seq: u64, motd: Option<String> },
+    // Waiting for a moderator
+    Pending,
+}
+
+// RoomState - everything a room knows, and how it changes. Nothing in here
+// waits or reads the clock, so any sequence of events can be replayed
+// against it and the effects checked.
+pub struct RoomState {
+    name: String,
+    settings: RoomSettings,
+    members: HashSet<Uuid>,
+    // Watching on spectator keys, who hear what members do but aren't
+    // counted, shown or let do anything
+    spectators: HashSet<Uuid>,
+    // Members who get their own messages back
+    echo: HashSet<Uuid>,
+    history: VecDeque<ChatMessage>,
+    next_seq: u64,
+    banned: HashMap<Uuid, Ban>,
+    // Shadow-muted members, whose messages only they get to see
+    muted: HashSet<Uuid>,
+    // Kicked members can't post until they join again
+    kicked: HashSet<Uuid>,
+    // Joiners waiting for a moderator, with whether they want echo, and the
+    // members who can let them in
+    pending: HashMap<Uuid, bool>,
+    // Members on an admin key, who may do everything
+    moderators: HashSet<Uuid>,
+    // Roles given to users, by id; everyone else is a member. They outlast
+    // leaving.
+    grants: HashMap<Uuid, String>,
+    // Invite codes, each good for one join
+    invites: HashSet<String>,
+    // What members want to be told about when it's posted. They outlast
+    // disconnecting for a while, like read markers.
+    keywords: Keywords,
+    // The last seq each person here has read up to. They outlast
+    // disconnecting for a while, so whoever comes back is told what they
+    // missed.
+    read: HashMap<Uuid, u64>,
+    // What each person here was writing and hasn't sent, which outlasts
+    // disconnecting the same way
+    drafts: HashMap<Uuid, String>,
+    // When each person who disconnected without leaving went, and so still
+    // has the above kept for them
+    remembered: HashMap<Uuid, Instant>,
+    // When each member last posted, for slow mode
+    last_posted: HashMap<Uuid, Instant>,
+    // When each member joined, and the links they posted lately, for
+    // catching link spam
+    joined_at: HashMap<Uuid, Instant>,
+    links: LinkTracker,
+    link_policy: Option<LinkPolicy>,
+    // When someone last mentioned everyone, for the cool-down
+    mentioned_all: Option<Instant>,
+    // Messages posted but not sent out yet, those of them mentioning
+    // everyone, and whether a Flush is queued
+    unsent: Vec<u64>,
+    unsent_mentions: Vec<u64>,
+    flush_queued: bool,
+    // Presence changes not sent out yet, and whether they're due to be
+    presence: Presence,
+    presence_queued: bool,
+    effects: Vec<Effect>,
+}
+impl RoomState {
+    pub fn new(settings: RoomSettings, link_policy: Option<LinkPolicy>) -> Self {
+        Self {
+            name: settings.name.clone(),
+            settings,
+            members: HashSet::new(),
+            spectators: HashSet::new(),
+            echo: HashSet::new(),
+            history: VecDeque::new(),
+            next_seq: 1,
+            banned: HashMap::new(),
+            muted: HashSet::new(),
+            kicked: HashSet::new(),
+            pending: HashMap::new(),
+            moderators: HashSet::new(),
+            grants: HashMap::new(),
+            invites: HashSet::new(),
+            keywords: Keywords::default(),
+            read: HashMap::new(),
+            drafts: HashMap::new(),
+            remembered: HashMap::new(),
+            last_posted: HashMap::new(),
+            joined_at: HashMap::new(),
+            links: LinkTracker::default(),
+            link_policy,
+            mentioned_all: None,
+            unsent: Vec::new(),
+            unsent_mentions: Vec::new(),
+            flush_queued: false,
+            presence: Presence::default(),
+            presence_queued: false,
+            effects: Vec::new(),
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    // What's been decided since the last call, oldest first
+    pub fn effects(&mut self) -> Vec<Effect> {
+        std::mem::take(&mut self.effects)
+    }
+
+    pub fn members(&self) -> Vec<Uuid> {
+        self.members.iter().copied().collect()
+    }
+
+    // Bans still in force, expired ones dropped
+    pub fn bans(&mut self, now_ms: u64) -> Vec<Ban> {
+        self.banned.retain(|_, ban| !ban.lifted(now_ms));
+        let mut bans: Vec<_> = self.banned.values().cloned().collect();
+        bans.sort_by_key(|ban| ban.user);
+        bans
+    }
+
+    fn banned(&self, id: &Uuid, now_ms: u64) -> Option<&Ban> {
+        self.banned.get(id).filter(|ban| !ban.lifted(now_ms))
+    }
+
+    pub fn muted(&self) -> impl Iterator<Item = &Uuid> {
+        self.muted.iter()
+    }
+
+    pub fn grants(&self) -> impl Iterator<Item = (&Uuid, &String)> {
+        self.grants.iter()
+    }
+
+    // What a role allows here: the room's own definition, or the built-in
+    // one
+    fn role(&self, role: &str) -> Option<Permissions> {
+        self.settings
+            .roles
+            .get(role)
+            .copied()
+            .or_else(|| permissions::builtin(role))
+    }
+
+    // Everything `id` may do here. Every check on what someone may do goes
+    // through this.
+    pub fn permissions(&self, id: Uuid) -> Permissions {
+        if self.moderators.contains(&id) {
+            return Permissions::all();
+        }
+        let role = self
+            .grants
+            .get(&id)
+            .map_or(permissions::MEMBER, String::as_str);
+        self.role(role).unwrap_or_else(Permissions::none)
+    }
+
+    pub fn authorize(&self, id: Uuid, permission: Permissions) -> Result<(), ProtocolError> {
+        if self.permissions(id).has(permission) {
+            Ok(())
+        } else {
+            Err(Permissions::missing(permission, &self.name))
+        }
+    }
+
+    // Gives someone a role here, or takes theirs away with None
+    pub fn assign(&mut self, id: Uuid, role: Option<String>) -> Result<(), ProtocolError> {
+        match role {
+            Some(role) if self.role(&role).is_none() => Err(ProtocolError::new(
+                "no_such_role",
+                format!("{} has no role called {}", self.name, role),
+            )),
+            Some(role) => {
+                self.grants.insert(id, role);
+                Ok(())
+            }
+            None => {
+                self.grants.remove(&id);
+                Ok(())
+            }
+        }
+    }
+
+    // Returns up to `limit` visible messages older than `before_seq` (or the
+    // newest ones), oldest first, and whether there is anything older still
+    fn page(&self, before_seq: Option<u64>, limit: usize) -> (Vec<&ChatMessage>, bool) {
+        let end = match before_seq {
+            Some(seq) => self.history.partition_point(|m| m.seq < seq),
+            None => self.history.len(),
+        };
+        let mut visible = self.history.range(..end).rev().filter(|m| !m.hidden);
+        let mut messages: Vec<_> = visible.by_ref().take(limit).collect();
+        messages.reverse();
+        (messages, visible.next().is_some())
+    }
+
+    fn message(&self, seq: u64) -> Option<&ChatMessage> {
+        let i = self.history.binary_search_by_key(&seq, |m| m.seq).ok()?;
+        self.history.get(i)
+    }
+
+    fn message_mut(&mut self, seq: u64) -> Option<&mut ChatMessage> {
+        let i = self.history.binary_search_by_key(&seq, |m| m.seq).ok()?;
+        self.history.get_mut(i)
+    }
+
+    fn remember(&mut self, message: ChatMessage) {
+        if self.history.len() == MAX_HISTORY {
+            self.history.pop_front();
+        }
+        self.history.push_back(message);
+    }
+
+    // Drops messages that have outlived the room's retention. Imported
+    // messages keep when they were sent but come after what's here, so the
+    // oldest aren't always at the front.
+    fn prune(&mut self, now_ms: u64) {
+        if let Some(cutoff) = self.cutoff(now_ms) {
+            self.history.retain(|m| m.sent_at >= cutoff);
+        }
+    }
+
+    // When the oldest message history keeps was sent, if anything drops out.
+    // Retention is whatever the owner set, so a huge one keeps everything.
+    fn cutoff(&self, now_ms: u64) -> Option<u64> {
+        let secs = self.settings.retention_secs?;
+        Some(now_ms.saturating_sub(secs.saturating_mul(1000)))
+    }
+
+    // Whether `id` gets the room's traffic
+    fn watching(&self, id: &Uuid) -> bool {
+        self.members.contains(id) || self.spectators.contains(id)
+    }
+
+    // Sends an event to everyone in the room, spectators too
+    fn broadcast(&mut self, event: Bytes) {
+        for id in self.members.iter().chain(self.spectators.iter()) {
+            self.effects.push(Effect::Send(*id, event.clone()));
+        }
+    }
+
+    // Sends an error to one member, if they're still here
+    fn refuse(&mut self, id: Uuid, error: ProtocolError) {
+        if self.watching(&id) {
+            let event = ServerEvent::Error(&error).to_json();
+            self.effects.push(Effect::Send(id, event));
+        }
+    }
+
+    // The payload of joined / left exports
+    fn membership(&self, id: Uuid) -> Bytes {
+        let event = serde_json::json!({ "room": self.name, "user": id });
+        serde_json::to_vec(&event)
+            .expect("Could not serialize event")
+            .into()
+    }
+
+    // A message event for one message, a batch for more
+    fn frame(&self, messages: &[&ChatMessage]) -> Option<Bytes> {
+        let mut events: Vec<_> = messages
+            .iter()
+            .map(|message| ServerEvent::Message {
+                room: &self.name,
+                message,
+            })
+            .collect();
+        match events.len() {
+            0 => None,
+            1 => Some(events.remove(0).to_json()),
+            _ => Some(ServerEvent::Batch { events }.to_json()),
+        }
+    }
+
+    // Sends out the messages posted since the last flush, to all but their
+    // senders (unless they asked for echo), as one batch frame per member
+    // when there's more than one. Messages from muted members go to nobody
+    // else. Members who posted none of them share one serialized frame.
+    pub fn flush(&mut self) {
+        let unsent = std::mem::take(&mut self.unsent);
+        let messages: Vec<_> = unsent.iter().filter_map(|seq| self.message(*seq)).collect();
+        if messages.is_empty() {
+            return;
+        }
+        let public: Vec<_> = messages
+            .iter()
+            .copied()
+            .filter(|message| !self.muted.contains(&message.from))
+            .collect();
+        let everyone = self.frame(&public);
+
+        let mut sends = Vec::new();
+        for id in self.members.iter() {
+            let frame = if messages.iter().any(|message| message.from == *id) {
+                let theirs: Vec<_> = messages
+                    .iter()
+                    .copied()
+                    .filter(|message| {
+                        if message.from == *id {
+                            self.echo.contains(id)
+                        } else {
+                            !self.muted.contains(&message.from)
+                        }
+                    })
+                    .collect();
+                self.frame(&theirs)
+            } else {
+                everyone.clone()
+            };
+            if let Some(frame) = frame {
+                sends.push(Effect::Send(*id, frame));
+            }
+        }
+        if let Some(frame) = everyone {
+            for id in self.spectators.iter() {
+                sends.push(Effect::Send(*id, frame.clone()));
+            }
+        }
+        // Keywords go after the messages they're in, to the members whose
+        // they are
+        for message in &public {
+            for (id, keyword) in self.keywords.matches(&message.body) {
+                if id == message.from || !self.members.contains(&id) {
+                    continue;
+                }
+                let event = ServerEvent::Keyword {
+                    room: &self.name,
+                    seq: message.seq,
+                    from: message.from,
+                    keyword: &keyword,
+                };
+                sends.push(Effect::Send(id, event.to_json()));
+            }
+        }
+        // Mentions go after the messages they're in
+        for seq in std::mem::take(&mut self.unsent_mentions) {
+            let from = match self.message(seq) {
+                Some(message) => message.from,
+                None => continue,
+            };
+            let event = ServerEvent::Mention {
+                room: &self.name,
+                seq,
+                from,
+            }
+            .to_json();
+            for id in self.members.iter().filter(|id| **id != from) {
+                sends.push(Effect::Send(*id, event.clone()));
+            }
+        }
+        self.effects.extend(sends);
+    }
+
+    // The queued Flush has come round
+    pub fn flush_due(&mut self) {
+        self.flush_queued = false;
+        self.flush();
+    }
+
+    // Has the presence changes go out once PRESENCE_WINDOW is up, rather
+    // than one frame per change
+    fn queue_presence(&mut self) {
+        if !self.presence_queued {
+            self.presence_queued = true;
+            self.effects.push(Effect::QueuePresence);
+        }
+    }
+
+    // The webhook call for a message that's one of the room's commands
+    fn command(&self, message: &ChatMessage) -> Option<Effect> {
+        let (command, args) = commands::parse(&message.body)?;
+        let webhook = self.settings.commands.get(command)?;
+        let invocation = Invocation {
+            room: self.name.clone(),
+            user: message.from,
+            seq: message.seq,
+            command: command.to_string(),
+            args: args.to_string(),
+        };
+        Some(Effect::Command(webhook.clone(), invocation))
+    }
+
+    // A command's webhook didn't work out; whoever posted it is told
+    pub fn command_failed(&mut self, id: Uuid, command: &str, reason: &str) {
+        let error = ProtocolError::new(
+            "command_failed",
+            format!("/{} didn't work: {}", command, reason),
+        );
+        self.refuse(id, error);
+    }
+
+    // A member typing, which others hear about with the rest of the
+    // presence changes. Shadow-muted members and those who can't send give
+    // nothing away.
+    pub fn typing(&mut self, id: Uuid) {
+        let silent = !self.members.contains(&id)
+            || self.muted.contains(&id)
+            || !self.permissions(id).has(Permissions::SEND);
+        if silent {
+            return;
+        }
+        self.presence.typing.insert(id);
+        self.queue_presence();
+    }
+
+    // PRESENCE_WINDOW is up: everything gathered since goes out as one
+    // event for each member, to those it's news to
+    pub fn flush_presence(&mut self) {
+        self.presence_queued = false;
+        let presence = std::mem::take(&mut self.presence);
+        if presence.is_empty() {
+            return;
+        }
+        let frame = |[joined, left, typing]: [Vec<Uuid>; 3]| {
+            if joined.is_empty() && left.is_empty() && typing.is_empty() {
+                return None;
+            }
+            let event = ServerEvent::Presence {
+                room: &self.name,
+                joined,
+                left,
+                typing,
+            };
+            Some(event.to_json())
+        };
+        let everyone = frame(presence.without(&Uuid::nil()));
+        let mut sends = Vec::new();
+        for id in self.members.iter() {
+            let frame = if presence.involves(id) {
+                frame(presence.without(id))
+            } else {
+                everyone.clone()
+            };
+            if let Some(frame) = frame {
+                sends.push(Effect::Send(*id, frame));
+            }
+        }
+        self.effects.extend(sends);
+    }
+
+    // A member (or a bot, which needn't be one) posting `body`, which
+    // becomes message `id` sent at `sent_at`
+    pub fn post(
+        &mut self,
+        from: Uuid,
+        body: String,
+        bot: Option<Bot>,
+        id: Uuid,
+        now: Instant,
+        sent_at: u64,
+    ) -> Result<Delivery, ProtocolError> {
+        if bot.is_some() && !self.settings.bots_allowed {
+            return Err(self.no_bots());
+        }
+        if self.banned(&from, sent_at).is_some() || self.kicked.contains(&from) {
+            return Ok(Delivery::Dropped);
+        }
+        // Bots on a post-only key post without joining; nobody else can
+        if bot.is_none() && !self.members.contains(&from) {
+            return Err(ProtocolError::new(
+                "not_joined",
+                format!("You are not in {}", self.name),
+            ));
+        }
+        // A key's scope says what a bot may do
+        if bot.is_none() {
+            self.authorize(from, Permissions::SEND)?;
+        }
+
+        // Everyone's notified, so only some may, and only so often
+        let mention = mentions_all(&body);
+        if mention {
+            self.authorize(from, Permissions::MENTION_ALL)?;
+            let wait = Duration::from_secs(self.settings.mention_cooldown_secs);
+            if let Some(last) = self.mentioned_all {
+                if now.duration_since(last) < wait {
+                    return Err(ProtocolError::new(
+                        "mention_cooldown",
+                        format!("Everyone in {} was mentioned lately", self.name),
+                    )
+                    .retryable(Some(wait.saturating_sub(now.duration_since(last)))));
+                }
+            }
+        }
+
+        if let Some(secs) = self.settings.slow_mode_secs {
+            let wait = Duration::from_secs(secs);
+            match self.last_posted.get(&from) {
+                Some(last) if now.duration_since(*last) < wait => {
+                    let error = ProtocolError::new(
+                        "slow_mode",
+                        format!("Slow mode is on, one message every {}s", secs),
+                    )
+                    .retryable(Some(wait.saturating_sub(now.duration_since(*last))));
+                    self.refuse(from, error);
+                    return Ok(Delivery::Dropped);
+                }
+                _ => {
+                    self.last_posted.insert(from, now);
+                }
+            }
+        }
+
+        let spam = match (&self.link_policy, self.joined_at.get(&from)) {
+            (Some(policy), Some(joined)) => self.links.spam(policy, from, *joined, now, &body),
+            _ => false,
+        };
+
+        let message = ChatMessage {
+            id,
+            seq: self.next_seq,
+            from,
+            body,
+            sent_at,
+            is_bot: bot.is_some(),
+            flair: bot.and_then(|bot| bot.flair),
+            author: None,
+            // Muted members' messages stay out of history and exports too
+            hidden: spam || self.muted.contains(&from),
+        };
+        self.next_seq += 1;
+
+        // Link spam is never sent out. It's kept hidden and reported, so a
+        // moderator can restore it by dismissing the report, and its author
+        // is dealt with.
+        if spam {
+            let seq = message.seq;
+            let report = FileReport {
+                room: self.name.clone(),
+                message_id: seq,
+                author: message.from,
+                body: message.body.clone(),
+                hidden: true,
+                report: Report {
+                    reporter: Uuid::nil(),
+                    reason: "Link spam (automatic)".to_string(),
+                },
+            };
+            self.remember(message);
+            self.effects.push(Effect::Report(report));
+            if let Some(policy) = self.link_policy {
+                self.punish(from, seq, policy, sent_at);
+            }
+            return Ok(Delivery::Dropped);
+        }
+
+        // Hold on to it until the rest of the burst is in. A Flush queued
+        // now runs once the messages already waiting have been handled, so
+        // a quiet room sends straight away.
+        if !message.hidden {
+            let event = ServerEvent::Message {
+                room: &self.name,
+                message: &message,
+            };
+            self.effects
+                .push(Effect::Export("message", event.to_json()));
+        }
+        self.effects.push(Effect::Posted {
+            from: message.from,
+            at: message.sent_at,
+        });
+        // Bots' commands are left alone, so webhooks can't set each other off
+        if !message.hidden && !message.is_bot {
+            if let Some(command) = self.command(&message) {
+                self.effects.push(command);
+            }
+        }
+        if mention && !message.hidden {
+            self.mentioned_all = Some(now);
+            self.unsent_mentions.push(message.seq);
+        }
+        let posted = match message.hidden {
+            true => Delivery::Dropped,
+            false => Delivery::Sent,
+        };
+        // Whatever they were writing has gone out now
+        if posted == Delivery::Sent && !message.is_bot {
+            self.drafts.remove(&from);
+        }
+        self.unsent.push(message.seq);
+        self.remember(message);
+        self.prune(sent_at);
+
+        if self.unsent.len() >= MAX_BATCH {
+            self.flush();
+        } else if !self.flush_queued {
+            self.flush_queued = true;
+            self.effects.push(Effect::QueueFlush);
+        }
+        Ok(posted)
+    }
+
+    // Has a link spammer dealt with as the policy says, and tells the
+    // members who can ban what was done, so they can look it over and undo
+    // it if it was a mistake
+    fn punish(&mut self, id: Uuid, seq: u64, policy: LinkPolicy, now_ms: u64) {
+        let expires_at = match policy.action {
+            SpamAction::Ban => policy
+                .ban_secs
+                .and_then(|secs| Ban::lasting(secs, now_ms).ok()),
+            _ => None,
+        };
+        let event = ServerEvent::SpamCaught {
+            room: &self.name,
+            user: id,
+            seq,
+            action: policy.action,
+            expires_at,
+        }
+        .to_json();
+        for moderator in self.allowed(Permissions::BAN) {
+            if moderator != id {
+                self.effects.push(Effect::Send(moderator, event.clone()));
+            }
+        }
+        self.effects
+            .push(Effect::Punish(id, policy.action, expires_at));
+    }
+
+    // Someone asking to join. Bots were let in by whoever gave them their
+    // key, so only people are held to the join policy.
+    pub fn join(
+        &mut self,
+        joiner: Joiner,
+        now: Instant,
+        now_ms: u64,
+    ) -> Result<Admission, ProtocolError> {
+        let id = joiner.id;
+        if joiner.bot && !self.settings.bots_allowed {
+            return Err(self.no_bots());
+        }
+        if let Some(error) = self.ban_error(&id, now_ms) {
+            return Err(error);
+        }
+        if joiner.spectator {
+            return Ok(self.spectate(id));
+        }
+        if let Some(error) = self.full(&id) {
+            return Err(error);
+        }
+
+        // Those who let others in needn't be let in themselves
+        let manager = self.permissions(id).has(Permissions::MANAGE_ROOM);
+        if !joiner.bot && !joiner.placed && !manager && !self.members.contains(&id) {
+            let invited = match &joiner.invite {
+                Some(code) if self.invites.remove(code) => true,
+                Some(_) => {
+                    return Err(ProtocolError::new(
+                        "bad_invite",
+                        format!("That invite to {} is used or made up", self.name),
+                    ))
+                }
+                None => false,
+            };
+            match self.settings.join_policy {
+                JoinPolicy::Approval if !invited => {
+                    self.ask(id, joiner.echo);
+                    return Ok(Admission::Pending);
+                }
+                JoinPolicy::Invite if !invited => {
+                    return Err(ProtocolError::new(
+                        "invite_only",
+                        format!("{} is invite only", self.name),
+                    ))
+                }
+                _ => {}
+            }
+        }
+        if joiner.moderator {
+            self.moderators.insert(id);
+        }
+        let last_seq = self.admit(id, joiner.echo, now);
+        // Bots get a new id each time, so there's no one to keep it for
+        if !joiner.bot {
+            self.read.entry(id).or_insert(last_seq);
+        }
+        Ok(Admission::Joined {
+            last_seq,
+            motd: self.motd(id),
+        })
+    }
+
+    // What a banned joiner is told, if `id` is still banned
+    fn ban_error(&self, id: &Uuid, now_ms: u64) -> Option<ProtocolError> {
+        let ban = self.banned(id, now_ms)?;
+        let message = match &ban.reason {
+            Some(reason) => format!("You are banned from {}: {}", self.name, reason),
+            None => format!("You are banned from {}", self.name),
+        };
+        let error = ProtocolError::new("banned", message);
+        Some(match ban.expires_at {
+            Some(at) => error.retryable(Some(Duration::from_millis(at - now_ms))),
+            None => error,
+        })
+    }
+
+    // Why `id` can't be let in as a member, if the room is at max_members
+    fn full(&self, id: &Uuid) -> Option<ProtocolError> {
+        let max = self.settings.max_members?;
+        if self.members.len() < max || self.members.contains(id) {
+            return None;
+        }
+        Some(ProtocolError::new("room_full", format!("{} is full", self.name)).retryable(None))
+    }
+
+    // Lets a spectator in, past the room's limits and policy since they
+    // take no part. Nobody is told, and nothing is exported.
+    fn spectate(&mut self, id: Uuid) -> Admission {
+        self.flush();
+        self.spectators.insert(id);
+        Admission::Joined {
+            last_seq: self.next_seq - 1,
+            motd: None,
+        }
+    }
+
+    // The room's MOTD for `id`, if it has one
+    fn motd(&self, id: Uuid) -> Option<String> {
+        let motd = self.settings.motd.as_ref()?;
+        Some(template::render(
+            motd,
+            &[
+                ("room", self.name.clone()),
+                ("members", self.members.len().to_string()),
+                ("user", names::guest(id)),
+            ],
+        ))
+    }
+
+    // Lets someone in. Answers with the last seq posted so far; everything
+    // after it reaches them live.
+    fn admit(&mut self, id: Uuid, echo: bool, now: Instant) -> u64 {
+        // Whatever is still unsent goes out first, or the joiner would get
+        // it live as well as in a replay
+        self.flush();
+        self.pending.remove(&id);
+        self.kicked.remove(&id);
+        self.remembered.remove(&id);
+        if echo {
+            self.echo.insert(id);
+        } else {
+            self.echo.remove(&id);
+        }
+        self.joined_at.insert(id, now);
+        if self.members.insert(id) {
+            self.effects
+                .push(Effect::Export("joined", self.membership(id)));
+            self.effects.push(Effect::Occupancy(self.members.len()));
+            // Back within the window is as good as never gone
+            if !self.presence.left.remove(&id) {
+                self.presence.joined.insert(id);
+            }
+            self.queue_presence();
+        }
+        self.next_seq - 1
+    }
+
+    // Parks a joiner until a moderator decides, telling the members who can
+    // let them in the first time they ask
+    fn ask(&mut self, id: Uuid, echo: bool) {
+        if self.pending.insert(id, echo).is_some() {
+            return;
+        }
+        let event = ServerEvent::JoinRequest {
+            room: &self.name,
+            user: id,
+        }
+        .to_json();
+        for moderator in self.allowed(Permissions::MANAGE_ROOM) {
+            self.effects.push(Effect::Send(moderator, event.clone()));
+        }
+    }
+
+    // The members who may do `permission`
+    fn allowed(&self, permission: Permissions) -> Vec<Uuid> {
+        self.members
+            .iter()
+            .copied()
+            .filter(|id| self.permissions(*id).has(permission))
+            .collect()
+    }
+
+    // Joiners waiting for a moderator
+    pub fn pending(&self) -> Vec<Uuid> {
+        let mut pending: Vec<_> = self.pending.keys().copied().collect();
+        pending.sort();
+        pending
+    }
+
+    // A moderator letting a joiner in, who's told they've joined; answers
+    // whether they were waiting. They're held to the same limits as when
+    // they asked: one banned since is turned away and told why, and while
+    // the room is full they're left waiting.
+    pub fn approve(&mut self, id: Uuid, now: Instant, now_ms: u64) -> Result<bool, ProtocolError> {
+        let echo = match self.pending.get(&id) {
+            Some(echo) => *echo,
+            None => return Ok(false),
+        };
+        if let Some(error) = self.ban_error(&id, now_ms) {
+            self.pending.remove(&id);
+            self.effects
+                .push(Effect::Send(id, ServerEvent::Error(&error).to_json()));
+            return Err(ProtocolError::new(
+                "banned",
+                format!("{} is banned from {}", id, self.name),
+            ));
+        }
+        if let Some(error) = self.full(&id) {
+            return Err(error);
+        }
+        let last_seq = self.admit(id, echo, now);
+        self.read.entry(id).or_insert(last_seq);
+        let event = ServerEvent::Joined { room: &self.name }.to_json();
+        self.effects.push(Effect::Send(id, event));
+        if let Some(motd) = self.motd(id) {
+            let event = ServerEvent::System {
+                room: &self.name,
+                body: &motd,
+            }
+            .to_json();
+            self.effects.push(Effect::Send(id, event));
+        }
+        Ok(true)
+    }
+
+    // A moderator turning a joiner away, who's told so; answers whether
+    // they were waiting
+    pub fn deny(&mut self, id: Uuid) -> bool {
+        if self.pending.remove(&id).is_none() {
+            return false;
+        }
+        let error = ProtocolError::new(
+            "join_denied",
+            format!("A moderator turned down your join to {}", self.name),
+        );
+        self.effects
+            .push(Effect::Send(id, ServerEvent::Error(&error).to_json()));
+        true
+    }
+
+    // An invite code someone can join with, once
+    pub fn invite(&mut self, code: String) {
+        self.invites.insert(code);
+    }
+
+    fn no_bots(&self) -> ProtocolError {
+        ProtocolError::new(
+            "bots_not_allowed",
+            format!("{} doesn't allow bots", self.name),
+        )
+    }
+
+    // The room being archived: everyone in it, or waiting to be, is put out
+    // and told why. Its history and what's kept for people stay, for when
+    // it's brought back.
+    pub fn archive(&mut self, now: Instant) {
+        // Whatever is still unsent goes out while there's someone to get it
+        self.flush();
+        let message = format!("{} has been archived", self.name);
+        for (id, _) in std::mem::take(&mut self.pending) {
+            let error = ProtocolError::new("archived", message.clone());
+            self.effects
+                .push(Effect::Send(id, ServerEvent::Error(&error).to_json()));
+        }
+        let everyone: Vec<Uuid> = self.members.union(&self.spectators).copied().collect();
+        for id in everyone {
+            self.refuse(id, ProtocolError::new("archived", message.clone()));
+            self.leave(id, now);
+        }
+        // Nobody is left to tell, and the room won't be round to do it
+        self.presence = Presence::default();
+        self.presence_queued = false;
+        self.flush_queued = false;
+    }
+
+    // Someone going, whose read marker, draft and keywords are kept for a while
+    // in case they're back
+    pub fn leave(&mut self, id: Uuid, now: Instant) {
+        self.spectators.remove(&id);
+        self.pending.remove(&id);
+        self.moderators.remove(&id);
+        if self.members.remove(&id) {
+            self.effects
+                .push(Effect::Export("left", self.membership(id)));
+            self.effects.push(Effect::Occupancy(self.members.len()));
+            self.gone(id);
+        }
+        self.echo.remove(&id);
+        self.last_posted.remove(&id);
+        self.joined_at.remove(&id);
+        self.links.forget(&id);
+        if self.read.contains_key(&id) || self.drafts.contains_key(&id) || self.keywords.has(id) {
+            self.remembered.insert(id, now);
+        }
+        self.forget_stale(now);
+    }
+
+    // Someone leaving on purpose, with nothing kept for them
+    pub fn forget(&mut self, id: Uuid) {
+        self.remembered.remove(&id);
+        self.read.remove(&id);
+        self.drafts.remove(&id);
+        self.keywords.forget(id);
+    }
+
+    // Drops what's kept for people long gone, and for the longest gone of the
+    // rest past MAX_REMEMBERED, since anyone can come and go as a new id
+    fn forget_stale(&mut self, now: Instant) {
+        let stale: Vec<Uuid> = self
+            .remembered
+            .iter()
+            .filter(|(_, left)| now.saturating_duration_since(**left) > REMEMBER_FOR)
+            .map(|(id, _)| *id)
+            .collect();
+        for id in stale {
+            self.forget(id);
+        }
+        while self.remembered.len() > MAX_REMEMBERED {
+            let oldest = self.remembered.iter().min_by_key(|(_, left)| **left);
+            match oldest.map(|(id, _)| *id) {
+                Some(id) => self.forget(id),
+                None => break,
+            }
+        }
+    }
+
+    pub fn set_features(&mut self, features: RoomFeatures) {
+        let before = self.limits();
+        self.settings.features = features;
+        self.limits_changed(before);
+    }
+
+    pub fn set_timezone(&mut self, timezone: Offset) {
+        self.settings.timezone = timezone;
+    }
+
+    pub fn timezone(&self) -> Offset {
+        self.settings.timezone
+    }
+
+    // Every setting but the room's name and where it hangs off, changed at
+    // once
+    pub fn configure(&mut self, settings: RoomSettings) {
+        let before = self.limits();
+        self.settings = RoomSettings {
+            name: self.settings.name.clone(),
+            parent: self.settings.parent.clone(),
+            ..settings
+        };
+        self.limits_changed(before);
+    }
+
+    fn limits(&self) -> Limits {
+        Limits {
+            features: self.settings.features,
+            max_members: self.settings.max_members,
+            slow_mode_secs: self.settings.slow_mode_secs,
+            mention_cooldown_secs: self.settings.mention_cooldown_secs,
+            bots_allowed: self.settings.bots_allowed,
+        }
+    }
+
+    // Tells everyone in about the room's new limits, if they're new, so
+    // nobody has to rejoin to find out
+    fn limits_changed(&mut self, before: Limits) {
+        let limits = self.limits();
+        if limits == before {
+            return;
+        }
+        let event = ServerEvent::ConfigChanged {
+            room: &self.name,
+            limits: &limits,
+        }
+        .to_json();
+        self.broadcast(event);
+    }
+
+    // The room going by a new name. Members and those waiting to join are
+    // told, after whatever is still unsent goes out under the old one.
+    pub fn rename(&mut self, name: String) {
+        self.flush();
+        let event = ServerEvent::RoomRenamed {
+            room: &self.name,
+            name: &name,
+        }
+        .to_json();
+        let told = self.members.iter().chain(self.spectators.iter());
+        for id in told.chain(self.pending.keys()) {
+            self.effects.push(Effect::Send(*id, event.clone()));
+        }
+        self.settings.name = name.clone();
+        self.name = name;
+    }
+
+    // A member asking for a page of older messages
+    pub fn history(
+        &mut self,
+        id: Uuid,
+        before_seq: Option<u64>,
+        limit: Option<usize>,
+        now_ms: u64,
+    ) {
+        if !self.settings.features.has(RoomFeatures::HISTORY) {
+            self.refuse(
+                id,
+                RoomFeatures::disabled(RoomFeatures::HISTORY, &self.name),
+            );
+            return;
+        }
+        // Anything in the page has to have gone out live first
+        self.flush();
+        self.prune(now_ms);
+        if !self.watching(&id) {
+            return;
+        }
+
+        let limit = limit
+            .unwrap_or(DEFAULT_HISTORY_LIMIT)
+            .min(MAX_HISTORY_LIMIT);
+        let (messages, has_more) = self.page(before_seq, limit);
+        let next_cursor = match messages.first() {
+            Some(oldest) if has_more => Some(page::encode(&oldest.seq.to_string())),
+            _ => None,
+        };
+        let event = ServerEvent::History {
+            room: &self.name,
+            messages,
+            has_more,
+            next_cursor,
+        };
+        let event = event.to_json();
+        self.effects.push(Effect::Send(id, event));
+    }
+
+    // The next chunk of a replay, as a frame and the seq to carry on after
+    // if there's more. None once the member has left.
+    pub fn replay(
+        &mut self,
+        id: Uuid,
+        after_seq: u64,
+        until_seq: u64,
+        now_ms: u64,
+    ) -> Option<(Bytes, Option<u64>)> {
+        if !self.watching(&id) {
+            return None;
+        }
+        // Nothing to replay without history; the joiner is told it's done
+        if !self.settings.features.has(RoomFeatures::HISTORY) {
+            let event = ServerEvent::Replay {
+                room: &self.name,
+                messages: Vec::new(),
+                done: true,
+            };
+            return Some((event.to_json(), None));
+        }
+        self.prune(now_ms);
+
+        let start = self.history.partition_point(|m| m.seq <= after_seq);
+        let mut missed = self
+            .history
+            .range(start..)
+            .take_while(|m| m.seq <= until_seq)
+            .filter(|m| !m.hidden);
+        let messages: Vec<_> = missed.by_ref().take(REPLAY_CHUNK).collect();
+        let more = match missed.next() {
+            Some(_) => messages.last().map(|m| m.seq),
+            None => None,
+        };
+        let event = ServerEvent::Replay {
+            room: &self.name,
+            messages,
+            done: more.is_none(),
+        };
+        Some((event.to_json(), more))
+    }
+
+    // A member's keywords, in place of what they had
+    pub fn set_keywords(&mut self, id: Uuid, keywords: Vec<String>) -> Result<(), ProtocolError> {
+        if !self.members.contains(&id) {
+            return Ok(());
+        }
+        self.keywords.set(id, keywords)
+    }
+
+    // What a member is writing, kept until they post or change it; blank
+    // drops it
+    pub fn set_draft(&mut self, id: Uuid, body: String) -> Result<(), ProtocolError> {
+        if !self.members.contains(&id) {
+            return Ok(());
+        }
+        if body.chars().count() > MAX_DRAFT_LEN {
+            return Err(ProtocolError::new(
+                "bad_draft",
+                format!("Drafts are up to {} characters", MAX_DRAFT_LEN),
+            ));
+        }
+        if body.trim().is_empty() {
+            self.drafts.remove(&id);
+        } else {
+            self.drafts.insert(id, body);
+        }
+        Ok(())
+    }
+
+    // A member having read everything up to `seq`. Markers only move
+    // forward, and not past what's been posted.
+    pub fn mark_read(&mut self, id: Uuid, seq: u64) {
+        if !self.members.contains(&id) {
+            return;
+        }
+        let seq = seq.min(self.next_seq - 1);
+        let marker = self.read.entry(id).or_insert(seq);
+        *marker = (*marker).max(seq);
+    }
+
+    // How far `id` has read here, if they've ever joined, and how many
+    // visible messages from others in history they haven't
+    pub fn unread(&mut self, id: Uuid, now_ms: u64) -> Option<Unread> {
+        let last_read_seq = *self.read.get(&id)?;
+        self.prune(now_ms);
+        let start = self.history.partition_point(|m| m.seq <= last_read_seq);
+        let unread = self
+            .history
+            .range(start..)
+            .filter(|m| !m.hidden && m.from != id)
+            .count();
+        Some(Unread {
+            room: self.name.clone(),
+            last_read_seq,
+            unread,
+            draft: self.drafts.get(&id).cloned(),
+        })
+    }
+
+    // A member asking who else is here
+    pub fn list_members(&mut self, id: Uuid) {
+        if !self.members.contains(&id) {
+            return;
+        }
+        let event = ServerEvent::Members {
+            room: &self.name,
+            members: self.members(),
+        };
+        let event = event.to_json();
+        self.effects.push(Effect::Send(id, event));
+    }
+
+    // A member flagging a message; the report for the moderation queue, if
+    // there's such a message
+    pub fn report(&mut self, id: Uuid, seq: u64, reason: String) -> Option<FileReport> {
+        if !self.members.contains(&id) {
+            return None;
+        }
+        self.flush();
+
+        match self.message(seq) {
+            Some(message) => Some(FileReport {
+                room: self.name.clone(),
+                message_id: message.seq,
+                author: message.from,
+                body: message.body.clone(),
+                hidden: message.hidden,
+                report: Report {
+                    reporter: id,
+                    reason,
+                },
+            }),
+            None => {
+                let error =
+                    ProtocolError::new("no_such_message", format!("No such message: {}", seq));
+                self.refuse(id, error);
+                None
+            }
+        }
+    }
+
+    // Enough reports came in; the message is hidden pending review
+    pub fn hide(&mut self, seq: u64) {
+        if let Some(message) = self.message_mut(seq) {
+            message.hidden = true;
+        }
+        let event = ServerEvent::MessageHidden {
+            room: &self.name,
+            seq,
+        };
+        let event = event.to_json();
+        self.effects
+            .push(Effect::Export("message_hidden", event.clone()));
+        self.broadcast(event);
+    }
+
+    // A moderator dismissing the reports against a message
+    pub fn restore(&mut self, seq: u64) {
+        if let Some(message) = self.message_mut(seq) {
+            message.hidden = false;
+        }
+    }
+
+    // A moderator removing a message for good
+    pub fn delete(&mut self, seq: u64) {
+        self.flush();
+        if let Ok(i) = self.history.binary_search_by_key(&seq, |m| m.seq) {
+            self.history.remove(i);
+            let event = ServerEvent::MessageDeleted {
+                room: &self.name,
+                seq,
+            };
+            let event = event.to_json();
+            self.effects
+                .push(Effect::Export("message_deleted", event.clone()));
+            self.broadcast(event);
+        }
+    }
+
+    // What the room shows before joining. Messages are only shown for public
+    // rooms that anyone may join and read history in.
+    pub fn preview(&mut self, now_ms: u64) -> Preview {
+        // Anything shown has to have gone out live first
+        self.flush();
+        self.prune(now_ms);
+        let readable = self.settings.visibility == Visibility::Public
+            && self.settings.join_policy == JoinPolicy::Open
+            && self.settings.features.has(RoomFeatures::HISTORY);
+        let messages = if readable {
+            let (messages, _) = self.page(None, PREVIEW_LIMIT);
+            messages.into_iter().cloned().collect()
+        } else {
+            Vec::new()
+        };
+        Preview {
+            room: self.name.clone(),
+            members: self.members.len(),
+            messages,
+        }
+    }
+
+    // The visible messages sent from `from` up to `until` (unix millis),
+    // oldest first, for a transcript
+    pub fn transcript(&mut self, from: u64, until: u64, now_ms: u64) -> Vec<ChatMessage> {
+        self.flush();
+        self.prune(now_ms);
+        self.history
+            .iter()
+            .filter(|m| !m.hidden && from <= m.sent_at && m.sent_at < until)
+            .cloned()
+            .collect()
+    }
+
+    // Messages from elsewhere, oldest first, added to history after what's
+    // there. They're history, so nobody is sent them live; those past the
+    // room's retention are left out. Answers with how many went in.
+    pub fn import(&mut self, messages: Vec<ChatMessage>, now_ms: u64) -> usize {
+        let cutoff = self.cutoff(now_ms).unwrap_or(0);
+        let mut imported = 0;
+        for mut message in messages.into_iter().filter(|m| m.sent_at >= cutoff) {
+            message.seq = self.next_seq;
+            self.next_seq += 1;
+            self.remember(message);
+            imported += 1;
+        }
+        imported
+    }
+
+    // A moderator deleting the newest `last` messages, or only those from
+    // one user; all of them if `last` is left out. Answers with how many
+    // went.
+    pub fn purge(&mut self, last: Option<usize>, from: Option<Uuid>) -> usize {
+        self.flush();
+        let mut seqs: Vec<u64> = self
+            .history
+            .iter()
+            .rev()
+            .filter(|m| from.is_none_or(|from| m.from == from))
+            .take(last.unwrap_or(usize::MAX))
+            .map(|m| m.seq)
+            .collect();
+        if seqs.is_empty() {
+            return 0;
+        }
+        seqs.reverse();
+        self.history.retain(|m| seqs.binary_search(&m.seq).is_err());
+
+        let event = ServerEvent::BulkDelete {
+            room: &self.name,
+            seqs: &seqs,
+        };
+        let event = event.to_json();
+        self.effects
+            .push(Effect::Export("bulk_delete", event.clone()));
+        self.broadcast(event);
+        seqs.len()
+    }
+
+    // A moderator throwing someone out, for good or until the ban expires;
+    // answers whether they were here. Banning someone already banned
+    // replaces the ban.
+    pub fn ban(&mut self, ban: Ban) -> bool {
+        let id = ban.user;
+        let message = match &ban.reason {
+            Some(reason) => format!("You have been banned from {}: {}", self.name, reason),
+            None => format!("You have been banned from {}", self.name),
+        };
+        self.banned.insert(id, ban);
+        self.refuse(id, ProtocolError::new("banned", message));
+        self.remove(id)
+    }
+
+    // Lifting a ban early; answers whether there was one
+    pub fn unban(&mut self, id: Uuid) -> bool {
+        self.banned.remove(&id).is_some()
+    }
+
+    // A moderator putting someone out, who may come back; answers whether
+    // they were here
+    pub fn kick(&mut self, id: Uuid) -> bool {
+        if !self.members.contains(&id) {
+            return false;
+        }
+        self.kicked.insert(id);
+        let error =
+            ProtocolError::new("kicked", format!("You have been kicked from {}", self.name));
+        self.refuse(id, error);
+        self.remove(id)
+    }
+
+    fn remove(&mut self, id: Uuid) -> bool {
+        self.spectators.remove(&id);
+        self.pending.remove(&id);
+        self.moderators.remove(&id);
+        let removed = self.members.remove(&id);
+        if removed {
+            self.effects.push(Effect::Occupancy(self.members.len()));
+            self.gone(id);
+        }
+        removed
+    }
+
+    // A member no longer here, for the next presence event
+    fn gone(&mut self, id: Uuid) {
+        self.presence.typing.remove(&id);
+        // Gone within the window they came in is as good as never here
+        if !self.presence.joined.remove(&id) {
+            self.presence.left.insert(id);
+        }
+        self.queue_presence();
+    }
+
+    // A moderator shadow-muting someone, or lifting it. They aren't told,
+    // and carry on seeing their own messages.
+    pub fn mute(&mut self, id: Uuid, muted: bool) {
+        // Whatever they posted before this goes out as it was
+        self.flush();
+        if muted {
+            self.muted.insert(id);
+        } else {
+            self.muted.remove(&id);
+        }
+    }
+}
+
+// Whether a message mentions everyone: `@all` or `@here` on its own, not as
+// part of a word or an address
+fn mentions_all(body: &str) -> bool {
+    body.split(|c: char| !(c.is_alphanumeric() || "@._-".contains(c)))
+        .map(|word| word.trim_end_matches('.'))
+        .any(|word| word == "@all" || word == "@here")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    const NOW_MS: u64 = 1_000_000;
+
+    // Op - one thing that can happen to a room, done by or to user n
+    #[derive(Clone, Debug)]
+    enum Op {
+        Join(u128),
+        Spectate(u128),
+        Leave(u128),
+        Kick(u128),
+        Ban(u128),
+        Unban(u128),
+        Post(u128, String),
+        Flush,
+    }
+
+    fn op() -> impl Strategy<Value = Op> {
+        let user = 0..4u128;
+        prop_oneof![
+            3 => user.clone().prop_map(Op::Join),
+            1 => user.clone().prop_map(Op::Spectate),
+            2 => user.clone().prop_map(Op::Leave),
+            1 => user.clone().prop_map(Op::Kick),
+            1 => user.clone().prop_map(Op::Ban),
+            1 => user.clone().prop_map(Op::Unban),
+            4 => (user, "[a-z ]{0,12}").prop_map(|(user, body)| Op::Post(user, body)),
+            1 => Just(Op::Flush),
+        ]
+    }
+
+    fn joiner(id: Uuid, spectator: bool) -> Joiner {
+        Joiner {
+            id,
+            echo: false,
+            bot: false,
+            moderator: false,
+            invite: None,
+            spectator,
+            placed: false,
+        }
+    }
+
+    // Model - who should be where, kept by hand alongside the real thing
+    #[derive(Default)]
+    struct Model {
+        members: HashSet<Uuid>,
+        spectators: HashSet<Uuid>,
+        banned: HashSet<Uuid>,
+        stored: usize,
+    }
+    impl Model {
+        fn watching(&self) -> HashSet<Uuid> {
+            self.members.union(&self.spectators).copied().collect()
+        }
+    }
+
+    proptest! {
+        #[test]
+        fn rooms_keep_their_invariants(ops in prop::collection::vec(op(), 0..200)) {
+            let mut state = RoomState::new(RoomSettings::named("lobby"), None);
+            let mut model = Model::default();
+            let now = Instant::now();
+            for op in ops {
+                let before = model.watching();
+                match op {
+                    Op::Join(n) => {
+                        let id = Uuid::from_u128(n);
+                        let admitted = state.join(joiner(id, false), now, NOW_MS).is_ok();
+                        prop_assert_eq!(admitted, !model.banned.contains(&id));
+                        if admitted {
+                            model.members.insert(id);
+                        }
+                    }
+                    Op::Spectate(n) => {
+                        let id = Uuid::from_u128(n);
+                        if state.join(joiner(id, true), now, NOW_MS).is_ok() {
+                            model.spectators.insert(id);
+                        }
+                    }
+                    Op::Leave(n) => {
+                        let id = Uuid::from_u128(n);
+                        state.leave(id, now);
+                        model.members.remove(&id);
+                        model.spectators.remove(&id);
+                    }
+                    Op::Kick(n) => {
+                        let id = Uuid::from_u128(n);
+                        if state.kick(id) {
+                            model.members.remove(&id);
+                            model.spectators.remove(&id);
+                        }
+                    }
+                    Op::Ban(n) => {
+                        let id = Uuid::from_u128(n);
+                        state.ban(Ban::new(id, None, NOW_MS));
+                        model.banned.insert(id);
+                        model.members.remove(&id);
+                        model.spectators.remove(&id);
+                    }
+                    Op::Unban(n) => {
+                        let id = Uuid::from_u128(n);
+                        state.unban(id);
+                        model.banned.remove(&id);
+                    }
+                    Op::Post(n, body) => {
+                        let id = Uuid::from_u128(n);
+                        let posted = state.post(id, body, None, Uuid::new_v4(), now, NOW_MS);
+                        if model.members.contains(&id) {
+                            prop_assert!(matches!(posted, Ok(Delivery::Sent)));
+                            model.stored += 1;
+                        } else {
+                            prop_assert!(!matches!(posted, Ok(Delivery::Sent)));
+                        }
+                    }
+                    Op::Flush => state.flush_due(),
+                }
+
+                // Frames only go to those who were watching before or after
+                let after = model.watching();
+                for effect in state.effects() {
+                    if let Effect::Send(id, _) = effect {
+                        prop_assert!(before.contains(&id) || after.contains(&id));
+                    }
+                }
+                let members: HashSet<_> = state.members().into_iter().collect();
+                prop_assert_eq!(&members, &model.members);
+                prop_assert_eq!(&state.spectators, &model.spectators);
+                prop_assert!(members.is_disjoint(&state.kicked));
+                prop_assert!(members.iter().all(|id| state.banned(id, NOW_MS).is_none()));
+                prop_assert_eq!(state.history.len(), model.stored.min(MAX_HISTORY));
+                prop_assert!(state.history.iter().zip(state.history.iter().skip(1)).all(|(a, b)| a.seq < b.seq));
+            }
+        }
+    }
+
+    #[test]
+    fn what_is_kept_for_people_gone_is_bounded() {
+        let mut state = RoomState::new(RoomSettings::named("lobby"), None);
+        let now = Instant::now();
+        let gone = MAX_REMEMBERED as u128 + 10;
+        for n in 1..=gone {
+            let id = Uuid::from_u128(n);
+            state.join(joiner(id, false), now, NOW_MS).unwrap();
+            state.set_draft(id, "half a thought".to_string()).unwrap();
+            state.leave(id, now + Duration::from_secs(n as u64));
+        }
+        assert_eq!(state.remembered.len(), MAX_REMEMBERED);
+        assert_eq!(state.read.len(), MAX_REMEMBERED);
+        assert_eq!(state.drafts.len(), MAX_REMEMBERED);
+        // The first to go are the first forgotten
+        assert!(!state.read.contains_key(&Uuid::from_u128(1)));
+        assert!(state.read.contains_key(&Uuid::from_u128(gone)));
+
+        // Leaving on purpose keeps nothing, and nothing outlasts REMEMBER_FOR
+        state.forget(Uuid::from_u128(gone));
+        assert!(!state.drafts.contains_key(&Uuid::from_u128(gone)));
+        let later = now + REMEMBER_FOR + Duration::from_secs(gone as u64 + 1);
+        state.leave(Uuid::from_u128(gone + 1), later);
+        assert!(state.remembered.is_empty());
+        assert!(state.read.is_empty() && state.drafts.is_empty());
+    }
+
+    #[test]
+    fn any_retention_is_taken() {
+        let settings = RoomSettings {
+            retention_secs: Some(u64::MAX),
+            ..RoomSettings::named("lobby")
+        };
+        let mut state = RoomState::new(settings, None);
+        let id = Uuid::from_u128(1);
+        state
+            .join(joiner(id, false), Instant::now(), NOW_MS)
+            .unwrap();
+        let posted = state.post(
+            id,
+            "yee".to_string(),
+            None,
+            Uuid::new_v4(),
+            Instant::now(),
+            0,
+        );
+        assert!(posted.is_ok());
+        state.prune(NOW_MS);
+        assert_eq!(state.history.len(), 1);
+        assert_eq!(state.import(Vec::new(), NOW_MS), 0);
+    }
+
+    #[test]
+    fn imported_messages_expire_behind_newer_ones() {
+        let settings = RoomSettings {
+            retention_secs: Some(60),
+            ..RoomSettings::named("lobby")
+        };
+        let mut state = RoomState::new(settings, None);
+        let id = Uuid::from_u128(1);
+        state
+            .join(joiner(id, false), Instant::now(), NOW_MS)
+            .unwrap();
+        state
+            .post(
+                id,
+                "new".to_string(),
+                None,
+                Uuid::new_v4(),
+                Instant::now(),
+                NOW_MS,
+            )
+            .unwrap();
+        let old = ChatMessage {
+            id: Uuid::new_v4(),
+            seq: 0,
+            from: id,
+            body: "old".to_string(),
+            sent_at: NOW_MS - 30_000,
+            is_bot: false,
+            flair: None,
+            author: None,
+            hidden: false,
+        };
+        assert_eq!(state.import(vec![old], NOW_MS), 1);
+
+        state.prune(NOW_MS + 45_000);
+        let left: Vec<&str> = state.history.iter().map(|m| m.body.as_str()).collect();
+        assert_eq!(left, ["new"]);
+    }
+
+    #[test]
+    fn archiving_puts_everyone_out_and_keeps_history() {
+        let settings = RoomSettings {
+            join_policy: JoinPolicy::Approval,
+            ..RoomSettings::named("lobby")
+        };
+        let mut state = RoomState::new(settings, None);
+        let now = Instant::now();
+        let (member, watcher, waiting) =
+            (Uuid::from_u128(1), Uuid::from_u128(2), Uuid::from_u128(3));
+        let placed = Joiner {
+            placed: true,
+            ..joiner(member, false)
+        };
+        state.join(placed, now, NOW_MS).unwrap();
+        state.join(joiner(watcher, true), now, NOW_MS).unwrap();
+        state.join(joiner(waiting, false), now, NOW_MS).unwrap();
+        state
+            .post(member, "yee".to_string(), None, Uuid::new_v4(), now, NOW_MS)
+            .unwrap();
+        state.effects();
+
+        state.archive(now);
+        assert!(state.members().is_empty() && state.pending().is_empty());
+        assert!(!state.watching(&watcher));
+        let told: HashSet<Uuid> = state
+            .effects()
+            .into_iter()
+            .filter_map(|effect| match effect {
+                Effect::Send(id, frame) if frame.windows(8).any(|w| w == b"archived") => Some(id),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(told, HashSet::from([member, watcher, waiting]));
+        assert_eq!(state.transcript(0, NOW_MS + 1, NOW_MS).len(), 1);
+    }
+
+    #[test]
+    fn approval_is_held_to_the_room_limits() {
+        let settings = RoomSettings {
+            join_policy: JoinPolicy::Approval,
+            max_members: Some(1),
+            ..RoomSettings::named("lobby")
+        };
+        let mut state = RoomState::new(settings, None);
+        let now = Instant::now();
+        let (first, waiting, banned) = (Uuid::from_u128(1), Uuid::from_u128(2), Uuid::from_u128(3));
+        let placed = Joiner {
+            placed: true,
+            ..joiner(first, false)
+        };
+        for id in [waiting, banned] {
+            let asked = state.join(joiner(id, false), now, NOW_MS).unwrap();
+            assert!(matches!(asked, Admission::Pending));
+        }
+        state.join(placed, now, NOW_MS).unwrap();
+
+        // Filled since asking, so left waiting for room to be made
+        let e = state.approve(waiting, now, NOW_MS).unwrap_err();
+        assert_eq!(e.code, "room_full");
+        assert_eq!(state.pending(), [waiting, banned]);
+
+        // Banned without being put out, so turned away when approved
+        state.leave(first, now);
+        state.banned.insert(banned, Ban::new(banned, None, NOW_MS));
+        let e = state.approve(banned, now, NOW_MS).unwrap_err();
+        assert_eq!(e.code, "banned");
+        assert_eq!(state.pending(), [waiting]);
+
+        assert!(matches!(state.approve(waiting, now, NOW_MS), Ok(true)));
+        assert!(state.members.contains(&waiting));
+        assert!(matches!(state.approve(banned, now, NOW_MS), Ok(false)));
+    }
+}
@@ -0,0 +1,362 @@
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::time::Duration;
+use uuid::Uuid;
+use xtra::prelude::*;
+
+use crate::registry::{FindRoom, RoomRegistry};
+use crate::services::Services;
+use crate::transcript::civil;
+use crate::{ids, metrics, now_millis, Bot, GotUserMessage, RoomMembers};
+
+// How many missed minutes a late Tick catches up on
+const MAX_CATCH_UP: i64 = 5;
+
+// Cron - when something recurs, as the five fields of a crontab line:
+// minute, hour, day of the month, month and day of the week (0 or 7 is
+// Sunday). Fields take `*`, numbers, ranges (`1-5`), steps (`*/15`, `9-17/2`)
+// and lists of those.
+#[derive(Clone, Deserialize, Serialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct Cron {
+    text: String,
+    // Bit n set for each value n that matches
+    minutes: u64,
+    hours: u64,
+    days: u64,
+    months: u64,
+    weekdays: u64,
+    // Whether the day fields were `*`; when neither is, either will do
+    any_day: bool,
+    any_weekday: bool,
+}
+impl Cron {
+    // Whether it's due in the minute `minutes` after the epoch, local time
+    fn matches(&self, minutes: i64) -> bool {
+        let days = minutes.div_euclid(24 * 60);
+        let (_, month, day) = civil(days);
+        // 1970-01-01 was a Thursday
+        let weekday = (days + 4).rem_euclid(7);
+        let day = match (self.any_day, self.any_weekday) {
+            (false, false) => has(self.days, day as i64) || has(self.weekdays, weekday),
+            _ => has(self.days, day as i64) && has(self.weekdays, weekday),
+        };
+        day && has(self.minutes, minutes.rem_euclid(60))
+            && has(self.hours, minutes.div_euclid(60).rem_euclid(24))
+            && has(self.months, month as i64)
+    }
+}
+impl TryFrom<String> for Cron {
+    type Error = String;
+
+    fn try_from(text: String) -> Result<Self, Self::Error> {
+        let fields: Vec<_> = text.split_whitespace().collect();
+        let [minutes, hours, days, months, weekdays] = match fields.as_slice() {
+            [a, b, c, d, e] => [*a, *b, *c, *d, *e],
+            _ => return Err(format!("{}: expected five fields", text)),
+        };
+        let mut weekdays_mask = field(weekdays, 0, 7)?;
+        // Sunday is 0 or 7
+        if has(weekdays_mask, 7) {
+            weekdays_mask |= 1;
+        }
+        Ok(Self {
+            minutes: field(minutes, 0, 59)?,
+            hours: field(hours, 0, 23)?,
+            days: field(days, 1, 31)?,
+            months: field(months, 1, 12)?,
+            weekdays: weekdays_mask,
+            any_day: days == "*",
+            any_weekday: weekdays == "*",
+            text,
+        })
+    }
+}
+impl From<Cron> for String {
+    fn from(cron: Cron) -> Self {
+        cron.text
+    }
+}
+
+fn has(mask: u64, value: i64) -> bool {
+    (0..64).contains(&value) && mask & (1 << value) != 0
+}
+
+// The values one field matches, from `min` to `max`, as a mask
+fn field(text: &str, min: u64, max: u64) -> Result<u64, String> {
+    let number = |n: &str| match n.parse::<u64>() {
+        Ok(n) if (min..=max).contains(&n) => Ok(n),
+        _ => Err(format!("{}: expected {} to {}", text, min, max)),
+    };
+    let mut mask = 0;
+    for part in text.split(',') {
+        let (range, step) = match part.split_once('/') {
+            Some((range, step)) => match step.parse::<u64>() {
+                Ok(step) if step > 0 => (range, step),
+                _ => return Err(format!("{}: bad step", text)),
+            },
+            None => (part, 1),
+        };
+        let (from, to) = match range.split_once('-') {
+            _ if range == "*" => (min, max),
+            Some((from, to)) => (number(from)?, number(to)?),
+            // `5/15` runs from 5 to the end
+            None if step > 1 => (number(range)?, max),
+            None => (number(range)?, number(range)?),
+        };
+        if from > to {
+            return Err(format!("{}: backwards range", text));
+        }
+        for value in (from..=to).step_by(step as usize) {
+            mask |= 1 << value;
+        }
+    }
+    Ok(mask)
+}
+
+// Offset - a fixed offset from UTC, `+02:00` or `-05:30`, or `UTC`. There's
+// no daylight saving; zones that have it need changing when it starts and
+// ends.
+#[derive(Clone, Copy, Default, Deserialize, Serialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct Offset {
+    minutes: i64,
+}
+impl Offset {
+    // Unix millis moved to what a clock at this offset would show
+    pub fn shift(self, ms: u64) -> i64 {
+        ms as i64 + self.minutes * 60_000
+    }
+
+    pub fn is_utc(self) -> bool {
+        self.minutes == 0
+    }
+}
+impl TryFrom<String> for Offset {
+    type Error = String;
+
+    fn try_from(text: String) -> Result<Self, Self::Error> {
+        if text == "UTC" || text == "Z" {
+            return Ok(Offset::default());
+        }
+        let bad = || format!("{}: expected UTC or an offset like +02:00", text);
+        let (sign, rest) = match text.split_at(text.len().min(1)) {
+            ("+", rest) => (1, rest),
+            ("-", rest) => (-1, rest),
+            _ => return Err(bad()),
+        };
+        let (hours, minutes) = rest.split_once(':').ok_or_else(bad)?;
+        let hours: i64 = hours.parse().map_err(|_| bad())?;
+        let minutes: i64 = minutes.parse().map_err(|_| bad())?;
+        if hours > 14 || minutes > 59 {
+            return Err(bad());
+        }
+        Ok(Offset {
+            minutes: sign * (hours * 60 + minutes),
+        })
+    }
+}
+impl From<Offset> for String {
+    fn from(offset: Offset) -> Self {
+        if offset.minutes == 0 {
+            return "UTC".to_string();
+        }
+        let sign = if offset.minutes < 0 { '-' } else { '+' };
+        let minutes = offset.minutes.abs();
+        format!("{}{:02}:{:02}", sign, minutes / 60, minutes % 60)
+    }
+}
+
+// Announcement - something posted to a room whenever `cron` says, in the
+// `timezone` given, by a bot flaired `announcement`
+#[derive(Clone, Deserialize, Serialize)]
+pub struct Announcement {
+    #[serde(default = "Uuid::nil", skip_deserializing)]
+    pub id: Uuid,
+    pub room: String,
+    pub cron: Cron,
+    #[serde(default)]
+    pub timezone: Offset,
+    pub body: String,
+    // Not posted while nobody is in the room to read it
+    #[serde(default = "skip_if_empty")]
+    pub skip_if_empty: bool,
+}
+fn skip_if_empty() -> bool {
+    true
+}
+
+// Announcements - a community's recurring announcements, by id. Tick
+// comes round each minute and posts the ones due.
+pub struct Announcements {
+    announcements: HashMap<Uuid, Announcement>,
+    // The last minute after the epoch, UTC, that was ticked
+    ticked: Option<i64>,
+    services: Services,
+}
+impl Actor for Announcements {}
+impl Announcements {
+    pub fn new(services: Services) -> Self {
+        Self {
+            announcements: HashMap::new(),
+            ticked: None,
+            services,
+        }
+    }
+
+    // Posts it to its room, unless the room is gone or nobody is there
+    async fn announce(&self, announcement: &Announcement) {
+        let registry = self.services.get::<RoomRegistry>();
+        let room = match registry.send(FindRoom(announcement.room.clone())).await {
+            Ok(Some((_, room))) => room,
+            _ => {
+                log::warn!("Announcement {} has no room", announcement.id);
+                return;
+            }
+        };
+        if announcement.skip_if_empty {
+            let members = match room.send(RoomMembers).await {
+                Ok(members) => members,
+                Err(_) => {
+                    log::warn!("Announcement {} has no room", announcement.id);
+                    return;
+                }
+            };
+            if members.is_empty() {
+                return;
+            }
+        }
+        let bot = Bot {
+            flair: Some("announcement".to_string()),
+        };
+        let posted = room
+            .send(GotUserMessage(
+                Uuid::nil(),
+                announcement.body.clone(),
+                Some(bot),
+            ))
+            .await;
+        match posted {
+            Ok(Err(e)) => log::warn!("Announcement {} refused: {}", announcement.id, e),
+            Err(_) => log::warn!("Announcement {} has no room", announcement.id),
+            Ok(Ok(_)) => (),
+        }
+    }
+}
+
+// AddAnnouncement - answers with it, given an id
+pub struct AddAnnouncement(pub Announcement);
+impl Message for AddAnnouncement {
+    type Result = Announcement;
+}
+#[async_trait::async_trait]
+impl Handler<AddAnnouncement> for Announcements {
+    async fn handle(&mut self, msg: AddAnnouncement, _ctx: &mut Context<Self>) -> Announcement {
+        let _timer = metrics::timer("add_announcement");
+        let announcement = Announcement {
+            id: ids::next(),
+            ..msg.0
+        };
+        self.announcements
+            .insert(announcement.id, announcement.clone());
+        announcement
+    }
+}
+
+// UpdateAnnouncement - replaces one; None if there's no such announcement
+pub struct UpdateAnnouncement(pub Uuid, pub Announcement);
+impl Message for UpdateAnnouncement {
+    type Result = Option<Announcement>;
+}
+#[async_trait::async_trait]
+impl Handler<UpdateAnnouncement> for Announcements {
+    async fn handle(
+        &mut self,
+        msg: UpdateAnnouncement,
+        _ctx: &mut Context<Self>,
+    ) -> Option<Announcement> {
+        let _timer = metrics::timer("update_announcement");
+        let announcement = self.announcements.get_mut(&msg.0)?;
+        *announcement = Announcement { id: msg.0, ..msg.1 };
+        Some(announcement.clone())
+    }
+}
+
+// RemoveAnnouncement - answers whether there was such an announcement
+pub struct RemoveAnnouncement(pub Uuid);
+impl Message for RemoveAnnouncement {
+    type Result = bool;
+}
+#[async_trait::async_trait]
+impl Handler<RemoveAnnouncement> for Announcements {
+    async fn handle(&mut self, msg: RemoveAnnouncement, _ctx: &mut Context<Self>) -> bool {
+        let _timer = metrics::timer("remove_announcement");
+        self.announcements.remove(&msg.0).is_some()
+    }
+}
+
+// ListAnnouncements - all of them, by id
+pub struct ListAnnouncements;
+impl Message for ListAnnouncements {
+    type Result = Vec<Announcement>;
+}
+#[async_trait::async_trait]
+impl Handler<ListAnnouncements> for Announcements {
+    async fn handle(
+        &mut self,
+        _msg: ListAnnouncements,
+        _ctx: &mut Context<Self>,
+    ) -> Vec<Announcement> {
+        let _timer = metrics::timer("list_announcements");
+        let mut announcements: Vec<_> = self.announcements.values().cloned().collect();
+        announcements.sort_by_key(|announcement| announcement.id);
+        announcements
+    }
+}
+
+// Tick - the minute has turned, at unix millis `now`. Minutes missed since
+// the last Tick, up to MAX_CATCH_UP, are caught up on.
+struct Tick(u64);
+impl Message for Tick {
+    type Result = ();
+}
+#[async_trait::async_trait]
+impl Handler<Tick> for Announcements {
+    async fn handle(&mut self, msg: Tick, _ctx: &mut Context<Self>) {
+        let _timer = metrics::timer("tick");
+        let now = (msg.0 / 60_000) as i64;
+        let from = match self.ticked {
+            Some(ticked) => (ticked + 1).max(now - MAX_CATCH_UP + 1),
+            None => now,
+        };
+        self.ticked = Some(now);
+        let due: Vec<_> = self
+            .announcements
+            .values()
+            .filter(|announcement| {
+                (from..=now).any(|minute| {
+                    announcement
+                        .cron
+                        .matches(minute + announcement.timezone.minutes)
+                })
+            })
+            .cloned()
+            .collect();
+        for announcement in &due {
+            self.announce(announcement).await;
+        }
+    }
+}
+
+// Sends Tick just after the start of every minute, until Announcements
+// stops
+pub async fn tick(announcements: Address<Announcements>) {
+    loop {
+        let wait = 60_000 - now_millis() % 60_000 + 50;
+        tokio::time::sleep(Duration::from_millis(wait)).await;
+        if announcements.send(Tick(now_millis())).await.is_err() {
+            return;
+        }
+    }
+}
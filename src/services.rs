@@ -0,0 +1,34 @@
+use std::any::{type_name, Any, TypeId};
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+use xtra::prelude::*;
+
+// Services - a community's long-lived actors, by type. Whatever needs one
+// looks it up when it's needed rather than being handed its address, so an
+// actor that's registered again later is what everyone gets from then on.
+// Each tenant has its own.
+#[derive(Clone, Default)]
+pub struct Services {
+    actors: Arc<RwLock<HashMap<TypeId, Box<dyn Any + Send + Sync>>>>,
+}
+impl Services {
+    // Makes `addr` the one to get for its type, in place of any before it
+    pub fn register<A: Actor>(&self, addr: Address<A>) {
+        let mut actors = self.actors.write().expect("Services lock poisoned");
+        actors.insert(TypeId::of::<A>(), Box::new(addr));
+    }
+
+    pub fn find<A: Actor>(&self) -> Option<Address<A>> {
+        let actors = self.actors.read().expect("Services lock poisoned");
+        actors
+            .get(&TypeId::of::<A>())
+            .and_then(|addr| addr.downcast_ref::<Address<A>>())
+            .cloned()
+    }
+
+    // For actors every community has; one that's missing is a bug in main
+    pub fn get<A: Actor>(&self) -> Address<A> {
+        self.find()
+            .unwrap_or_else(|| panic!("No {} registered", type_name::<A>()))
+    }
+}
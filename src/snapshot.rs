@@ -0,0 +1,105 @@
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use uuid::Uuid;
+use xtra::prelude::*;
+
+use crate::permissions;
+use crate::registry::{ExportRooms, ListTemplates, RestoreRooms, RoomRegistry, RoomSettings};
+use crate::room::Ban;
+use crate::{AssignRole, BanUser, ListBans, ProtocolError, RoomGrants};
+
+// Snapshot - a community's configuration as one document, for backing it
+// up or moving it to another server. Messages, members and keys aren't in
+// it, and neither are threads.
+#[derive(Deserialize, Serialize)]
+pub struct Snapshot {
+    #[serde(default)]
+    pub rooms: Vec<SavedRoom>,
+    #[serde(default)]
+    pub templates: Vec<RoomSettings>,
+}
+
+// SavedRoom - one room: its settings (roles and commands included), its
+// other names, the roles it's given out and its bans
+#[derive(Deserialize, Serialize)]
+pub struct SavedRoom {
+    pub settings: RoomSettings,
+    #[serde(default)]
+    pub aliases: Vec<String>,
+    #[serde(default)]
+    pub grants: BTreeMap<Uuid, String>,
+    #[serde(default)]
+    pub bans: Vec<Ban>,
+}
+
+pub async fn export(registry: &Address<RoomRegistry>) -> Snapshot {
+    let rooms = registry
+        .send(ExportRooms)
+        .await
+        .expect("Could not reach the registry");
+    let mut saved = Vec::new();
+    for (settings, aliases, room) in rooms {
+        saved.push(SavedRoom {
+            settings,
+            aliases,
+            grants: room.send(RoomGrants).await.expect("Could not list grants"),
+            bans: room.send(ListBans).await.expect("Could not list bans"),
+        });
+    }
+    Snapshot {
+        rooms: saved,
+        templates: registry
+            .send(ListTemplates)
+            .await
+            .expect("Could not reach the registry"),
+    }
+}
+
+// Applies a snapshot on top of what's here, answering with how many rooms
+// were in it. Anything that won't do is found before any of it is applied.
+pub async fn restore(
+    registry: &Address<RoomRegistry>,
+    snapshot: Snapshot,
+) -> Result<usize, ProtocolError> {
+    for room in &snapshot.rooms {
+        let roles = &room.settings.roles;
+        if let Some(role) = room
+            .grants
+            .values()
+            .find(|role| !roles.contains_key(*role) && permissions::builtin(role).is_none())
+        {
+            return Err(ProtocolError::new(
+                "no_such_role",
+                format!("{} has no role called {}", room.settings.name, role),
+            ));
+        }
+    }
+
+    let (rooms, given): (Vec<_>, Vec<_>) = snapshot
+        .rooms
+        .into_iter()
+        .map(|room| ((room.settings, room.aliases), (room.grants, room.bans)))
+        .unzip();
+    let addrs = registry
+        .send(RestoreRooms {
+            rooms,
+            templates: snapshot.templates,
+        })
+        .await
+        .expect("Could not reach the registry")?;
+
+    for (room, (grants, bans)) in addrs.iter().zip(given) {
+        for (user, role) in grants {
+            room.send(AssignRole {
+                user,
+                role: Some(role),
+            })
+            .await
+            .expect("Could not reach the room")?;
+        }
+        for ban in bans {
+            room.send(BanUser(ban)).await.expect("Could not ban user");
+        }
+    }
+    Ok(addrs.len())
+}
@@ -0,0 +1,97 @@
+use serde::Serialize;
+use std::collections::{HashMap, VecDeque};
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+use uuid::Uuid;
+
+// Links posted this soon after joining are spam, however few
+const JUST_JOINED: Duration = Duration::from_secs(10);
+
+// SpamAction - what happens to someone caught link spamming
+#[derive(Clone, Copy, Debug, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SpamAction {
+    // Out of the room until they join again
+    Kick,
+    // Out of the room and its threads for LinkPolicy::ban_secs
+    Ban,
+    // Still there, but nobody else sees what they post
+    Mute,
+}
+impl FromStr for SpamAction {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "kick" => Ok(SpamAction::Kick),
+            "ban" => Ok(SpamAction::Ban),
+            "mute" => Ok(SpamAction::Mute),
+            _ => Err(format!("{}: expected kick, ban or mute", s)),
+        }
+    }
+}
+
+// LinkPolicy - more than `limit` different links within `window` is spam
+#[derive(Clone, Copy, Debug)]
+pub struct LinkPolicy {
+    pub limit: usize,
+    pub window: Duration,
+    pub action: SpamAction,
+    // How long a ban lasts; for good if None
+    pub ban_secs: Option<u64>,
+}
+
+// The links in a message, lowercased and without trailing punctuation
+fn links(body: &str) -> impl Iterator<Item = String> + '_ {
+    body.split_whitespace()
+        .map(|word| word.trim_end_matches(|c: char| ".,;:!?)]}>'\"".contains(c)))
+        .filter(|word| {
+            let word = word.to_ascii_lowercase();
+            word.starts_with("http://") || word.starts_with("https://") || word.starts_with("www.")
+        })
+        .map(str::to_ascii_lowercase)
+}
+
+// LinkTracker - the links each member of a room posted lately
+#[derive(Default)]
+pub struct LinkTracker {
+    posted: HashMap<Uuid, VecDeque<(Instant, String)>>,
+}
+impl LinkTracker {
+    // Notes the links in `body`, posted at `now`, and says whether its
+    // author is spamming
+    pub fn spam(
+        &mut self,
+        policy: &LinkPolicy,
+        id: Uuid,
+        joined: Instant,
+        now: Instant,
+        body: &str,
+    ) -> bool {
+        let mut found = links(body).peekable();
+        if found.peek().is_none() {
+            return false;
+        }
+        if now.duration_since(joined) < JUST_JOINED {
+            return true;
+        }
+
+        let posted = self.posted.entry(id).or_default();
+        while posted
+            .front()
+            .is_some_and(|(at, _)| now.duration_since(*at) > policy.window)
+        {
+            posted.pop_front();
+        }
+        for link in found {
+            if !posted.iter().any(|(_, seen)| *seen == link) {
+                posted.push_back((now, link));
+            }
+        }
+        posted.len() > policy.limit
+    }
+
+    pub fn forget(&mut self, id: &Uuid) {
+        self.posted.remove(id);
+    }
+}
@@ -0,0 +1,195 @@
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap};
+use uuid::Uuid;
+use warp::{Filter, Rejection, Reply};
+use xtra::prelude::*;
+
+use crate::metrics;
+use crate::registry::{self, GetRoom, RoomRegistry};
+
+const HOUR_MS: u64 = 60 * 60 * 1000;
+const DAY_MS: u64 = 24 * HOUR_MS;
+// How far back the hourly and daily counts go
+const HOURS_KEPT: u64 = 24;
+const DAYS_KEPT: u64 = 30;
+const TOP_POSTERS: usize = 10;
+
+// RoomStats - running totals for one room
+#[derive(Default)]
+struct RoomStats {
+    // Messages per hour and per day, keyed by the bucket's start
+    hourly: BTreeMap<u64, u64>,
+    daily: BTreeMap<u64, u64>,
+    members: usize,
+    peak_members: usize,
+    posters: HashMap<Uuid, u64>,
+}
+
+// Stats - keeps each room's statistics up to date as rooms tell it what
+// happened. Rooms never wait on it.
+#[derive(Default)]
+pub struct Stats {
+    rooms: HashMap<String, RoomStats>,
+}
+impl Actor for Stats {}
+
+// Posted - a message went out in a room
+pub struct Posted {
+    pub room: String,
+    pub from: Uuid,
+    // Milliseconds since the unix epoch
+    pub at: u64,
+}
+impl Message for Posted {
+    type Result = ();
+}
+#[async_trait::async_trait]
+impl Handler<Posted> for Stats {
+    async fn handle(&mut self, msg: Posted, _ctx: &mut Context<Self>) {
+        let _timer = metrics::timer("posted");
+        let stats = self.rooms.entry(msg.room).or_default();
+        let hour = msg.at - msg.at % HOUR_MS;
+        let day = msg.at - msg.at % DAY_MS;
+        *stats.hourly.entry(hour).or_default() += 1;
+        *stats.daily.entry(day).or_default() += 1;
+        *stats.posters.entry(msg.from).or_default() += 1;
+
+        // Old buckets only ever fall out of range
+        let hours_from = hour.saturating_sub((HOURS_KEPT - 1) * HOUR_MS);
+        let days_from = day.saturating_sub((DAYS_KEPT - 1) * DAY_MS);
+        stats.hourly = stats.hourly.split_off(&hours_from);
+        stats.daily = stats.daily.split_off(&days_from);
+    }
+}
+
+// Renamed - a room goes by a new name now, and its statistics with it
+pub struct Renamed {
+    pub from: String,
+    pub to: String,
+}
+impl Message for Renamed {
+    type Result = ();
+}
+#[async_trait::async_trait]
+impl Handler<Renamed> for Stats {
+    async fn handle(&mut self, msg: Renamed, _ctx: &mut Context<Self>) {
+        let _timer = metrics::timer("renamed");
+        if let Some(stats) = self.rooms.remove(&msg.from) {
+            self.rooms.insert(msg.to, stats);
+        }
+    }
+}
+
+// Occupancy - how many members a room has now
+pub struct Occupancy {
+    pub room: String,
+    pub members: usize,
+}
+impl Message for Occupancy {
+    type Result = ();
+}
+#[async_trait::async_trait]
+impl Handler<Occupancy> for Stats {
+    async fn handle(&mut self, msg: Occupancy, _ctx: &mut Context<Self>) {
+        let _timer = metrics::timer("occupancy");
+        let stats = self.rooms.entry(msg.room).or_default();
+        stats.members = msg.members;
+        stats.peak_members = stats.peak_members.max(msg.members);
+    }
+}
+
+#[derive(Serialize)]
+pub struct Bucket {
+    // Milliseconds since the unix epoch
+    start: u64,
+    messages: u64,
+}
+
+#[derive(Serialize)]
+pub struct Poster {
+    user: Uuid,
+    messages: u64,
+}
+
+// Summary - what GET /rooms/:name/stats answers with
+#[derive(Serialize)]
+pub struct Summary {
+    room: String,
+    members: usize,
+    peak_members: usize,
+    // Hours and days with messages in them, oldest first
+    hourly: Vec<Bucket>,
+    daily: Vec<Bucket>,
+    top_posters: Vec<Poster>,
+}
+
+// GetStats - a room's statistics so far, zeroes if nothing has happened
+pub struct GetStats(pub String);
+impl Message for GetStats {
+    type Result = Summary;
+}
+#[async_trait::async_trait]
+impl Handler<GetStats> for Stats {
+    async fn handle(&mut self, msg: GetStats, _ctx: &mut Context<Self>) -> Summary {
+        let _timer = metrics::timer("get_stats");
+        let empty = RoomStats::default();
+        let stats = self.rooms.get(&msg.0).unwrap_or(&empty);
+        let buckets = |counts: &BTreeMap<u64, u64>| {
+            counts
+                .iter()
+                .map(|(&start, &messages)| Bucket { start, messages })
+                .collect()
+        };
+
+        let mut top_posters: Vec<_> = stats
+            .posters
+            .iter()
+            .map(|(&user, &messages)| Poster { user, messages })
+            .collect();
+        top_posters.sort_by(|a, b| b.messages.cmp(&a.messages).then(a.user.cmp(&b.user)));
+        top_posters.truncate(TOP_POSTERS);
+
+        Summary {
+            hourly: buckets(&stats.hourly),
+            daily: buckets(&stats.daily),
+            members: stats.members,
+            peak_members: stats.peak_members,
+            top_posters,
+            room: msg.0,
+        }
+    }
+}
+
+// GET /rooms/:name/stats
+pub fn routes(
+    stats: Address<Stats>,
+    registry: Address<RoomRegistry>,
+) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    let stats = warp::any().map(move || stats.clone());
+    let registry = warp::any().map(move || registry.clone());
+
+    warp::path!("rooms" / String / "stats")
+        .and(warp::get())
+        .and(stats)
+        .and(registry)
+        .and_then(room_stats)
+}
+
+async fn room_stats(
+    name: String,
+    stats: Address<Stats>,
+    registry: Address<RoomRegistry>,
+) -> Result<impl Reply, Rejection> {
+    let name = registry::normalize(&name).map_err(|_| warp::reject::not_found())?;
+    registry
+        .send(GetRoom(name.clone()))
+        .await
+        .expect("Could not reach the registry")
+        .ok_or_else(warp::reject::not_found)?;
+
+    let summary = stats
+        .send(GetStats(name))
+        .await
+        .expect("Could not get stats");
+    Ok(warp::reply::json(&summary))
+}
@@ -0,0 +1,75 @@
+// Fills in operator-written text, like a room's MOTD: `{room}`, `{members}`
+// and `{user}` are swapped for their values when it's sent, and `{{` / `}}`
+// stand for literal braces.
+
+// The variables there are
+pub const VARIABLES: &[&str] = &["room", "members", "user"];
+
+// Part - a piece of a template
+enum Part<'a> {
+    Text(&'a str),
+    Variable(&'a str),
+}
+
+fn parse(template: &str) -> Result<Vec<Part<'_>>, String> {
+    let mut parts = Vec::new();
+    let mut rest = template;
+    while let Some(i) = rest.find(['{', '}']) {
+        parts.push(Part::Text(&rest[..i]));
+        let (brace, after) = rest[i..].split_at(1);
+        rest = match (brace, after.strip_prefix(brace)) {
+            // Doubled, so a literal brace
+            (_, Some(after)) => {
+                parts.push(Part::Text(brace));
+                after
+            }
+            ("{", None) => {
+                let end = after
+                    .find('}')
+                    .ok_or_else(|| format!("{{ without }} in {:?}", template))?;
+                let name = &after[..end];
+                if !VARIABLES.contains(&name) {
+                    return Err(format!(
+                        "No variable {{{}}}, there's {}",
+                        name,
+                        VARIABLES
+                            .iter()
+                            .map(|name| format!("{{{}}}", name))
+                            .collect::<Vec<_>>()
+                            .join(", ")
+                    ));
+                }
+                parts.push(Part::Variable(name));
+                &after[end + 1..]
+            }
+            _ => return Err(format!("}} without {{ in {:?}", template)),
+        };
+    }
+    parts.push(Part::Text(rest));
+    Ok(parts)
+}
+
+// Why a template won't do, if it won't
+pub fn check(template: &str) -> Result<(), String> {
+    parse(template).map(|_| ())
+}
+
+// The template with `values` (name, value) filled in. Templates are checked
+// before they're used, so a bad one only comes back as it is.
+pub fn render(template: &str, values: &[(&str, String)]) -> String {
+    let parts = match parse(template) {
+        Ok(parts) => parts,
+        Err(_) => return template.to_string(),
+    };
+    parts
+        .into_iter()
+        .map(|part| match part {
+            Part::Text(text) => text,
+            Part::Variable(name) => values
+                .iter()
+                .find(|(variable, _)| *variable == name)
+                .map(|(_, value)| value.as_str())
+                .unwrap_or(""),
+        })
+        .collect()
+}
@@ -0,0 +1,75 @@
+use std::convert::Infallible;
+use std::str::FromStr;
+use std::sync::Arc;
+use warp::{Filter, Rejection};
+
+use crate::proxy::Peer;
+
+// Tenant - a community of its own, served on its own host. Each gets its
+// own rooms, users, keys and quotas, and reads CHAT_<NAME>_* settings ahead
+// of the CHAT_* ones.
+#[derive(Debug)]
+pub struct Tenant {
+    pub name: String,
+    pub host: String,
+}
+impl FromStr for Tenant {
+    type Err = String;
+
+    // `<name>=<host>`, e.g. `acme=chat.acme.com`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (name, host) = s
+            .split_once('=')
+            .ok_or_else(|| format!("{}: expected <name>=<host>", s))?;
+        let name = name.trim().to_ascii_lowercase();
+        let named = !name.is_empty() && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_');
+        if !named {
+            return Err(format!("{}: names are letters, digits and _", s));
+        }
+        Ok(Self {
+            name,
+            host: host.trim().to_ascii_lowercase(),
+        })
+    }
+}
+
+// The host a request was for, without its port
+fn hostname(peer: &Peer) -> Option<String> {
+    let host = peer.host.as_deref()?.to_ascii_lowercase();
+    let port = host
+        .rsplit_once(':')
+        .filter(|(_, port)| !port.is_empty() && port.chars().all(|c| c.is_ascii_digit()));
+    Some(match port {
+        // Not the inside of an IPv6 address
+        Some((name, _)) if !name.contains(':') || name.ends_with(']') => name.to_string(),
+        _ => host,
+    })
+}
+
+// Lets through requests for a tenant's `host`, or with none, for hosts no
+// tenant has. Requests only ever reach one community this way,
+// so what one turns away can't be answered by another.
+pub fn serving<P>(
+    host: Option<String>,
+    claimed: Arc<Vec<String>>,
+    peer: P,
+) -> impl Filter<Extract = (), Error = Rejection> + Clone
+where
+    P: Filter<Extract = (Peer,), Error = Infallible> + Clone + Send + Sync + 'static,
+{
+    peer.and_then(move |peer: Peer| {
+        let ours = match (&host, hostname(&peer)) {
+            (Some(host), asked) => asked.as_ref() == Some(host),
+            (None, Some(asked)) => !claimed.contains(&asked),
+            (None, None) => true,
+        };
+        async move {
+            if ours {
+                Ok(())
+            } else {
+                Err(warp::reject::not_found())
+            }
+        }
+    })
+    .untuple_one()
+}
@@ -0,0 +1,100 @@
+use serde::Serialize;
+use serde_json::Value;
+use std::collections::VecDeque;
+use std::sync::Mutex;
+
+use crate::now_millis;
+
+// Tracing - how much of each connection's traffic is kept
+#[derive(Clone, Copy)]
+pub struct Tracing {
+    // Frames kept, in and out together
+    pub frames: usize,
+    // Whether message bodies are kept as they are, rather than as their length
+    pub bodies: bool,
+}
+
+// Direction - which way a frame went
+#[derive(Clone, Copy, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Direction {
+    In,
+    Out,
+}
+
+// Frame - one frame a connection sent or was sent
+#[derive(Clone, Serialize)]
+pub struct Frame {
+    // Unix millis
+    pub at: u64,
+    pub direction: Direction,
+    pub frame: String,
+}
+
+// Trace - the last frames of one connection, for debugging clients. The
+// reader and the writer both record into it, so it's behind a lock, held
+// only to push or copy.
+pub struct Trace {
+    tracing: Tracing,
+    frames: Mutex<VecDeque<Frame>>,
+}
+impl Trace {
+    pub fn new(tracing: Tracing) -> Self {
+        Self {
+            tracing,
+            frames: Mutex::new(VecDeque::with_capacity(tracing.frames)),
+        }
+    }
+
+    pub fn record(&self, direction: Direction, frame: &str) {
+        let frame = if self.tracing.bodies {
+            frame.to_string()
+        } else {
+            redact(frame)
+        };
+        let mut frames = self.frames.lock().expect("Trace lock poisoned");
+        if frames.len() == self.tracing.frames {
+            frames.pop_front();
+        }
+        frames.push_back(Frame {
+            at: now_millis(),
+            direction,
+            frame,
+        });
+    }
+
+    // Everything kept, oldest first
+    pub fn frames(&self) -> Vec<Frame> {
+        let frames = self.frames.lock().expect("Trace lock poisoned");
+        frames.iter().cloned().collect()
+    }
+}
+
+// The frame with every `body` swapped for its length. Frames that aren't
+// JSON are kept as their length alone.
+fn redact(frame: &str) -> String {
+    match serde_json::from_str::<Value>(frame) {
+        Ok(mut value) => {
+            redact_value(&mut value);
+            value.to_string()
+        }
+        Err(_) => format!("<{} bytes>", frame.len()),
+    }
+}
+
+fn redact_value(value: &mut Value) {
+    match value {
+        Value::Object(fields) => {
+            for (name, field) in fields.iter_mut() {
+                match field {
+                    Value::String(body) if name == "body" => {
+                        *field = Value::String(format!("<{} bytes>", body.len()));
+                    }
+                    field => redact_value(field),
+                }
+            }
+        }
+        Value::Array(items) => items.iter_mut().for_each(redact_value),
+        _ => {}
+    }
+}
@@ -0,0 +1,135 @@
+use std::fmt::Write;
+
+use crate::schedule::Offset;
+use crate::{names, ChatMessage};
+
+// Renders messages as a standalone HTML page: no scripts, nothing loaded
+// from elsewhere, and everything users wrote escaped, so it can be published
+// as is. Times are shown at the room's offset from UTC.
+pub fn render(
+    room: &str,
+    from: u64,
+    until: u64,
+    messages: &[ChatMessage],
+    timezone: Offset,
+) -> String {
+    let title = format!(
+        "{} {} to {}",
+        escape(room),
+        timestamp(from, timezone),
+        timestamp(until, timezone)
+    );
+    let mut html = String::new();
+    write!(
+        html,
+        "<!doctype html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n<title>{}</title>\n\
+         <style>{}</style>\n</head>\n<body>\n<h1>{}</h1>\n<p>{} messages, times in {}</p>\n",
+        title,
+        STYLE,
+        title,
+        messages.len(),
+        String::from(timezone)
+    )
+    .expect("Could not render transcript");
+
+    // A heading at the start of each day, and a line per message
+    let mut day = None;
+    for message in messages {
+        let (date, time) = split(timezone.shift(message.sent_at));
+        if day.as_ref() != Some(&date) {
+            if day.is_some() {
+                html.push_str("</ol>\n");
+            }
+            write!(html, "<h2>{}</h2>\n<ol>\n", date).expect("Could not render transcript");
+            day = Some(date);
+        }
+        writeln!(
+            html,
+            "<li><time datetime=\"{}\">{}</time> <b title=\"{}\">{}</b> <span>{}</span></li>",
+            timestamp(message.sent_at, timezone),
+            time,
+            message.from,
+            escape(&author(message)),
+            escape(&message.body)
+        )
+        .expect("Could not render transcript");
+    }
+    if day.is_some() {
+        html.push_str("</ol>\n");
+    }
+    html.push_str("</body>\n</html>\n");
+    html
+}
+
+const STYLE: &str = "body{font-family:sans-serif;max-width:50em;margin:2em auto}\
+ol{list-style:none;padding:0}li{margin:.2em 0}\
+time{color:#777;font-family:monospace}span{white-space:pre-wrap}";
+
+// What to call a message's author; bots go by their flair, and imported
+// messages by who they were from
+fn author(message: &ChatMessage) -> String {
+    if let Some(author) = &message.author {
+        return author.clone();
+    }
+    if message.is_bot {
+        return message.flair.clone().unwrap_or_else(|| "bot".to_string());
+    }
+    names::guest(message.from)
+}
+
+// Makes text safe to put between tags or in an attribute
+fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+// Unix millis as `2021-06-30T14:05:09Z`, or `2021-06-30T16:05:09+02:00`
+// at an offset
+fn timestamp(ms: u64, timezone: Offset) -> String {
+    let (date, time) = split(timezone.shift(ms));
+    if timezone.is_utc() {
+        return format!("{}T{}Z", date, time);
+    }
+    format!("{}T{}{}", date, time, String::from(timezone))
+}
+
+// Unix millis as a date (`2021-06-30`) and time of day (`14:05:09`)
+fn split(ms: i64) -> (String, String) {
+    let secs = ms.div_euclid(1000);
+    let (days, secs) = (secs.div_euclid(86_400), secs.rem_euclid(86_400));
+    let (year, month, day) = civil(days);
+    (
+        format!("{:04}-{:02}-{:02}", year, month, day),
+        format!(
+            "{:02}:{:02}:{:02}",
+            secs / 3600,
+            secs % 3600 / 60,
+            secs % 60
+        ),
+    )
+}
+
+// The calendar date `days` after 1970-01-01, by Howard Hinnant's
+// civil_from_days
+pub fn civil(days: i64) -> (i64, u32, u32) {
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    (year, month, day)
+}
@@ -0,0 +1,201 @@
+use futures::future::{BoxFuture, Shared};
+use futures::FutureExt;
+use serde::Deserialize;
+use std::collections::{HashMap, VecDeque};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use uuid::Uuid;
+use xtra::prelude::*;
+
+use crate::metrics;
+
+// Translations kept for reuse; the oldest go first past this
+const CACHE_SIZE: usize = 10_000;
+// How long a translation may take before it's given up on
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+// Translator - turns text into another language. Answers with why not if
+// it can't.
+#[async_trait::async_trait]
+pub trait Translator: Send + Sync {
+    async fn translate(&self, text: &str, lang: &str) -> Result<String, String>;
+}
+
+// LibreTranslate - a LibreTranslate server at `addr` (host:port), over
+// plain HTTP, so it's meant to run next to us
+pub struct LibreTranslate {
+    pub addr: String,
+    pub api_key: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct Translated {
+    #[serde(rename = "translatedText")]
+    text: Option<String>,
+    error: Option<String>,
+}
+
+impl LibreTranslate {
+    // POSTs `body` to /translate. HTTP/1.0, so the answer comes in one
+    // piece and ends when the connection does.
+    async fn post(&self, body: &[u8]) -> std::io::Result<Vec<u8>> {
+        let mut stream = TcpStream::connect(&self.addr).await?;
+        let head = format!(
+            "POST /translate HTTP/1.0\r\nHost: {}\r\nContent-Type: application/json\r\n\
+             Content-Length: {}\r\n\r\n",
+            self.addr,
+            body.len()
+        );
+        stream.write_all(head.as_bytes()).await?;
+        stream.write_all(body).await?;
+        let mut response = Vec::new();
+        stream.read_to_end(&mut response).await?;
+        Ok(response)
+    }
+}
+
+#[async_trait::async_trait]
+impl Translator for LibreTranslate {
+    async fn translate(&self, text: &str, lang: &str) -> Result<String, String> {
+        let request = serde_json::json!({
+            "q": text,
+            "source": "auto",
+            "target": lang,
+            "format": "text",
+            "api_key": self.api_key,
+        });
+        let request = serde_json::to_vec(&request).expect("Could not serialize request");
+        let response = tokio::time::timeout(TIMEOUT, self.post(&request))
+            .await
+            .map_err(|_| "timed out".to_string())?
+            .map_err(|e| e.to_string())?;
+
+        // The status doesn't matter; errors come with an `error` body
+        let body = match response.windows(4).position(|w| w == b"\r\n\r\n") {
+            Some(end) => &response[end + 4..],
+            None => return Err("no body in the response".to_string()),
+        };
+        let translated: Translated = serde_json::from_slice(body).map_err(|e| e.to_string())?;
+        match (translated.text, translated.error) {
+            (Some(text), _) => Ok(text),
+            (None, Some(error)) => Err(error),
+            (None, None) => Err("nothing in the response".to_string()),
+        }
+    }
+}
+
+// Translation - one on its way or made, for whoever wants it
+#[derive(Clone)]
+pub struct Translation(Shared<BoxFuture<'static, Option<String>>>);
+impl Translation {
+    // The translated text, once it's there. None if the translator
+    // couldn't; that's kept too, rather than asking again.
+    pub async fn text(self) -> Option<String> {
+        self.0.await
+    }
+}
+
+// TranslationCache - translations by message id and language, each asked
+// for once however many want it
+pub struct TranslationCache {
+    translator: Arc<dyn Translator>,
+    translations: HashMap<(Uuid, String), Translation>,
+    // In the order they came, for forgetting the oldest
+    order: VecDeque<(Uuid, String)>,
+}
+impl Actor for TranslationCache {}
+impl TranslationCache {
+    pub fn new(translator: Arc<dyn Translator>) -> Self {
+        Self {
+            translator,
+            translations: HashMap::new(),
+            order: VecDeque::new(),
+        }
+    }
+}
+
+// Translate - message `id` in `lang`
+pub struct Translate {
+    pub id: Uuid,
+    pub body: String,
+    pub lang: String,
+}
+impl Message for Translate {
+    type Result = Translation;
+}
+#[async_trait::async_trait]
+impl Handler<Translate> for TranslationCache {
+    async fn handle(&mut self, msg: Translate, _ctx: &mut Context<Self>) -> Translation {
+        let _timer = metrics::timer("translate");
+        let key = (msg.id, msg.lang);
+        if let Some(translation) = self.translations.get(&key) {
+            return translation.clone();
+        }
+
+        let translator = self.translator.clone();
+        let (id, lang, body) = (msg.id, key.1.clone(), msg.body);
+        let translation = async move {
+            match translator.translate(&body, &lang).await {
+                Ok(translated) => Some(translated),
+                Err(e) => {
+                    log::warn!("Could not translate {} to {}: {}", id, lang, e);
+                    None
+                }
+            }
+        }
+        .boxed()
+        .shared();
+        let translation = Translation(translation);
+        self.translations.insert(key.clone(), translation.clone());
+        self.order.push_back(key);
+        while self.order.len() > CACHE_SIZE {
+            if let Some(oldest) = self.order.pop_front() {
+                self.translations.remove(&oldest);
+            }
+        }
+        translation
+    }
+}
+
+// Original - a message as delivered, to be translated
+#[derive(Deserialize)]
+pub struct Original {
+    pub room: String,
+    pub id: Uuid,
+    pub seq: u64,
+    pub from: Uuid,
+    pub body: String,
+}
+
+#[derive(Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum Live {
+    Message(Original),
+    Batch { events: Vec<Live> },
+}
+
+// The messages in a live frame, in order
+pub fn originals(frame: &[u8]) -> Vec<Original> {
+    fn flatten(live: Live, originals: &mut Vec<Original>) {
+        match live {
+            Live::Message(original) => originals.push(original),
+            Live::Batch { events } => {
+                for live in events {
+                    flatten(live, originals);
+                }
+            }
+        }
+    }
+    let mut originals = Vec::new();
+    if let Ok(live) = serde_json::from_slice(frame) {
+        flatten(live, &mut originals);
+    }
+    originals
+}
+
+// Whether `lang` looks like a language code: `de`, `pt-BR`, `zh-Hans`
+pub fn valid_lang(lang: &str) -> bool {
+    (2..=12).contains(&lang.len()) && lang.chars().all(|c| c.is_ascii_alphabetic() || c == '-')
+}